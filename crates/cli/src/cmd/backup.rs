@@ -0,0 +1,497 @@
+//! # Backup Module
+//!
+//! The `backup` module exports every tally to a single JSON document and restores it, e.g. to
+//! keep the lockout state when a host is reimaged or to move it to another host. The document
+//! carries a format version, and every tally is written with the same serialization code the PAM
+//! module uses.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use common::{
+    config::Config,
+    error::AuthRampError,
+    state_file,
+    tally::{create_tally_file_dir, layout_tally_file, Tally},
+};
+use serde::{Deserialize, Serialize};
+use std::{ffi::OsStr, fs, io, path::Path};
+
+use super::{load_config, tally_entries, tally_exists};
+use crate::permissions::{self, Invoker, TargetState};
+use crate::{ArCliError, ArCliInfo, ArCliResult as Acr, ArCliSuccess, ArCliWarning};
+
+/// The version of the export format. Imports of other versions are refused.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// An exported tally directory.
+#[derive(Debug, Serialize, Deserialize)]
+struct Export {
+    /// The version of the format.
+    version: u32,
+    /// The instant the tallies were exported at.
+    exported_at: String,
+    /// The exported tallies.
+    tallies: Vec<ExportedTally>,
+}
+
+/// An exported tally. Instants are RFC 3339 timestamps.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedTally {
+    /// The name of the tally file, the user name or the uid.
+    user: String,
+    /// The user name last seen in a uid-keyed tally.
+    user_name: Option<String>,
+    failures: i32,
+    failure_instant: String,
+    first_failure_instant: Option<String>,
+    unlock_instant: Option<String>,
+    #[serde(default)]
+    manual_lock: bool,
+    last_success: Option<String>,
+    service: Option<String>,
+    rhost: Option<String>,
+    tty: Option<String>,
+}
+
+impl ExportedTally {
+    /// Exports a tally.
+    fn new(user: String, tally: &Tally) -> Self {
+        let instant = |instant: Option<DateTime<Utc>>| instant.map(|i| i.to_rfc3339());
+        ExportedTally {
+            user,
+            user_name: tally.user_name.clone(),
+            failures: tally.failures_count,
+            failure_instant: tally.failure_instant.to_rfc3339(),
+            first_failure_instant: instant(tally.first_failure_instant),
+            unlock_instant: instant(tally.unlock_instant),
+            manual_lock: tally.manual_lock,
+            last_success: instant(tally.last_success),
+            service: tally.service.clone(),
+            rhost: tally.rhost.clone(),
+            tty: tally.tty.clone(),
+        }
+    }
+
+    /// Restores the tally.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message naming the malformed instant.
+    fn to_tally(&self) -> Result<Tally, String> {
+        let instant = |name: &str, instant: Option<&String>| {
+            instant
+                .map(|i| i.parse::<DateTime<Utc>>())
+                .transpose()
+                .map_err(|e| format!("invalid {name}: {e}"))
+        };
+
+        Ok(Tally {
+            failures_count: self.failures,
+            failure_instant: instant("failure_instant", Some(&self.failure_instant))?
+                .unwrap_or_default(),
+            first_failure_instant: instant(
+                "first_failure_instant",
+                self.first_failure_instant.as_ref(),
+            )?,
+            unlock_instant: instant("unlock_instant", self.unlock_instant.as_ref())?,
+            manual_lock: self.manual_lock,
+            last_success: instant("last_success", self.last_success.as_ref())?,
+            service: self.service.clone(),
+            rhost: self.rhost.clone(),
+            tty: self.tty.clone(),
+            user_name: self.user_name.clone(),
+            ..Tally::default()
+        })
+    }
+}
+
+/// The most recent instant a tally was updated at.
+fn last_update(tally: &Tally) -> DateTime<Utc> {
+    tally
+        .last_success
+        .map_or(tally.failure_instant, |last_success| {
+            last_success.max(tally.failure_instant)
+        })
+}
+
+/// Exports the tallies of all users to a JSON file.
+///
+/// # Arguments
+///
+/// - `output`: The file to write the export to.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If the export is written, returns `ArCliResult::Success` with the number of tallies.
+/// - If the invoker isn't permitted by `[Cli.permissions]`, returns `ArCliResult::Denied`.
+/// - If the tally directory can't be read or the file can't be written, returns
+///   `ArCliResult::Error` with the error message.
+#[must_use]
+pub fn export(output: &str) -> Acr {
+    let config = load_config();
+
+    if let Some(denied) = permissions::check(
+        "export",
+        "*",
+        TargetState::default(),
+        &config,
+        &Invoker::current(),
+    ) {
+        return denied;
+    }
+
+    export_tallies(&config, Path::new(output), Utc::now())
+}
+
+/// Exports the tallies of the tally directory to a JSON file.
+///
+/// Tallies that can't be read are reported as warnings and left out. The file is only readable
+/// by its owner, like the tallies.
+///
+/// # Arguments
+///
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `output`: The file to write the export to.
+/// - `now`: The instant of the export.
+///
+/// # Returns
+///
+/// An `ArCliResult` with the number of exported tallies.
+fn export_tallies(config: &Config, output: &Path, now: DateTime<Utc>) -> Acr {
+    let tallies = match tally_entries(&config.tally_dir, config) {
+        Ok(tallies) => tallies
+            .filter_map(|(user, tally)| match tally {
+                Ok(tally) => Some(ExportedTally::new(user, &tally)),
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        ArCliWarning {
+                            message: format!("Skipping tally of user '{}': {e}", user.yellow()),
+                        }
+                    );
+                    None
+                }
+            })
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            return AuthRampError::io(
+                format!(
+                    "Error reading tally directory {}",
+                    config.tally_dir.display()
+                ),
+                e,
+            )
+            .into()
+        }
+    };
+
+    let export = Export {
+        version: EXPORT_VERSION,
+        exported_at: now.to_rfc3339(),
+        tallies,
+    };
+
+    let written = serde_json::to_vec_pretty(&export)
+        .map_err(io::Error::from)
+        .and_then(|json| state_file::write(output, &json, 0o600, true));
+    if let Err(e) = written {
+        return AuthRampError::io(format!("Error writing export file {}", output.display()), e)
+            .into();
+    }
+
+    Acr::Success(Some(ArCliSuccess {
+        message: format!(
+            "{} tallies exported to: '{}'",
+            export.tallies.len(),
+            output.display().to_string().yellow()
+        ),
+        ..Default::default()
+    }))
+}
+
+/// Restores the tallies of a JSON export.
+///
+/// # Arguments
+///
+/// - `input`: The export file.
+/// - `force`: Overwrite local tallies updated after the exported ones.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If the export is restored, returns `ArCliResult::Success` with the number of tallies.
+/// - If the export has no tallies, returns `ArCliResult::Info`.
+/// - If the invoker isn't permitted by `[Cli.permissions]`, returns `ArCliResult::Denied`.
+/// - If the export can't be read, is malformed or has another version, returns
+///   `ArCliResult::Error` with the error message.
+#[must_use]
+pub fn import(input: &str, force: bool) -> Acr {
+    let config = load_config();
+
+    if let Some(denied) = permissions::check(
+        "import",
+        "*",
+        TargetState::default(),
+        &config,
+        &Invoker::current(),
+    ) {
+        return denied;
+    }
+
+    import_tallies(&config, Path::new(input), force)
+}
+
+/// Restores the tallies of a JSON export into the tally directory.
+///
+/// Tallies with names that can't be used as tally file names, malformed tallies and tallies
+/// updated locally after the export are skipped with a warning.
+///
+/// # Arguments
+///
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `input`: The export file.
+/// - `force`: Overwrite local tallies updated after the exported ones.
+///
+/// # Returns
+///
+/// An `ArCliResult` with the number of restored and skipped tallies.
+fn import_tallies(config: &Config, input: &Path, force: bool) -> Acr {
+    let export: Export = match fs::read(input)
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()))
+    {
+        Ok(export) => export,
+        Err(e) => {
+            return Acr::Error(ArCliError {
+                message: format!("Error reading export file {}: {e}", input.display()),
+            })
+        }
+    };
+
+    if export.version != EXPORT_VERSION {
+        return Acr::Error(ArCliError {
+            message: format!(
+                "Unsupported export version {}, expected {EXPORT_VERSION}",
+                export.version
+            ),
+        });
+    }
+
+    if export.tallies.is_empty() {
+        return Acr::Info(ArCliInfo {
+            message: "No tallies found in the export".to_string(),
+            ..Default::default()
+        });
+    }
+
+    let (mut imported, mut skipped) = (0, 0);
+    for exported in &export.tallies {
+        match import_tally(config, exported, force) {
+            Ok(()) => imported += 1,
+            Err(message) => {
+                eprintln!(
+                    "{}",
+                    ArCliWarning {
+                        message: format!(
+                            "Skipping tally of user '{}': {message}",
+                            exported.user.yellow()
+                        ),
+                    }
+                );
+                skipped += 1;
+            }
+        }
+    }
+
+    Acr::Success(Some(ArCliSuccess {
+        message: format!("{imported} tallies imported, {skipped} skipped"),
+        ..Default::default()
+    }))
+}
+
+/// Restores a single exported tally.
+///
+/// # Arguments
+///
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `exported`: The exported tally.
+/// - `force`: Overwrite a local tally updated after the exported one.
+///
+/// # Returns
+///
+/// A message describing why the tally wasn't restored.
+fn import_tally(config: &Config, exported: &ExportedTally, force: bool) -> Result<(), String> {
+    let tally_path = layout_tally_file(config, OsStr::new(&exported.user))
+        .map_err(|e| format!("invalid user name: {e}"))?;
+    let tally = exported.to_tally()?;
+
+    if !force && tally_exists(&tally_path).map_err(|e| e.to_string())? {
+        let local = Tally::read_trusted_tally_file(&tally_path, config)
+            .map_err(|e| format!("{e}, use --force to overwrite it"))?;
+        if last_update(&local) > last_update(&tally) {
+            return Err(
+                "the local tally is newer than the exported one, use --force to overwrite it"
+                    .to_string(),
+            );
+        }
+    }
+
+    create_tally_file_dir(&tally_path, config)
+        .and_then(|()| tally.write_tally_file(&tally_path, config))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_export_import() {
+        let temp_dir = TempDir::new("test_export_import").unwrap();
+        let export_file = temp_dir.path().join("state.json");
+        let now = Utc::now();
+        let config = |host: &str| Config {
+            tally_dir: temp_dir.path().join(host),
+            ..Config::default()
+        };
+        let old_host = config("old_host");
+        let new_host = config("new_host");
+
+        fs::create_dir(&old_host.tally_dir).unwrap();
+        let locked = Tally {
+            failures_count: 7,
+            failure_instant: now - Duration::minutes(5),
+            first_failure_instant: Some(now - Duration::minutes(10)),
+            unlock_instant: Some(now + Duration::hours(2)),
+            manual_lock: true,
+            rhost: Some("192.0.2.1".to_string()),
+            ..Tally::default()
+        };
+        locked
+            .write_tally_file(&old_host.tally_dir.join("locked_user"), &old_host)
+            .unwrap();
+        fs::write(old_host.tally_dir.join("broken_user"), "not a tally").unwrap();
+
+        assert!(matches!(
+            export_tallies(&old_host, &export_file, now),
+            Acr::Success(_)
+        ));
+        assert_eq!(
+            fs::metadata(&export_file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let json: serde_json::Value =
+            serde_json::from_slice(&fs::read(&export_file).unwrap()).unwrap();
+        assert_eq!(json["version"], EXPORT_VERSION);
+        assert_eq!(json["tallies"].as_array().unwrap().len(), 1);
+        assert_eq!(json["tallies"][0]["user"], "locked_user");
+        assert_eq!(json["tallies"][0]["rhost"], "192.0.2.1");
+
+        // restored with all instants
+        assert!(matches!(
+            import_tallies(&new_host, &export_file, false),
+            Acr::Success(_)
+        ));
+        let restored = Tally::read_tally_file(&new_host.tally_dir.join("locked_user")).unwrap();
+        assert_eq!(restored.failures_count, 7);
+        assert_eq!(restored.failure_instant, locked.failure_instant);
+        assert_eq!(restored.first_failure_instant, locked.first_failure_instant);
+        assert_eq!(restored.unlock_instant, locked.unlock_instant);
+        assert!(restored.manual_lock);
+        assert_eq!(restored.rhost.as_deref(), Some("192.0.2.1"));
+
+        // newer local state is kept unless forced
+        let newer = Tally {
+            failures_count: 1,
+            failure_instant: now,
+            ..Tally::default()
+        };
+        let tally_path = new_host.tally_dir.join("locked_user");
+        newer.write_tally_file(&tally_path, &new_host).unwrap();
+        let Acr::Success(Some(success)) = import_tallies(&new_host, &export_file, false) else {
+            panic!("import failed");
+        };
+        assert_eq!(success.message, "0 tallies imported, 1 skipped");
+        assert_eq!(
+            Tally::read_tally_file(&tally_path).unwrap().failures_count,
+            1
+        );
+        import_tallies(&new_host, &export_file, true);
+        assert_eq!(
+            Tally::read_tally_file(&tally_path).unwrap().failures_count,
+            7
+        );
+    }
+
+    #[test]
+    fn test_import_validation() {
+        let temp_dir = TempDir::new("test_import_validation").unwrap();
+        let export_file = temp_dir.path().join("state.json");
+        let config = Config {
+            tally_dir: temp_dir.path().join("tally"),
+            ..Config::default()
+        };
+
+        // names escaping the tally directory are refused
+        fs::write(
+            &export_file,
+            serde_json::json!({
+                "version": EXPORT_VERSION,
+                "exported_at": Utc::now().to_rfc3339(),
+                "tallies": [
+                    { "user": "../evil", "failures": 3, "failure_instant": Utc::now().to_rfc3339() },
+                    { "user": "bad_instant", "failures": 3, "failure_instant": "yesterday" },
+                ],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let Acr::Success(Some(success)) = import_tallies(&config, &export_file, false) else {
+            panic!("import failed");
+        };
+        assert_eq!(success.message, "0 tallies imported, 2 skipped");
+        assert!(!temp_dir.path().join("evil").exists());
+        assert!(!config.tally_dir.join("bad_instant").exists());
+
+        // other versions and malformed documents are refused
+        fs::write(
+            &export_file,
+            r#"{"version": 2, "exported_at": "", "tallies": []}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            import_tallies(&config, &export_file, false),
+            Acr::Error(_)
+        ));
+        fs::write(&export_file, "not json").unwrap();
+        assert!(matches!(
+            import_tallies(&config, &export_file, false),
+            Acr::Error(_)
+        ));
+    }
+}
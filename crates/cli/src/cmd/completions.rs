@@ -0,0 +1,191 @@
+//! # Completions Module
+//!
+//! The `completions` module generates the shell completion scripts of the `authramp` CLI. The
+//! scripts complete `--user` with the users that have a tally, which they list with the hidden
+//! `__list-users` command.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use clap_complete::Shell;
+use std::path::Path;
+
+use super::{load_config_silently, tally_entries};
+use crate::ArCliResult as Acr;
+
+/// Completes `--user` of bash with the users that have a tally.
+const BASH_USERS: &str = r#"
+_authramp_users() {
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "${prev}" == "--user" || "${prev}" == "-u" ]]; then
+        COMPREPLY=($(compgen -W "$(authramp __list-users 2>/dev/null)" -- "${COMP_WORDS[COMP_CWORD]}"))
+        return 0
+    fi
+    _authramp "$@"
+}
+
+complete -F _authramp_users -o nosort -o bashdefault -o default authramp
+"#;
+
+/// Completes `--user` of zsh with the users that have a tally.
+const ZSH_USERS: &str = r#"
+_authramp_users() {
+    local -a users
+    users=(${(f)"$(authramp __list-users 2>/dev/null)"})
+    _describe 'user' users
+}
+"#;
+
+/// Completes `--user` of fish with the users that have a tally.
+const FISH_USERS: &str = r#"
+complete -c authramp -n "__fish_seen_subcommand_from reset lock set-unlock status" -s u -l user -x -a "(authramp __list-users 2>/dev/null)"
+"#;
+
+/// Generates the completion script of a shell.
+///
+/// # Arguments
+///
+/// - `shell`: The shell to generate the script for.
+/// - `command`: The definition of the CLI.
+///
+/// # Returns
+///
+/// An `ArCliResult::Plain` with the completion script.
+#[must_use]
+pub fn generate(shell: Shell, command: &clap::Command) -> Acr {
+    // hidden subcommands like `__list-users` aren't offered for completion
+    let mut command = clap::Command::new("authramp")
+        .args(command.get_arguments().cloned())
+        .subcommands(
+            command
+                .get_subcommands()
+                .filter(|subcommand| !subcommand.is_hide_set())
+                .cloned(),
+        );
+
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, "authramp", &mut script);
+    let mut script = String::from_utf8_lossy(&script).into_owned();
+
+    match shell {
+        Shell::Bash => script.push_str(BASH_USERS),
+        Shell::Zsh => {
+            // the user values are completed by the helper instead of as plain strings
+            script = script.replace(":USER:_default", ":USER:_authramp_users");
+            script.push_str(ZSH_USERS);
+        }
+        Shell::Fish => script.push_str(FISH_USERS),
+        _ => (),
+    }
+
+    Acr::Plain(script.trim_end().to_string())
+}
+
+/// Lists the users that have a tally, one per line, for the completion scripts.
+///
+/// Nothing is listed if the tally directory can't be read.
+///
+/// # Returns
+///
+/// An `ArCliResult::Plain` with the user names.
+#[must_use]
+pub fn list_users() -> Acr {
+    let config = load_config_silently();
+
+    Acr::Plain(users(&config.tally_dir, &config).join("\n"))
+}
+
+/// The sorted names of the users that have a tally in a tally directory.
+///
+/// Uid-keyed tallies are listed with the user name they remember, the placeholders of unknown
+/// users are left out.
+///
+/// # Arguments
+///
+/// - `tally_dir`: The directory containing the tally files.
+/// - `config`: The loaded `AuthRamp` configuration.
+///
+/// # Returns
+///
+/// The user names.
+fn users(tally_dir: &Path, config: &common::config::Config) -> Vec<String> {
+    let Ok(tallies) = tally_entries(tally_dir, config) else {
+        return Vec::new();
+    };
+
+    let mut users: Vec<String> = tallies
+        .map(|(user, tally)| tally.ok().and_then(|tally| tally.user_name).unwrap_or(user))
+        .filter(|user| common::unknown::placeholder_hash(user).is_none())
+        .collect();
+    users.sort();
+    users.dedup();
+    users
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, Command};
+    use common::{config::Config, tally::Tally};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_users() {
+        let temp_dir = TempDir::new("test_completion_users").unwrap();
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+
+        fs::write(temp_dir.path().join("bob"), "not a tally").unwrap();
+        fs::write(temp_dir.path().join(".alice.corrupt-1"), "").unwrap();
+        Tally {
+            user_name: Some("alice".to_string()),
+            ..Tally::default()
+        }
+        .write_tally_file(&temp_dir.path().join("1000"), &config)
+        .unwrap();
+
+        assert_eq!(users(temp_dir.path(), &config), vec!["alice", "bob"]);
+
+        // unreadable directories list nothing
+        assert!(users(&temp_dir.path().join("missing"), &config).is_empty());
+    }
+
+    #[test]
+    fn test_generate() {
+        let command = Command::new("authramp")
+            .subcommand(
+                Command::new("reset")
+                    .arg(Arg::new("user").long("user").short('u').value_name("USER")),
+            )
+            .subcommand(Command::new("__list-users").hide(true));
+
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let Acr::Plain(script) = generate(shell, &command) else {
+                panic!("no script for {shell}");
+            };
+            assert!(
+                script.contains("authramp __list-users 2>/dev/null"),
+                "{shell}"
+            );
+            assert_eq!(script.matches("__list-users").count(), 1, "{shell}");
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! # Config Module
+//!
+//! The `config` module provides functionality to validate the configuration file and to show the
+//! effective configuration. The PAM module falls back to the defaults for a file it can't parse,
+//! so a typo in a key changes the policy. The check reports these cases with the parser of
+//! `common::config`, which the PAM module logs with as well.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use colored::Colorize;
+use common::config::{Config, Severity, DEFAULT_CONFIG_FILE_PATH};
+use pam::PamHandle;
+use std::{fmt::Write, fs, path::Path};
+
+use crate::{ArCliError, ArCliResult as Acr, ArCliSuccess, ArCliWarning};
+
+/// Checks a configuration file strictly.
+///
+/// # Arguments
+///
+/// - `path`: The configuration file, the default path if not set.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If the configuration is valid, returns `ArCliResult::Success`.
+/// - If the file can't be read or has problems, returns `ArCliResult::Error` listing them.
+#[must_use]
+pub fn check(path: Option<&str>) -> Acr {
+    check_file(Path::new(path.unwrap_or(DEFAULT_CONFIG_FILE_PATH)))
+}
+
+/// Checks the configuration file at a path.
+///
+/// # Arguments
+///
+/// - `path`: The configuration file.
+///
+/// # Returns
+///
+/// An `ArCliResult` with the problems found.
+fn check_file(path: &Path) -> Acr {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            return Acr::Error(ArCliError {
+                message: format!("Error reading {}: {e}", path.display()),
+            })
+        }
+    };
+
+    let issues = Config::check(&content);
+    if issues.is_empty() {
+        return Acr::Success(Some(ArCliSuccess {
+            message: format!("{} is valid", path.display()),
+            ..Default::default()
+        }));
+    }
+
+    let mut message = if issues.iter().any(|issue| issue.severity == Severity::Fatal) {
+        format!(
+            "{} can't be parsed, all values fall back to their defaults:",
+            path.display()
+        )
+    } else {
+        format!(
+            "{} problem{} in {}, the affected values fall back to their defaults:",
+            issues.len(),
+            if issues.len() == 1 { "" } else { "s" },
+            path.display()
+        )
+    };
+    for issue in issues {
+        let _ = write!(
+            message,
+            "\n  {}: {}",
+            issue.location.yellow(),
+            issue.message
+        );
+    }
+    Acr::Error(ArCliError { message })
+}
+
+/// Shows the effective configuration, with the defaults of everything not configured.
+///
+/// # Arguments
+///
+/// - `path`: The configuration file, the default path if not set.
+/// - `json`: Render the configuration as JSON instead of TOML.
+///
+/// # Returns
+///
+/// `ArCliResult::Plain` with the rendered configuration.
+#[must_use]
+pub fn show(path: Option<&str>, json: bool) -> Acr {
+    let path = path.unwrap_or(DEFAULT_CONFIG_FILE_PATH);
+
+    if !Path::new(path).exists() {
+        eprintln!(
+            "{}",
+            ArCliWarning {
+                message: format!("{path} doesn't exist, showing the defaults"),
+            }
+        );
+    }
+
+    render(&Config::load_file::<PamHandle>(Some(path), None), json)
+}
+
+/// Renders a configuration as TOML or JSON.
+fn render(config: &Config, json: bool) -> Acr {
+    let effective = config.to_toml();
+
+    if json {
+        Acr::Plain(serde_json::to_string(&effective).unwrap_or_default())
+    } else {
+        Acr::Plain(effective.to_string().trim_end().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_check_file() {
+        let temp_dir = TempDir::new("test_check_file").unwrap();
+        let path = temp_dir.path().join("authramp.conf");
+
+        assert!(matches!(check_file(&path), Acr::Error(_)));
+
+        fs::write(&path, "[Configuration]\nfree_tries = 3\n").unwrap();
+        assert!(matches!(check_file(&path), Acr::Success(_)));
+
+        fs::write(&path, "[Settings]\nfree_tries = 3\n").unwrap();
+        let Acr::Error(error) = check_file(&path) else {
+            panic!("expected the legacy section to be reported");
+        };
+        assert!(error.message.contains("1 problem in"));
+        assert!(error.message.contains("rename it to [Configuration]"));
+
+        // a stray quote voids the whole file, the error is shown as the parser reports it
+        fs::write(&path, "[Configuration]\nfree_tries = \"3\n").unwrap();
+        let Acr::Error(error) = check_file(&path) else {
+            panic!("expected the syntax error to be reported");
+        };
+        assert!(error.message.contains("can't be parsed"));
+        assert!(error.message.contains("line 2, column 16"));
+    }
+
+    #[test]
+    fn test_render() {
+        let config = Config {
+            free_tries: 3,
+            ..Config::default()
+        };
+
+        let Acr::Plain(toml) = render(&config, false) else {
+            panic!("expected plain output");
+        };
+        assert!(toml.starts_with("[Configuration]\n"));
+        assert!(toml.contains("\nfree_tries = 3\n"));
+
+        let Acr::Plain(json) = render(&config, true) else {
+            panic!("expected plain output");
+        };
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["Configuration"]["free_tries"], 3);
+        assert_eq!(json["Configuration"]["base_delay_seconds"], 30);
+    }
+}
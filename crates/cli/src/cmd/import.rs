@@ -0,0 +1,432 @@
+//! # Import Module
+//!
+//! The `import` module carries the failures recorded by `pam_faillock` over to `AuthRamp` tallies,
+//! so existing lockouts survive the switch from `pam_faillock`. Every file in the faillock
+//! directory holds the failure records of the user it is named after, as an array of `struct
+//! tally` from `faillock.h`. The valid records become the failures of the tally, the most recent
+//! one its last failure instant.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Duration, Utc};
+use colored::Colorize;
+use common::{
+    config::{Config, TallyKey},
+    error::AuthRampError,
+    settings::Settings,
+    tally::{create_tally_file_dir, Tally},
+};
+use std::{fmt::Write, fs, os::unix::ffi::OsStrExt, path::Path};
+
+use super::{load_config, tally_exists, tally_target};
+use crate::permissions::{self, Invoker, TargetState};
+use crate::{ArCliInfo, ArCliResult as Acr, ArCliSuccess, ArCliTally, ArCliWarning};
+
+/// The default tally directory of `pam_faillock`.
+pub const FAILLOCK_DIR: &str = "/var/run/faillock";
+
+/// The size of a `struct tally` record: the source, two `u16` and the `u64` time.
+const RECORD_SIZE: usize = 64;
+
+/// The size of the source field of a record.
+const SOURCE_SIZE: usize = 52;
+
+/// The record holds a failure.
+const STATUS_VALID: u16 = 0x1;
+
+/// The source of the record is a remote host.
+const STATUS_RHOST: u16 = 0x2;
+
+/// The source of the record is a terminal.
+const STATUS_TTY: u16 = 0x4;
+
+/// A valid failure record of a faillock tally file.
+#[derive(Debug, PartialEq)]
+struct FaillockRecord {
+    /// The remote host or terminal of the failure, if recorded.
+    source: Option<String>,
+    /// The status flags of the record.
+    status: u16,
+    /// The instant of the failure.
+    time: DateTime<Utc>,
+}
+
+/// What importing the faillock tally of a user did.
+#[derive(Debug)]
+enum Outcome {
+    /// The failures were written to the tally.
+    Imported(ArCliTally),
+    /// The tally already has at least as many failures.
+    Kept(i32),
+    /// No failure is recent enough to be imported.
+    Expired,
+}
+
+/// Imports the faillock tallies of all users.
+///
+/// The function reads the configuration and converts every faillock tally file in the directory
+/// into an `AuthRamp` tally. Files that can't be read or imported are reported as warnings
+/// without aborting the import.
+///
+/// # Arguments
+///
+/// - `dir`: The faillock tally directory, `/var/run/faillock` if not given.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If faillock tallies are found, returns `ArCliResult::Success` with a summary per user.
+/// - If the invoker isn't permitted by `[Cli.permissions]`, returns `ArCliResult::Denied`.
+/// - If the directory has no faillock tallies, returns `ArCliResult::Info`.
+/// - If the directory can't be read, returns `ArCliResult::Error` with the error message.
+#[must_use]
+pub fn faillock(dir: Option<&str>) -> Acr {
+    let config = load_config();
+
+    if let Some(denied) = permissions::check(
+        "import-faillock",
+        "*",
+        TargetState::default(),
+        &config,
+        &Invoker::current(),
+    ) {
+        return denied;
+    }
+
+    import_dir(Path::new(dir.unwrap_or(FAILLOCK_DIR)), &config, Utc::now())
+}
+
+/// Imports the faillock tallies of a directory at a given instant.
+///
+/// # Arguments
+///
+/// - `faillock_dir`: The faillock tally directory.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `now`: The instant the age of the failures is evaluated at.
+///
+/// # Returns
+///
+/// An `ArCliResult` with the summary per user.
+fn import_dir(faillock_dir: &Path, config: &Config, now: DateTime<Utc>) -> Acr {
+    let mut users: Vec<String> = match fs::read_dir(faillock_dir) {
+        Ok(dir_entries) => dir_entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .filter(|entry| !entry.file_name().as_bytes().starts_with(b"."))
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(e) => {
+            return AuthRampError::io(
+                format!(
+                    "Error reading faillock directory {}",
+                    faillock_dir.display()
+                ),
+                e,
+            )
+            .into()
+        }
+    };
+
+    if users.is_empty() {
+        return Acr::Info(ArCliInfo {
+            message: format!(
+                "No faillock tallies found in '{}'",
+                faillock_dir.display().to_string().yellow()
+            ),
+            ..Default::default()
+        });
+    }
+
+    users.sort();
+
+    let mut summary = String::new();
+    let mut tallies = Vec::new();
+    let mut failed = 0;
+    for user in users {
+        let status = match import_user(&faillock_dir.join(&user), &user, config, now) {
+            Ok(Outcome::Imported(tally)) => {
+                let status = format!("imported {} failures", tally.failures);
+                tallies.push(tally);
+                status
+            }
+            Ok(Outcome::Kept(failures)) => format!("kept the tally with {failures} failures"),
+            Ok(Outcome::Expired) => "no recent failures".to_string(),
+            Err(message) => {
+                eprintln!(
+                    "{}",
+                    ArCliWarning {
+                        message: format!(
+                            "Skipping faillock tally of user '{}': {message}",
+                            user.yellow()
+                        ),
+                    }
+                );
+                failed += 1;
+                "failed".to_string()
+            }
+        };
+        let _ = write!(summary, "\n{user:<32} {status}");
+    }
+
+    Acr::Success(Some(ArCliSuccess {
+        message: format!(
+            "{} faillock tallies imported, {failed} failed{summary}",
+            tallies.len()
+        ),
+        tallies,
+    }))
+}
+
+/// Imports the faillock tally file of a user.
+///
+/// Failures older than `reset_after_seconds` are skipped. An existing tally is only replaced if
+/// it has fewer failures, so the import can be repeated.
+///
+/// # Arguments
+///
+/// - `path`: The faillock tally file.
+/// - `user`: The user the file is named after.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `now`: The instant the age of the failures is evaluated at.
+///
+/// # Returns
+///
+/// What the import did, or a message describing why the tally couldn't be imported.
+fn import_user(
+    path: &Path,
+    user: &str,
+    config: &Config,
+    now: DateTime<Utc>,
+) -> Result<Outcome, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+
+    let mut config = config.clone();
+    config.apply_user_override(user);
+
+    let expired_before = (config.reset_after_seconds > 0)
+        .then(|| now - Duration::seconds(config.reset_after_seconds));
+    let records: Vec<FaillockRecord> = parse_records(&data)
+        .into_iter()
+        .filter(|record| expired_before.is_none_or(|instant| record.time > instant))
+        .collect();
+
+    let (Some(first), Some(last)) = (
+        records.iter().map(|record| record.time).min(),
+        records.iter().max_by_key(|record| record.time),
+    ) else {
+        return Ok(Outcome::Expired);
+    };
+
+    let (_, tally_path) =
+        tally_target(&config, Some(user), None).map_err(|e| e.message().to_string())?;
+    let mut tally = if tally_exists(&tally_path).map_err(|e| e.to_string())? {
+        Tally::read_trusted_tally_file(&tally_path, &config).map_err(|e| e.to_string())?
+    } else {
+        Tally::default()
+    };
+
+    let failures = i32::try_from(records.len()).unwrap_or(i32::MAX);
+    if tally.failures_count >= failures {
+        return Ok(Outcome::Kept(tally.failures_count));
+    }
+
+    tally.failures_count = failures;
+    tally.failure_instant = last.time;
+    tally.first_failure_instant = Some(first);
+    tally.rhost = last
+        .source
+        .clone()
+        .filter(|_| last.status & STATUS_RHOST != 0);
+    tally.tty = last
+        .source
+        .clone()
+        .filter(|_| last.status & STATUS_TTY != 0);
+    if config.tally_key == TallyKey::Uid {
+        tally.user_name = Some(user.to_string());
+    }
+
+    create_tally_file_dir(&tally_path, &config)
+        .and_then(|()| tally.write_tally_file(&tally_path, &config))
+        .map_err(|e| e.to_string())?;
+
+    let settings = Settings {
+        config,
+        ..Settings::default()
+    };
+    let unlock_instant = tally.effective_unlock_instant(&settings);
+    Ok(Outcome::Imported(ArCliTally {
+        user: user.to_string(),
+        failures,
+        unlock_instant,
+        locked: unlock_instant.is_some_and(|unlock_instant| now < unlock_instant),
+    }))
+}
+
+/// Parses the valid failure records of a faillock tally file.
+///
+/// The records are written in the byte order of the host. A truncated record at the end is
+/// ignored, like `pam_faillock` does.
+///
+/// # Arguments
+///
+/// - `data`: The contents of the faillock tally file.
+///
+/// # Returns
+///
+/// The valid records.
+fn parse_records(data: &[u8]) -> Vec<FaillockRecord> {
+    data.chunks_exact(RECORD_SIZE)
+        .filter_map(|record| {
+            let status = u16::from_ne_bytes([record[SOURCE_SIZE + 2], record[SOURCE_SIZE + 3]]);
+            let time = u64::from_ne_bytes(record[SOURCE_SIZE + 4..].try_into().ok()?);
+            if status & STATUS_VALID == 0 {
+                return None;
+            }
+
+            // the source isn't necessarily NUL terminated
+            let source = &record[..SOURCE_SIZE];
+            let source = &source[..source.iter().position(|&b| b == 0).unwrap_or(SOURCE_SIZE)];
+
+            Some(FaillockRecord {
+                source: (!source.is_empty()).then(|| String::from_utf8_lossy(source).into_owned()),
+                status,
+                time: DateTime::from_timestamp(i64::try_from(time).ok()?, 0)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    /// Encodes a faillock record like `pam_faillock` writes it.
+    fn record(source: &str, status: u16, time: DateTime<Utc>) -> Vec<u8> {
+        let mut record = vec![0; RECORD_SIZE];
+        record[..source.len()].copy_from_slice(source.as_bytes());
+        record[SOURCE_SIZE + 2..SOURCE_SIZE + 4].copy_from_slice(&status.to_ne_bytes());
+        record[SOURCE_SIZE + 4..]
+            .copy_from_slice(&u64::try_from(time.timestamp()).unwrap().to_ne_bytes());
+        record
+    }
+
+    #[test]
+    fn test_parse_records() {
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut data = record("192.0.2.1", STATUS_VALID | STATUS_RHOST, time);
+        // cleared records are invalid
+        data.extend(record("tty1", STATUS_TTY, time));
+        data.extend(record(&"x".repeat(SOURCE_SIZE), STATUS_VALID, time));
+        // a truncated record
+        data.extend([0; 10]);
+
+        assert_eq!(
+            parse_records(&data),
+            vec![
+                FaillockRecord {
+                    source: Some("192.0.2.1".to_string()),
+                    status: STATUS_VALID | STATUS_RHOST,
+                    time,
+                },
+                FaillockRecord {
+                    source: Some("x".repeat(SOURCE_SIZE)),
+                    status: STATUS_VALID,
+                    time,
+                },
+            ]
+        );
+        assert!(parse_records(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_import_dir() {
+        let temp_dir = TempDir::new("test_import_dir").unwrap();
+        let faillock_dir = temp_dir.path().join("faillock");
+        let tally_dir = temp_dir.path().join("tally");
+        fs::create_dir(&faillock_dir).unwrap();
+        let now = Utc::now();
+        let config = Config {
+            tally_dir: tally_dir.clone(),
+            reset_after_seconds: 3600,
+            ..Config::default()
+        };
+
+        // seven recent failures lock the account, the old one is skipped
+        let mut data = record("", STATUS_VALID, now - Duration::hours(2));
+        for seconds in (1..=7).rev() {
+            data.extend(record(
+                "192.0.2.1",
+                STATUS_VALID | STATUS_RHOST,
+                now - Duration::seconds(seconds),
+            ));
+        }
+        fs::write(faillock_dir.join("locked_user"), data).unwrap();
+        fs::write(
+            faillock_dir.join("old_user"),
+            record("tty1", STATUS_VALID | STATUS_TTY, now - Duration::hours(2)),
+        )
+        .unwrap();
+        // a name that can't be used as a tally file name fails on its own
+        fs::write(
+            faillock_dir.join("bad..user"),
+            record("", STATUS_VALID, now),
+        )
+        .unwrap();
+
+        let Acr::Success(Some(success)) = import_dir(&faillock_dir, &config, now) else {
+            panic!("import failed");
+        };
+        assert_eq!(success.tallies.len(), 1);
+        assert_eq!(success.tallies[0].user, "locked_user");
+        assert!(success.tallies[0].locked);
+        assert!(success.message.contains("no recent failures"));
+        assert!(success
+            .message
+            .contains("1 faillock tallies imported, 1 failed"));
+
+        let tally = Tally::read_tally_file(&tally_dir.join("locked_user")).unwrap();
+        assert_eq!(tally.failures_count, 7);
+        assert_eq!(
+            tally.failure_instant.timestamp(),
+            (now - Duration::seconds(1)).timestamp()
+        );
+        assert_eq!(
+            tally
+                .first_failure_instant
+                .map(|instant| instant.timestamp()),
+            Some((now - Duration::seconds(7)).timestamp())
+        );
+        assert_eq!(tally.rhost.as_deref(), Some("192.0.2.1"));
+        assert!(!tally_dir.join("old_user").exists());
+
+        // importing again keeps the tally
+        let Acr::Success(Some(success)) = import_dir(&faillock_dir, &config, now) else {
+            panic!("import failed");
+        };
+        assert!(success.tallies.is_empty());
+        assert!(success.message.contains("kept the tally with 7 failures"));
+
+        assert!(matches!(
+            import_dir(&temp_dir.path().join("missing"), &config, now),
+            Acr::Error(_)
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+//! # List Module
+//!
+//! The `list` module provides functionality to enumerate the tallies stored in the tally directory.
+//! Every tally file is parsed with the same code the PAM module uses and summarized in a table of
+//! user, failures and unlock time.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use common::{config::Config, error::AuthRampError, settings::Settings};
+use std::{fmt::Write, path::Path};
+
+use super::{load_config, tally_entries, user_label};
+use crate::{ArCliInfo, ArCliResult as Acr, ArCliSuccess, ArCliTally, ArCliWarning};
+
+/// Lists the tallies of all users.
+///
+/// The function reads the configuration and summarizes every tally file in the tally directory.
+/// Files that cannot be parsed are reported as warnings.
+///
+/// # Arguments
+///
+/// - `locked_only`: Only list users that are currently locked.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If tallies are found, returns `ArCliResult::Success` with the table.
+/// - If the tally directory is missing or has no matching tallies, returns `ArCliResult::Info`.
+/// - If the tally directory cannot be read, returns `ArCliResult::Error` with the error message.
+#[must_use]
+pub fn users(locked_only: bool) -> Acr {
+    let config = load_config();
+
+    let tally_dir = config.tally_dir.clone();
+
+    list_tallies(&tally_dir, config, locked_only, Utc::now())
+}
+
+/// Builds the tally table for a tally directory at a given instant.
+///
+/// # Arguments
+///
+/// - `tally_dir`: The directory containing the tally files.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `locked_only`: Only list users that are locked at `now`.
+/// - `now`: The instant the lock state is evaluated at.
+///
+/// # Returns
+///
+/// An `ArCliResult` containing the table.
+fn list_tallies(tally_dir: &Path, config: Config, locked_only: bool, now: DateTime<Utc>) -> Acr {
+    let settings = Settings {
+        config,
+        ..Settings::default()
+    };
+
+    let tallies = match tally_entries(tally_dir, &settings.config) {
+        Ok(tallies) => tallies,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Acr::Info(ArCliInfo {
+                message: format!(
+                    "No tally directory found at: '{}'",
+                    tally_dir.display().to_string().yellow()
+                ),
+                ..Default::default()
+            })
+        }
+        Err(e) => {
+            return AuthRampError::io(
+                format!("Error reading tally directory {}", tally_dir.display()),
+                e,
+            )
+            .into()
+        }
+    };
+
+    let mut entries: Vec<ArCliTally> = tallies
+        .filter_map(|(user, tally)| {
+            match tally {
+                Ok(tally) => {
+                    let unlock_instant = tally.effective_unlock_instant(&settings);
+                    Some(ArCliTally {
+                        // uid-keyed tallies remember the user name
+                        user: tally.user_name.unwrap_or(user),
+                        failures: tally.failures_count,
+                        unlock_instant,
+                        locked: unlock_instant.is_some_and(|unlock_instant| now < unlock_instant),
+                    })
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        ArCliWarning {
+                            message: format!("Skipping tally of user '{}': {e}", user.yellow()),
+                        }
+                    );
+                    None
+                }
+            }
+        })
+        .filter(|entry| !locked_only || entry.locked)
+        .collect();
+
+    if entries.is_empty() {
+        return Acr::Info(ArCliInfo {
+            message: if locked_only {
+                "No locked users found".to_string()
+            } else {
+                "No tallies found".to_string()
+            },
+            ..Default::default()
+        });
+    }
+
+    entries.sort_by(|a, b| a.user.cmp(&b.user));
+
+    let mut message = format!(
+        "{} tallies found\n{:<32} {:>8}  {}",
+        entries.len(),
+        "USER",
+        "FAILURES",
+        "UNLOCKS AT"
+    );
+    for entry in &entries {
+        let unlock = match entry.unlock_instant {
+            Some(unlock_instant) if entry.locked => unlock_instant.to_string(),
+            Some(_) => "unlocked".to_string(),
+            None => "-".to_string(),
+        };
+        let _ = write!(
+            message,
+            "\n{:<32} {:>8}  {unlock}",
+            user_label(&entry.user),
+            entry.failures
+        );
+    }
+
+    Acr::Success(Some(ArCliSuccess {
+        message,
+        tallies: entries,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_list_tallies() {
+        let temp_dir =
+            TempDir::new("test_list_tallies").expect("Failed to create temporary directory");
+        let now = Utc::now();
+
+        // missing directory
+        let result = list_tallies(
+            &temp_dir.path().join("missing"),
+            Config::default(),
+            false,
+            now,
+        );
+        assert!(
+            matches!(result, Acr::Info(_)),
+            "Expected missing directory info"
+        );
+
+        // empty directory
+        let result = list_tallies(temp_dir.path(), Config::default(), false, now);
+        assert!(
+            matches!(result, Acr::Info(_)),
+            "Expected empty directory info"
+        );
+
+        // one locked, one at the free tries boundary and one broken tally
+        fs::write(
+            temp_dir.path().join("locked_user"),
+            format!(
+                "[Fails]\ncount = 7\ninstant = \"{now}\"\nunlock_instant = \"{}\"",
+                now + Duration::seconds(30)
+            ),
+        )
+        .expect("Failed to write tally");
+        fs::write(
+            temp_dir.path().join("free_user"),
+            format!(
+                "[Fails]\ncount = 6\ninstant = \"{now}\"\nunlock_instant = \"{}\"",
+                now + Duration::seconds(30)
+            ),
+        )
+        .expect("Failed to write tally");
+        fs::write(temp_dir.path().join("broken_user"), "not a tally")
+            .expect("Failed to write tally");
+        fs::write(
+            temp_dir.path().join("unknown:0123456789abcdef"),
+            format!("[Fails]\ncount = 2\ninstant = \"{now}\""),
+        )
+        .expect("Failed to write tally");
+
+        let result = list_tallies(temp_dir.path(), Config::default(), false, now);
+        let Acr::Success(Some(success)) = result else {
+            panic!("Expected a table");
+        };
+        assert!(success.message.contains("locked_user"));
+        assert!(success.message.contains("free_user"));
+        assert!(!success.message.contains("broken_user"));
+        assert!(success.message.contains("unknown user (0123456789abcdef)"));
+
+        let result = list_tallies(temp_dir.path(), Config::default(), true, now);
+        let Acr::Success(Some(success)) = result else {
+            panic!("Expected a table");
+        };
+        assert!(success.message.contains("locked_user"));
+        assert!(!success.message.contains("free_user"));
+    }
+}
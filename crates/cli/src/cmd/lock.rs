@@ -0,0 +1,376 @@
+//! # Lock Module
+//!
+//! The `lock` module provides functionality to lock a user manually, e.g. after a credential got
+//! compromised. It writes a tally over the free tries with an explicit unlock instant, using the
+//! same serialization code the PAM module uses, so the module honors the lock immediately.
+//! `set-unlock` does the same to schedule when an account unlocks, e.g. once a manager confirmed
+//! the employee.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use colored::Colorize;
+use common::{
+    config::Config,
+    tally::{create_tally_file_dir, Tally},
+};
+use std::path::Path;
+use uzers::get_user_by_name;
+
+use super::{load_config, parse_duration, tally_exists, tally_target};
+use crate::permissions::{self, Invoker, TargetState};
+use crate::{ArCliError, ArCliResult as Acr, ArCliSuccess, ArCliTally, ArCliWarning};
+
+/// Locks a specific user until an instant.
+///
+/// The function reads the configuration, resolves the unlock instant and writes the tally file of
+/// the given user. Root is only locked with `force`, and the module only enforces the lock if
+/// `even_deny_root` is enabled.
+///
+/// # Arguments
+///
+/// - `user`: The username to lock.
+/// - `until`: The timestamp the lock ends at, e.g. "2024-02-04T12:00:00Z".
+/// - `duration`: The duration of the lock, e.g. "2h" or "1h30m". Ignored if `until` is set.
+/// - `force`: Lock root as well.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If successful, returns `ArCliResult::Success` with the effective unlock time.
+/// - If the invoker isn't permitted by `[Cli.permissions]`, returns `ArCliResult::Denied`.
+/// - If the unlock instant is invalid, the user is root without `force` or the tally can't be
+///   written, returns `ArCliResult::Error` with the error message.
+/// - If the user name can't be used as a tally file name, returns `ArCliResult::Error`.
+#[must_use]
+pub fn user(user: &str, until: Option<&str>, duration: Option<&str>, force: bool) -> Acr {
+    lock_user("lock", user, until, duration, force)
+}
+
+/// Sets the unlock instant of a specific user.
+///
+/// Like [`user`], but permitted by the `set-unlock` rule of `[Cli.permissions]`. An existing lock
+/// is replaced, so the unlock can be moved forward as well.
+///
+/// # Arguments
+///
+/// - `user`: The username to lock.
+/// - `at`: The timestamp the account unlocks at, e.g. "2024-06-01T17:00:00" in local time.
+/// - `after`: The duration until the account unlocks, e.g. "4h". Ignored if `at` is set.
+/// - `force`: Lock root as well.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation, see [`user`].
+#[must_use]
+pub fn set_unlock(user: &str, at: Option<&str>, after: Option<&str>, force: bool) -> Acr {
+    lock_user("set-unlock", user, at, after, force)
+}
+
+/// Locks a specific user until an instant on behalf of a command.
+///
+/// # Arguments
+///
+/// - `command`: The command as named in `[Cli.permissions]`.
+/// - `user`: The username to lock.
+/// - `until`: The timestamp the lock ends at.
+/// - `duration`: The duration of the lock. Ignored if `until` is set.
+/// - `force`: Lock root as well.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation, see [`user`].
+fn lock_user(
+    command: &str,
+    user: &str,
+    until: Option<&str>,
+    duration: Option<&str>,
+    force: bool,
+) -> Acr {
+    let mut config = load_config();
+
+    // locks are checked against the hold rule whatever the state of the account
+    if let Some(denied) = permissions::check(
+        command,
+        user,
+        TargetState::default(),
+        &config,
+        &Invoker::current(),
+    ) {
+        return denied;
+    }
+
+    let now = Utc::now();
+
+    let unlock_instant = match unlock_instant(until, duration, now) {
+        Ok(unlock_instant) => unlock_instant,
+        Err(message) => return Acr::Error(ArCliError { message }),
+    };
+
+    let is_root = get_user_by_name(user).is_some_and(|user| user.uid() == 0);
+    if is_root && !force {
+        return Acr::Error(ArCliError {
+            message: format!("Refusing to lock '{}' without --force", user.yellow()),
+        });
+    }
+
+    config.apply_user_override(user);
+
+    if is_root && !config.even_deny_root {
+        eprintln!(
+            "{}",
+            ArCliWarning {
+                message: "The module ignores the tally of root unless even_deny_root is enabled"
+                    .to_string(),
+            }
+        );
+    }
+
+    let tally_path = match tally_target(&config, Some(user), None) {
+        Ok((_, tally_path)) => tally_path,
+        Err(e) => return e,
+    };
+
+    lock_tally(&tally_path, user, &config, unlock_instant, now)
+}
+
+/// Parses an unlock timestamp.
+///
+/// Timestamps with an offset, like "2024-02-04T12:00:00Z" or "2024-06-01T17:00:00+02:00", are
+/// taken as they are. Timestamps without one, like "2024-06-01T17:00:00" or "2024-06-01 17:00",
+/// are in the local timezone.
+///
+/// # Arguments
+///
+/// - `timestamp`: The timestamp.
+///
+/// # Returns
+///
+/// The instant, or a message describing why the timestamp is invalid.
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(instant) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(instant.with_timezone(&Utc));
+    }
+
+    let local = [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+    .ok_or_else(|| {
+        format!("Invalid timestamp '{timestamp}', expected e.g. '2024-06-01T17:00:00'")
+    })?;
+
+    // skipped or repeated by a daylight saving time change
+    Local
+        .from_local_datetime(&local)
+        .single()
+        .map(|instant| instant.with_timezone(&Utc))
+        .ok_or_else(|| format!("The local time '{timestamp}' is ambiguous or doesn't exist"))
+}
+
+/// Resolves the unlock instant from the command line arguments.
+///
+/// # Arguments
+///
+/// - `until`: The timestamp the lock ends at.
+/// - `duration`: The duration of the lock.
+/// - `now`: The instant the lock starts at.
+///
+/// # Returns
+///
+/// The unlock instant, or a message describing why it is invalid.
+fn unlock_instant(
+    until: Option<&str>,
+    duration: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>, String> {
+    let unlock_instant = match (until, duration) {
+        (Some(until), _) => parse_timestamp(until)?,
+        (None, Some(duration)) => parse_duration(duration)
+            .and_then(|duration| now.checked_add_signed(duration))
+            .ok_or_else(|| {
+                format!("Invalid duration '{duration}', expected e.g. '2h' or '1h30m'")
+            })?,
+        (None, None) => return Err("Either an unlock time or a duration is required".to_string()),
+    };
+
+    if unlock_instant <= now {
+        return Err(format!("The unlock time {unlock_instant} is in the past"));
+    }
+
+    Ok(unlock_instant)
+}
+
+/// Writes a locked tally file for a specific user.
+///
+/// An existing tally keeps its failures if there are more than the free tries, and its first
+/// failure instant.
+///
+/// # Arguments
+///
+/// - `path`: The path to the tally file.
+/// - `user`: The username associated with the tally file.
+/// - `config`: The loaded `AuthRamp` configuration, with the overrides of the user.
+/// - `unlock_instant`: The instant the lock ends at.
+/// - `now`: The instant the lock starts at.
+///
+/// # Returns
+///
+/// An `ArCliResult` with the effective unlock time.
+fn lock_tally(
+    path: &Path,
+    user: &str,
+    config: &Config,
+    unlock_instant: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Acr {
+    let existing = tally_exists(path).and_then(|exists| {
+        exists
+            .then(|| Tally::read_trusted_tally_file(path, config))
+            .transpose()
+    });
+    let mut tally = match existing {
+        Ok(tally) => tally.unwrap_or_default(),
+        Err(e) => return e.into(),
+    };
+
+    // just over the threshold, so the lock only ends with the unlock instant
+    tally.failures_count = tally.failures_count.max(config.free_tries.max(0) + 1);
+    tally.failure_instant = now;
+    tally.first_failure_instant.get_or_insert(now);
+    tally.unlock_instant = Some(unlock_instant);
+    tally.manual_lock = true;
+
+    let written =
+        create_tally_file_dir(path, config).and_then(|()| tally.write_tally_file(path, config));
+    if let Err(e) = written {
+        return e.into();
+    }
+
+    Acr::Success(Some(ArCliSuccess {
+        message: format!("user '{}' locked until {unlock_instant}", user.yellow()),
+        tallies: vec![ArCliTally {
+            user: user.to_string(),
+            failures: tally.failures_count,
+            unlock_instant: Some(unlock_instant),
+            locked: true,
+        }],
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use common::settings::Settings;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("45s"), Some(Duration::seconds(45)));
+        assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("1d"), Some(Duration::days(1)));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(parse_duration("2"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("2w"), None);
+        assert_eq!(parse_duration("99999999999999999d"), None);
+    }
+
+    #[test]
+    fn test_unlock_instant() {
+        let now = Utc::now();
+
+        assert_eq!(
+            unlock_instant(None, Some("2h"), now),
+            Ok(now + Duration::hours(2))
+        );
+        assert_eq!(
+            unlock_instant(Some("2999-01-01T00:00:00Z"), None, now),
+            Ok("2999-01-01T00:00:00Z".parse().unwrap())
+        );
+        assert!(unlock_instant(Some("2000-01-01T00:00:00Z"), None, now).is_err());
+        assert!(unlock_instant(Some("tomorrow"), None, now).is_err());
+        assert!(unlock_instant(None, None, now).is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let utc: DateTime<Utc> = "2024-06-01T15:00:00Z".parse().unwrap();
+        assert_eq!(parse_timestamp("2024-06-01T15:00:00Z"), Ok(utc));
+        assert_eq!(parse_timestamp("2024-06-01T17:00:00+02:00"), Ok(utc));
+
+        // without an offset in local time
+        let local = Local
+            .with_ymd_and_hms(2024, 6, 1, 17, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(parse_timestamp("2024-06-01T17:00:00"), Ok(local));
+        assert_eq!(parse_timestamp("2024-06-01 17:00"), Ok(local));
+
+        assert!(parse_timestamp("17:00").is_err());
+        assert!(parse_timestamp("2024-06-01").is_err());
+        assert!(parse_timestamp("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_lock_tally() {
+        let temp_dir = TempDir::new("test_lock_tally").unwrap();
+        let tally_path = temp_dir.path().join("tally").join("test_user");
+        let now = Utc::now();
+        let unlock = now + Duration::hours(2);
+
+        let result = lock_tally(&tally_path, "test_user", &Config::default(), unlock, now);
+        assert!(matches!(result, Acr::Success(_)));
+
+        // the module sees the account locked until the explicit instant
+        let tally = Tally::read_tally_file(&tally_path).unwrap();
+        assert_eq!(tally.failures_count, 7);
+        assert!(tally.manual_lock);
+        assert_eq!(
+            tally.effective_unlock_instant(&Settings::default()),
+            Some(unlock)
+        );
+
+        // more failures than the free tries are kept
+        fs::write(
+            &tally_path,
+            format!("[Fails]\ncount = 12\ninstant = \"{now}\"\nfirst_instant = \"{now}\""),
+        )
+        .unwrap();
+        lock_tally(
+            &tally_path,
+            "test_user",
+            &Config::default(),
+            unlock,
+            now + Duration::seconds(1),
+        );
+        let tally = Tally::read_tally_file(&tally_path).unwrap();
+        assert_eq!(tally.failures_count, 12);
+        assert_eq!(tally.first_failure_instant, Some(now));
+        assert_eq!(tally.unlock_instant, Some(unlock));
+    }
+}
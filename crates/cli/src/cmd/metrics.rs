@@ -0,0 +1,334 @@
+//! # Metrics Module
+//!
+//! The `metrics` module exports the tallies of the `AuthRamp` PAM module in the Prometheus text
+//! exposition format, e.g. for the textfile collector of `node_exporter`. It exports the failures
+//! per user, the number of locked users, the total failures and the number of skipped corrupt
+//! tallies as gauges.
+//!
+//! The metrics file is written to a hidden temporary file next to it and renamed into place, so
+//! a collector reading concurrently never sees a partial file.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use common::{config::Config, error::AuthRampError, settings::Settings};
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+    process,
+};
+
+use super::{load_config, tally_entries};
+use crate::{ArCliResult as Acr, ArCliSuccess};
+
+/// Exports the tallies of all users as Prometheus metrics.
+///
+/// # Arguments
+///
+/// - `output`: The metrics file to write, the metrics are printed if not given.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If the metrics file is written, returns `ArCliResult::Success`.
+/// - If no metrics file is given, returns `ArCliResult::Plain` with the metrics.
+/// - If the tally directory cannot be read or the metrics file cannot be written, returns
+///   `ArCliResult::Error` with the error message.
+#[must_use]
+pub fn export(output: Option<&str>) -> Acr {
+    let config = load_config();
+    let tally_dir = config.tally_dir.clone();
+    let now = Utc::now();
+
+    let Some(output) = output else {
+        let mut metrics = Vec::new();
+        return match write_metrics(&tally_dir, config, now, &mut metrics) {
+            Ok(()) => Acr::Plain(String::from_utf8_lossy(&metrics).trim_end().to_string()),
+            Err(e) => AuthRampError::io(
+                format!("Error reading tally directory {}", tally_dir.display()),
+                e,
+            )
+            .into(),
+        };
+    };
+
+    let output = Path::new(output);
+    match write_metrics_file(output, |file| write_metrics(&tally_dir, config, now, file)) {
+        Ok(()) => Acr::Success(Some(ArCliSuccess {
+            message: format!(
+                "Metrics written to: '{}'",
+                output.display().to_string().yellow()
+            ),
+            ..Default::default()
+        })),
+        Err(e) => AuthRampError::io(
+            format!("Error writing metrics file {}", output.display()),
+            e,
+        )
+        .into(),
+    }
+}
+
+/// Writes the metrics of a tally directory in the Prometheus text exposition format.
+///
+/// The tallies are written while the tally directory is read, so the memory use doesn't grow
+/// with the number of tallies. A missing tally directory exports no users.
+///
+/// # Arguments
+///
+/// - `tally_dir`: The directory containing the tally files.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `now`: The instant the lock state is evaluated at.
+/// - `out`: The writer the metrics are written to.
+///
+/// # Errors
+///
+/// Returns the `io::Error` if the tally directory can't be read or the metrics can't be written.
+fn write_metrics(
+    tally_dir: &Path,
+    config: Config,
+    now: DateTime<Utc>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let settings = Settings {
+        config,
+        ..Settings::default()
+    };
+
+    let tallies = match tally_entries(tally_dir, &settings.config) {
+        Ok(tallies) => Some(tallies),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let (mut locked_users, mut total_failures, mut skipped) = (0u64, 0u64, 0u64);
+
+    write_family(out, "authramp_failures", "Failures in the tally of a user.")?;
+    for (user, tally) in tallies.into_iter().flatten() {
+        let Ok(tally) = tally else {
+            skipped += 1;
+            continue;
+        };
+        if tally.failures_count <= 0 {
+            continue;
+        }
+
+        locked_users += u64::from(
+            tally
+                .effective_unlock_instant(&settings)
+                .is_some_and(|unlock_instant| now < unlock_instant),
+        );
+        total_failures += u64::try_from(tally.failures_count).unwrap_or_default();
+        // uid-keyed tallies remember the user name
+        let user = tally.user_name.unwrap_or(user);
+        writeln!(
+            out,
+            "authramp_failures{{user=\"{}\"}} {}",
+            escape_label_value(&user),
+            tally.failures_count
+        )?;
+    }
+
+    for (name, help, value) in [
+        (
+            "authramp_locked_users",
+            "Users locked at the time of the export.",
+            locked_users,
+        ),
+        (
+            "authramp_total_failures",
+            "Failures in the tallies of all users.",
+            total_failures,
+        ),
+        (
+            "authramp_skipped_tallies",
+            "Tallies that couldn't be parsed or failed the integrity check.",
+            skipped,
+        ),
+    ] {
+        write_family(out, name, help)?;
+        writeln!(out, "{name} {value}")?;
+    }
+
+    Ok(())
+}
+
+/// Writes the `HELP` and `TYPE` lines of a gauge.
+fn write_family(out: &mut impl Write, name: &str, help: &str) -> io::Result<()> {
+    writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge")
+}
+
+/// Escapes a label value of the Prometheus text exposition format.
+///
+/// Backslashes, double quotes and line feeds are escaped, everything else is kept, so any user
+/// name results in a valid label.
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replaces a metrics file atomically.
+///
+/// The content is written to a hidden temporary file in the same directory, which the
+/// `node_exporter` textfile collector ignores, and renamed over the metrics file. The temporary
+/// file is removed if writing fails.
+///
+/// # Arguments
+///
+/// - `path`: The path to the metrics file.
+/// - `write`: Writes the content to the temporary file.
+///
+/// # Errors
+///
+/// Returns the `io::Error` if the temporary file can't be written or renamed.
+fn write_metrics_file(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<fs::File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        process::id()
+    ));
+
+    let written = fs::File::create(&temp_path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()
+    });
+
+    match written.and_then(|()| fs::rename(&temp_path, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("test_user"), "test_user");
+        assert_eq!(
+            escape_label_value("we\"ird\\na\nme"),
+            "we\\\"ird\\\\na\\nme"
+        );
+    }
+
+    #[test]
+    fn test_write_metrics() {
+        let temp_dir =
+            TempDir::new("test_write_metrics").expect("Failed to create temporary directory");
+        let tally_dir = temp_dir.path().join("tally");
+        fs::create_dir(&tally_dir).expect("Failed to create tally directory");
+        let now = Utc::now();
+
+        fs::write(
+            tally_dir.join("locked_user"),
+            format!(
+                "[Fails]\ncount = 7\ninstant = \"{now}\"\nunlock_instant = \"{}\"",
+                now + Duration::seconds(30)
+            ),
+        )
+        .expect("Failed to write tally");
+        fs::write(
+            tally_dir.join("1000"),
+            format!("[Fails]\ncount = 2\ninstant = \"{now}\"\nuser = \"we\\\"ird\""),
+        )
+        .expect("Failed to write tally");
+        fs::write(
+            tally_dir.join("reset_user"),
+            format!("[Fails]\ncount = 0\ninstant = \"{now}\""),
+        )
+        .expect("Failed to write tally");
+        fs::write(tally_dir.join("broken_user"), "not a tally").expect("Failed to write tally");
+
+        let metrics_file = temp_dir.path().join("authramp.prom");
+        write_metrics_file(&metrics_file, |file| {
+            write_metrics(&tally_dir, Config::default(), now, file)
+        })
+        .expect("Failed to write metrics");
+
+        let metrics = fs::read_to_string(&metrics_file).expect("Failed to read metrics");
+        assert!(metrics.contains("# TYPE authramp_failures gauge\n"));
+        assert!(metrics.contains("authramp_failures{user=\"locked_user\"} 7\n"));
+        assert!(metrics.contains("authramp_failures{user=\"we\\\"ird\"} 2\n"));
+        assert!(!metrics.contains("reset_user"));
+        assert!(metrics.contains("\nauthramp_locked_users 1\n"));
+        assert!(metrics.contains("\nauthramp_total_failures 9\n"));
+        assert!(metrics.contains("\nauthramp_skipped_tallies 1\n"));
+
+        // only the metrics file is left behind
+        let files: Vec<_> = fs::read_dir(temp_dir.path())
+            .expect("Failed to read directory")
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name())
+            .collect();
+        assert_eq!(files.len(), 2);
+
+        // a failed write keeps the previous metrics
+        let failed = write_metrics_file(&metrics_file, |_| Err(io::Error::other("failed")));
+        assert!(failed.is_err());
+        assert_eq!(
+            fs::read_to_string(&metrics_file).expect("Failed to read metrics"),
+            metrics
+        );
+        assert_eq!(
+            fs::read_dir(temp_dir.path())
+                .expect("Failed to read directory")
+                .count(),
+            2
+        );
+
+        // a missing tally directory exports no users
+        let mut metrics = Vec::new();
+        write_metrics(
+            &temp_dir.path().join("missing"),
+            Config::default(),
+            now,
+            &mut metrics,
+        )
+        .expect("Failed to write metrics");
+        let metrics = String::from_utf8(metrics).expect("Expected UTF-8 metrics");
+        assert!(metrics.contains("\nauthramp_total_failures 0\n"));
+    }
+}
@@ -1 +1,283 @@
+pub mod backup;
+pub mod completions;
+pub mod config;
+pub mod import;
+pub mod list;
+pub mod lock;
+pub mod metrics;
+pub mod remaining;
 pub mod reset;
+pub mod simulate;
+pub mod stats;
+pub mod status;
+pub mod watch;
+
+use chrono::Duration;
+use colored::Colorize;
+use common::{
+    config::{Config, TallyKey, DEFAULT_CONFIG_FILE_PATH},
+    error::AuthRampError,
+    tally::{self, find_tally_file, Tally},
+    unknown,
+};
+use pam::PamHandle;
+use std::{
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use uzers::{get_user_by_name, get_user_by_uid, User};
+
+use crate::{ArCliError, ArCliInfo, ArCliResult, ArCliWarning};
+
+/// The tally directory given with `--tally-dir`, e.g. for testing without root.
+pub static TALLY_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Loads the configuration the commands act on.
+///
+/// Like [`Config::load_file`], but warns instead of silently using the defaults if the
+/// configuration file can't be read for lack of permissions or can't be parsed, and applies
+/// `--tally-dir`.
+///
+/// # Returns
+///
+/// The loaded `AuthRamp` configuration.
+pub fn load_config() -> Config {
+    let mut config = match Config::try_load_file::<PamHandle>(DEFAULT_CONFIG_FILE_PATH, None) {
+        Ok(config) => config,
+        Err(e) => {
+            let hint = match e {
+                _ if e.is_permission_denied() => Some("Run authramp as root, e.g. with sudo"),
+                AuthRampError::Invalid { .. } => Some("Run authramp config check for details"),
+                _ => None,
+            };
+            if let Some(hint) = hint {
+                eprintln!(
+                    "{}",
+                    ArCliWarning {
+                        message: format!("{e}, using the defaults. {hint}"),
+                    }
+                );
+            }
+            Config::default()
+        }
+    };
+
+    if let Some(tally_dir) = TALLY_DIR.get() {
+        config.tally_dir.clone_from(tally_dir);
+    }
+    config
+}
+
+/// Loads the configuration the commands act on without any warnings.
+///
+/// Like [`load_config`], but silently uses the defaults if the configuration file can't be
+/// read, e.g. for the output the shell completions parse.
+///
+/// # Returns
+///
+/// The loaded `AuthRamp` configuration.
+pub fn load_config_silently() -> Config {
+    let mut config =
+        Config::try_load_file::<PamHandle>(DEFAULT_CONFIG_FILE_PATH, None).unwrap_or_default();
+
+    if let Some(tally_dir) = TALLY_DIR.get() {
+        config.tally_dir.clone_from(tally_dir);
+    }
+    config
+}
+
+/// Resolves the user a command acts on and the tally file of that user.
+///
+/// User names are normalized according to `normalize_user` like the PAM module does, so
+/// `Alice@corp.example` finds the tally of `alice`. With `tally_key = "name"` unknown user names
+/// are accepted, with `tally_key = "uid"` unknown uids are, so tallies of deleted accounts can
+/// still be inspected.
+///
+/// # Arguments
+///
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `user`: The user name, if given.
+/// - `uid`: The uid, used if no user name is given.
+///
+/// # Returns
+///
+/// The user name for messages and the path of the tally file.
+///
+/// # Errors
+///
+/// Returns `ArCliResult::NotFound` if the user can't be resolved, `ArCliResult::Error` if no user
+/// is given or its name can't be used as a tally file name.
+pub fn tally_target(
+    config: &Config,
+    user: Option<&str>,
+    uid: Option<u32>,
+) -> Result<(String, PathBuf), ArCliResult> {
+    let not_found = |message| {
+        ArCliResult::NotFound(ArCliInfo {
+            message,
+            ..Default::default()
+        })
+    };
+
+    let resolved = match (user, uid) {
+        (Some(name), _) => {
+            let name = config.normalize_user.apply(name);
+            get_user_by_name(&name)
+                .or_else(|| (config.tally_key == TallyKey::Name).then(|| User::new(0, &name, 0)))
+                .ok_or_else(|| not_found(format!("Unknown user '{}'", name.yellow())))
+        }
+        (None, Some(uid)) => get_user_by_uid(uid)
+            .or_else(|| {
+                (config.tally_key == TallyKey::Uid).then(|| User::new(uid, &uid.to_string(), uid))
+            })
+            .ok_or_else(|| not_found(format!("Unknown uid {}", uid.to_string().yellow()))),
+        (None, None) => Err(ArCliResult::Error(ArCliError {
+            message: "Either --user or --uid is required".to_string(),
+        })),
+    }?;
+
+    let name = resolved.name().to_string_lossy().into_owned();
+
+    find_tally_file(config, &resolved)
+        .map(|tally_path| (name.clone(), tally_path))
+        .map_err(|e| {
+            ArCliResult::Error(ArCliError {
+                message: format!("Invalid user name '{}': {e}", name.yellow()),
+            })
+        })
+}
+
+/// Checks whether a tally file exists.
+///
+/// Unlike [`Path::exists`], a tally directory that can't be accessed is reported instead of
+/// taken for a missing tally.
+///
+/// # Arguments
+///
+/// - `path`: The path to the tally file.
+///
+/// # Returns
+///
+/// Whether the tally file exists.
+///
+/// # Errors
+///
+/// Returns an `AuthRampError` if the tally file can't be accessed.
+pub fn tally_exists(path: &Path) -> Result<bool, AuthRampError> {
+    path.try_exists()
+        .map_err(|e| AuthRampError::io(format!("Error accessing tally file {}", path.display()), e))
+}
+
+/// Parses a duration like "45s", "90m", "2h", "1d" or "1h30m".
+///
+/// # Arguments
+///
+/// - `duration`: The duration as numbers with the units `s`, `m`, `h` or `d`.
+///
+/// # Returns
+///
+/// The positive duration, or `None` if it is malformed or out of range.
+#[must_use]
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let mut seconds: i64 = 0;
+    let mut digits = String::new();
+
+    for c in duration.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return None,
+        };
+        let value: i64 = digits.parse().ok()?;
+        seconds = seconds.checked_add(value.checked_mul(unit)?)?;
+        digits.clear();
+    }
+
+    (digits.is_empty() && seconds > 0 && seconds <= i64::MAX / 1000)
+        .then(|| Duration::seconds(seconds))
+}
+
+/// Formats a duration like "1d2h3m4s", the reverse of [`parse_duration`].
+///
+/// # Arguments
+///
+/// - `duration`: The duration, rounded down to whole seconds.
+///
+/// # Returns
+///
+/// The duration with the units `d`, `h`, `m` and `s`, "0s" for less than a second.
+#[must_use]
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    if seconds == 0 {
+        return "0s".to_string();
+    }
+
+    let mut formatted = String::new();
+    let mut remaining = seconds;
+    for (unit, length) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        let value = remaining / length;
+        remaining %= length;
+        if value > 0 {
+            let _ = write!(formatted, "{value}{unit}");
+        }
+    }
+    formatted
+}
+
+/// Labels a tally user for text output.
+///
+/// # Arguments
+///
+/// - `user`: The user name of the tally.
+///
+/// # Returns
+///
+/// The user name, or the hash of the placeholder of unknown users.
+#[must_use]
+pub fn user_label(user: &str) -> String {
+    unknown::placeholder_hash(user)
+        .map_or_else(|| user.to_string(), |hash| format!("unknown user ({hash})"))
+}
+
+/// Reads the tallies of a tally directory one at a time.
+///
+/// Every tally file is parsed with the same code the PAM module uses when the iterator advances,
+/// so the memory use doesn't grow with the number of tallies. Quarantined corrupt tallies are
+/// hidden files and skipped.
+///
+/// # Arguments
+///
+/// - `tally_dir`: The directory containing the tally files.
+/// - `config`: The loaded `AuthRamp` configuration, with the key file of signed tallies.
+///
+/// # Returns
+///
+/// An iterator over the file names of the tallies and the parsed tallies.
+///
+/// # Errors
+///
+/// Returns the `io::Error` if the tally directory can't be read.
+pub fn tally_entries<'a>(
+    tally_dir: &Path,
+    config: &'a Config,
+) -> io::Result<impl Iterator<Item = (String, Result<Tally, AuthRampError>)> + 'a> {
+    Ok(tally::tally_files(tally_dir)?.into_iter().map(|path| {
+        (
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            Tally::read_trusted_tally_file(&path, config),
+        )
+    }))
+}
@@ -0,0 +1,227 @@
+//! # Remaining Module
+//!
+//! The `remaining` module prints how long a user stays locked out, for greeter scripts and status
+//! bars like i3blocks that tell the user instead of the PAM conversation. The unlock instant is
+//! computed with [`query`], like the PAM module does when it bounces an authentication.
+//!
+//! With `--watch` the remaining time is re-printed on an interval until the lock ends. Users
+//! can query themselves without root if `status_world_readable` makes the tallies readable.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Duration, Utc};
+use colored::Colorize;
+use common::{config::Config, query};
+use std::{path::Path, thread};
+
+use super::{format_duration, load_config, parse_duration, tally_target};
+
+use crate::{ArCliError, ArCliInfo, ArCliLocked, ArCliResult as Acr, ArCliTally};
+
+/// Prints the remaining lockout time of a user, optionally until the lock ends.
+///
+/// # Arguments
+///
+/// - `user`: The username.
+/// - `watch`: Re-print the remaining time every `interval` until the user is unlocked.
+/// - `interval`: The interval of `watch`, like "5s" or "1m".
+/// - `json`: Print the re-printed results as JSON objects.
+///
+/// # Returns
+///
+/// - If the user is locked and not watched, returns `ArCliResult::Locked` with the remaining time.
+/// - If the user isn't locked or has no tally, returns `ArCliResult::Info`.
+/// - If the user can't be resolved, returns `ArCliResult::NotFound`.
+/// - If the tally can't be read for lack of permissions, returns `ArCliResult::Denied`.
+/// - If the interval is invalid or the tally can't be parsed, returns `ArCliResult::Error`.
+#[must_use]
+pub fn user(user: &str, watch: bool, interval: &str, json: bool) -> Acr {
+    let Some(interval) = parse_duration(interval) else {
+        return Acr::Error(ArCliError {
+            message: format!(
+                "Invalid interval '{}', expected e.g. 5s or 1m",
+                interval.yellow()
+            ),
+        });
+    };
+
+    let config = load_config();
+    let (user, tally_path) = match tally_target(&config, Some(user), None) {
+        Ok(target) => target,
+        Err(e) => return e,
+    };
+
+    loop {
+        let now = Utc::now();
+        let (result, locked_until) = remaining(&tally_path, &user, &config, now);
+
+        let Some(locked_until) = locked_until.filter(|_| watch) else {
+            return result;
+        };
+
+        if json {
+            println!("{}", result.to_json("remaining", Some(&user)));
+        } else {
+            println!("{result}");
+        }
+
+        // wake up when the lock ends rather than an interval later
+        let pause = interval.min(locked_until - now);
+        thread::sleep(pause.to_std().unwrap_or_default());
+    }
+}
+
+/// Computes the remaining lockout time of a tally file at a given instant with [`query`].
+///
+/// # Arguments
+///
+/// - `path`: The path to the tally file.
+/// - `user`: The username associated with the tally file.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `now`: The instant the lock state is evaluated at.
+///
+/// # Returns
+///
+/// The `ArCliResult` describing the remaining time, and the instant the user is locked until if
+/// locked.
+fn remaining(
+    path: &Path,
+    user: &str,
+    config: &Config,
+    now: DateTime<Utc>,
+) -> (Acr, Option<DateTime<Utc>>) {
+    let status = match query::tally_lock_status(path, config, now) {
+        Ok(status) => status,
+        Err(e) if e.is_permission_denied() => {
+            return (
+                Acr::Denied(ArCliError {
+                    message: format!(
+                        "{e}. Run authramp as root, or set status_world_readable to let users \
+                         read their tally"
+                    ),
+                }),
+                None,
+            )
+        }
+        Err(e) => return (e.into(), None),
+    };
+
+    let Some(locked_until) = status.as_ref().and_then(|status| status.locked_until) else {
+        return (
+            Acr::Info(ArCliInfo {
+                message: format!("'{}' is not locked", user.yellow()),
+                tallies: status
+                    .map(|status| ArCliTally {
+                        user: user.to_string(),
+                        failures: status.failures,
+                        unlock_instant: status.unlock_instant,
+                        locked: false,
+                    })
+                    .into_iter()
+                    .collect(),
+            }),
+            None,
+        );
+    };
+
+    // round up, so the last second of the lock doesn't read as 0s
+    let remaining = Duration::seconds(((locked_until - now).num_milliseconds() + 999) / 1000);
+    let failures = status.map_or(0, |status| status.failures);
+
+    (
+        Acr::Locked(ArCliLocked {
+            message: format!(
+                "'{}' is locked for {}, until {}",
+                user.yellow(),
+                format_duration(remaining),
+                config.message_time(locked_until)
+            ),
+            tallies: vec![ArCliTally {
+                user: user.to_string(),
+                failures,
+                unlock_instant: Some(locked_until),
+                locked: true,
+            }],
+        }),
+        Some(locked_until),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_remaining() {
+        let temp_dir = TempDir::new("test_remaining").unwrap();
+        let path = temp_dir.path().join("alice");
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let now = Utc::now();
+
+        // without a tally the user isn't locked
+        let (result, locked_until) = remaining(&path, "alice", &config, now);
+        assert!(matches!(result, Acr::Info(_)));
+        assert_eq!(locked_until, None);
+
+        let unlock_instant = now + Duration::seconds(30);
+        fs::write(
+            &path,
+            format!(
+                "[Fails]\ncount = 7\ninstant = \"{now}\"\nunlock_instant = \"{unlock_instant}\""
+            ),
+        )
+        .unwrap();
+
+        let (result, locked_until) = remaining(&path, "alice", &config, now);
+        assert_eq!(locked_until, Some(unlock_instant));
+        let Acr::Locked(locked) = result else {
+            panic!("alice is locked");
+        };
+        assert!(locked.message.contains("locked for 30s"));
+        assert_eq!(locked.tallies[0].failures, 7);
+
+        // the last fraction of a second is still reported
+        let (result, _) = remaining(
+            &path,
+            "alice",
+            &config,
+            unlock_instant - Duration::milliseconds(200),
+        );
+        assert!(result.to_string().contains("locked for 1s"));
+
+        // the lock ended
+        let (result, locked_until) = remaining(&path, "alice", &config, unlock_instant);
+        assert_eq!(locked_until, None);
+        let Acr::Info(info) = result else {
+            panic!("alice is unlocked");
+        };
+        assert!(!info.tallies[0].locked);
+
+        fs::write(&path, "not a tally").unwrap();
+        assert!(matches!(
+            remaining(&path, "alice", &config, now).0,
+            Acr::Error(_)
+        ));
+    }
+}
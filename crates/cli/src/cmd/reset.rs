@@ -2,7 +2,9 @@
 //!
 //! The `reset` module provides functionality to reset the tally information for a user.
 //! It is used in the context of the `sm_authenticate` PAM hook when the `reset` command is specified.
-//! The tally information is stored in a file, and this module allows resetting the tally for a specific user.
+//! The tally information is stored in a file, and this module allows resetting the tally for a specific user
+//! or for all users at once. A reset zeroes the tally but keeps the failure instants for auditing,
+//! purging deletes the tally file.
 //!
 //! ## License
 //!
@@ -23,33 +25,407 @@
 //! along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use colored::Colorize;
-use common::config::Config;
-use std::{fs, path::PathBuf};
+use common::{
+    config::Config,
+    error::AuthRampError,
+    notify::{self, Notification, NotifyEvent},
+    tally::{self, Tally},
+};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 
-use crate::{ArCliError, ArCliInfo, ArCliResult as Acr, ArCliSuccess};
+use super::{load_config, tally_exists, tally_target};
+use crate::permissions::{self, Invoker, TargetState};
+use crate::{ArCliError, ArCliInfo, ArCliResult as Acr, ArCliSuccess, ArCliWarning};
+
+/// The outcome of the reset of a single user, aggregated by [`users`].
+#[derive(Debug)]
+pub struct UserReset {
+    /// The user name or uid the reset was requested for.
+    pub target: String,
+    /// The result of the reset.
+    pub result: Acr,
+}
+
+impl UserReset {
+    /// Whether the tally was reset.
+    #[must_use]
+    pub fn is_reset(&self) -> bool {
+        matches!(self.result, Acr::Success(_))
+    }
+
+    /// Whether the user is unknown or has no tally.
+    #[must_use]
+    pub fn is_not_found(&self) -> bool {
+        matches!(self.result, Acr::NotFound(_))
+    }
+}
+
+/// Resets the tally information of several users, each independently.
+///
+/// A single user gets the result of its reset. For several users, a result line is printed to
+/// stderr per user, followed by a summary.
+///
+/// # Arguments
+///
+/// - `users`: The usernames for which the tally information should be reset.
+/// - `uid`: The uid for which the tally information should be reset, if no username is given.
+/// - `purge`: Delete the tally files instead of zeroing them.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - For a single user, returns the result of [`user`].
+/// - If every reset succeeded or found no tally, returns `ArCliResult::Success` with a summary.
+/// - If any reset was refused and none failed otherwise, returns `ArCliResult::Denied` with a
+///   summary.
+/// - If any reset failed, returns `ArCliResult::Error` with a summary.
+pub fn users(users: &[String], uid: Option<u32>, purge: bool) -> Acr {
+    if users.len() <= 1 {
+        return user(users.first().map(String::as_str), uid, purge).result;
+    }
+
+    let resets: Vec<UserReset> = users
+        .iter()
+        .map(|target| user(Some(target), None, purge))
+        .inspect(|reset| eprintln!("{}", reset.result))
+        .collect();
+
+    summarize(&resets)
+}
+
+/// Summarizes the resets of several users.
+///
+/// # Arguments
+///
+/// - `resets`: The outcomes of the resets.
+///
+/// # Returns
+///
+/// The `ArCliResult` of the resets, see [`users`].
+fn summarize(resets: &[UserReset]) -> Acr {
+    let reset = resets.iter().filter(|reset| reset.is_reset()).count();
+    let not_found = resets.iter().filter(|reset| reset.is_not_found()).count();
+    let failed: Vec<&UserReset> = resets
+        .iter()
+        .filter(|reset| !reset.is_reset() && !reset.is_not_found())
+        .collect();
+
+    let mut message = format!("reset {reset} of {} users", resets.len());
+    if not_found > 0 {
+        let _ = write!(message, ", {not_found} without a tally");
+    }
+    if !failed.is_empty() {
+        let targets: Vec<&str> = failed.iter().map(|reset| reset.target.as_str()).collect();
+        let _ = write!(message, ", failed for: {}", targets.join(", "));
+    }
+
+    if failed.is_empty() {
+        Acr::Success(Some(ArCliSuccess {
+            message,
+            ..Default::default()
+        }))
+    } else if failed
+        .iter()
+        .all(|reset| matches!(reset.result, Acr::Denied(_)))
+    {
+        Acr::Denied(ArCliError { message })
+    } else {
+        Acr::Error(ArCliError { message })
+    }
+}
 
 /// Resets the tally information for a specific user.
 ///
 /// The function reads the configuration, constructs the path to the tally file for the given user,
-/// and attempts to delete the tally file. It returns a result indicating the success or failure of the operation.
+/// and attempts to reset or delete the tally file. It returns a result indicating the success or failure of the operation.
 ///
 /// # Arguments
 ///
 /// - `user`: The username for which the tally information should be reset.
+/// - `uid`: The uid for which the tally information should be reset, if no username is given.
+/// - `purge`: Delete the tally file instead of zeroing it.
+///
+/// # Returns
+///
+/// A `UserReset` with the target and the result of the reset:
+///
+/// - If successful, `ArCliResult::Success` with an optional `ArCliSuccess` containing a success message.
+/// - If the user can't be resolved or the tally file does not exist, `ArCliResult::NotFound` with an `ArCliInfo` containing an informational message.
+/// - If the invoker isn't permitted by `[Cli.permissions]`, `ArCliResult::Denied`.
+/// - If an error occurs during the reset, `ArCliResult::Error` with an `ArCliError` containing the error message.
+/// - If the user name can't be used as a tally file name, `ArCliResult::Error`.
+pub fn user(user: Option<&str>, uid: Option<u32>, purge: bool) -> UserReset {
+    let config = load_config();
+    let target = user.map_or_else(
+        || uid.map(|uid| uid.to_string()).unwrap_or_default(),
+        ToString::to_string,
+    );
+
+    let result = match tally_target(&config, user, uid) {
+        Ok((user, tally_path)) => permissions::check(
+            "reset",
+            &user,
+            TargetState::of(&config, &user, &tally_path),
+            &config,
+            &Invoker::current(),
+        )
+        .unwrap_or_else(|| reset_tally(&tally_path, &user, &config, purge)),
+        Err(e) => e,
+    };
+
+    UserReset { target, result }
+}
+
+/// Resets the tally information of every user in the tally directory.
+///
+/// Unless `yes` is set, the user is asked for confirmation first. A result line is printed to
+/// stderr per user, failures to delete single tallies are reported without aborting.
+///
+/// # Arguments
+///
+/// - `yes`: Skip the confirmation prompt.
+/// - `purge`: Delete the tally files instead of zeroing them.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If every tally was reset, returns `ArCliResult::Success` with a summary.
+/// - If there are no tallies or the reset was not confirmed, returns `ArCliResult::Info`.
+/// - If the invoker isn't permitted by `[Cli.permissions]`, returns `ArCliResult::Denied`.
+/// - If any tally couldn't be reset, returns `ArCliResult::Error` with a summary.
+#[must_use]
+pub fn all(yes: bool, purge: bool) -> Acr {
+    let config = load_config();
+
+    if let Some(denied) =
+        permissions::check("reset", "*", TargetState::ANY, &config, &Invoker::current())
+    {
+        return denied;
+    }
+
+    reset_all(&config, purge, |count| {
+        yes || confirm(count, &config.tally_dir)
+    })
+}
+
+/// Asks the user on stdin to confirm resetting all tallies.
+///
+/// # Arguments
+///
+/// - `count`: The number of tallies to reset.
+/// - `tally_dir`: The directory containing the tally files.
+///
+/// # Returns
+///
+/// `true` if the user answered yes.
+fn confirm(count: usize, tally_dir: &Path) -> bool {
+    eprint!(
+        "Reset the tallies of all {count} users in '{}'? [y/N] ",
+        tally_dir.display()
+    );
+    let _ = io::stderr().flush();
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Resets every tally file in the tally directory.
+///
+/// # Arguments
+///
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `purge`: Delete the tally files instead of zeroing them.
+/// - `confirm`: Decides whether to proceed, given the number of tallies.
+///
+/// # Returns
+///
+/// An `ArCliResult` summarizing the reset.
+fn reset_all(config: &Config, purge: bool, confirm: impl FnOnce(usize) -> bool) -> Acr {
+    let tally_dir = &config.tally_dir;
+    // quarantined corrupt tallies are hidden files and skipped
+    let mut tallies: Vec<(PathBuf, String)> = match tally::tally_files(tally_dir) {
+        Ok(tally_files) => tally_files
+            .into_iter()
+            .map(|path| {
+                let user = path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned();
+                (path, user)
+            })
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            return AuthRampError::io(
+                format!("Error reading tally directory {}", tally_dir.display()),
+                e,
+            )
+            .into()
+        }
+    };
+
+    if tallies.is_empty() {
+        return Acr::Info(ArCliInfo {
+            message: "No tallies found".to_string(),
+            ..Default::default()
+        });
+    }
+
+    if !confirm(tallies.len()) {
+        return Acr::Info(ArCliInfo {
+            message: "Reset aborted".to_string(),
+            ..Default::default()
+        });
+    }
+
+    tallies.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let total = tallies.len();
+    let reset = tallies
+        .iter()
+        .map(|(path, user)| reset_tally(path, user, config, purge))
+        .inspect(|result| eprintln!("{result}"))
+        .filter(|result| matches!(result, Acr::Success(_)))
+        .count();
+
+    let message = format!("reset {reset} of {total} tallies");
+    if reset == total {
+        Acr::Success(Some(ArCliSuccess {
+            message,
+            ..Default::default()
+        }))
+    } else {
+        Acr::Error(ArCliError { message })
+    }
+}
+
+/// Resets the tally file of a specific user.
+///
+/// Unless `purge` is set, the tally is read with the module's parser and written back with zero
+/// failures and no lock, like the module clears it after a successful authentication. The
+/// failure instants are kept for auditing. The tally is written with a MAC if configured, a
+/// tally that failed the integrity check is repaired as well.
+///
+/// If the tally had failures, the recipients of `notify_email` are notified about the reset.
+///
+/// # Arguments
+///
+/// - `path`: The path to the tally file.
+/// - `user`: The username associated with the tally file.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `purge`: Delete the tally file instead of zeroing it.
 ///
 /// # Returns
 ///
 /// A `Result` representing the outcome of the operation.
 ///
 /// - If successful, returns `ArCliResult::Success` with an optional `ArCliSuccess` containing a success message.
-/// - If the tally file does not exist, returns `ArCliResult::Info` with an `ArCliInfo` containing an informational message.
-/// - If an error occurs during the file deletion, returns `ArCliResult::Error` with an `ArCliError` containing the error message.
-pub fn user(user: &str) -> Acr {
-    let config = Config::load_file(None, None);
+/// - If the tally file does not exist, returns `ArCliResult::NotFound` with an `ArCliInfo` containing an informational message.
+/// - If the tally file can't be read or written, returns `ArCliResult::Error` with an `ArCliError` containing the error message.
+fn reset_tally(path: &Path, user: &str, config: &Config, purge: bool) -> Acr {
+    let failures = Tally::read_tally_file(path).map_or(0, |tally| tally.failures_count);
+
+    let result = if purge {
+        delete_tally(path, user)
+    } else {
+        zero_tally(path, user, config)
+    };
+
+    if matches!(result, Acr::Success(_)) && failures > 0 {
+        notify_reset(user, failures, config);
+    }
+    result
+}
+
+/// Zeroes the tally file of a specific user, keeping the failure instants.
+///
+/// # Arguments
+///
+/// - `path`: The path to the tally file.
+/// - `user`: The username associated with the tally file.
+/// - `config`: The loaded `AuthRamp` configuration.
+///
+/// # Returns
+///
+/// The `ArCliResult` of the reset, see [`reset_tally`].
+fn zero_tally(path: &Path, user: &str, config: &Config) -> Acr {
+    let exists = match tally_exists(path) {
+        Ok(exists) => exists,
+        Err(e) => return e.into(),
+    };
+    if !exists {
+        return Acr::NotFound(ArCliInfo {
+            message: format!("No tally found for user: '{}'", user.yellow()),
+            ..Default::default()
+        });
+    }
+
+    let mut tally = match Tally::read_tally_file(path) {
+        Ok(tally) => tally,
+        Err(e) => return e.into(),
+    };
+
+    tally.failures_count = 0;
+    tally.unlock_instant = None;
+
+    match tally.write_tally_file(path, config) {
+        Ok(()) => Acr::Success(Some(ArCliSuccess {
+            message: format!("tally reset for user: '{}'", user.yellow()),
+            ..Default::default()
+        })),
+        Err(e) => e.into(),
+    }
+}
+
+/// Emails the recipients of `notify_email` about a reset tally.
+///
+/// A failed notification is printed as a warning, the reset itself succeeded.
+///
+/// # Arguments
+///
+/// - `user`: The username associated with the tally file.
+/// - `failures`: The failures of the tally before the reset.
+/// - `config`: The loaded `AuthRamp` configuration.
+fn notify_reset(user: &str, failures: i32, config: &Config) {
+    if config.notify_email.is_empty() {
+        return;
+    }
 
-    let tally_path = config.tally_dir.join(user);
+    let notification = Notification {
+        event: NotifyEvent::Reset,
+        user,
+        failures,
+        service: None,
+        rhost: None,
+        unlock_instant: None,
+    };
+    let template = config
+        .notify_email_template
+        .as_deref()
+        .unwrap_or(notify::DEFAULT_TEMPLATE);
 
-    delete_tally(&tally_path, user)
+    if let Err(e) = notify::send(
+        &config.notify_sendmail,
+        &config.notify_email,
+        template,
+        &notification,
+    ) {
+        eprintln!(
+            "{}",
+            ArCliWarning {
+                message: format!("Error notifying about the reset of user '{user}': {e}"),
+            }
+        );
+    }
 }
 
 /// Deletes the tally file for a specific user.
@@ -67,22 +443,22 @@ pub fn user(user: &str) -> Acr {
 /// A `Result` representing the outcome of the operation.
 ///
 /// - If successful, returns `ArCliResult::Success` with an optional `ArCliSuccess` containing a success message.
-/// - If the tally file does not exist, returns `ArCliResult::Info` with an `ArCliInfo` containing an informational message.
+/// - If the tally file does not exist, returns `ArCliResult::NotFound` with an `ArCliInfo` containing an informational message.
 /// - If an error occurs during the file deletion, returns `ArCliResult::Error` with an `ArCliError` containing the error message.
-fn delete_tally(path: &PathBuf, user: &str) -> Acr {
+fn delete_tally(path: &Path, user: &str) -> Acr {
     match fs::remove_file(path) {
         Ok(()) => Acr::Success(Some(ArCliSuccess {
             message: format!("tally reset for user: '{}'", user.yellow()),
+            ..Default::default()
         })),
         Err(e) => {
             if e.kind().eq(&std::io::ErrorKind::NotFound) {
-                Acr::Info(ArCliInfo {
+                Acr::NotFound(ArCliInfo {
                     message: format!("No tally found for user: '{}'", user.yellow()),
+                    ..Default::default()
                 })
             } else {
-                Acr::Error(ArCliError {
-                    message: format!("{e}").to_string(),
-                })
+                AuthRampError::io(format!("Error deleting tally file {}", path.display()), e).into()
             }
         }
     }
@@ -91,6 +467,7 @@ fn delete_tally(path: &PathBuf, user: &str) -> Acr {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::{Duration, Utc};
     use tempdir::TempDir;
 
     #[test]
@@ -109,4 +486,118 @@ mod tests {
         // Assert that the file is deleted successfully
         assert!(!temp_tally_path.exists(), "Tally File not deleted!");
     }
+
+    #[test]
+    fn test_reset_all() {
+        let temp_dir = TempDir::new("test_reset_all").unwrap();
+        fs::write(temp_dir.path().join("alice"), "[Fails]\ncount = 7").unwrap();
+        fs::write(temp_dir.path().join("bob"), "[Fails]\ncount = 2").unwrap();
+        fs::create_dir(temp_dir.path().join("not_a_tally")).unwrap();
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+
+        // declined
+        let result = reset_all(&config, true, |count| {
+            assert_eq!(count, 2);
+            false
+        });
+        assert!(matches!(result, Acr::Info(_)));
+        assert!(temp_dir.path().join("alice").exists());
+
+        // confirmed, directories are skipped
+        let result = reset_all(&config, true, |_| true);
+        match result {
+            Acr::Success(Some(success)) => assert_eq!(success.message, "reset 2 of 2 tallies"),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(!temp_dir.path().join("alice").exists());
+        assert!(!temp_dir.path().join("bob").exists());
+        assert!(temp_dir.path().join("not_a_tally").exists());
+
+        // nothing left
+        assert!(matches!(reset_all(&config, true, |_| true), Acr::Info(_)));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let reset = |target: &str, result: Acr| UserReset {
+            target: target.to_string(),
+            result,
+        };
+        let success = || Acr::Success(None);
+        let not_found = || Acr::NotFound(ArCliInfo::default());
+        let denied = || {
+            Acr::Denied(ArCliError {
+                message: String::new(),
+            })
+        };
+        let error = || {
+            Acr::Error(ArCliError {
+                message: String::new(),
+            })
+        };
+
+        // missing tallies aren't failures
+        match summarize(&[reset("alice", success()), reset("bob", not_found())]) {
+            Acr::Success(Some(success)) => {
+                assert_eq!(success.message, "reset 1 of 2 users, 1 without a tally");
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        match summarize(&[
+            reset("alice", success()),
+            reset("bob", denied()),
+            reset("carol", error()),
+        ]) {
+            Acr::Error(error) => {
+                assert_eq!(error.message, "reset 1 of 3 users, failed for: bob, carol");
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        assert!(matches!(
+            summarize(&[reset("alice", success()), reset("bob", denied())]),
+            Acr::Denied(_)
+        ));
+    }
+
+    #[test]
+    fn test_reset_tally_keeps_instants() {
+        let temp_dir = TempDir::new("test_reset_tally_keeps_instants").unwrap();
+        let temp_tally_path = temp_dir.path().join("test_user");
+        let now = Utc::now();
+
+        // a locked tally
+        let locked = Tally {
+            file: Some(temp_tally_path.clone()),
+            failures_count: 7,
+            failure_instant: now,
+            first_failure_instant: Some(now - Duration::seconds(60)),
+            unlock_instant: Some(now + Duration::seconds(30)),
+            ..Tally::default()
+        };
+        fs::write(&temp_tally_path, locked.to_toml()).unwrap();
+
+        let result = reset_tally(&temp_tally_path, "test_user", &Config::default(), false);
+        assert!(matches!(result, Acr::Success(_)));
+
+        // zeroed and unlocked, the instants survive
+        let tally = Tally::read_tally_file(&temp_tally_path).unwrap();
+        assert_eq!(tally.failures_count, 0);
+        assert_eq!(tally.unlock_instant, None);
+        assert_eq!(tally.failure_instant, locked.failure_instant);
+        assert_eq!(tally.first_failure_instant, locked.first_failure_instant);
+
+        // purge deletes the file
+        let result = reset_tally(&temp_tally_path, "test_user", &Config::default(), true);
+        assert!(matches!(result, Acr::Success(_)));
+        assert!(!temp_tally_path.exists());
+
+        // nothing to reset
+        let result = reset_tally(&temp_tally_path, "test_user", &Config::default(), false);
+        assert!(matches!(result, Acr::NotFound(_)));
+    }
 }
@@ -0,0 +1,248 @@
+//! # Simulate Module
+//!
+//! The `simulate` module prints the delay curve of a configuration, to tune `delay_algorithm`,
+//! `ramp_multiplier`, `base_delay_seconds` and `max_lockout_seconds` without locking accounts.
+//! The delays are computed with the code the PAM module uses and no state is touched, so it works
+//! without root.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::Duration;
+use colored::Colorize;
+use common::{
+    config::{Config, DEFAULT_CONFIG_FILE_PATH},
+    settings::Settings,
+    tally::{is_over_threshold, Tally},
+};
+use pam::PamHandle;
+use std::{fmt::Write, path::Path};
+
+use super::format_duration;
+
+use crate::{ArCliResult as Acr, ArCliSuccess, ArCliWarning};
+
+/// The delay after a number of failures.
+#[derive(Debug, PartialEq)]
+struct Step {
+    failure: i32,
+    delay: Duration,
+    capped: bool,
+    cumulative: Duration,
+}
+
+/// Simulates the delays of consecutive failures with the effective configuration.
+///
+/// # Arguments
+///
+/// - `failures`: The number of consecutive failures to simulate.
+/// - `path`: The configuration file, the default path if not set.
+/// - `json`: Render the curve as JSON instead of a table.
+///
+/// # Returns
+///
+/// `ArCliResult::Success` with the table, or `ArCliResult::Plain` with the JSON document.
+#[must_use]
+pub fn delays(failures: i32, path: Option<&str>, json: bool) -> Acr {
+    let path = path.unwrap_or(DEFAULT_CONFIG_FILE_PATH);
+
+    if !Path::new(path).exists() {
+        eprintln!(
+            "{}",
+            ArCliWarning {
+                message: format!("{path} doesn't exist, simulating the defaults"),
+            }
+        );
+    }
+
+    let settings = Settings {
+        config: Config::load_file::<PamHandle>(Some(path), None),
+        ..Settings::default()
+    };
+    let steps = simulate(failures, &settings);
+
+    if json {
+        Acr::Plain(render_json(&steps, &settings.config))
+    } else {
+        Acr::Success(Some(ArCliSuccess {
+            message: render_text(&steps, &settings.config),
+            ..Default::default()
+        }))
+    }
+}
+
+/// Computes the delay of every failure with `Tally::get_delay`.
+///
+/// Failures within the free tries don't delay. Every delay is capped at `max_lockout_seconds`
+/// like the PAM module does, and the cumulative lockout adds up the capped delays.
+///
+/// # Arguments
+///
+/// - `failures`: The number of consecutive failures to simulate.
+/// - `settings`: The settings the delays are computed with.
+///
+/// # Returns
+///
+/// A step per failure.
+fn simulate(failures: i32, settings: &Settings) -> Vec<Step> {
+    let mut cumulative = Duration::zero();
+
+    (1..=failures)
+        .map(|failure| {
+            let tally = Tally {
+                failures_count: failure,
+                ..Tally::default()
+            };
+            let (delay, capped) = if is_over_threshold(failure, settings.config.free_tries) {
+                let delay = tally.get_capped_delay(settings);
+                (delay, delay < tally.get_delay(settings))
+            } else {
+                (Duration::zero(), false)
+            };
+            cumulative += delay;
+
+            Step {
+                failure,
+                delay,
+                capped,
+                cumulative,
+            }
+        })
+        .collect()
+}
+
+/// Renders the delay curve as a table.
+fn render_text(steps: &[Step], config: &Config) -> String {
+    let mut message = format!(
+        "delay curve of {} with {} free tries\n{:>8}  {:>12}  {:>14}",
+        config.delay_algorithm.name().yellow(),
+        config.free_tries,
+        "FAILURE",
+        "DELAY",
+        "CUMULATIVE"
+    );
+    for step in steps {
+        let _ = write!(
+            message,
+            "\n{:>8}  {:>12}  {:>14}{}",
+            step.failure,
+            format_duration(step.delay),
+            format_duration(step.cumulative),
+            if step.capped { "  (capped)" } else { "" }
+        );
+    }
+
+    match steps.iter().find(|step| step.capped) {
+        Some(step) => {
+            let _ = write!(
+                message,
+                "\nthe cap of max_lockout_seconds = {} kicks in at failure {}",
+                config.max_lockout_seconds, step.failure
+            );
+        }
+        None if config.lockout_cap().is_none() => message.push_str("\nlockouts are uncapped"),
+        None => {
+            let _ = write!(
+                message,
+                "\nthe cap of max_lockout_seconds = {} isn't reached",
+                config.max_lockout_seconds
+            );
+        }
+    }
+    message
+}
+
+/// Renders the delay curve as JSON for graphing.
+fn render_json(steps: &[Step], config: &Config) -> String {
+    serde_json::json!({
+        "delay_algorithm": config.delay_algorithm.name(),
+        "free_tries": config.free_tries,
+        "max_lockout_seconds": config.max_lockout_seconds,
+        "cap_failure": steps.iter().find(|step| step.capped).map(|step| step.failure),
+        "steps": steps
+            .iter()
+            .map(|step| {
+                serde_json::json!({
+                    "failure": step.failure,
+                    "delay_seconds": step.delay.num_seconds(),
+                    "capped": step.capped,
+                    "cumulative_seconds": step.cumulative.num_seconds(),
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulate() {
+        let settings = Settings {
+            config: Config {
+                free_tries: 6,
+                base_delay_seconds: 30,
+                ramp_multiplier: 50.0,
+                max_lockout_seconds: 600,
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+
+        let steps = simulate(13, &settings);
+        assert_eq!(steps.len(), 13);
+        assert!(steps[..6].iter().all(|step| step.delay.is_zero()));
+
+        // the delays are the ones of the PAM module
+        for step in &steps[6..] {
+            let tally = Tally {
+                failures_count: step.failure,
+                ..Tally::default()
+            };
+            assert_eq!(step.delay, tally.get_capped_delay(&settings));
+        }
+        assert_eq!(steps[6].delay, Duration::seconds(30));
+        assert_eq!(
+            steps[12].cumulative,
+            steps.iter().map(|step| step.delay).sum::<Duration>()
+        );
+
+        // 50 × 6 × ln(6) + 30 = 567s, 50 × 7 × ln(7) + 30 = 711s
+        let cap = steps.iter().find(|step| step.capped).unwrap();
+        assert_eq!(cap.failure, 13);
+        assert_eq!(steps[11].delay, Duration::seconds(567));
+        assert_eq!(cap.delay, Duration::seconds(600));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render_json(&steps, &settings.config)).unwrap();
+        assert_eq!(json["cap_failure"], 13);
+        assert_eq!(json["steps"][12]["delay_seconds"], 600);
+        assert_eq!(json["steps"][0]["cumulative_seconds"], 0);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::zero()), "0s");
+        assert_eq!(format_duration(Duration::seconds(45)), "45s");
+        assert_eq!(format_duration(Duration::seconds(3600)), "1h");
+        assert_eq!(format_duration(Duration::seconds(93784)), "1d2h3m4s");
+        assert_eq!(format_duration(Duration::days(400)), "400d");
+    }
+}
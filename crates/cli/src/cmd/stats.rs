@@ -0,0 +1,450 @@
+//! # Stats Module
+//!
+//! The `stats` module provides an overview of the `AuthRamp` PAM module for reporting. It
+//! aggregates the tally directory into the number of accounts with failures, the locked accounts,
+//! the failures within a time window and the accounts with the most failures. The tally files are
+//! parsed one at a time with the same code the PAM module uses, so the memory use doesn't grow
+//! with the number of tallies. Corrupt tallies are skipped and counted.
+//!
+//! The anonymous histograms of cleared tallies per PAM service are shown as well. Everything can
+//! be rendered as text or as JSON.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use common::{
+    config::Config,
+    error::AuthRampError,
+    settings::Settings,
+    stats::{Histogram, Stats, FAILURE_BUCKETS, SECONDS_BUCKETS},
+};
+use serde_json::json;
+use std::{fmt::Write, io, path::Path};
+
+use super::{load_config, parse_duration, tally_entries, user_label};
+use crate::{ArCliError, ArCliInfo, ArCliResult as Acr, ArCliSuccess};
+
+/// Maximum width of a text histogram bar.
+const BAR_WIDTH: u64 = 40;
+
+/// Number of accounts listed by failures.
+const TOP_ACCOUNTS: usize = 10;
+
+/// The aggregated tallies of the tally directory.
+#[derive(Debug, Default, PartialEq)]
+struct TallySummary {
+    /// Accounts with at least one recorded failure
+    accounts: u64,
+    /// Accounts locked at the time of the summary
+    locked: u64,
+    /// Failures of the accounts whose last failure is within the time window
+    recent_failures: u64,
+    /// Accounts with the most failures, ordered by failures and user
+    top: Vec<(String, i32)>,
+    /// Tallies that couldn't be parsed or failed the integrity check
+    skipped: u64,
+}
+
+impl TallySummary {
+    /// Adds the tally of an account with failures.
+    ///
+    /// Only the accounts with the most failures are kept, so the summary stays bounded.
+    fn add(&mut self, user: String, failures: i32, locked: bool, recent: bool) {
+        self.accounts += 1;
+        self.locked += u64::from(locked);
+        if recent {
+            self.recent_failures += u64::try_from(failures).unwrap_or_default();
+        }
+
+        let index = self.top.partition_point(|(top_user, top_failures)| {
+            (*top_failures, std::cmp::Reverse(top_user.as_str()))
+                >= (failures, std::cmp::Reverse(user.as_str()))
+        });
+        if index < TOP_ACCOUNTS {
+            self.top.insert(index, (user, failures));
+            self.top.truncate(TOP_ACCOUNTS);
+        }
+    }
+}
+
+/// Shows the aggregated tallies and the recorded statistics.
+///
+/// # Arguments
+///
+/// - `histograms`: Render the histograms of every service.
+/// - `json`: Render the output as JSON instead of text.
+/// - `since`: The time window failures are counted in, e.g. "24h" or "7d".
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If tallies or statistics are found, returns `ArCliResult::Success` with the rendered text or
+///   `ArCliResult::Plain` with the JSON document.
+/// - If there are no tallies and no statistics have been recorded yet, returns
+///   `ArCliResult::Info`.
+/// - If the time window is invalid or the tally directory or stats file cannot be read, returns
+///   `ArCliResult::Error` with the error message.
+#[must_use]
+pub fn show(histograms: bool, json: bool, since: &str) -> Acr {
+    let Some(window) = parse_duration(since) else {
+        return Acr::Error(ArCliError {
+            message: format!("Invalid duration '{since}', expected e.g. '24h' or '7d'"),
+        });
+    };
+
+    let config = load_config();
+    let now = Utc::now();
+
+    show_stats(config, histograms, json, now - window, now)
+}
+
+/// Renders the tallies of a tally directory and the statistics stored in a stats file.
+///
+/// # Arguments
+///
+/// - `config`: The loaded `AuthRamp` configuration, with the tally directory and stats file.
+/// - `histograms`: Render the histograms of every service.
+/// - `json`: Render the output as JSON instead of text.
+/// - `since`: The start of the time window failures are counted in.
+/// - `now`: The instant the lock state is evaluated at.
+///
+/// # Returns
+///
+/// An `ArCliResult` containing the rendered tallies and statistics.
+fn show_stats(
+    config: Config,
+    histograms: bool,
+    json: bool,
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Acr {
+    let stats = match Stats::load(&config.stats_file) {
+        Ok(stats) => stats,
+        Err(e) => {
+            return Acr::Error(ArCliError {
+                message: format!("Error reading stats file: {e}"),
+            })
+        }
+    };
+
+    let tally_dir = config.tally_dir.clone();
+    let summary = match summarize_tallies(&tally_dir, config, since, now) {
+        Ok(summary) => summary,
+        Err(e) => {
+            return AuthRampError::io(
+                format!("Error reading tally directory {}", tally_dir.display()),
+                e,
+            )
+            .into()
+        }
+    };
+
+    if summary == TallySummary::default() && stats.histograms.is_empty() {
+        return Acr::Info(ArCliInfo {
+            message: format!(
+                "No tallies or stats recorded at: '{}'",
+                tally_dir.display().to_string().yellow()
+            ),
+            ..Default::default()
+        });
+    }
+
+    if json {
+        Acr::Plain(render_json(&summary, &stats, histograms, since))
+    } else {
+        Acr::Success(Some(ArCliSuccess {
+            message: render_text(&summary, &stats, histograms, since),
+            ..Default::default()
+        }))
+    }
+}
+
+/// Aggregates the tallies of a tally directory.
+///
+/// # Arguments
+///
+/// - `tally_dir`: The directory containing the tally files.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `since`: The start of the time window failures are counted in.
+/// - `now`: The instant the lock state is evaluated at.
+///
+/// # Returns
+///
+/// The summary of the tallies, empty if the tally directory doesn't exist.
+///
+/// # Errors
+///
+/// Returns the `io::Error` if the tally directory exists but cannot be read.
+fn summarize_tallies(
+    tally_dir: &Path,
+    config: Config,
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> io::Result<TallySummary> {
+    let settings = Settings {
+        config,
+        ..Settings::default()
+    };
+
+    let tallies = match tally_entries(tally_dir, &settings.config) {
+        Ok(tallies) => tallies,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(TallySummary::default()),
+        Err(e) => return Err(e),
+    };
+
+    let mut summary = TallySummary::default();
+    for (user, tally) in tallies {
+        let Ok(tally) = tally else {
+            summary.skipped += 1;
+            continue;
+        };
+        if tally.failures_count <= 0 {
+            continue;
+        }
+
+        let locked = tally
+            .effective_unlock_instant(&settings)
+            .is_some_and(|unlock_instant| now < unlock_instant);
+        // only the last failure instant is recorded, so the failures of an account are counted
+        // if its last failure is within the window
+        let recent = since <= tally.failure_instant;
+        // uid-keyed tallies remember the user name
+        let user = tally.user_name.unwrap_or(user);
+        summary.add(user, tally.failures_count, locked, recent);
+    }
+
+    Ok(summary)
+}
+
+/// Renders the tallies and statistics as JSON.
+fn render_json(
+    summary: &TallySummary,
+    stats: &Stats,
+    histograms: bool,
+    since: DateTime<Utc>,
+) -> String {
+    let buckets = |edges: &[i64], histogram: &Histogram| {
+        histogram
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(index, count)| json!({ "le": edges.get(index), "count": count }))
+            .collect::<Vec<_>>()
+    };
+
+    let services: serde_json::Map<String, serde_json::Value> = stats
+        .histograms
+        .iter()
+        .map(|(service, service_histograms)| {
+            let mut value = json!({ "clears": service_histograms.failures.total() });
+            if histograms {
+                value["failures"] = json!(buckets(&FAILURE_BUCKETS, &service_histograms.failures));
+                value["seconds"] = json!(buckets(&SECONDS_BUCKETS, &service_histograms.seconds));
+            }
+            (service.clone(), value)
+        })
+        .collect();
+
+    let top: Vec<_> = summary
+        .top
+        .iter()
+        .map(|(user, failures)| json!({ "user": user, "failures": failures }))
+        .collect();
+
+    json!({
+        "tallies": {
+            "since": since.to_rfc3339(),
+            "accounts_with_failures": summary.accounts,
+            "locked_accounts": summary.locked,
+            "recent_failures": summary.recent_failures,
+            "top": top,
+            "skipped": summary.skipped,
+        },
+        "services": services,
+    })
+    .to_string()
+}
+
+/// Renders the tallies and statistics as text.
+fn render_text(
+    summary: &TallySummary,
+    stats: &Stats,
+    histograms: bool,
+    since: DateTime<Utc>,
+) -> String {
+    let mut message = format!(
+        "tallies\n  accounts with failures: {}\n  locked accounts: {}\n  failures since {since}: {}",
+        summary.accounts, summary.locked, summary.recent_failures
+    );
+    if summary.skipped > 0 {
+        let _ = write!(
+            message,
+            "\n  {}",
+            format!("skipped corrupt tallies: {}", summary.skipped).yellow()
+        );
+    }
+
+    if !summary.top.is_empty() {
+        let _ = write!(message, "\n\n{:<32} {:>8}", "TOP USERS", "FAILURES");
+        for (user, failures) in &summary.top {
+            let _ = write!(message, "\n{:<32} {failures:>8}", user_label(user));
+        }
+    }
+
+    if !stats.histograms.is_empty() {
+        message += "\n\nrecorded stats";
+    }
+
+    for (service, service_histograms) in &stats.histograms {
+        let _ = write!(
+            message,
+            "\n\nservice: '{}' ({} clears)",
+            service.yellow(),
+            service_histograms.failures.total()
+        );
+
+        if histograms {
+            message += "\n  failures before success";
+            message += &render_histogram(&FAILURE_BUCKETS, &service_histograms.failures);
+            message += "\n  seconds from first failure to success";
+            message += &render_histogram(&SECONDS_BUCKETS, &service_histograms.seconds);
+        }
+    }
+
+    message
+}
+
+/// Renders a single histogram as text bars.
+fn render_histogram(edges: &[i64], histogram: &Histogram) -> String {
+    let max = histogram.counts.iter().copied().max().unwrap_or_default();
+    let mut rendered = String::new();
+
+    for (index, count) in histogram.counts.iter().enumerate() {
+        let label = match edges.get(index) {
+            Some(edge) => format!("<= {edge}"),
+            None => format!("> {}", edges.last().unwrap_or(&0)),
+        };
+        let bar = (count * BAR_WIDTH).checked_div(max).unwrap_or_default();
+        let _ = write!(
+            rendered,
+            "\n    {label:>10} {:<width$} {count}",
+            "#".repeat(usize::try_from(bar).unwrap_or_default()),
+            width = usize::try_from(BAR_WIDTH).unwrap_or_default()
+        );
+    }
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn write_tally(tally_dir: &Path, user: &str, count: i32, instant: DateTime<Utc>) {
+        fs::write(
+            tally_dir.join(user),
+            format!("[Fails]\ncount = {count}\ninstant = \"{instant}\""),
+        )
+        .expect("Failed to write tally");
+    }
+
+    #[test]
+    fn test_show_stats() {
+        let temp_dir =
+            TempDir::new("test_show_stats").expect("Failed to create temporary directory");
+        let stats_file = temp_dir.path().join("stats.toml");
+        let config = || Config {
+            tally_dir: temp_dir.path().join("tally"),
+            stats_file: stats_file.clone(),
+            ..Config::default()
+        };
+        let now = Utc::now();
+        let since = now - Duration::hours(24);
+
+        // nothing recorded
+        let result = show_stats(config(), true, false, since, now);
+        assert!(matches!(result, Acr::Info(_)), "Expected no stats info");
+
+        let mut stats = Stats::default();
+        stats.record_clear("sshd", 3, 45);
+        stats.save(&stats_file).expect("Failed to write stats");
+
+        let Acr::Success(Some(success)) = show_stats(config(), true, false, since, now) else {
+            panic!("Expected text histograms");
+        };
+        assert!(success.message.contains("accounts with failures: 0"));
+        assert!(success.message.contains("failures before success"));
+        assert!(success.message.contains("<= 3"));
+
+        let Acr::Plain(output) = show_stats(config(), true, true, since, now) else {
+            panic!("Expected JSON histograms");
+        };
+        let value: serde_json::Value = serde_json::from_str(&output).expect("Expected valid JSON");
+        assert_eq!(value["services"]["sshd"]["clears"], 1);
+        assert_eq!(value["services"]["sshd"]["failures"][2]["count"], 1);
+        assert_eq!(value["services"]["sshd"]["failures"][2]["le"], 3);
+    }
+
+    #[test]
+    fn test_summarize_tallies() {
+        let temp_dir =
+            TempDir::new("test_summarize_tallies").expect("Failed to create temporary directory");
+        let tally_dir = temp_dir.path();
+        let now = Utc::now();
+        let since = now - Duration::hours(24);
+
+        // 12 recent accounts, the six over the free tries are locked
+        for failures in 1..=12 {
+            write_tally(tally_dir, &format!("user_{failures:02}"), failures, now);
+        }
+        // failures before the window, a reset tally and a corrupt one
+        write_tally(tally_dir, "old_user", 20, now - Duration::days(2));
+        write_tally(tally_dir, "reset_user", 0, now);
+        fs::write(tally_dir.join("broken_user"), "not a tally").expect("Failed to write tally");
+
+        let summary = summarize_tallies(tally_dir, Config::default(), since, now)
+            .expect("Failed to summarize tallies");
+        assert_eq!(summary.accounts, 13);
+        assert_eq!(summary.locked, 6);
+        assert_eq!(summary.recent_failures, (1..=12).sum::<u64>());
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.top.len(), TOP_ACCOUNTS);
+        assert_eq!(summary.top[0], ("old_user".to_string(), 20));
+        assert_eq!(summary.top[1], ("user_12".to_string(), 12));
+        assert_eq!(summary.top[9], ("user_04".to_string(), 4));
+
+        // ties are ordered by user
+        let mut summary = TallySummary::default();
+        summary.add("b".to_string(), 3, false, true);
+        summary.add("a".to_string(), 3, false, true);
+        assert_eq!(
+            summary.top,
+            vec![("a".to_string(), 3), ("b".to_string(), 3)]
+        );
+
+        let missing = summarize_tallies(&tally_dir.join("missing"), Config::default(), since, now)
+            .expect("Expected an empty summary");
+        assert_eq!(missing, TallySummary::default());
+    }
+}
@@ -0,0 +1,296 @@
+//! # Status Module
+//!
+//! The `status` module provides functionality to inspect the tally information of a user.
+//! It reads the tally file with the same parsing code the PAM module uses and reports the
+//! failure count, the last failure, the last successful login recorded by the session hook,
+//! whether the account is currently locked and when it unlocks.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use common::{config::Config, query};
+use std::{fmt::Write, path::Path};
+
+use super::{load_config, tally_target};
+
+use crate::{ArCliInfo, ArCliLocked, ArCliResult as Acr, ArCliTally};
+
+/// Shows the tally information for a specific user.
+///
+/// The function reads the configuration, constructs the path to the tally file for the given user,
+/// and reports the lockout status stored in it.
+///
+/// # Arguments
+///
+/// - `user`: The username for which the tally information should be shown.
+/// - `uid`: The uid for which the tally information should be shown, if no username is given.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If the account is locked, returns `ArCliResult::Locked` with the tally details.
+/// - If the account is not locked, returns `ArCliResult::Info`.
+/// - If the user can't be resolved or the tally file does not exist, returns
+///   `ArCliResult::NotFound`.
+/// - If the tally file cannot be parsed, returns `ArCliResult::Error` with the error message.
+/// - If the user name can't be used as a tally file name, returns `ArCliResult::Error`.
+#[must_use]
+pub fn user(user: Option<&str>, uid: Option<u32>) -> Acr {
+    let config = load_config();
+
+    let (user, tally_path) = match tally_target(&config, user, uid) {
+        Ok(target) => target,
+        Err(e) => return e,
+    };
+
+    tally_status(&tally_path, &user, &config, Utc::now())
+}
+
+/// Computes the status report of a tally file at a given instant with [`query`].
+///
+/// # Arguments
+///
+/// - `path`: The path to the tally file.
+/// - `user`: The username associated with the tally file.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `now`: The instant the lock state is evaluated at.
+///
+/// # Returns
+///
+/// An `ArCliResult` describing the tally.
+fn tally_status(path: &Path, user: &str, config: &Config, now: DateTime<Utc>) -> Acr {
+    let status = match query::tally_lock_status(path, config, now) {
+        Ok(Some(status)) => status,
+        Ok(None) => {
+            return Acr::NotFound(ArCliInfo {
+                message: format!("No tally found for user: '{}'", user.yellow()),
+                ..Default::default()
+            })
+        }
+        Err(e) => return e.into(),
+    };
+
+    let locked = status.is_locked();
+
+    let mut message = format!(
+        "tally for user: '{}'\n  failures:     {}",
+        user.yellow(),
+        status.failures
+    );
+
+    if let Some(last_success) = status.last_success {
+        let _ = write!(message, " since last successful login at {last_success}");
+    }
+
+    if let Some(last_failure) = status.last_failure {
+        let _ = write!(message, "\n  last failure: {last_failure}");
+    }
+
+    for (label, value) in [
+        ("user name:   ", &status.user_name),
+        ("service:     ", &status.service),
+        ("rhost:       ", &status.rhost),
+        ("tty:         ", &status.tty),
+    ] {
+        if let Some(value) = value {
+            let _ = write!(message, "\n  {label} {value}");
+        }
+    }
+
+    let _ = write!(
+        message,
+        "\n  locked:       {}",
+        if locked { "yes" } else { "no" }
+    );
+
+    if let Some(unlock_instant) = status.unlock_instant {
+        let _ = write!(
+            message,
+            "\n  unlocks at:   {}",
+            config.message_time(unlock_instant)
+        );
+    }
+
+    let tallies = vec![ArCliTally {
+        user: user.to_string(),
+        failures: status.failures,
+        unlock_instant: status.unlock_instant,
+        locked,
+    }];
+
+    if locked {
+        Acr::Locked(ArCliLocked { message, tallies })
+    } else {
+        Acr::Info(ArCliInfo { message, tallies })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use common::config::NormalizeUser;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_normalized_target() {
+        let temp_dir = TempDir::new("test_normalized_target").unwrap();
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+
+        // the name is used as given without normalize_user
+        let (user, _) = tally_target(&config, Some("Status_User@corp.example"), None).unwrap();
+        assert_eq!(user, "Status_User@corp.example");
+
+        // and normalized like the PAM module does with it
+        let config = Config {
+            normalize_user: NormalizeUser {
+                lowercase: true,
+                strip_realm: true,
+            },
+            ..config
+        };
+        let (user, tally_path) =
+            tally_target(&config, Some("Status_User@corp.example"), None).unwrap();
+        assert_eq!(user, "status_user");
+        assert_eq!(tally_path, temp_dir.path().join("status_user"));
+    }
+
+    #[test]
+    fn test_tally_status() {
+        let temp_dir =
+            TempDir::new("test_tally_status").expect("Failed to create temporary directory");
+        let now = Utc::now();
+
+        // no tally file
+        let temp_tally_path = temp_dir.path().join("test_user");
+        let result = tally_status(&temp_tally_path, "test_user", &Config::default(), now);
+        assert!(
+            matches!(result, Acr::NotFound(ref info) if info.message.contains("No tally found")),
+            "Expected missing tally to be reported"
+        );
+
+        // tally with 0 failures
+        fs::write(&temp_tally_path, "[Fails]\ncount = 0").expect("Failed to write tally");
+        let result = tally_status(&temp_tally_path, "test_user", &Config::default(), now);
+        assert!(
+            matches!(result, Acr::Info(ref info) if info.message.contains("failures:     0")),
+            "Expected empty tally to be reported"
+        );
+
+        // locked tally
+        fs::write(
+            &temp_tally_path,
+            format!(
+                "[Fails]\ncount = 7\ninstant = \"{now}\"\nunlock_instant = \"{}\"",
+                now + Duration::seconds(30)
+            ),
+        )
+        .expect("Failed to write tally");
+        let result = tally_status(&temp_tally_path, "test_user", &Config::default(), now);
+        assert!(
+            matches!(result, Acr::Locked(_)),
+            "Expected user to be locked"
+        );
+
+        assert!(
+            matches!(result, Acr::Locked(ref locked) if !locked.message.contains("rhost:")),
+            "Expected no source without source items"
+        );
+
+        // source items of the last authentication
+        fs::write(
+            &temp_tally_path,
+            format!(
+                "[Fails]\ncount = 7\ninstant = \"{now}\"\nunlock_instant = \"{}\"\nservice = \"sshd\"\nrhost = \"192.0.2.1\"",
+                now + Duration::seconds(30)
+            ),
+        )
+        .expect("Failed to write tally");
+        let result = tally_status(&temp_tally_path, "test_user", &Config::default(), now);
+        assert!(
+            matches!(result, Acr::Locked(ref locked) if locked.message.contains("service:      sshd\n  rhost:        192.0.2.1")),
+            "Expected source items to be reported"
+        );
+
+        // expired lock
+        let result = tally_status(
+            &temp_tally_path,
+            "test_user",
+            &Config::default(),
+            now + Duration::seconds(31),
+        );
+        assert!(
+            matches!(result, Acr::Info(ref info) if info.message.contains("locked:       no")),
+            "Expected lock to be expired"
+        );
+
+        // last successful login recorded by the session hook
+        fs::write(
+            &temp_tally_path,
+            format!("[Fails]\ncount = 5\ninstant = \"{now}\"\nlast_success = \"{now}\""),
+        )
+        .expect("Failed to write tally");
+        let result = tally_status(&temp_tally_path, "test_user", &Config::default(), now);
+        assert!(
+            matches!(result, Acr::Info(ref info) if info.message.contains(&format!("failures:     5 since last successful login at {now}"))),
+            "Expected the last success to be reported"
+        );
+    }
+
+    #[test]
+    fn test_tally_status_free_tries_boundary() {
+        let temp_dir = TempDir::new("test_tally_status_free_tries_boundary")
+            .expect("Failed to create temporary directory");
+        let temp_tally_path = temp_dir.path().join("test_user");
+        let now = Utc::now();
+
+        let write_tally = |count: i32| {
+            fs::write(
+                &temp_tally_path,
+                format!(
+                    "[Fails]\ncount = {count}\ninstant = \"{now}\"\nunlock_instant = \"{}\"",
+                    now + Duration::seconds(30)
+                ),
+            )
+            .expect("Failed to write tally");
+        };
+
+        // count == free_tries is still free
+        write_tally(6);
+        let result = tally_status(&temp_tally_path, "test_user", &Config::default(), now);
+        assert!(
+            matches!(result, Acr::Info(ref info) if info.message.contains("locked:       no")),
+            "Expected free tries not to lock"
+        );
+
+        // count == free_tries + 1 locks
+        write_tally(7);
+        let result = tally_status(&temp_tally_path, "test_user", &Config::default(), now);
+        assert!(
+            matches!(result, Acr::Locked(_)),
+            "Expected user to be locked"
+        );
+    }
+}
@@ -0,0 +1,614 @@
+//! # Watch Module
+//!
+//! The `watch` module follows the tally directory live and prints an event whenever a tally is
+//! created, its failures increase, its user gets locked, it is reset or it is removed. Changes are
+//! picked up with inotify, or by polling the tally directory where inotify isn't available.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use common::{error::AuthRampError, settings::Settings, tally};
+use inotify::{Inotify, WatchMask};
+use serde::{Serialize, Serializer};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{load_config, user_label};
+use crate::{ArCliError, ArCliInfo, ArCliResult as Acr, ArCliWarning};
+
+/// How often Ctrl-C and new inotify events are checked for.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How long the tally directory has to be quiet before rewrites are reported.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// The longest a change is held back while the tally directory keeps changing.
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the tally directory is read without inotify.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of a tally the events are derived from.
+#[derive(Debug, Clone, PartialEq)]
+struct WatchedTally {
+    user: String,
+    failures: i32,
+    unlock_instant: Option<DateTime<Utc>>,
+}
+
+impl WatchedTally {
+    /// Whether the user is locked at an instant.
+    fn locked(&self, now: DateTime<Utc>) -> bool {
+        self.unlock_instant
+            .is_some_and(|unlock_instant| now < unlock_instant)
+    }
+}
+
+/// The kinds of changes to a tally.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    /// A tally file appeared.
+    Created,
+    /// The failures of a tally increased.
+    Failed,
+    /// The user got locked, by exceeding the free tries or manually.
+    Locked,
+    /// The failures of a tally were reset.
+    Reset,
+    /// A tally file disappeared.
+    Removed,
+}
+
+impl EventKind {
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Failed => "failed",
+            EventKind::Locked => "locked",
+            EventKind::Reset => "reset",
+            EventKind::Removed => "removed",
+        }
+    }
+}
+
+/// A change to a tally, printed as a line per event.
+#[derive(Debug, Serialize)]
+struct WatchEvent {
+    event: EventKind,
+    #[serde(serialize_with = "serialize_time")]
+    time: DateTime<Utc>,
+    user: String,
+    failures: i32,
+    #[serde(serialize_with = "crate::serialize_instant")]
+    unlock_instant: Option<DateTime<Utc>>,
+    locked: bool,
+}
+
+impl WatchEvent {
+    fn new(event: EventKind, tally: &WatchedTally, now: DateTime<Utc>) -> Self {
+        WatchEvent {
+            event,
+            time: now,
+            user: tally.user.clone(),
+            failures: tally.failures,
+            unlock_instant: tally.unlock_instant,
+            locked: tally.locked(now),
+        }
+    }
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = format!("{:<8}", self.event.name());
+        let name = match self.event {
+            EventKind::Created => name.cyan(),
+            EventKind::Failed => name.yellow(),
+            EventKind::Locked => name.red(),
+            EventKind::Reset | EventKind::Removed => name.green(),
+        }
+        .bold();
+        write!(
+            f,
+            "{} {name} {}  failures: {}",
+            self.time.format("%F %T UTC"),
+            user_label(&self.user),
+            self.failures
+        )?;
+        match self.unlock_instant {
+            Some(unlock_instant) if self.locked => {
+                write!(f, ", unlocks at {}", unlock_instant.format("%F %T UTC"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Serializes the instant of an event as an RFC 3339 string.
+fn serialize_time<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339())
+}
+
+/// Follows the tally directory and prints the changes to the tallies until Ctrl-C.
+///
+/// # Arguments
+///
+/// - `json`: Print every event as a JSON object instead of a line of text.
+///
+/// # Returns
+///
+/// A `Result` representing the outcome of the operation.
+///
+/// - If the watch is stopped with Ctrl-C, returns `ArCliResult::Info`.
+/// - If the tally directory cannot be read, returns `ArCliResult::Denied` or
+///   `ArCliResult::Error` with the error message.
+#[must_use]
+pub fn tallies(json: bool) -> Acr {
+    let settings = Settings {
+        config: load_config(),
+        ..Settings::default()
+    };
+    let tally_dir = settings.config.tally_dir.clone();
+
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = Arc::clone(&running);
+    if let Err(e) = ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst)) {
+        return Acr::Error(ArCliError {
+            message: format!("Error handling Ctrl-C: {e}"),
+        });
+    }
+
+    let mut snapshot = match scan(&tally_dir, &settings, &BTreeMap::new()) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return AuthRampError::io(
+                format!("Error reading tally directory {}", tally_dir.display()),
+                e,
+            )
+            .into()
+        }
+    };
+
+    let mut watcher = Watcher::new();
+    watcher.watch(&tally_dir, &snapshot);
+
+    eprintln!(
+        "{}",
+        ArCliInfo {
+            message: format!(
+                "Watching '{}', press Ctrl-C to stop",
+                tally_dir.display().to_string().yellow()
+            ),
+            ..Default::default()
+        }
+    );
+
+    while running.load(Ordering::SeqCst) {
+        if !watcher.wait(&running) {
+            continue;
+        }
+
+        let now = Utc::now();
+        let tallies = match scan(&tally_dir, &settings, &snapshot) {
+            Ok(tallies) => tallies,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    ArCliWarning {
+                        message: format!(
+                            "Error reading tally directory {}: {e}",
+                            tally_dir.display()
+                        ),
+                    }
+                );
+                continue;
+            }
+        };
+
+        for event in events(&snapshot, &tallies, now) {
+            if json {
+                println!("{}", serde_json::to_string(&event).unwrap_or_default());
+            } else {
+                println!("{event}");
+            }
+        }
+
+        snapshot = tallies;
+        watcher.watch(&tally_dir, &snapshot);
+    }
+
+    Acr::Info(ArCliInfo {
+        message: format!(
+            "Stopped watching '{}'",
+            tally_dir.display().to_string().yellow()
+        ),
+        ..Default::default()
+    })
+}
+
+/// Reads the state of every tally in the tally directory.
+///
+/// A missing tally directory has no tallies. Tallies that can't be parsed, e.g. while another
+/// writer isn't done yet, keep their previous state and are reported as warnings.
+///
+/// # Arguments
+///
+/// - `tally_dir`: The directory containing the tally files.
+/// - `settings`: The settings the unlock instants are computed with.
+/// - `previous`: The state of the tallies at the previous scan.
+///
+/// # Returns
+///
+/// The state of the tallies by path.
+///
+/// # Errors
+///
+/// Returns the `io::Error` if the tally directory can't be read.
+fn scan(
+    tally_dir: &Path,
+    settings: &Settings,
+    previous: &BTreeMap<PathBuf, WatchedTally>,
+) -> io::Result<BTreeMap<PathBuf, WatchedTally>> {
+    let paths = match tally::tally_files(tally_dir) {
+        Ok(paths) => paths,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut tallies = BTreeMap::new();
+    for path in paths {
+        match tally::Tally::read_trusted_tally_file(&path, &settings.config) {
+            Ok(tally) => {
+                let watched = WatchedTally {
+                    // uid-keyed tallies remember the user name
+                    user: tally.user_name.clone().unwrap_or_else(|| {
+                        path.file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .to_string()
+                    }),
+                    failures: tally.failures_count,
+                    unlock_instant: tally.effective_unlock_instant(settings),
+                };
+                tallies.insert(path, watched);
+            }
+            // the file disappeared since the directory was read
+            Err(_) if !path.exists() => (),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    ArCliWarning {
+                        message: format!("Skipping tally {}: {e}", path.display()),
+                    }
+                );
+                if let Some(watched) = previous.get(&path) {
+                    tallies.insert(path, watched.clone());
+                }
+            }
+        }
+    }
+    Ok(tallies)
+}
+
+/// Derives the events between two states of the tallies.
+///
+/// # Arguments
+///
+/// - `old`: The state of the tallies at the previous scan.
+/// - `new`: The current state of the tallies.
+/// - `now`: The instant the lock states are evaluated at.
+///
+/// # Returns
+///
+/// The events, ordered by tally path.
+fn events(
+    old: &BTreeMap<PathBuf, WatchedTally>,
+    new: &BTreeMap<PathBuf, WatchedTally>,
+    now: DateTime<Utc>,
+) -> Vec<WatchEvent> {
+    let mut events = Vec::new();
+
+    for (path, tally) in new {
+        let mut kinds = Vec::new();
+        match old.get(path) {
+            None => kinds.push(EventKind::Created),
+            Some(previous) if tally.failures > previous.failures => {
+                kinds.push(EventKind::Failed);
+            }
+            Some(previous) if tally.failures < previous.failures => kinds.push(EventKind::Reset),
+            Some(_) => (),
+        }
+        // the lock may have expired since the previous scan, so both are evaluated now
+        let was_locked = old.get(path).is_some_and(|previous| previous.locked(now));
+        if tally.locked(now) && !was_locked {
+            kinds.push(EventKind::Locked);
+        }
+
+        events.extend(
+            kinds
+                .into_iter()
+                .map(|kind| WatchEvent::new(kind, tally, now)),
+        );
+    }
+
+    for (path, tally) in old {
+        if !new.contains_key(path) {
+            let removed = WatchedTally {
+                failures: 0,
+                unlock_instant: None,
+                ..tally.clone()
+            };
+            events.push(WatchEvent::new(EventKind::Removed, &removed, now));
+        }
+    }
+
+    events
+}
+
+/// Waits for changes to the tally directory, with inotify or by polling.
+struct Watcher {
+    inotify: Option<Inotify>,
+    watching: bool,
+    buffer: Vec<u8>,
+}
+
+impl Watcher {
+    /// Sets up inotify, falling back to polling if it isn't available.
+    fn new() -> Self {
+        let inotify = Inotify::init()
+            .map_err(|e| {
+                eprintln!(
+                    "{}",
+                    ArCliWarning {
+                        message: format!(
+                            "inotify is not available ({e}), polling the tally directory instead"
+                        ),
+                    }
+                );
+            })
+            .ok();
+
+        Watcher {
+            inotify,
+            watching: false,
+            buffer: vec![0; 4096],
+        }
+    }
+
+    /// Watches the tally directory and its shard directories.
+    ///
+    /// Watches are added again after every change, to follow new shard directories and a
+    /// recreated tally directory. While the tally directory can't be watched, e.g. until it's
+    /// created, it is polled.
+    ///
+    /// # Arguments
+    ///
+    /// - `tally_dir`: The directory containing the tally files.
+    /// - `tallies`: The tallies found in it.
+    fn watch(&mut self, tally_dir: &Path, tallies: &BTreeMap<PathBuf, WatchedTally>) {
+        let Some(inotify) = &self.inotify else {
+            return;
+        };
+        let mask = WatchMask::CREATE
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::MODIFY
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF;
+
+        let mut watches = inotify.watches();
+        self.watching = watches.add(tally_dir, mask).is_ok();
+        for shard_dir in tallies.keys().filter_map(|path| path.parent()) {
+            if shard_dir != tally_dir {
+                let _ = watches.add(shard_dir, mask);
+            }
+        }
+    }
+
+    /// Waits until the tally directory may have changed.
+    ///
+    /// Rapid rewrites are debounced: changes are only reported once the tally directory has been
+    /// quiet for a moment, or after `MAX_DELAY` at the latest.
+    ///
+    /// # Arguments
+    ///
+    /// - `running`: Cleared by Ctrl-C to stop waiting.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the tally directory should be read again.
+    fn wait(&mut self, running: &AtomicBool) -> bool {
+        if !self.watching {
+            let start = Instant::now();
+            while start.elapsed() < POLL_INTERVAL && running.load(Ordering::SeqCst) {
+                thread::sleep(TICK);
+            }
+            return running.load(Ordering::SeqCst);
+        }
+
+        if !self.has_events() {
+            thread::sleep(TICK);
+            return false;
+        }
+
+        let start = Instant::now();
+        let mut last_event = start;
+        while start.elapsed() < MAX_DELAY
+            && last_event.elapsed() < DEBOUNCE
+            && running.load(Ordering::SeqCst)
+        {
+            thread::sleep(TICK);
+            if self.has_events() {
+                last_event = Instant::now();
+            }
+        }
+        true
+    }
+
+    /// Reads the pending inotify events.
+    ///
+    /// # Returns
+    ///
+    /// `true` if there were events.
+    fn has_events(&mut self) -> bool {
+        let Some(inotify) = &mut self.inotify else {
+            return false;
+        };
+        match inotify.read_events(&mut self.buffer) {
+            Ok(events) => events.count() > 0,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(_) => {
+                // poll from now on
+                self.inotify = None;
+                self.watching = false;
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use common::{config::Config, tally::Tally};
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn watched(user: &str, failures: i32, unlock_instant: Option<DateTime<Utc>>) -> WatchedTally {
+        WatchedTally {
+            user: user.to_string(),
+            failures,
+            unlock_instant,
+        }
+    }
+
+    fn kinds(events: &[WatchEvent]) -> Vec<(&str, EventKind)> {
+        events
+            .iter()
+            .map(|event| (event.user.as_str(), event.event))
+            .collect()
+    }
+
+    #[test]
+    fn test_events() {
+        let now = Utc::now();
+        let locked = Some(now + Duration::minutes(5));
+        let expired = Some(now - Duration::minutes(5));
+
+        let old = BTreeMap::from([
+            ("/t/failing".into(), watched("failing", 1, None)),
+            ("/t/crossing".into(), watched("crossing", 5, None)),
+            ("/t/resetting".into(), watched("resetting", 7, locked)),
+            ("/t/expired".into(), watched("expired", 6, expired)),
+            ("/t/still_locked".into(), watched("still_locked", 6, locked)),
+            ("/t/removed".into(), watched("removed", 3, None)),
+            ("/t/unchanged".into(), watched("unchanged", 2, None)),
+        ]);
+        let new = BTreeMap::from([
+            ("/t/created".into(), watched("created", 1, None)),
+            ("/t/failing".into(), watched("failing", 2, None)),
+            ("/t/crossing".into(), watched("crossing", 6, locked)),
+            ("/t/resetting".into(), watched("resetting", 0, None)),
+            ("/t/expired".into(), watched("expired", 7, locked)),
+            ("/t/still_locked".into(), watched("still_locked", 7, locked)),
+            ("/t/unchanged".into(), watched("unchanged", 2, None)),
+        ]);
+
+        let events = events(&old, &new, now);
+        assert_eq!(
+            kinds(&events),
+            vec![
+                ("created", EventKind::Created),
+                ("crossing", EventKind::Failed),
+                ("crossing", EventKind::Locked),
+                ("expired", EventKind::Failed),
+                ("expired", EventKind::Locked),
+                ("failing", EventKind::Failed),
+                ("resetting", EventKind::Reset),
+                ("still_locked", EventKind::Failed),
+                ("removed", EventKind::Removed),
+            ]
+        );
+        assert!(events[2].locked);
+        assert!(!events[6].locked);
+
+        let json = serde_json::to_value(&events[2]).unwrap();
+        assert_eq!(json["event"], "locked");
+        assert_eq!(json["user"], "crossing");
+        assert_eq!(json["failures"], 6);
+        assert_eq!(json["locked"], true);
+
+        // a manual lock without new failures
+        let manual = BTreeMap::from([("/t/unchanged".into(), watched("unchanged", 2, locked))]);
+        let unchanged = BTreeMap::from([("/t/unchanged".into(), watched("unchanged", 2, None))]);
+        assert_eq!(
+            kinds(&super::events(&unchanged, &manual, now)),
+            vec![("unchanged", EventKind::Locked)]
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let temp_dir = TempDir::new("test_watch_scan").unwrap();
+        let settings = Settings {
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+
+        // a missing directory has no tallies
+        let missing = temp_dir.path().join("missing");
+        assert!(scan(&missing, &settings, &BTreeMap::new())
+            .unwrap()
+            .is_empty());
+
+        Tally {
+            user_name: Some("alice".to_string()),
+            failures_count: 2,
+            ..Tally::default()
+        }
+        .write_tally_file(&temp_dir.path().join("1000"), &settings.config)
+        .unwrap();
+        let tallies = scan(temp_dir.path(), &settings, &BTreeMap::new()).unwrap();
+        let alice = temp_dir.path().join("1000");
+        assert_eq!(tallies[&alice], watched("alice", 2, None));
+
+        // a broken tally keeps its previous state
+        fs::write(&alice, "not a tally").unwrap();
+        assert_eq!(scan(temp_dir.path(), &settings, &tallies).unwrap(), tallies);
+        assert!(scan(temp_dir.path(), &settings, &BTreeMap::new())
+            .unwrap()
+            .is_empty());
+    }
+}
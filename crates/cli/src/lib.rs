@@ -0,0 +1,343 @@
+//! # `AuthRamp` CLI Library
+//!
+//! The `cli` library holds the commands of the `authramp` binary and the results they return.
+//! The binary parses the command line and prints the results, integration tests call the
+//! commands directly, e.g. to evaluate `[Cli.permissions]` against fixture group memberships.
+//!
+//! # Structs
+//!
+//! - [`ArCliError`](struct.ArCliError.html): Represents an error result in the `AuthRamp` CLI.
+//! - [`ArCliSuccess`](struct.ArCliSuccess.html): Represents a success result in the `AuthRamp` CLI.
+//! - [`ArCliInfo`](struct.ArCliInfo.html): Represents an informational result in the `AuthRamp` CLI.
+//! - [`ArCliLocked`](struct.ArCliLocked.html): Represents a locked account result in the `AuthRamp` CLI.
+//! - [`ArCliWarning`](struct.ArCliWarning.html): Represents a non-fatal warning in the `AuthRamp` CLI.
+//! - [`ArCliResult`](struct.ArCliResult.html): Represents the result of a command execution in the `AuthRamp` CLI.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use common::error::AuthRampError;
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+pub mod cmd;
+pub mod permissions;
+
+/// Exit code of a locked account.
+const EXIT_LOCKED: i32 = 1;
+
+/// Exit code of an unknown user or a missing tally (`EX_NOUSER`).
+const EXIT_NOT_FOUND: i32 = 67;
+
+/// Exit code of a failed command (`EX_SOFTWARE`).
+const EXIT_ERROR: i32 = 70;
+
+/// Exit code of a command refused by `[Cli.permissions]` or the file permissions (`EX_NOPERM`).
+const EXIT_DENIED: i32 = 77;
+
+/// Structs and enum to represent CLI output with colored formatting.
+///
+/// `ArCliError`, `ArCliSuccess`, `ArCliInfo`, `ArCliLocked` and `ArCliWarning` implement `Display`
+/// to format the message with colors and text.
+///
+/// `ArCliResult` is an enum with variants to hold the different structs.
+/// It implements `Display` to delegate to the inner value's implementation, and `Serialize` for
+/// the `--format json` output.
+
+#[derive(Debug, Serialize)]
+pub struct ArCliError {
+    message: String,
+}
+
+impl fmt::Display for ArCliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", "error:".red().bold(), self.message)
+    }
+}
+
+/// Shows the same context the PAM module logs for the error.
+impl From<AuthRampError> for ArCliError {
+    fn from(error: AuthRampError) -> Self {
+        ArCliError {
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Reports missing permissions as `Denied` with a hint to run as root, other errors as `Error`.
+impl From<AuthRampError> for ArCliResult {
+    fn from(error: AuthRampError) -> Self {
+        if error.is_permission_denied() {
+            ArCliResult::Denied(ArCliError {
+                message: format!("{error}. Run authramp as root, e.g. with sudo"),
+            })
+        } else {
+            ArCliResult::Error(error.into())
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ArCliSuccess {
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tallies: Vec<ArCliTally>,
+}
+
+impl fmt::Display for ArCliSuccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", "success:".green().bold(), self.message)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ArCliInfo {
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tallies: Vec<ArCliTally>,
+}
+
+impl fmt::Display for ArCliInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", "info:".yellow().bold(), self.message)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ArCliLocked {
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tallies: Vec<ArCliTally>,
+}
+
+impl fmt::Display for ArCliLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", "locked:".red().bold(), self.message)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArCliWarning {
+    pub message: String,
+}
+
+impl fmt::Display for ArCliWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", "warning:".yellow().bold(), self.message)
+    }
+}
+
+/// The details of a single tally in a command result.
+#[derive(Debug, Serialize)]
+pub struct ArCliTally {
+    user: String,
+    failures: i32,
+    #[serde(serialize_with = "serialize_instant")]
+    unlock_instant: Option<DateTime<Utc>>,
+    locked: bool,
+}
+
+/// Serializes an optional instant as an RFC 3339 string.
+#[allow(clippy::ref_option)] // the signature is dictated by `serialize_with`
+fn serialize_instant<S: Serializer>(
+    instant: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match instant {
+        Some(instant) => serializer.serialize_some(&instant.to_rfc3339()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum ArCliResult {
+    Success(Option<ArCliSuccess>),
+    Info(ArCliInfo),
+    NotFound(ArCliInfo),
+    Locked(ArCliLocked),
+    Denied(ArCliError),
+    Error(ArCliError),
+    Plain(String),
+}
+
+impl fmt::Display for ArCliResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArCliResult::Success(Some(ref success)) => write!(f, "{success}"),
+            ArCliResult::Success(None) => Ok(()),
+            ArCliResult::Error(ref error) | ArCliResult::Denied(ref error) => write!(f, "{error}"),
+            ArCliResult::Info(ref info) | ArCliResult::NotFound(ref info) => write!(f, "{info}"),
+            ArCliResult::Locked(ref locked) => write!(f, "{locked}"),
+            ArCliResult::Plain(ref output) => write!(f, "{output}"),
+        }
+    }
+}
+
+impl ArCliResult {
+    /// The name of the result in the JSON output.
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ArCliResult::Success(_) | ArCliResult::Plain(_) => "success",
+            ArCliResult::Info(_) => "info",
+            ArCliResult::NotFound(_) => "not_found",
+            ArCliResult::Locked(_) => "locked",
+            ArCliResult::Denied(_) => "denied",
+            ArCliResult::Error(_) => "error",
+        }
+    }
+
+    /// The message of the result, without the colored prefix.
+    #[must_use]
+    pub fn message(&self) -> &str {
+        match self {
+            ArCliResult::Success(Some(success)) => &success.message,
+            ArCliResult::Success(None) => "",
+            ArCliResult::Info(info) | ArCliResult::NotFound(info) => &info.message,
+            ArCliResult::Locked(locked) => &locked.message,
+            ArCliResult::Denied(error) | ArCliResult::Error(error) => &error.message,
+            ArCliResult::Plain(output) => output,
+        }
+    }
+
+    /// The exit code of the result, so scripts and monitoring checks can branch on it.
+    ///
+    /// # Returns
+    ///
+    /// - `0` for success and informational results
+    /// - `1` for a locked account
+    /// - `67` for an unknown user or a missing tally
+    /// - `70` for a failed command
+    /// - `77` for a command refused by `[Cli.permissions]` or the file permissions
+    #[must_use]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ArCliResult::Success(_) | ArCliResult::Info(_) | ArCliResult::Plain(_) => 0,
+            ArCliResult::Locked(_) => EXIT_LOCKED,
+            ArCliResult::NotFound(_) => EXIT_NOT_FOUND,
+            ArCliResult::Error(_) => EXIT_ERROR,
+            ArCliResult::Denied(_) => EXIT_DENIED,
+        }
+    }
+
+    /// Renders the result as a single JSON object for scripts.
+    ///
+    /// `Plain` output is already formatted by the command and returned unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// - `action`: The name of the executed subcommand.
+    /// - `user`: The user the subcommand was run for, if any.
+    ///
+    /// # Returns
+    ///
+    /// The JSON object with the action, user, result, message and tally details.
+    #[must_use]
+    pub fn to_json(&self, action: &str, user: Option<&str>) -> String {
+        if let ArCliResult::Plain(ref output) = self {
+            return output.clone();
+        }
+
+        let mut json = serde_json::json!({ "action": action, "result": self.kind() });
+        if let Some(user) = user {
+            json["user"] = user.into();
+        }
+        if let (Some(object), Ok(serde_json::Value::Object(details))) =
+            (json.as_object_mut(), serde_json::to_value(self))
+        {
+            object.extend(details);
+        }
+
+        json.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let locked = ArCliResult::Locked(ArCliLocked {
+            message: "tally for user: 'test_user'".to_string(),
+            tallies: vec![ArCliTally {
+                user: "test_user".to_string(),
+                failures: 7,
+                unlock_instant: DateTime::from_timestamp(1_700_000_000, 0),
+                locked: true,
+            }],
+        });
+        let json: serde_json::Value =
+            serde_json::from_str(&locked.to_json("status", Some("test_user"))).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "action": "status",
+                "user": "test_user",
+                "result": "locked",
+                "message": "tally for user: 'test_user'",
+                "tallies": [{
+                    "user": "test_user",
+                    "failures": 7,
+                    "unlock_instant": "2023-11-14T22:13:20+00:00",
+                    "locked": true,
+                }],
+            })
+        );
+
+        // errors carry no tallies and commands without user omit it
+        let error = ArCliResult::Error(ArCliError {
+            message: "Permission denied".to_string(),
+        });
+        let json: serde_json::Value = serde_json::from_str(&error.to_json("list", None)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "action": "list",
+                "result": "error",
+                "message": "Permission denied",
+            })
+        );
+
+        // plain output is passed through
+        let plain = ArCliResult::Plain("{}".to_string());
+        assert_eq!(plain.to_json("stats", None), "{}");
+    }
+
+    #[test]
+    fn test_permission_denied_result() {
+        let denied = ArCliResult::from(AuthRampError::io(
+            "Error deleting tally file /var/run/authramp/alice",
+            std::io::Error::from(std::io::ErrorKind::PermissionDenied),
+        ));
+        let ArCliResult::Denied(error) = denied else {
+            panic!("not denied");
+        };
+        assert!(error.message.contains("/var/run/authramp/alice"));
+        assert!(error.message.contains("sudo"));
+
+        let failed = ArCliResult::from(AuthRampError::io(
+            "Error writing tally file /var/run/authramp/alice",
+            std::io::Error::other("disk full"),
+        ));
+        assert!(matches!(failed, ArCliResult::Error(_)));
+    }
+}
@@ -12,18 +12,75 @@
 //! ```bash
 //! # Reset a locked PAM user
 //! authramp reset --user example_user
+//!
+//! # Lock a PAM user for two hours
+//! authramp lock --user example_user --duration 2h
+//!
+//! # Keep a PAM user locked until 5pm local time
+//! authramp set-unlock --user example_user --at "2024-06-01T17:00:00"
+//!
+//! # Show the tally of a PAM user
+//! authramp status --user example_user
+//!
+//! # List all locked PAM users
+//! authramp list --locked-only
+//!
+//! # Print the remaining lockout time of the invoking user until the lock ends
+//! authramp remaining --watch --interval 5s
+//!
+//! # Show the tally of a PAM user as JSON
+//! authramp --format json status --user example_user
+//!
+//! # Show the locked accounts and failures of the last week as JSON
+//! authramp --format json stats --since 7d
+//!
+//! # Show the recorded histograms as JSON
+//! authramp stats --histograms --json
+//!
+//! # Export the tallies for the node_exporter textfile collector
+//! authramp metrics --output /var/lib/node_exporter/authramp.prom
+//!
+//! # Check the configuration file for unknown keys and invalid values
+//! authramp config check
+//!
+//! # Carry the lockouts of pam_faillock over
+//! authramp import-faillock --dir /var/run/faillock
+//!
+//! # Back the tallies up and restore them on a reimaged host
+//! authramp export --output state.json
+//! authramp import --input state.json
+//!
+//! # Print the delays of 20 consecutive failures with the configuration
+//! authramp simulate --failures 20
+//!
+//! # Follow the lockout events live
+//! authramp watch
+//!
+//! # Install the bash completions
+//! authramp completions bash > /etc/bash_completion.d/authramp
 //! ```
 //!
 //! # Commands
 //!
 //! - [`reset`](cmd/reset/index.html): Resets a locked PAM user.
+//! - [`lock`](cmd/lock/index.html): Locks a PAM user until a time.
+//! - [`set-unlock`](cmd/lock/index.html): Sets the time a PAM user unlocks at.
+//! - [`status`](cmd/status/index.html): Shows the tally of a PAM user.
+//! - [`remaining`](cmd/remaining/index.html): Prints the remaining lockout time of a PAM user.
+//! - [`list`](cmd/list/index.html): Lists the tallies of all PAM users.
+//! - [`stats`](cmd/stats/index.html): Shows the tally overview and anonymous statistics of the PAM
+//!   module.
+//! - [`metrics`](cmd/metrics/index.html): Exports the tallies as Prometheus metrics.
+//! - [`config`](cmd/config/index.html): Checks the configuration or shows the effective one.
+//! - [`import-faillock`](cmd/import/index.html): Imports the tallies of `pam_faillock`.
+//! - [`export`](cmd/backup/index.html) and [`import`](cmd/backup/index.html): Export the tallies
+//!   to a JSON document and restore them.
+//! - [`simulate`](cmd/simulate/index.html): Simulates the delays of consecutive failures.
+//! - [`watch`](cmd/watch/index.html): Follows the lockout events live.
+//! - [`completions`](cmd/completions/index.html): Prints the completion script of a shell.
 //!
 //! # Structs
 //!
-//! - [`ArCliError`](struct.ArCliError.html): Represents an error result in the `AuthRamp` CLI.
-//! - [`ArCliSuccess`](struct.ArCliSuccess.html): Represents a success result in the `AuthRamp` CLI.
-//! - [`ArCliInfo`](struct.ArCliInfo.html): Represents an informational result in the `AuthRamp` CLI.
-//! - [`ArCliResult`](struct.ArCliResult.html): Represents the result of a command execution in the `AuthRamp` CLI.
 //! - [`Cli`](struct.Cli.html): Represents the main CLI struct.
 //! - [`Command`](enum.Command.html): Represents the available subcommands.
 //!
@@ -45,11 +102,18 @@
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use clap::{Parser, Subcommand};
-use cmd::reset;
-use colored::Colorize;
-use std::fmt;
-mod cmd;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use cli::cmd::{
+    self, backup, completions, config, import, list, lock, metrics, remaining, reset, simulate,
+    stats, status, watch,
+};
+use cli::{permissions, ArCliResult, ArCliWarning};
+use std::{
+    env,
+    ffi::OsStr,
+    io::{self, IsTerminal},
+    path::PathBuf,
+};
 
 const BANNER: &str = r" 
 
@@ -60,61 +124,45 @@ const BANNER: &str = r"
 ██   ██ ██████    ██   ██   ████   ████   ████      ████
 
 by 34n0@immerda.ch";
-/// Structs and enum to represent CLI output with colored formatting.
-///
-/// `ArCliError`, `ArCliSuccess`, and `ArCliInfo` implement `Display`
-/// to format the message with colors and text.
-///
-/// `ArCliResult` is an enum with variants to hold the different structs.
-/// It implements `Display` to delegate to the inner value's implementation.
-
-#[derive(Debug)]
-pub struct ArCliError {
-    message: String,
-}
-
-impl fmt::Display for ArCliError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", "error:".red().bold(), self.message)
-    }
+/// The output format of the command results.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum Format {
+    /// Colored text for humans
+    #[default]
+    Human,
+    /// A single JSON object for scripts
+    Json,
 }
 
-#[derive(Debug)]
-pub struct ArCliSuccess {
-    message: String,
+/// When the output is colored.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum ColorChoice {
+    /// Color a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    /// Always color the output
+    Always,
+    /// Never color the output
+    Never,
 }
 
-impl fmt::Display for ArCliSuccess {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", "success:".green().bold(), self.message)
-    }
-}
-
-#[derive(Debug)]
-pub struct ArCliInfo {
-    message: String,
-}
-
-impl fmt::Display for ArCliInfo {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", "info:".yellow().bold(), self.message)
-    }
-}
-
-#[derive(Debug)]
-pub enum ArCliResult {
-    Success(Option<ArCliSuccess>),
-    Info(ArCliInfo),
-    Error(ArCliError),
-}
-
-impl fmt::Display for ArCliResult {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl ColorChoice {
+    /// Decides whether to color the output.
+    ///
+    /// # Arguments
+    ///
+    /// - `is_terminal`: Whether stdout is a terminal.
+    /// - `no_color`: The value of the `NO_COLOR` environment variable, which disables colors
+    ///   unless it is empty.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the output is colored.
+    fn colorize(self, is_terminal: bool, no_color: Option<&OsStr>) -> bool {
         match self {
-            ArCliResult::Success(Some(ref success)) => write!(f, "{success}"),
-            ArCliResult::Success(None) => Ok(()),
-            ArCliResult::Error(ref error) => write!(f, "{error}"),
-            ArCliResult::Info(ref info) => write!(f, "{info}"),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => is_terminal && no_color.is_none_or(OsStr::is_empty),
         }
     }
 }
@@ -125,8 +173,29 @@ impl fmt::Display for ArCliResult {
     author = "34n0",
     about = &BANNER,
 )]
-
 struct Cli {
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        help = "Output format"
+    )]
+    format: Format,
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        help = "When to color the output"
+    )]
+    color: ColorChoice,
+    #[clap(
+        long,
+        global = true,
+        help = "Tally directory overriding the configured one, e.g. for testing without root"
+    )]
+    tally_dir: Option<PathBuf>,
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -135,23 +204,352 @@ struct Cli {
 enum Command {
     #[command(about = "Reset a locked PAM user")]
     Reset {
+        #[clap(
+            long,
+            short,
+            required_unless_present_any = ["all", "uid"],
+            conflicts_with_all = ["all", "uid"],
+            help = "Reset the tally of a user, repeat for several users"
+        )]
+        user: Vec<String>,
+        #[clap(long, conflicts_with = "all", help = "Reset the tally of a uid")]
+        uid: Option<u32>,
+        #[clap(long, help = "Reset the tallies of all users")]
+        all: bool,
+        #[clap(long, short, requires = "all", help = "Skip the confirmation prompt")]
+        yes: bool,
+        #[clap(long, help = "Delete the tally files instead of zeroing them")]
+        purge: bool,
+    },
+    #[command(about = "Lock a PAM user until a time")]
+    #[clap(group(clap::ArgGroup::new("unlock").required(true).args(["until", "duration"])))]
+    Lock {
         #[clap(long, short)]
         user: String,
+        #[clap(long, help = "Unlock time, e.g. 2024-02-04T12:00:00Z")]
+        until: Option<String>,
+        #[clap(long, help = "Lock duration, e.g. 2h or 1h30m")]
+        duration: Option<String>,
+        #[clap(long, help = "Lock root as well")]
+        force: bool,
+    },
+    #[command(about = "Set the time a PAM user unlocks at")]
+    #[clap(group(clap::ArgGroup::new("unlock").required(true).args(["at", "in"])))]
+    SetUnlock {
+        #[clap(long, short)]
+        user: String,
+        #[clap(long, help = "Unlock time, e.g. 2024-06-01T17:00:00 in local time")]
+        at: Option<String>,
+        #[clap(
+            long = "in",
+            id = "in",
+            help = "Time until the unlock, e.g. 4h or 1h30m"
+        )]
+        after: Option<String>,
+        #[clap(long, help = "Lock root as well")]
+        force: bool,
+    },
+    #[command(about = "Show the tally of a PAM user")]
+    Status {
+        #[clap(long, short, required_unless_present = "uid", conflicts_with = "uid")]
+        user: Option<String>,
+        #[clap(long, help = "Show the tally of a uid")]
+        uid: Option<u32>,
+    },
+    #[command(about = "Print the remaining lockout time of a PAM user")]
+    Remaining {
+        #[clap(long, short, help = "The PAM user [default: the invoking user]")]
+        user: Option<String>,
+        #[clap(
+            long,
+            short,
+            help = "Re-print the remaining time until the user is unlocked"
+        )]
+        watch: bool,
+        #[clap(
+            long,
+            default_value = "1s",
+            requires = "watch",
+            help = "Interval of --watch, e.g. 5s or 1m"
+        )]
+        interval: String,
+    },
+    #[command(about = "List the tallies of all PAM users")]
+    List {
+        #[clap(long, short)]
+        locked_only: bool,
+    },
+    #[command(about = "Show the tally overview and anonymous statistics of the PAM module")]
+    Stats {
+        #[clap(long)]
+        histograms: bool,
+        #[clap(long)]
+        json: bool,
+        #[clap(
+            long,
+            default_value = "24h",
+            help = "Time window failures are counted in, e.g. 24h or 7d"
+        )]
+        since: String,
+    },
+    #[command(about = "Export the tallies as Prometheus metrics")]
+    Metrics {
+        #[clap(
+            long,
+            short,
+            help = "Metrics file, e.g. /var/lib/node_exporter/authramp.prom [default: print]"
+        )]
+        output: Option<String>,
     },
+    #[command(about = "Check the configuration or show the effective one")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    #[command(about = "Import the tallies of pam_faillock")]
+    ImportFaillock {
+        #[clap(long, help = "Faillock tally directory [default: /var/run/faillock]")]
+        dir: Option<String>,
+    },
+    #[command(about = "Export the tallies of all PAM users to a JSON file")]
+    Export {
+        #[clap(long, short, help = "Export file, e.g. state.json")]
+        output: String,
+    },
+    #[command(about = "Restore the tallies of a JSON export")]
+    Import {
+        #[clap(long, short, help = "Export file, e.g. state.json")]
+        input: String,
+        #[clap(long, help = "Overwrite tallies updated after the export")]
+        force: bool,
+    },
+    #[command(about = "Simulate the delays of consecutive failures")]
+    Simulate {
+        #[clap(
+            long,
+            default_value_t = 20,
+            value_parser = clap::value_parser!(i32).range(1..=10_000),
+            help = "Number of consecutive failures"
+        )]
+        failures: i32,
+        #[clap(
+            long,
+            help = "Configuration file [default: /etc/security/authramp.conf]"
+        )]
+        config: Option<String>,
+    },
+    #[command(about = "Follow the lockout events live")]
+    Watch,
+    #[command(about = "Print the completion script of a shell")]
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    #[command(name = "__list-users", hide = true)]
+    ListUsers,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    #[command(about = "Report unknown keys and invalid values of the configuration file")]
+    Check {
+        #[clap(
+            long,
+            help = "Configuration file [default: /etc/security/authramp.conf]"
+        )]
+        path: Option<String>,
+    },
+    #[command(about = "Show the effective configuration, including defaults")]
+    Show {
+        #[clap(
+            long,
+            help = "Configuration file [default: /etc/security/authramp.conf]"
+        )]
+        path: Option<String>,
+    },
+}
+
+/// Warns a user other than root that can't access the tally directory.
+///
+/// Reading and changing tallies needs access to the tally directory, which only root has by
+/// default. Members of admin groups may have been given access, so the commands still run and
+/// report the operations that fail.
+///
+/// # Arguments
+///
+/// - `command`: The subcommand about to run.
+fn check_privileges(command: &Command) {
+    let uid = unsafe { libc::getuid() };
+    if uid == 0
+        || matches!(
+            command,
+            Command::Config { .. }
+                | Command::Remaining { .. }
+                | Command::Simulate { .. }
+                | Command::Completions { .. }
+                | Command::ListUsers
+        )
+    {
+        return;
+    }
+
+    let tally_dir = cmd::load_config().tally_dir;
+    let Ok(c_tally_dir) = std::ffi::CString::new(tally_dir.as_os_str().as_encoded_bytes()) else {
+        return;
+    };
+    let accessible =
+        unsafe { libc::access(c_tally_dir.as_ptr(), libc::R_OK | libc::W_OK | libc::X_OK) } == 0;
+
+    if tally_dir.exists() && !accessible {
+        eprintln!(
+            "{}",
+            ArCliWarning {
+                message: format!(
+                    "Running as uid {uid} without access to the tally directory {}. Reading and \
+                     changing tallies needs root: run authramp with sudo, or pass --tally-dir to \
+                     use another directory",
+                    tally_dir.display()
+                ),
+            }
+        );
+    }
 }
 
 /// Main entry point for the `AuthRamp` CLI binary.
 ///
-/// Initializes the syslog, parses command-line arguments, executes the corresponding subcommand,
-/// and prints the result.
+/// Parses command-line arguments, executes the corresponding subcommand, and prints the result.
+/// Exits with the [exit code](ArCliResult::exit_code) of the result. Refusals are logged with a
+/// fixed `authramp` ident, so no process lookup is needed.
+#[allow(clippy::too_many_lines)] // one dispatch arm per subcommand
 fn main() {
-    //syslog::init_cli_log().unwrap_or_else(|e| println!("{e:?}: Error initializing cli log:"));
+    let cli = Cli::parse();
 
-    let cli_res = match Cli::parse().command {
-        Some(Command::Reset { user }) => reset::user(&user),
-        _ => ArCliResult::Success(None),
+    // pipes, cron mails and CI logs get no escape sequences, and neither does JSON
+    colored::control::set_override(
+        cli.format == Format::Human
+            && cli.color.colorize(
+                io::stdout().is_terminal(),
+                env::var_os("NO_COLOR").as_deref(),
+            ),
+    );
+
+    if let Some(tally_dir) = cli.tally_dir {
+        let _ = cmd::TALLY_DIR.set(tally_dir);
+    }
+
+    if let Some(command) = &cli.command {
+        check_privileges(command);
+    }
+    let json_format = cli.format == Format::Json;
+
+    let (action, user, cli_res) = match cli.command {
+        Some(Command::Reset {
+            user, uid, purge, ..
+        }) if !user.is_empty() || uid.is_some() => {
+            let cli_res = reset::users(&user, uid, purge);
+            let user = match user.as_slice() {
+                [] => uid.map(|uid| uid.to_string()),
+                [user] => Some(user.clone()),
+                users => Some(users.join(",")),
+            };
+            ("reset", user, cli_res)
+        }
+        Some(Command::Reset { yes, purge, .. }) => ("reset", None, reset::all(yes, purge)),
+        Some(Command::Lock {
+            user,
+            until,
+            duration,
+            force,
+        }) => {
+            let cli_res = lock::user(&user, until.as_deref(), duration.as_deref(), force);
+            ("lock", Some(user), cli_res)
+        }
+        Some(Command::SetUnlock {
+            user,
+            at,
+            after,
+            force,
+        }) => {
+            let cli_res = lock::set_unlock(&user, at.as_deref(), after.as_deref(), force);
+            ("set-unlock", Some(user), cli_res)
+        }
+        Some(Command::Status { user, uid }) => {
+            let cli_res = status::user(user.as_deref(), uid);
+            ("status", user.or(uid.map(|uid| uid.to_string())), cli_res)
+        }
+        Some(Command::Remaining {
+            user,
+            watch,
+            interval,
+        }) => {
+            let user = user.unwrap_or_else(|| permissions::Invoker::current().name);
+            let cli_res = remaining::user(&user, watch, &interval, json_format);
+            ("remaining", Some(user), cli_res)
+        }
+        Some(Command::List { locked_only }) => ("list", None, list::users(locked_only)),
+        Some(Command::Stats {
+            histograms,
+            json,
+            since,
+        }) => (
+            "stats",
+            None,
+            stats::show(histograms, json || json_format, &since),
+        ),
+        Some(Command::Metrics { output }) => ("metrics", None, metrics::export(output.as_deref())),
+        Some(Command::Config {
+            command: ConfigCommand::Check { path },
+        }) => ("config", None, config::check(path.as_deref())),
+        Some(Command::Config {
+            command: ConfigCommand::Show { path },
+        }) => ("config", None, config::show(path.as_deref(), json_format)),
+        Some(Command::ImportFaillock { dir }) => {
+            ("import-faillock", None, import::faillock(dir.as_deref()))
+        }
+        Some(Command::Export { output }) => ("export", None, backup::export(&output)),
+        Some(Command::Import { input, force }) => ("import", None, backup::import(&input, force)),
+        Some(Command::Simulate { failures, config }) => (
+            "simulate",
+            None,
+            simulate::delays(failures, config.as_deref(), json_format),
+        ),
+        Some(Command::Watch) => ("watch", None, watch::tallies(json_format)),
+        Some(Command::Completions { shell }) => (
+            "completions",
+            None,
+            completions::generate(shell, &Cli::command()),
+        ),
+        Some(Command::ListUsers) => ("__list-users", None, completions::list_users()),
+        _ => ("", None, ArCliResult::Success(None)),
     };
 
     // Print the result
-    println!("{cli_res}");
+    match cli.format {
+        Format::Human => println!("{cli_res}"),
+        Format::Json => println!("{}", cli_res.to_json(action, user.as_deref())),
+    }
+
+    // Let scripts branch on locked accounts, missing tallies, errors and refused commands
+    let exit_code = cli_res.exit_code();
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colorize() {
+        let no_color = Some(OsStr::new("1"));
+
+        assert!(ColorChoice::Auto.colorize(true, None));
+        assert!(ColorChoice::Auto.colorize(true, Some(OsStr::new(""))));
+        assert!(!ColorChoice::Auto.colorize(false, None));
+        assert!(!ColorChoice::Auto.colorize(true, no_color));
+
+        assert!(ColorChoice::Always.colorize(false, no_color));
+        assert!(!ColorChoice::Never.colorize(true, None));
+    }
 }
@@ -0,0 +1,422 @@
+//! # Permissions Module
+//!
+//! The `permissions` module restricts CLI commands to admin groups. The rules are configured in
+//! the `[Cli.permissions]` table of `authramp.conf`, mapping a command to the groups permitted to
+//! run it:
+//!
+//! ```toml
+//! [Cli.permissions]
+//! reset = ["helpdesk", "security"]
+//! reset_watched = ["security"]
+//! hold = ["security"]
+//! ```
+//!
+//! The state of the target account escalates the rule: resetting a watched account, one with a
+//! `[user.<name>]` override, or a held one, locked with `authramp lock`, is checked against
+//! `reset_watched`. Locking and moving the unlock, `lock` and `set-unlock`, are checked against
+//! `hold`. An escalated rule that isn't configured falls back to the rule of the command.
+//!
+//! Commands without a rule are unrestricted and root is always permitted. The rules are evaluated
+//! against the group memberships of the real uid invoking the CLI, resolved by a
+//! [`UserDirectory`]. Refusals are logged to the configured `log_facility`, `authpriv` by default,
+//! or to journald with `log_backend = "journald"`.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use colored::Colorize;
+use common::{
+    config::{Config, LogBackend},
+    tally::Tally,
+};
+use std::{collections::BTreeMap, ffi::CString, path::Path};
+use uzers::{get_current_uid, get_user_by_uid};
+
+use crate::{ArCliError, ArCliResult as Acr};
+
+/// Resolves the names and group memberships of users.
+pub trait UserDirectory {
+    /// The name of a uid, `None` if there is no such user.
+    fn user_name(&self, uid: u32) -> Option<String>;
+
+    /// The groups a uid is a member of, including its primary group.
+    fn groups(&self, uid: u32) -> Vec<String>;
+}
+
+/// The users and groups of the system, resolved through NSS.
+pub struct SystemDirectory;
+
+impl UserDirectory for SystemDirectory {
+    fn user_name(&self, uid: u32) -> Option<String> {
+        get_user_by_uid(uid).map(|user| user.name().to_string_lossy().into_owned())
+    }
+
+    fn groups(&self, uid: u32) -> Vec<String> {
+        get_user_by_uid(uid)
+            .and_then(|user| user.groups())
+            .unwrap_or_default()
+            .iter()
+            .map(|group| group.name().to_string_lossy().into_owned())
+            .collect()
+    }
+}
+
+/// The user invoking the CLI.
+pub struct Invoker {
+    pub uid: u32,
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+impl Invoker {
+    /// Resolves the user and group memberships of the real uid.
+    #[must_use]
+    pub fn current() -> Self {
+        Self::resolve(get_current_uid(), &SystemDirectory)
+    }
+
+    /// Resolves the user and group memberships of a uid.
+    ///
+    /// # Arguments
+    ///
+    /// - `uid`: The real uid of the invoker.
+    /// - `directory`: The directory the name and groups are looked up in.
+    ///
+    /// # Returns
+    ///
+    /// The invoker, named by its uid if it has no name.
+    #[must_use]
+    pub fn resolve(uid: u32, directory: &impl UserDirectory) -> Self {
+        Invoker {
+            uid,
+            name: directory.user_name(uid).unwrap_or_else(|| uid.to_string()),
+            groups: directory.groups(uid),
+        }
+    }
+}
+
+/// The state of the account a command acts on, which can escalate the rule of the command.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TargetState {
+    /// The account has a `[user.<name>]` override.
+    pub watched: bool,
+    /// The account is held by a manual lock.
+    pub held: bool,
+}
+
+impl TargetState {
+    /// Every account at once, like `reset --all`, which includes the watched and held ones.
+    pub const ANY: TargetState = TargetState {
+        watched: true,
+        held: true,
+    };
+
+    /// Determines the state of an account.
+    ///
+    /// # Arguments
+    ///
+    /// - `config`: The loaded `AuthRamp` configuration.
+    /// - `user`: The name of the account.
+    /// - `tally_file`: The tally file of the account, which may not exist.
+    ///
+    /// # Returns
+    ///
+    /// The state of the account. An unreadable tally doesn't hold the account.
+    #[must_use]
+    pub fn of(config: &Config, user: &str, tally_file: &Path) -> Self {
+        TargetState {
+            watched: config.user_overrides.contains_key(user),
+            held: Tally::read_tally_file(tally_file).is_ok_and(|tally| tally.manual_lock),
+        }
+    }
+}
+
+/// Names the rule a command is checked against, given the state of its target.
+///
+/// # Arguments
+///
+/// - `command`: The command as named in `[Cli.permissions]`.
+/// - `target`: The state of the account the command acts on.
+///
+/// # Returns
+///
+/// `reset_watched` for a reset of a watched or held account, `hold` for `lock` and `set-unlock`,
+/// `None` if the command isn't escalated.
+fn escalated_rule(command: &str, target: TargetState) -> Option<&'static str> {
+    match command {
+        "reset" if target.watched || target.held => Some("reset_watched"),
+        "lock" | "set-unlock" => Some("hold"),
+        _ => None,
+    }
+}
+
+/// Looks up the rule that applies to a command.
+///
+/// # Returns
+///
+/// The name of the rule and its permitted groups, `None` if neither the escalated rule nor the
+/// rule of the command is configured.
+fn applied_rule<'a>(
+    command: &'a str,
+    target: TargetState,
+    rules: &'a BTreeMap<String, Vec<String>>,
+) -> Option<(&'a str, &'a [String])> {
+    escalated_rule(command, target)
+        .and_then(|rule| rules.get_key_value(rule))
+        .or_else(|| rules.get_key_value(command))
+        .map(|(rule, permitted)| (rule.as_str(), permitted.as_slice()))
+}
+
+/// Decides whether a command is permitted.
+///
+/// # Arguments
+///
+/// - `command`: The command as named in `[Cli.permissions]`.
+/// - `target`: The state of the account the command acts on.
+/// - `rules`: The permitted groups per rule.
+/// - `uid`: The real uid of the invoker.
+/// - `groups`: The group memberships of the invoker.
+///
+/// # Returns
+///
+/// `true` if the invoker is root, no rule applies or the invoker is in a group permitted by the
+/// applied rule.
+#[must_use]
+pub fn is_permitted(
+    command: &str,
+    target: TargetState,
+    rules: &BTreeMap<String, Vec<String>>,
+    uid: u32,
+    groups: &[String],
+) -> bool {
+    if uid == 0 {
+        return true;
+    }
+
+    applied_rule(command, target, rules).is_none_or(|(_, permitted)| {
+        permitted
+            .iter()
+            .any(|permitted_group| groups.contains(permitted_group))
+    })
+}
+
+/// Checks a command against the configured rules and logs refusals.
+///
+/// # Arguments
+///
+/// - `command`: The command as named in `[Cli.permissions]`.
+/// - `target`: The user the command acts on.
+/// - `state`: The state of the target account.
+/// - `config`: The loaded `AuthRamp` configuration.
+/// - `invoker`: The user invoking the CLI.
+///
+/// # Returns
+///
+/// `None` if the command is permitted, `ArCliResult::Denied` otherwise.
+#[must_use]
+pub fn check(
+    command: &str,
+    target: &str,
+    state: TargetState,
+    config: &Config,
+    invoker: &Invoker,
+) -> Option<Acr> {
+    if is_permitted(
+        command,
+        state,
+        &config.cli_permissions,
+        invoker.uid,
+        &invoker.groups,
+    ) {
+        return None;
+    }
+
+    let rule =
+        applied_rule(command, state, &config.cli_permissions).map_or(command, |(rule, _)| rule);
+
+    log_refusal(
+        config,
+        target,
+        &format!(
+            "permission denied: user \"{}\" (uid {}) attempted \"{command}\" on the \"{target}\" account, refused by the \"{rule}\" rule",
+            invoker.name, invoker.uid
+        ),
+    );
+
+    Some(Acr::Denied(ArCliError {
+        message: format!(
+            "'{}' is not permitted to {command} '{}'. Permitted groups are configured by the '{rule}' rule in [Cli.permissions].",
+            invoker.name.yellow(),
+            target.yellow()
+        ),
+    }))
+}
+
+/// Logs a refused command to the configured backend and facility.
+///
+/// With the journald backend the target account is attached as `AUTHRAMP_USER`. Refusals
+/// journald doesn't accept are logged to the syslog.
+fn log_refusal(config: &Config, target: &str, message: &str) {
+    if !config.log_level.allows(pam::LogLevel::Warning) {
+        return;
+    }
+
+    let facility = config.log_facility.code();
+
+    if config.log_backend == LogBackend::Journald {
+        let (priority, syslog_facility) =
+            (libc::LOG_WARNING.to_string(), (facility >> 3).to_string());
+        let sent = pam::journal::send(&[
+            ("MESSAGE", message),
+            ("PRIORITY", &priority),
+            ("SYSLOG_FACILITY", &syslog_facility),
+            ("SYSLOG_IDENTIFIER", "authramp"),
+            ("AUTHRAMP_USER", target),
+        ]);
+        if sent.is_ok() {
+            return;
+        }
+    }
+
+    let (Ok(ident), Ok(format), Ok(message)) = (
+        CString::new("authramp"),
+        CString::new("%s"),
+        CString::new(message),
+    ) else {
+        return;
+    };
+
+    unsafe {
+        libc::openlog(ident.as_ptr(), libc::LOG_PID, facility);
+        libc::syslog(libc::LOG_WARNING, format.as_ptr(), message.as_ptr());
+        libc::closelog();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_permitted() {
+        let rules: BTreeMap<String, Vec<String>> = [
+            ("reset", vec!["helpdesk", "security"]),
+            ("reset_watched", vec!["security"]),
+            ("hold", vec!["security"]),
+        ]
+        .into_iter()
+        .map(|(rule, groups)| {
+            (
+                rule.to_string(),
+                groups.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+
+        let groups = |groups: &[&str]| groups.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let plain = TargetState::default();
+        let watched = TargetState {
+            watched: true,
+            held: false,
+        };
+        let held = TargetState {
+            watched: false,
+            held: true,
+        };
+
+        // (command, target, uid, groups, expected)
+        let matrix = [
+            ("reset", plain, 0, groups(&[]), true),
+            ("reset", plain, 1000, groups(&["helpdesk"]), true),
+            ("reset", plain, 1000, groups(&["users", "security"]), true),
+            ("reset", plain, 1000, groups(&["users"]), false),
+            ("reset", plain, 1000, groups(&[]), false),
+            ("reset", watched, 0, groups(&[]), true),
+            ("reset", watched, 1000, groups(&["helpdesk"]), false),
+            ("reset", watched, 1000, groups(&["security"]), true),
+            ("reset", held, 1000, groups(&["helpdesk"]), false),
+            ("reset", held, 1000, groups(&["security"]), true),
+            (
+                "reset",
+                TargetState::ANY,
+                1000,
+                groups(&["helpdesk"]),
+                false,
+            ),
+            ("lock", plain, 0, groups(&[]), true),
+            ("lock", plain, 1000, groups(&["helpdesk"]), false),
+            ("lock", plain, 1000, groups(&["security"]), true),
+            ("set-unlock", held, 1000, groups(&["helpdesk"]), false),
+            ("set-unlock", held, 1000, groups(&["security"]), true),
+            ("status", watched, 1000, groups(&[]), true),
+        ];
+
+        for (command, target, uid, groups, expected) in matrix {
+            assert_eq!(
+                is_permitted(command, target, &rules, uid, &groups),
+                expected,
+                "{command} of {target:?} by uid {uid} in {groups:?}"
+            );
+        }
+
+        // an escalated rule that isn't configured falls back to the rule of the command
+        let reset_only: BTreeMap<String, Vec<String>> =
+            [("reset".to_string(), vec!["helpdesk".to_string()])]
+                .into_iter()
+                .collect();
+        assert!(is_permitted(
+            "reset",
+            watched,
+            &reset_only,
+            1000,
+            &groups(&["helpdesk"])
+        ));
+        assert!(!is_permitted(
+            "reset",
+            held,
+            &reset_only,
+            1000,
+            &groups(&["users"])
+        ));
+        assert!(is_permitted("lock", held, &reset_only, 1000, &[]));
+
+        // no rules at all
+        assert!(is_permitted("reset", watched, &BTreeMap::new(), 1000, &[]));
+    }
+
+    #[test]
+    fn test_target_state() {
+        let temp_dir = tempdir::TempDir::new("test_target_state").unwrap();
+        let tally_file = temp_dir.path().join("alice");
+        let mut config = Config::default();
+
+        assert_eq!(
+            TargetState::of(&config, "alice", &tally_file),
+            TargetState::default()
+        );
+
+        config
+            .user_overrides
+            .insert("alice".to_string(), common::config::UserOverride::default());
+        std::fs::write(&tally_file, "[Fails]\ncount = 0\nmanual_lock = true\n").unwrap();
+        assert_eq!(
+            TargetState::of(&config, "alice", &tally_file),
+            TargetState::ANY
+        );
+    }
+}
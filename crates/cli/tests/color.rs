@@ -0,0 +1,74 @@
+//! # Color Tests
+//!
+//! Runs the `authramp` binary with its output captured and checks that it only contains escape
+//! sequences when colors are forced on.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use assert_cmd::Command;
+use tempdir::TempDir;
+
+/// Runs `authramp status` for a user without a tally and returns its stdout.
+fn status_output(color: Option<&str>, no_color: Option<&str>) -> String {
+    let temp_dir = TempDir::new("test_color").unwrap();
+
+    let mut command = Command::cargo_bin("authramp").unwrap();
+    command
+        .env_remove("NO_COLOR")
+        .arg("--tally-dir")
+        .arg(temp_dir.path());
+    if let Some(color) = color {
+        command.args(["--color", color]);
+    }
+    if let Some(no_color) = no_color {
+        command.env("NO_COLOR", no_color);
+    }
+
+    let output = command
+        .args(["status", "--user", "color_user"])
+        .assert()
+        .code(67)
+        .get_output()
+        .stdout
+        .clone();
+    String::from_utf8(output).unwrap()
+}
+
+fn has_escapes(output: &str) -> bool {
+    output.contains('\u{1b}')
+}
+
+#[test]
+fn test_captured_output_is_plain() {
+    let output = status_output(None, None);
+    assert!(output.starts_with("info: No tally found"), "{output}");
+    assert!(!has_escapes(&output), "{output:?}");
+
+    assert!(!has_escapes(&status_output(Some("auto"), None)));
+    assert!(!has_escapes(&status_output(Some("never"), None)));
+}
+
+#[test]
+fn test_color_always() {
+    assert!(has_escapes(&status_output(Some("always"), None)));
+
+    // an explicit flag wins over NO_COLOR
+    assert!(has_escapes(&status_output(Some("always"), Some("1"))));
+    assert!(!has_escapes(&status_output(Some("auto"), Some("1"))));
+}
@@ -0,0 +1,163 @@
+//! # Exit Code Tests
+//!
+//! Runs the `authramp` binary against a temporary tally directory and checks the exit codes
+//! scripts and monitoring checks branch on.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use assert_cmd::Command;
+use chrono::Utc;
+use std::{fs, path::Path};
+use tempdir::TempDir;
+
+/// Runs `authramp` with the tally directory and returns the assertion on its output.
+fn authramp(tally_dir: &Path, args: &[&str]) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("authramp")
+        .unwrap()
+        .arg("--tally-dir")
+        .arg(tally_dir)
+        .args(args)
+        .assert()
+}
+
+/// Writes a tally with a recent failure, locked with the default free tries.
+fn write_locked_tally(tally_dir: &Path, user: &str) {
+    fs::create_dir_all(tally_dir).unwrap();
+    fs::write(
+        tally_dir.join(user),
+        format!("[Fails]\ncount = 12\ninstant = \"{}\"", Utc::now()),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_status_exit_codes() {
+    let temp_dir = TempDir::new("test_status_exit_codes").unwrap();
+    let tally_dir = temp_dir.path().join("tally");
+
+    // no tally
+    authramp(&tally_dir, &["status", "--user", "exit_code_user"]).code(67);
+
+    // locked
+    write_locked_tally(&tally_dir, "exit_code_user");
+    authramp(&tally_dir, &["status", "--user", "exit_code_user"]).code(1);
+
+    // reset and no longer locked
+    authramp(&tally_dir, &["reset", "--user", "exit_code_user"]).success();
+    authramp(&tally_dir, &["status", "--user", "exit_code_user"]).success();
+}
+
+#[test]
+fn test_remaining_exit_codes() {
+    let temp_dir = TempDir::new("test_remaining_exit_codes").unwrap();
+    let tally_dir = temp_dir.path().join("tally");
+
+    // no tally, so not locked
+    authramp(&tally_dir, &["remaining", "--user", "exit_code_user"]).success();
+
+    write_locked_tally(&tally_dir, "exit_code_user");
+    authramp(&tally_dir, &["remaining", "--user", "exit_code_user"]).code(1);
+
+    // watching ends as soon as the user is unlocked
+    authramp(&tally_dir, &["reset", "--user", "exit_code_user"]).success();
+    authramp(
+        &tally_dir,
+        &["remaining", "--user", "exit_code_user", "--watch"],
+    )
+    .success();
+}
+
+#[test]
+fn test_reset_exit_codes() {
+    let temp_dir = TempDir::new("test_reset_exit_codes").unwrap();
+    let tally_dir = temp_dir.path().join("tally");
+
+    authramp(
+        &tally_dir,
+        &["reset", "--user", "exit_code_user", "--purge"],
+    )
+    .code(67);
+
+    write_locked_tally(&tally_dir, "exit_code_user");
+    authramp(
+        &tally_dir,
+        &["reset", "--user", "exit_code_user", "--purge"],
+    )
+    .success();
+    assert!(!tally_dir.join("exit_code_user").exists());
+}
+
+#[test]
+fn test_error_exit_codes() {
+    let temp_dir = TempDir::new("test_error_exit_codes").unwrap();
+    let tally_dir = temp_dir.path().join("tally");
+
+    // invalid arguments
+    authramp(
+        &tally_dir,
+        &["lock", "--user", "exit_code_user", "--duration", "soon"],
+    )
+    .code(70);
+
+    // a tally that can't be parsed
+    fs::create_dir_all(&tally_dir).unwrap();
+    fs::write(tally_dir.join("exit_code_user"), "not a tally").unwrap();
+    authramp(&tally_dir, &["status", "--user", "exit_code_user"]).code(70);
+
+    // a user name that can't be a tally file name
+    authramp(&tally_dir, &["status", "--user", "../exit_code_user"]).code(70);
+}
+
+#[test]
+fn test_json_not_found() {
+    let temp_dir = TempDir::new("test_json_not_found").unwrap();
+
+    let output = authramp(
+        &temp_dir.path().join("tally"),
+        &["--format", "json", "status", "--user", "exit_code_user"],
+    )
+    .code(67)
+    .get_output()
+    .stdout
+    .clone();
+    let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(json["result"], "not_found");
+}
+
+#[test]
+fn test_reset_several_users_exit_codes() {
+    let temp_dir = TempDir::new("test_reset_several_users_exit_codes").unwrap();
+    let tally_dir = temp_dir.path().join("tally");
+    write_locked_tally(&tally_dir, "exit_code_user");
+
+    // a missing tally doesn't fail the reset
+    authramp(
+        &tally_dir,
+        &["reset", "-u", "exit_code_user", "-u", "exit_code_other"],
+    )
+    .success();
+    authramp(&tally_dir, &["status", "--user", "exit_code_user"]).success();
+
+    // an invalid user name does
+    authramp(
+        &tally_dir,
+        &["reset", "-u", "exit_code_user", "-u", "../exit_code_user"],
+    )
+    .code(70);
+}
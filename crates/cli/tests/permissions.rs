@@ -0,0 +1,139 @@
+//! # Permission Tests
+//!
+//! Evaluates `[Cli.permissions]` of a configuration file against fixture group memberships,
+//! including the escalation of resets of watched and held accounts.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cli::{
+    permissions::{check, Invoker, TargetState, UserDirectory},
+    ArCliResult,
+};
+use common::config::Config;
+use pam::PamHandle;
+use std::{collections::BTreeMap, fs, path::Path};
+use tempdir::TempDir;
+
+/// Group memberships by uid, instead of the ones of the system.
+struct FixtureDirectory(BTreeMap<u32, (&'static str, &'static [&'static str])>);
+
+impl UserDirectory for FixtureDirectory {
+    fn user_name(&self, uid: u32) -> Option<String> {
+        self.0.get(&uid).map(|(name, _)| (*name).to_string())
+    }
+
+    fn groups(&self, uid: u32) -> Vec<String> {
+        self.0
+            .get(&uid)
+            .map(|(_, groups)| groups.iter().map(ToString::to_string).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Checks a command of an invoker on an account with a tally in `tally_dir`.
+fn run(
+    command: &str,
+    target: &str,
+    config: &Config,
+    tally_dir: &Path,
+    invoker: &Invoker,
+) -> Option<ArCliResult> {
+    let state = TargetState::of(config, target, &tally_dir.join(target));
+    check(command, target, state, config, invoker)
+}
+
+#[test]
+fn test_watched_account_escalation() {
+    colored::control::set_override(false);
+
+    let temp_dir = TempDir::new("test_watched_account_escalation").unwrap();
+    let conf_file = temp_dir.path().join("authramp.conf");
+    let tally_dir = temp_dir.path().join("tally");
+    fs::write(
+        &conf_file,
+        r#"
+        [Cli.permissions]
+        reset = ["helpdesk", "security"]
+        reset_watched = ["security"]
+        hold = ["security"]
+
+        [user.breakglass]
+        free_tries = 2
+        "#,
+    )
+    .unwrap();
+    fs::create_dir_all(&tally_dir).unwrap();
+    fs::write(tally_dir.join("alice"), "[Fails]\ncount = 3\n").unwrap();
+    fs::write(tally_dir.join("breakglass"), "[Fails]\ncount = 3\n").unwrap();
+    fs::write(
+        tally_dir.join("bob"),
+        "[Fails]\ncount = 0\nmanual_lock = true\n",
+    )
+    .unwrap();
+    let config = Config::try_load_file::<PamHandle>(conf_file.to_str().unwrap(), None).unwrap();
+
+    let directory = FixtureDirectory(
+        [
+            (1001, ("helen", &["users", "helpdesk"][..])),
+            (1002, ("sam", &["users", "security"][..])),
+            (1003, ("ivan", &["users"][..])),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    let helpdesk = Invoker::resolve(1001, &directory);
+    let security = Invoker::resolve(1002, &directory);
+    let intern = Invoker::resolve(1003, &directory);
+
+    // helpdesk resets ordinary accounts, but neither watched nor held ones
+    assert!(run("reset", "alice", &config, &tally_dir, &helpdesk).is_none());
+    for target in ["breakglass", "bob"] {
+        let Some(denied) = run("reset", target, &config, &tally_dir, &helpdesk) else {
+            panic!("helpdesk reset {target}");
+        };
+        assert_eq!(denied.exit_code(), 77);
+        assert_eq!(
+            denied.to_string(),
+            format!("error: 'helen' is not permitted to reset '{target}'. Permitted groups are configured by the 'reset_watched' rule in [Cli.permissions].")
+        );
+    }
+    assert!(run("lock", "alice", &config, &tally_dir, &helpdesk).is_some());
+    assert!(run("set-unlock", "bob", &config, &tally_dir, &helpdesk).is_some());
+
+    // the security team escalates
+    for (command, target) in [
+        ("reset", "alice"),
+        ("reset", "breakglass"),
+        ("reset", "bob"),
+        ("lock", "alice"),
+        ("set-unlock", "bob"),
+    ] {
+        assert!(
+            run(command, target, &config, &tally_dir, &security).is_none(),
+            "security {command} {target}"
+        );
+    }
+
+    // other users reset nothing, root everything
+    assert!(run("reset", "alice", &config, &tally_dir, &intern).is_some());
+    let root = Invoker::resolve(0, &directory);
+    assert_eq!(root.name, "0");
+    assert!(root.groups.is_empty());
+    assert!(run("reset", "breakglass", &config, &tally_dir, &root).is_none());
+}
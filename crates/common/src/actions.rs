@@ -35,4 +35,6 @@ pub enum Actions {
     PREAUTH,
     AUTHSUCC,
     AUTHFAIL,
+    // Set by the session hook, never parsed from the arguments
+    SESSION,
 }
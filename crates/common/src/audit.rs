@@ -0,0 +1,188 @@
+//! # Audit Module
+//!
+//! The `audit` module writes a Linux audit record when the failures of an account cross
+//! `free_tries`, like `pam_faillock` does. Compliance frameworks expect lockouts in the audit
+//! trail and not only in the syslog. The record has the type `AUDIT_ANOM_LOGIN_FAILURES` and
+//! carries the uid, account and PAM service, plus the remote host and terminal.
+//!
+//! The records are written with `audit_log_user_message` of libaudit. The bindings are only built
+//! with the `audit` feature, so minimal builds don't link libaudit. Without it, every record
+//! fails with an error the caller logs.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt::Write;
+
+/// Record type of failed logins over a threshold, see `linux/audit.h`.
+pub const AUDIT_ANOM_LOGIN_FAILURES: libc::c_int = 2100;
+
+/// The uid audit records use for an unknown account.
+pub const AUDIT_UNSET_UID: u32 = u32::MAX;
+
+/// An account crossing `free_tries`.
+#[derive(Debug)]
+pub struct LockoutRecord<'a> {
+    /// The uid of the account, [`AUDIT_UNSET_UID`] for unknown users
+    pub uid: u32,
+    pub user: &'a str,
+    pub service: Option<&'a str>,
+    pub rhost: Option<&'a str>,
+    pub tty: Option<&'a str>,
+}
+
+impl LockoutRecord<'_> {
+    /// Formats the message of the record in the `key=value` style of audit records.
+    ///
+    /// Values the user controls are encoded with [`encode_value`], so they can't add fields.
+    #[must_use]
+    pub fn message(&self) -> String {
+        let mut message = format!(
+            "op=pam_authramp suid={} acct={}",
+            self.uid,
+            encode_value(self.user)
+        );
+        if let Some(service) = self.service {
+            let _ = write!(message, " service={}", encode_value(service));
+        }
+        message
+    }
+}
+
+/// Encodes a value of an audit record field like `audit_encode_nv_string`.
+///
+/// Values with spaces, double quotes or characters outside of printable ASCII are hex encoded,
+/// others are quoted.
+#[must_use]
+pub fn encode_value(value: &str) -> String {
+    if value.bytes().any(|b| b <= b' ' || b == b'"' || b > b'~') {
+        value.bytes().fold(String::new(), |mut hex, b| {
+            let _ = write!(hex, "{b:02X}");
+            hex
+        })
+    } else {
+        format!("\"{value}\"")
+    }
+}
+
+/// Writes the audit record of an account crossing `free_tries`.
+///
+/// # Errors
+///
+/// Returns a message if the audit netlink socket can't be opened, e.g. on a kernel without audit
+/// support, or the record can't be written.
+#[cfg(feature = "audit")]
+pub fn log_lockout(record: &LockoutRecord) -> Result<(), String> {
+    use std::ffi::CString;
+    use std::ptr;
+
+    #[link(name = "audit")]
+    extern "C" {
+        fn audit_open() -> libc::c_int;
+        fn audit_close(fd: libc::c_int);
+        fn audit_log_user_message(
+            audit_fd: libc::c_int,
+            type_: libc::c_int,
+            message: *const libc::c_char,
+            hostname: *const libc::c_char,
+            addr: *const libc::c_char,
+            tty: *const libc::c_char,
+            result: libc::c_int,
+        ) -> libc::c_int;
+    }
+
+    let to_cstring = |value: &str| CString::new(value).map_err(|e| e.to_string());
+    let message = to_cstring(&record.message())?;
+    let rhost = record.rhost.map(to_cstring).transpose()?;
+    let tty = record.tty.map(to_cstring).transpose()?;
+
+    let audit_fd = unsafe { audit_open() };
+    if audit_fd < 0 {
+        return Err(format!(
+            "Error opening the audit socket: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    // result 1, the lockout itself succeeded
+    let written = unsafe {
+        audit_log_user_message(
+            audit_fd,
+            AUDIT_ANOM_LOGIN_FAILURES,
+            message.as_ptr(),
+            rhost.as_ref().map_or(ptr::null(), |rhost| rhost.as_ptr()),
+            ptr::null(),
+            tty.as_ref().map_or(ptr::null(), |tty| tty.as_ptr()),
+            1,
+        )
+    };
+    let error = std::io::Error::last_os_error();
+    unsafe { audit_close(audit_fd) };
+
+    if written <= 0 {
+        return Err(format!("Error writing the audit record: {error}"));
+    }
+    Ok(())
+}
+
+/// Writes the audit record of an account crossing `free_tries`.
+///
+/// # Errors
+///
+/// Always returns a message, the module was built without the `audit` feature.
+#[cfg(not(feature = "audit"))]
+pub fn log_lockout(_record: &LockoutRecord) -> Result<(), String> {
+    Err("pam_authramp was built without the audit feature".to_string())
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_value() {
+        assert_eq!(encode_value("user"), "\"user\"");
+        assert_eq!(encode_value("a b"), "612062");
+        assert_eq!(encode_value("a\"b"), "612262");
+        assert_eq!(encode_value("ü"), "C3BC");
+    }
+
+    #[test]
+    fn test_message() {
+        let mut record = LockoutRecord {
+            uid: 1000,
+            user: "user",
+            service: Some("sshd"),
+            rhost: Some("192.0.2.1"),
+            tty: Some("ssh"),
+        };
+        assert_eq!(
+            record.message(),
+            "op=pam_authramp suid=1000 acct=\"user\" service=\"sshd\""
+        );
+
+        // a user name can't add fields
+        record.user = "x op=other";
+        record.service = None;
+        assert_eq!(
+            record.message(),
+            "op=pam_authramp suid=1000 acct=78206F703D6F74686572"
+        );
+    }
+}
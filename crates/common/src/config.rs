@@ -12,6 +12,22 @@
 //! # Structs
 //!
 //! - [`Config`](struct.Config.html): Represents the configuration settings for `AuthRamp`.
+//! - [`UserLookup`](enum.UserLookup.html): How the PAM user is resolved.
+//! - [`TallyKey`](enum.TallyKey.html): What the tally files are named after.
+//! - [`TallyLayout`](enum.TallyLayout.html): How the tally files are spread over directories.
+//! - [`PolicyDisclosure`](enum.PolicyDisclosure.html): How much of the policy is disclosed.
+//! - [`CountdownStyle`](enum.CountdownStyle.html): How often the countdown is sent.
+//! - [`DelayMode`](enum.DelayMode.html): How a locked account is delayed.
+//! - [`DelayAlgorithm`](enum.DelayAlgorithm.html): How the delay grows with the failures.
+//! - [`LogFacility`](enum.LogFacility.html): The syslog facility of the module and CLI.
+//! - [`LogBackend`](enum.LogBackend.html): Where the module and CLI send their logs.
+//! - [`LogThreshold`](enum.LogThreshold.html): The least severe messages the module and CLI log.
+//! - [`FailMode`](enum.FailMode.html): What the module returns when it fails internally.
+//! - [`UnknownUser`](enum.UnknownUser.html): How users missing from the user database are handled.
+//! - [`UserOverride`](struct.UserOverride.html): Settings overridden for a single user or PAM
+//!   hook.
+//! - [`ConfigIssue`](struct.ConfigIssue.html): A problem found by the strict configuration check.
+//! - [`Severity`](enum.Severity.html): How serious a configuration problem is.
 //!
 //! ## License
 //!
@@ -31,153 +47,1910 @@
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::{fs, path::PathBuf};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Duration, Local, TimeZone, Utc,
+};
+use serde::{
+    de::{self, Unexpected},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{collections::BTreeMap, fmt, fs, path::PathBuf};
 
-use pam::PamHandle;
+use pam::{PamApi, PamResultCode};
 
-const DEFAULT_CONFIG_FILE_PATH: &str = "/etc/security/authramp.conf";
+use crate::error::AuthRampError;
+use crate::messages::{Locale, Message};
 
-#[derive(Debug)]
+/// Path of the configuration file read if no other path is given.
+pub const DEFAULT_CONFIG_FILE_PATH: &str = "/etc/security/authramp.conf";
+
+/// Default tally directory, on a tmpfs cleared by every reboot.
+pub const VOLATILE_TALLY_DIR: &str = "/var/run/authramp";
+
+/// Default tally directory with `persist_across_reboot`, kept across reboots.
+pub const PERSISTENT_TALLY_DIR: &str = "/var/lib/authramp/tally";
+
+/// The `message_time_format` used if none or an invalid one is configured.
+pub const DEFAULT_MESSAGE_TIME_FORMAT: &str = "%Y-%m-%d %I:%M:%S %p";
+
+/// Formats an instant in a timezone with a strftime format.
+///
+/// # Arguments
+/// - `instant`: The instant to format
+/// - `tz`: The timezone the instant is shown in
+/// - `format`: The strftime format, [`DEFAULT_MESSAGE_TIME_FORMAT`] is used if it is invalid
+///
+/// # Returns
+/// The formatted instant
+pub fn format_time<Tz: TimeZone>(instant: DateTime<Utc>, tz: &Tz, format: &str) -> String
+where
+    Tz::Offset: fmt::Display,
+{
+    // formatting with an invalid specifier panics
+    let format = if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        DEFAULT_MESSAGE_TIME_FORMAT
+    } else {
+        format
+    };
+    instant.with_timezone(tz).format(format).to_string()
+}
+
+/// How serious a configuration problem is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// A deprecated name is used.
+    Warning,
+    /// The configuration contradicts itself, the affected value is ignored.
+    Error,
+    /// The file can't be parsed, e.g. because of a syntax error, an unknown key or a value of the
+    /// wrong type, so all values fall back to their defaults.
+    Fatal,
+}
+
+/// A problem found by [`Config::check`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigIssue {
+    /// Where the problem is, e.g. `[Settings] free_tries` or `line 3, column 14`.
+    pub location: String,
+    /// What is wrong.
+    pub message: String,
+    /// How serious the problem is.
+    pub severity: Severity,
+}
+
+impl ConfigIssue {
+    fn new(location: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigIssue {
+            location: location.into(),
+            message: message.into(),
+            severity: Severity::Warning,
+        }
+    }
+
+    fn conflict(location: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigIssue {
+            severity: Severity::Error,
+            ..ConfigIssue::new(location, message)
+        }
+    }
+
+    /// An error of the parser, located by its line and column.
+    fn parse_error(content: &str, error: &toml::de::Error) -> Self {
+        let location = error.span().map_or_else(
+            || "file".to_string(),
+            |span| {
+                let before = &content[..span.start];
+                let line = before.matches('\n').count() + 1;
+                let column = before
+                    .rsplit('\n')
+                    .next()
+                    .unwrap_or_default()
+                    .chars()
+                    .count()
+                    + 1;
+                format!("line {line}, column {column}")
+            },
+        );
+        ConfigIssue {
+            severity: Severity::Fatal,
+            ..ConfigIssue::new(location, error.message())
+        }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// How the PAM user is resolved.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserLookup {
+    /// Look the user up in the NSS user database.
+    #[default]
+    Nss,
+    /// Skip the lookup and key everything by the PAM user name.
+    None,
+}
+
+impl UserLookup {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            UserLookup::Nss => "nss",
+            UserLookup::None => "none",
+        }
+    }
+}
+
+/// The modes of `normalize_user`.
+const NORMALIZE_USER_MODES: [&str; 3] = ["none", "lowercase", "strip_realm"];
+
+/// How the PAM user name is normalized before it is resolved, with any combination of the modes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct NormalizeUser {
+    /// Lowercase the PAM user name, so case variants share a tally.
+    pub lowercase: bool,
+    /// Strip a realm suffix like "@EXAMPLE.COM" from the PAM user name.
+    pub strip_realm: bool,
+}
+
+impl NormalizeUser {
+    /// Reads the modes from the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// - `value`: A mode like `"lowercase"`, or an array of modes like
+    ///   `["lowercase", "strip_realm"]`.
+    ///
+    /// # Returns
+    ///
+    /// The combined modes, or `None` if the value isn't a mode or an array of modes.
+    fn from_value(value: &toml::Value) -> Option<Self> {
+        let modes = match value {
+            toml::Value::String(mode) => vec![mode.as_str()],
+            toml::Value::Array(modes) => modes
+                .iter()
+                .map(toml::Value::as_str)
+                .collect::<Option<Vec<_>>>()?,
+            _ => return None,
+        };
+
+        modes
+            .into_iter()
+            .try_fold(NormalizeUser::default(), |normalize, mode| match mode {
+                "none" => Some(normalize),
+                "lowercase" => Some(NormalizeUser {
+                    lowercase: true,
+                    ..normalize
+                }),
+                "strip_realm" => Some(NormalizeUser {
+                    strip_realm: true,
+                    ..normalize
+                }),
+                _ => None,
+            })
+    }
+
+    /// The value in the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// `"none"`, a single mode, or an array of the modes.
+    #[must_use]
+    pub fn to_value(self) -> toml::Value {
+        let modes: Vec<&str> = [
+            (self.lowercase, "lowercase"),
+            (self.strip_realm, "strip_realm"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, mode)| enabled.then_some(mode))
+        .collect();
+
+        match modes.as_slice() {
+            [] => "none".into(),
+            [mode] => (*mode).into(),
+            _ => modes.into(),
+        }
+    }
+
+    /// Normalizes a PAM user name.
+    ///
+    /// A realm is only stripped if a name is left, so "@EXAMPLE.COM" stays as it is. Of names
+    /// with several "@", only the last suffix is the realm.
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The PAM user name.
+    ///
+    /// # Returns
+    ///
+    /// The normalized user name.
+    #[must_use]
+    pub fn apply(self, name: &str) -> String {
+        let name = match name.rsplit_once('@') {
+            Some((user, _)) if self.strip_realm && !user.is_empty() => user,
+            _ => name,
+        };
+
+        if self.lowercase {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NormalizeUser {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+        NormalizeUser::from_value(&value).ok_or_else(|| {
+            de::Error::custom(format!(
+                "expected one or an array of \"{}\", found {value}",
+                NORMALIZE_USER_MODES.join("\", \"")
+            ))
+        })
+    }
+}
+
+impl Serialize for NormalizeUser {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_value().serialize(serializer)
+    }
+}
+
+/// What the tally files are named after.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TallyKey {
+    /// The user name.
+    #[default]
+    Name,
+    /// The numeric uid, so name variants of one account share a tally.
+    Uid,
+}
+
+impl TallyKey {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            TallyKey::Name => "name",
+            TallyKey::Uid => "uid",
+        }
+    }
+}
+
+/// How the tally files are spread over the tally directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TallyLayout {
+    /// All tally files directly in `tally_dir`.
+    #[default]
+    Flat,
+    /// In subdirectories named after the first two hex digits of the SHA-256 of the file name,
+    /// so large user bases don't end up in one huge directory.
+    Sharded,
+}
+
+impl TallyLayout {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            TallyLayout::Flat => "flat",
+            TallyLayout::Sharded => "sharded",
+        }
+    }
+}
+
+/// What the module returns when it fails internally.
+///
+/// Internal failures are everything but the decisions of the module, e.g. an unreadable
+/// configuration, tally IO errors or a failed user lookup.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailMode {
+    /// Return `PAM_IGNORE`, so the stack continues as if the module wasn't there.
+    #[default]
+    Open,
+    /// Deny with `PAM_AUTH_ERR`, or `PAM_PERM_DENIED` in the account hook.
+    Closed,
+}
+
+impl FailMode {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            FailMode::Open => "open",
+            FailMode::Closed => "closed",
+        }
+    }
+
+    /// Maps the result of a hook according to the fail mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_hook`: The PAM hook, `"account"` and `"password"` deny with `PAM_PERM_DENIED`,
+    ///   `"session"` with `PAM_SESSION_ERR`.
+    /// * `result`: The result code of the hook.
+    ///
+    /// # Returns
+    ///
+    /// `result` if it's a decision of the module: `PAM_SUCCESS`, `PAM_IGNORE`, `PAM_AUTH_ERR`,
+    /// `PAM_PERM_DENIED` or `PAM_SESSION_ERR`. The result of the fail mode for any other code.
+    #[must_use]
+    pub fn result(self, pam_hook: &str, result: PamResultCode) -> PamResultCode {
+        match (result, self) {
+            (
+                result @ (PamResultCode::PAM_SUCCESS
+                | PamResultCode::PAM_IGNORE
+                | PamResultCode::PAM_AUTH_ERR
+                | PamResultCode::PAM_PERM_DENIED
+                | PamResultCode::PAM_SESSION_ERR),
+                _,
+            ) => result,
+            (_, FailMode::Open) => PamResultCode::PAM_IGNORE,
+            (_, FailMode::Closed) => deny_result(pam_hook),
+        }
+    }
+}
+
+/// The result code denying in a PAM hook.
+///
+/// # Arguments
+///
+/// * `pam_hook`: The PAM hook.
+///
+/// # Returns
+///
+/// `PAM_PERM_DENIED` in the account and password hooks, `PAM_SESSION_ERR` in the session hook,
+/// `PAM_AUTH_ERR` otherwise.
+#[must_use]
+pub fn deny_result(pam_hook: &str) -> PamResultCode {
+    match pam_hook {
+        "account" | "password" => PamResultCode::PAM_PERM_DENIED,
+        "session" => PamResultCode::PAM_SESSION_ERR,
+        _ => PamResultCode::PAM_AUTH_ERR,
+    }
+}
+
+/// How users missing from the user database are handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownUser {
+    /// Return `PAM_IGNORE`, so the module steps aside.
+    #[default]
+    Ignore,
+    /// Deny, and tally the attempts under a placeholder derived from the name.
+    /// Denies even with `track_unknown_users` disabled.
+    Deny,
+}
+
+impl UnknownUser {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            UnknownUser::Ignore => "ignore",
+            UnknownUser::Deny => "deny",
+        }
+    }
+}
+
+/// How much of the lockout policy is disclosed to users requesting it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyDisclosure {
+    /// Show the effective policy.
+    #[default]
+    Full,
+    /// Refuse to show the policy and log the request.
+    Minimal,
+}
+
+impl PolicyDisclosure {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            PolicyDisclosure::Full => "full",
+            PolicyDisclosure::Minimal => "minimal",
+        }
+    }
+}
+
+/// How often the lockout countdown is sent to the user.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CountdownStyle {
+    /// Send the remaining time again whenever it changes, in hours, minutes or
+    /// `countdown_interval_seconds` depending on how long it is.
+    #[default]
+    Repeat,
+    /// Send the remaining time once and wait silently.
+    Single,
+}
+
+impl CountdownStyle {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            CountdownStyle::Repeat => "repeat",
+            CountdownStyle::Single => "single",
+        }
+    }
+}
+
+/// How a locked account is delayed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DelayMode {
+    /// Block in the module, counting down the lock if `countdown` is enabled.
+    #[default]
+    Sleep,
+    /// Request the remaining lock time from the application with `pam_fail_delay`.
+    PamFailDelay,
+}
+
+impl DelayMode {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            DelayMode::Sleep => "sleep",
+            DelayMode::PamFailDelay => "pam_fail_delay",
+        }
+    }
+}
+
+/// How the delay grows with the failures past the free tries.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DelayAlgorithm {
+    /// `ramp_multiplier × over × ln(over) + base_delay_seconds`, with `over` the failures past
+    /// the free tries.
+    #[default]
+    Ramp,
+    /// `base_delay_seconds × delay_factor^over`, e.g. doubling with every failure.
+    Exponential,
+    /// `base_delay_seconds` for every failure.
+    Fixed,
+}
+
+impl DelayAlgorithm {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            DelayAlgorithm::Ramp => "ramp",
+            DelayAlgorithm::Exponential => "exponential",
+            DelayAlgorithm::Fixed => "fixed",
+        }
+    }
+}
+
+/// The syslog facility lockout events are logged to.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LogFacility {
+    /// Security and authorization messages, corresponds to `LOG_AUTH`
+    Auth,
+    /// Private security and authorization messages, corresponds to `LOG_AUTHPRIV`
+    #[default]
+    AuthPriv,
+    /// System daemons, corresponds to `LOG_DAEMON`
+    Daemon,
+    /// Generic user-level messages, corresponds to `LOG_USER`
+    User,
+}
+
+impl LogFacility {
+    /// Parses a facility name like "authpriv", case-insensitively.
+    ///
+    /// # Returns
+    ///
+    /// The facility, or `None` if the name is unknown.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "auth" => Some(LogFacility::Auth),
+            "authpriv" => Some(LogFacility::AuthPriv),
+            "daemon" => Some(LogFacility::Daemon),
+            "user" => Some(LogFacility::User),
+            _ => None,
+        }
+    }
+
+    /// The name of the facility in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            LogFacility::Auth => "auth",
+            LogFacility::AuthPriv => "authpriv",
+            LogFacility::Daemon => "daemon",
+            LogFacility::User => "user",
+        }
+    }
+
+    /// The syslog facility code to pass to `openlog` or `syslog`.
+    #[must_use]
+    pub fn code(self) -> libc::c_int {
+        match self {
+            LogFacility::Auth => libc::LOG_AUTH,
+            LogFacility::AuthPriv => libc::LOG_AUTHPRIV,
+            LogFacility::Daemon => libc::LOG_DAEMON,
+            LogFacility::User => libc::LOG_USER,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LogFacility {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        LogFacility::from_name(&name).ok_or_else(|| {
+            de::Error::invalid_value(
+                Unexpected::Str(&name),
+                &"one of \"auth\", \"authpriv\", \"daemon\", \"user\"",
+            )
+        })
+    }
+}
+
+impl Serialize for LogFacility {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// Deserializes a message to the user, which the conversation can't send with a NUL byte.
+fn deserialize_message<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let message = String::deserialize(deserializer)?;
+    if message.contains('\0') {
+        return Err(de::Error::invalid_value(
+            Unexpected::Str(&message),
+            &"a message without a NUL byte",
+        ));
+    }
+    Ok(Some(message))
+}
+
+/// The least severe messages the module and CLI log.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogThreshold {
+    /// Only errors
+    Error,
+    /// Errors and warnings
+    Warn,
+    /// Everything but debug messages
+    #[default]
+    Info,
+    /// Everything, including the delay calculation and the loaded configuration
+    Debug,
+}
+
+impl LogThreshold {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            LogThreshold::Error => "error",
+            LogThreshold::Warn => "warn",
+            LogThreshold::Info => "info",
+            LogThreshold::Debug => "debug",
+        }
+    }
+
+    /// The least severe level of the threshold.
+    #[must_use]
+    pub fn level(self) -> pam::LogLevel {
+        match self {
+            LogThreshold::Error => pam::LogLevel::Error,
+            LogThreshold::Warn => pam::LogLevel::Warning,
+            LogThreshold::Info => pam::LogLevel::Info,
+            LogThreshold::Debug => pam::LogLevel::Debug,
+        }
+    }
+
+    /// Whether messages of a level are logged.
+    #[must_use]
+    pub fn allows(self, level: pam::LogLevel) -> bool {
+        level.is_within(self.level())
+    }
+}
+
+/// Where the module and CLI send their logs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogBackend {
+    /// The syslog socket, with the configured facility.
+    #[default]
+    Syslog,
+    /// The native journald protocol, with structured `AUTHRAMP_*` fields.
+    Journald,
+}
+
+impl LogBackend {
+    /// The name of the value in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            LogBackend::Syslog => "syslog",
+            LogBackend::Journald => "journald",
+        }
+    }
+}
+
+/// Settings overridden for a single user by a `[user.<name>]` table, or for a PAM hook by an
+/// `[auth]` or `[account]` table.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserOverride {
+    pub free_tries: Option<i32>,
+    pub base_delay_seconds: Option<i32>,
+    pub ramp_multiplier: Option<f64>,
+    pub even_deny_root: Option<bool>,
+    pub countdown: Option<bool>,
+    pub nodelay: Option<bool>,
+}
+
+/// The `[Configuration]` section. Keys that aren't set keep their defaults.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config {
     // Directory where tally information is stored.
     pub tally_dir: PathBuf,
+    // Keep tallies across reboots if tally_dir isn't set
+    pub persist_across_reboot: bool,
+    // Flush tally writes to disk before they're used
+    pub durable_writes: bool,
+    // Let every user read the tally files, e.g. for `authramp remaining`
+    pub status_world_readable: bool,
+    // File where anonymous usage statistics are stored.
+    pub stats_file: PathBuf,
     // Number of allowed free authentication attempts before applying delays.
     pub free_tries: i32,
     // Base delay applied to each authentication failure.
     pub base_delay_seconds: i32,
     // Multiplier for the delay calculation based on the number of failures.
-    pub ramp_multiplier: i32,
+    pub ramp_multiplier: f64,
+    // How the delay grows with the failures
+    pub delay_algorithm: DelayAlgorithm,
+    // Growth per failure of the exponential delay
+    pub delay_factor: f64,
+    // Maximum duration of a single lockout, 0 means no cap
+    pub max_lockout_seconds: i64,
+    // Seconds after the last failure until failures expire, 0 means never
+    pub reset_after_seconds: i64,
     // Even lock out root user
     pub even_deny_root: bool,
+    // Even lock out root on the physical console
+    pub even_deny_root_console: bool,
+    // free_tries of root, the global value if unset
+    pub root_free_tries: Option<i32>,
+    // max_lockout_seconds of root, the global value if unset
+    pub root_max_lockout_seconds: Option<i64>,
     // Count down lockout loop,
     pub countdown: bool,
+    // strftime format of the unlock time in user messages, in the local timezone
+    pub message_time_format: String,
+    // Language of the user messages, "auto" detects it from the environment
+    pub locale: String,
+    // Message telling a locked user when the account is unlocked, empty to send none
+    #[serde(deserialize_with = "deserialize_message")]
+    pub lockout_message: Option<String>,
+    // Message of the countdown, empty to send none
+    #[serde(deserialize_with = "deserialize_message")]
+    pub countdown_message: Option<String>,
+    // How often the countdown is sent
+    pub countdown_style: CountdownStyle,
+    // Granularity of the repeated countdown in its last two minutes
+    pub countdown_interval_seconds: i64,
+    // How a locked account is delayed
+    pub delay_mode: DelayMode,
+    // Delay of every failure within the free tries, 0 means none
+    pub soft_delay_seconds: i64,
+    // Consecutive failed countdown messages until the countdown gives up
+    pub max_conversation_failures: i32,
+    // Maximum time the countdown blocks, 0 means no cap
+    pub max_conversation_block_seconds: i64,
+    // Return PAM_IGNORE from the account hook when there is nothing to clear
+    pub account_neutral: bool,
+    // Deny locked accounts immediately instead of holding the conversation open
+    pub nodelay: bool,
+    // PAM services the module doesn't act on
+    pub exempt_services: Vec<String>,
+    // PAM services and terminals that never display the countdown
+    pub noninteractive_services: Vec<String>,
+    // Members of these groups are never locked out
+    pub exempt_groups: Vec<String>,
+    // Users, or @groups, the module doesn't act on
+    pub ignore_users: Vec<String>,
+    // Lock out logins on the local console
+    pub deny_local: bool,
+    // Record failures on the local console exempt with deny_local = false
+    pub count_local_failures: bool,
+    // Command run in the background when an account gets locked or unlocked
+    pub hook_command: Option<PathBuf>,
+    // Recipients emailed when an account gets locked or reset
+    pub notify_email: Vec<String>,
+    // Template of the notification, the default template if not set
+    pub notify_email_template: Option<String>,
+    // Executable the notifications are piped to
+    pub notify_sendmail: PathBuf,
+    // Root-only secret the tally files are authenticated with
+    pub tally_hmac_key_file: Option<PathBuf>,
+    // Refuse authentications of tallies whose MAC doesn't verify
+    pub tally_hmac_fail_closed: bool,
+    // Syslog facility of the module and CLI
+    pub log_facility: LogFacility,
+    // Where the module and CLI send their logs
+    pub log_backend: LogBackend,
+    // Interval identical bounce log lines are collapsed in, 0 logs every line
+    pub log_repeat_interval_seconds: i64,
+    // Least severe messages logged
+    pub log_level: LogThreshold,
+    // Write a Linux audit record when an account gets locked
+    pub audit_lockouts: bool,
+    // Signal lockouts on the D-Bus system bus
+    pub dbus: bool,
+    // How the PAM user is resolved
+    pub user_lookup: UserLookup,
+    // How the PAM user name is normalized
+    pub normalize_user: NormalizeUser,
+    // What the tally files are named after
+    pub tally_key: TallyKey,
+    // How the tally files are spread over the tally directory
+    pub tally_layout: TallyLayout,
+    // How much of the policy the policy argument discloses
+    pub policy_disclosure: PolicyDisclosure,
+    // Subtract failures of the current PAM transaction when it ends in a success
+    pub forgive_same_transaction_failures: bool,
+    // Record failures while the account is locked
+    pub count_while_locked: bool,
+    // Shorten recorded locks to the delay of the current configuration
+    pub recompute_on_config_change: bool,
+    // What the module returns when it fails internally
+    pub fail_mode: FailMode,
+    // How users missing from the user database are handled
+    pub unknown_user: UnknownUser,
+    // Tally users missing from the user database under a placeholder
+    pub track_unknown_users: bool,
+    // Root-only salt of the placeholder names, generated on first use
+    pub unknown_user_salt_file: PathBuf,
+    // Groups permitted to run restricted CLI commands, keyed by command
+    #[serde(skip)]
+    pub cli_permissions: BTreeMap<String, Vec<String>>,
+    // Settings overridden per user, keyed by user name
+    #[serde(skip)]
+    pub user_overrides: BTreeMap<String, UserOverride>,
+    // Settings overridden per PAM hook, keyed by hook name
+    #[serde(skip)]
+    pub hook_overrides: BTreeMap<String, UserOverride>,
+}
+
+/// The configuration file, with the [`Config`] nested in its `[Configuration]` section next to
+/// the CLI and override sections.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct Configuration {
+    #[serde(rename = "Configuration", skip_serializing_if = "Option::is_none")]
+    config: Option<Config>,
+    // Legacy name of [Configuration], which takes precedence
+    #[serde(rename = "Settings", skip_serializing)]
+    settings: Option<Config>,
+    #[serde(rename = "Cli", skip_serializing_if = "CliSection::is_empty")]
+    cli: CliSection,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    user: BTreeMap<String, UserOverride>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<UserOverride>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<UserOverride>,
+}
+
+/// The `[Cli]` section.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct CliSection {
+    // Groups permitted to run restricted CLI commands, keyed by command
+    permissions: BTreeMap<String, Vec<String>>,
+}
+
+impl CliSection {
+    fn is_empty(&self) -> bool {
+        self.permissions.is_empty()
+    }
 }
 
 impl Default for Config {
     /// Creates a default 'Config' struct. Default configruation values are set here.
     fn default() -> Self {
         Config {
-            tally_dir: PathBuf::from("/var/run/authramp"),
+            tally_dir: PathBuf::from(VOLATILE_TALLY_DIR),
+            persist_across_reboot: false,
+            durable_writes: true,
+            status_world_readable: false,
+            stats_file: PathBuf::from("/var/lib/authramp/stats.toml"),
             free_tries: 6,
             base_delay_seconds: 30,
-            ramp_multiplier: 50,
+            ramp_multiplier: 50.0,
+            delay_algorithm: DelayAlgorithm::default(),
+            delay_factor: 2.0,
+            max_lockout_seconds: 86400,
+            reset_after_seconds: 0,
             even_deny_root: false,
+            even_deny_root_console: false,
+            root_free_tries: None,
+            root_max_lockout_seconds: None,
             countdown: false,
+            message_time_format: DEFAULT_MESSAGE_TIME_FORMAT.to_string(),
+            locale: "auto".to_string(),
+            lockout_message: None,
+            countdown_message: None,
+            countdown_style: CountdownStyle::default(),
+            countdown_interval_seconds: 2,
+            delay_mode: DelayMode::default(),
+            soft_delay_seconds: 0,
+            max_conversation_failures: 3,
+            max_conversation_block_seconds: 0,
+            account_neutral: true,
+            nodelay: false,
+            exempt_services: Vec::new(),
+            noninteractive_services: vec!["sshd".to_string(), "sudo".to_string()],
+            exempt_groups: Vec::new(),
+            ignore_users: Vec::new(),
+            deny_local: true,
+            count_local_failures: true,
+            hook_command: None,
+            notify_email: Vec::new(),
+            notify_email_template: None,
+            notify_sendmail: PathBuf::from("/usr/sbin/sendmail"),
+            tally_hmac_key_file: None,
+            tally_hmac_fail_closed: true,
+            log_facility: LogFacility::default(),
+            log_backend: LogBackend::default(),
+            log_repeat_interval_seconds: 60,
+            log_level: LogThreshold::default(),
+            audit_lockouts: false,
+            dbus: false,
+            user_lookup: UserLookup::default(),
+            normalize_user: NormalizeUser::default(),
+            tally_key: TallyKey::default(),
+            tally_layout: TallyLayout::default(),
+            policy_disclosure: PolicyDisclosure::default(),
+            forgive_same_transaction_failures: true,
+            count_while_locked: false,
+            recompute_on_config_change: true,
+            fail_mode: FailMode::default(),
+            unknown_user: UnknownUser::default(),
+            track_unknown_users: false,
+            unknown_user_salt_file: PathBuf::from("/var/lib/authramp/unknown_user.salt"),
+            cli_permissions: BTreeMap::new(),
+            user_overrides: BTreeMap::new(),
+            hook_overrides: BTreeMap::new(),
         }
     }
 }
 
-impl Config {
-    /// Loads configuration from a TOML file, returning a `Config` instance.
+impl Config {
+    /// Loads configuration from a TOML file, returning a `Config` instance.
+    ///
+    /// This function reads the specified TOML file and parses its content into a `Config` instance.
+    /// If the file is not present or cannot be loaded, default configuration values are used. A
+    /// file that can't be parsed is logged as an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: An optional string slice specifying the path to the TOML file. If not provided,
+    ///   the default configuration file path is used.
+    /// * `pam_h`: An optional mutable reference to a PAM handle. If provided, logs a message
+    ///   indicating the successful loading of the configuration.
+    ///
+    /// # Returns
+    ///
+    /// A `Config` instance populated with values from the configuration file, or default values
+    /// if the file is not present or cannot be loaded.
+    #[must_use]
+    pub fn load_file<P: PamApi>(path: Option<&str>, mut pam_h: Option<&mut P>) -> Config {
+        let path = path.unwrap_or(DEFAULT_CONFIG_FILE_PATH);
+        Self::try_load_file(path, pam_h.as_deref_mut()).unwrap_or_else(|e| {
+            // without a file the defaults are intended, with a broken one they aren't
+            if let (AuthRampError::Invalid { .. }, Some(pam_h)) = (&e, pam_h) {
+                let _ = pam_h.log(
+                    pam::LogLevel::Error,
+                    format!("{e}. Ignoring the whole file and using the defaults."),
+                );
+            }
+            Config::default()
+        })
+    }
+
+    /// Loads configuration from a TOML file that has to be readable and valid.
+    ///
+    /// Like `load_file`, but reports a missing or unreadable file, syntax errors, unknown
+    /// sections and keys, and values of the wrong type instead of using the defaults. The
+    /// problems that don't void the file, see [`Config::check`], are logged.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The path to the TOML file.
+    /// * `pam_h`: An optional mutable reference to a PAM handle the problems are logged with.
+    ///
+    /// # Returns
+    ///
+    /// A `Config` instance populated with values from the configuration file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AuthRampError` with the `io::Error` if the file can't be read, and an invalid
+    /// one with the line, column and message of the parser if it can't be parsed.
+    pub fn try_load_file<P: PamApi>(
+        path: &str,
+        pam_h: Option<&mut P>,
+    ) -> Result<Config, AuthRampError> {
+        // Read TOML file using the toml crate
+        let content = fs::read_to_string(PathBuf::from(path)).map_err(|e| {
+            AuthRampError::io(format!("Error reading the configuration file {path}"), e)
+        })?;
+
+        // Parse the TOML content strictly, any error voids the whole file
+        let (config, issues) = Self::parse(&content).map_err(|e| {
+            AuthRampError::invalid(
+                format!("Error parsing the configuration file {path}"),
+                ConfigIssue::parse_error(&content, &e).to_string(),
+            )
+        })?;
+
+        // when there is no pam_h, there don't need to be logs
+        if let Some(pam_h) = pam_h {
+            for issue in issues {
+                let _ = pam_h.log(
+                    match issue.severity {
+                        Severity::Warning => pam::LogLevel::Warning,
+                        Severity::Error | Severity::Fatal => pam::LogLevel::Error,
+                    },
+                    format!("Invalid configuration in {path}: {issue}"),
+                );
+            }
+            let _ = pam_h.log(
+                pam::LogLevel::Info,
+                format!("Successfully loaded config: {config:?}"),
+            );
+        }
+        Ok(config)
+    }
+
+    /// Parses the content of a configuration file strictly.
+    ///
+    /// The legacy `[Settings]` section is merged into `[Configuration]`, which takes precedence.
+    /// Values below their minimum are raised to it, and wildcard entries of `ignore_users` are
+    /// dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `content`: The content of the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// The configuration, and the problems that don't void the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first syntax error, unknown section or key, or value of the wrong type.
+    fn parse(content: &str) -> Result<(Config, Vec<ConfigIssue>), toml::de::Error> {
+        let file: Configuration = toml::from_str(content)?;
+        let (section, mut issues) = merge_legacy_section(&toml::from_str(content)?);
+
+        // a wildcard would disable the module
+        for (name, section_config) in [
+            ("Configuration", &file.config),
+            ("Settings", &file.settings),
+        ] {
+            let entries = section_config
+                .iter()
+                .flat_map(|config| &config.ignore_users);
+            for entry in entries.filter(|entry| is_wildcard_entry(entry)) {
+                issues.push(ConfigIssue::conflict(
+                    format!("[{name}] ignore_users"),
+                    format!("\"{entry}\" would exempt every user from the module and is ignored"),
+                ));
+            }
+        }
+
+        let mut config = match (file.config, file.settings) {
+            // the sections are merged key by key
+            (Some(_), Some(_)) => section
+                .clone()
+                .map(Config::deserialize)
+                .transpose()?
+                .unwrap_or_default(),
+            (configuration, settings) => configuration.or(settings).unwrap_or_default(),
+        };
+
+        // persist_across_reboot only selects the default directory
+        let tally_dir_set = section.is_some_and(|section| section.get("tally_dir").is_some());
+        if config.persist_across_reboot && !tally_dir_set {
+            config.tally_dir = PathBuf::from(PERSISTENT_TALLY_DIR);
+        }
+        config.clamp();
+
+        config.cli_permissions = file.cli.permissions;
+        config.user_overrides = file.user;
+        config.hook_overrides = [("auth", file.auth), ("account", file.account)]
+            .into_iter()
+            .filter_map(|(hook, hook_override)| Some((hook.to_string(), hook_override?)))
+            .collect();
+
+        Ok((config, issues))
+    }
+
+    /// Raises values below their minimum, like negative durations, and drops the wildcard entries
+    /// of `ignore_users`.
+    fn clamp(&mut self) {
+        self.max_lockout_seconds = self.max_lockout_seconds.max(0);
+        self.reset_after_seconds = self.reset_after_seconds.max(0);
+        self.root_max_lockout_seconds = self.root_max_lockout_seconds.map(|val| val.max(0));
+        self.countdown_interval_seconds = self.countdown_interval_seconds.max(1);
+        self.soft_delay_seconds = self.soft_delay_seconds.max(0);
+        self.max_conversation_failures = self.max_conversation_failures.max(1);
+        self.max_conversation_block_seconds = self.max_conversation_block_seconds.max(0);
+        self.log_repeat_interval_seconds = self.log_repeat_interval_seconds.max(0);
+        self.ignore_users.retain(|entry| !is_wildcard_entry(entry));
+    }
+
+    /// Checks the content of a configuration file strictly.
+    ///
+    /// This reports the first error voiding the whole file with its line and column: a syntax
+    /// error, an unknown section or key, or a value of the wrong type. The deprecated `[Settings]`
+    /// section is reported as a warning, its values conflicting with `[Configuration]` and
+    /// wildcard entries of `ignore_users` as errors.
+    ///
+    /// # Arguments
+    ///
+    /// * `content`: The content of the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// The problems found, empty if the configuration is valid.
+    #[must_use]
+    pub fn check(content: &str) -> Vec<ConfigIssue> {
+        match Self::parse(content) {
+            Ok((_, issues)) => issues,
+            Err(e) => vec![ConfigIssue::parse_error(content, &e)],
+        }
+    }
+
+    /// Renders the effective configuration as TOML.
+    ///
+    /// Every key is included, with its default if it isn't configured. Unset optional paths
+    /// are omitted.
+    ///
+    /// # Returns
+    ///
+    /// The TOML table with the `[Configuration]`, `[Cli.permissions]`, `[user.<name>]`, `[auth]`
+    /// and `[account]` sections.
+    #[must_use]
+    pub fn to_toml(&self) -> toml::Table {
+        let file = Configuration {
+            config: Some(self.clone()),
+            settings: None,
+            cli: CliSection {
+                permissions: self.cli_permissions.clone(),
+            },
+            user: self.user_overrides.clone(),
+            auth: self.hook_overrides.get("auth").cloned(),
+            account: self.hook_overrides.get("account").cloned(),
+        };
+        // only paths that aren't UTF-8 fail, which the file can't contain
+        toml::Table::try_from(file).unwrap_or_default()
+    }
+
+    /// Formats an instant for user messages with `message_time_format` in the local timezone.
+    ///
+    /// The local timezone is taken from the `TZ` environment variable, or `/etc/localtime`.
+    ///
+    /// # Arguments
+    ///
+    /// * `instant`: The instant to format.
+    #[must_use]
+    pub fn message_time(&self, instant: DateTime<Utc>) -> String {
+        format_time(instant, &Local, &self.message_time_format)
+    }
+
+    /// The locale of the user messages.
+    ///
+    /// # Returns
+    ///
+    /// The configured `locale`, detected from the environment if it is "auto". Locales without a
+    /// translation fall back to English.
+    #[must_use]
+    pub fn locale(&self) -> Locale {
+        if self.locale == "auto" {
+            Locale::detect()
+        } else {
+            Locale::from_name(&self.locale).unwrap_or_default()
+        }
+    }
+
+    /// The `lockout_message`, the translation of the locale if none is configured.
+    #[must_use]
+    pub fn lockout_message(&self, locale: Locale) -> &str {
+        self.lockout_message
+            .as_deref()
+            .unwrap_or_else(|| locale.text(Message::Lockout))
+    }
+
+    /// The `countdown_message`, the translation of the locale if none is configured.
+    #[must_use]
+    pub fn countdown_message(&self, locale: Locale) -> &str {
+        self.countdown_message
+            .as_deref()
+            .unwrap_or_else(|| locale.text(Message::Countdown))
+    }
+
+    /// Applies the `[user.<name>]` overrides of a user over the global configuration.
+    ///
+    /// Values set for the user take precedence over the `[Configuration]` section, which takes
+    /// precedence over the defaults.
+    ///
+    /// # Arguments
+    ///
+    /// * `user`: The name of the user.
+    pub fn apply_user_override(&mut self, user: &str) {
+        if let Some(user_override) = self.user_overrides.get(user).cloned() {
+            self.apply_override(&user_override);
+        }
+    }
+
+    /// Applies the `[auth]` or `[account]` overrides of a PAM hook over the global configuration.
+    ///
+    /// Values set for the hook take precedence over the `[Configuration]` section. The
+    /// `[user.<name>]` overrides are applied after them and take precedence over both.
+    ///
+    /// # Arguments
+    ///
+    /// * `hook`: The name of the PAM hook, e.g. "account".
+    pub fn apply_hook_override(&mut self, hook: &str) {
+        if let Some(hook_override) = self.hook_overrides.get(hook).cloned() {
+            self.apply_override(&hook_override);
+        }
+    }
+
+    /// Applies the values set in an override table.
+    fn apply_override(&mut self, user_override: &UserOverride) {
+        if let Some(free_tries) = user_override.free_tries {
+            self.free_tries = free_tries;
+        }
+        if let Some(base_delay_seconds) = user_override.base_delay_seconds {
+            self.base_delay_seconds = base_delay_seconds;
+        }
+        if let Some(ramp_multiplier) = user_override.ramp_multiplier {
+            self.ramp_multiplier = ramp_multiplier;
+        }
+        if let Some(even_deny_root) = user_override.even_deny_root {
+            self.even_deny_root = even_deny_root;
+        }
+        if let Some(countdown) = user_override.countdown {
+            self.countdown = countdown;
+        }
+        if let Some(nodelay) = user_override.nodelay {
+            self.nodelay = nodelay;
+        }
+    }
+
+    /// Returns the maximum duration of a single lockout.
+    ///
+    /// # Returns
+    ///
+    /// The cap from `max_lockout_seconds`, or `None` if it is 0 and lockouts are uncapped.
+    #[must_use]
+    pub fn lockout_cap(&self) -> Option<Duration> {
+        (self.max_lockout_seconds > 0).then(|| Duration::seconds(self.max_lockout_seconds))
+    }
+
+    /// Returns the permissions of the tally directory and its shard directories.
+    ///
+    /// # Returns
+    ///
+    /// `0o711` with `status_world_readable`, so everyone can open a tally by name without listing
+    /// the directory, `0o700` otherwise.
+    #[must_use]
+    pub fn tally_dir_mode(&self) -> u32 {
+        if self.status_world_readable {
+            0o711
+        } else {
+            0o700
+        }
+    }
+
+    /// Returns the permissions of the tally files.
+    ///
+    /// # Returns
+    ///
+    /// `0o644` with `status_world_readable`, `0o600` otherwise.
+    #[must_use]
+    pub fn tally_file_mode(&self) -> u32 {
+        if self.status_world_readable {
+            0o644
+        } else {
+            0o600
+        }
+    }
+
+    /// Applies `root_free_tries` and `root_max_lockout_seconds` over the values of everyone else.
+    ///
+    /// Called for authentications of uid 0, after the user overrides and module arguments.
+    pub fn apply_root_policy(&mut self) {
+        if let Some(root_free_tries) = self.root_free_tries {
+            self.free_tries = root_free_tries;
+        }
+        if let Some(root_max_lockout_seconds) = self.root_max_lockout_seconds {
+            self.max_lockout_seconds = root_max_lockout_seconds;
+        }
+    }
+
+    /// Checks whether root is locked out on a terminal.
+    ///
+    /// Root is only locked out with `even_deny_root`, and on the physical console, a terminal
+    /// like `tty1`, only with `even_deny_root_console` too.
+    ///
+    /// # Arguments
+    ///
+    /// * `tty`: The terminal name of the session, if set.
+    ///
+    /// # Returns
+    ///
+    /// `true` if root is locked out on the terminal.
+    #[must_use]
+    pub fn denies_root(&self, tty: Option<&str>) -> bool {
+        self.even_deny_root && (self.even_deny_root_console || !tty.is_some_and(is_console_tty))
+    }
+
+    /// Returns the maximum time the countdown blocks.
+    ///
+    /// # Returns
+    ///
+    /// The cap from `max_conversation_block_seconds`, or `None` if it is 0 and the countdown runs
+    /// until the account is unlocked.
+    #[must_use]
+    pub fn conversation_block_cap(&self) -> Option<Duration> {
+        (self.max_conversation_block_seconds > 0)
+            .then(|| Duration::seconds(self.max_conversation_block_seconds))
+    }
+
+    /// Checks whether a PAM service is exempt from lockout.
     ///
-    /// This function reads the specified TOML file and parses its content into a `Config` instance.
-    /// If the file is not present or cannot be loaded, default configuration values are used.
+    /// Services are matched case-insensitively against `exempt_services`. An entry ending in `*`
+    /// matches every service starting with the text before it.
     ///
     /// # Arguments
     ///
-    /// * `path`: An optional string slice specifying the path to the TOML file. If not provided,
-    ///   the default configuration file path is used.
-    /// * `pam_h`: An optional mutable reference to a `PamHandle`. If provided, logs a message
-    ///   indicating the successful loading of the configuration.
+    /// * `service`: The name of the PAM service.
     ///
     /// # Returns
     ///
-    /// A `Config` instance populated with values from the configuration file, or default values
-    /// if the file is not present or cannot be loaded.
+    /// `true` if the service matches an entry of `exempt_services`.
     #[must_use]
-    pub fn load_file(path: Option<&str>, pam_h: Option<&mut PamHandle>) -> Config {
-        // Read TOML file using the toml crate
-        let content =
-            fs::read_to_string(PathBuf::from(path.unwrap_or(DEFAULT_CONFIG_FILE_PATH))).ok();
+    pub fn is_exempt_service(&self, service: &str) -> bool {
+        matches_any(&self.exempt_services, service)
+    }
 
-        // Parse TOML content into a TomlTable
-        let toml_table: Option<toml::value::Table> =
-            content.and_then(|c| toml::de::from_str(&c).ok());
+    /// Checks whether a PAM conversation can't display the countdown.
+    ///
+    /// The service and the terminal name are matched against `noninteractive_services` like
+    /// `exempt_services`.
+    ///
+    /// # Arguments
+    ///
+    /// * `service`: The name of the PAM service, if set.
+    /// * `tty`: The terminal name of the session, if set.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the service or the terminal matches an entry of `noninteractive_services`.
+    #[must_use]
+    pub fn is_noninteractive(&self, service: Option<&str>, tty: Option<&str>) -> bool {
+        [service, tty]
+            .into_iter()
+            .flatten()
+            .any(|name| matches_any(&self.noninteractive_services, name))
+    }
 
-        // Extract the "Config" section from the TOML table
-        let toml_config = toml_table.and_then(|t| t.get("Configuration").cloned());
+    /// Checks whether a login is exempt from lockout as a local console login.
+    ///
+    /// With `deny_local = false`, a login is local if it has no remote host and its terminal is
+    /// a console like `tty1`. Services switching users, like su, are never local: their remote
+    /// host is empty even in a remote session.
+    ///
+    /// # Arguments
+    ///
+    /// * `service`: The name of the PAM service, if set.
+    /// * `tty`: The terminal name of the session, if set.
+    /// * `rhost`: The remote host of the session, if set.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the login is exempt from lockout.
+    #[must_use]
+    pub fn is_exempt_local(
+        &self,
+        service: Option<&str>,
+        tty: Option<&str>,
+        rhost: Option<&str>,
+    ) -> bool {
+        !self.deny_local
+            && rhost.is_none_or(str::is_empty)
+            && tty.is_some_and(is_console_tty)
+            && !service.is_some_and(|service| {
+                SWITCH_USER_SERVICES.contains(&service.to_lowercase().as_str())
+            })
+    }
 
-        match toml_config {
-            Some(toml_config) => Self::map_config(&toml_config, pam_h),
-            None => Config::default(),
-        }
+    /// Finds the `ignore_users` entry matching a user.
+    ///
+    /// Entries match the user name exactly, entries starting with `@` match a group of the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user`: The name of the user.
+    /// * `groups`: The names of the primary and supplementary groups of the user.
+    ///
+    /// # Returns
+    ///
+    /// The matching entry, or `None` if the user isn't ignored.
+    #[must_use]
+    pub fn ignored_user(&self, user: &str, groups: &[String]) -> Option<&str> {
+        self.ignore_users
+            .iter()
+            .find(|entry| match entry.strip_prefix('@') {
+                Some(group) => groups.iter().any(|name| name == group),
+                None => *entry == user,
+            })
+            .map(String::as_str)
     }
 
-    /// Maps configuration values from a TOML representation to a `Config` instance.
+    /// Finds the first group of a user that is exempt from lockout.
+    ///
+    /// Group names are matched exactly against `exempt_groups`.
     ///
     /// # Arguments
     ///
-    /// * `toml_config`: A reference to a `toml::Value` representing the configuration
-    ///   loaded from a TOML file.
-    /// * `pam_h`: An optional mutable reference to a `PamHandle`. If provided, logs
-    ///   a message indicating the successful loading of the configuration.
+    /// * `groups`: The names of the primary and supplementary groups of the user.
     ///
     /// # Returns
     ///
-    /// A `Config` instance populated with values from the TOML configuration, or
-    /// default values if any values are missing or cannot be parsed.
-    fn map_config(toml_config: &toml::Value, pam_h: Option<&mut PamHandle>) -> Config {
-        let config = Config {
-            tally_dir: toml_config
-                .get("tally_dir")
-                .and_then(|val| val.as_str().map(PathBuf::from))
-                .unwrap_or_else(|| Config::default().tally_dir),
-
-            free_tries: toml_config
-                .get("free_tries")
-                .and_then(toml::Value::as_integer)
-                .map_or_else(|| Config::default().free_tries, |val| val as i32),
-
-            base_delay_seconds: toml_config
-                .get("base_delay_seconds")
-                .and_then(toml::Value::as_integer)
-                .map_or_else(|| Config::default().base_delay_seconds, |val| val as i32),
-
-            ramp_multiplier: toml_config
-                .get("ramp_multiplier")
-                .and_then(toml::Value::as_float)
-                .map_or_else(|| Config::default().ramp_multiplier, |val| val as i32),
-
-            even_deny_root: toml_config
-                .get("even_deny_root")
-                .and_then(toml::Value::as_bool)
-                .unwrap_or_else(|| Config::default().even_deny_root),
-
-            countdown: toml_config
-                .get("countdown")
-                .and_then(toml::Value::as_bool)
-                .unwrap_or_else(|| Config::default().countdown),
-        };
-        // when there is no pam_h, there don't need to be logs
-        if let Some(pam_h) = pam_h {
-            let _ = pam_h.log(
-                pam::LogLevel::Info,
-                format!("Successfully loaded config: {config:?}"),
-            );
+    /// The name of the matching group, or `None` if no group is exempt.
+    #[must_use]
+    pub fn exempt_group<'g>(&self, groups: &'g [String]) -> Option<&'g str> {
+        groups
+            .iter()
+            .find(|group| self.exempt_groups.contains(group))
+            .map(String::as_str)
+    }
+}
+
+/// Merges the legacy `[Settings]` section into the `[Configuration]` section.
+///
+/// Releases before the section was renamed read `[Settings]`. Its values are still used, but
+/// `[Configuration]` takes precedence.
+///
+/// # Arguments
+///
+/// * `toml_table`: The parsed configuration file.
+///
+/// # Returns
+///
+/// The merged section, if any, and the deprecation and conflict issues.
+fn merge_legacy_section(toml_table: &toml::Table) -> (Option<toml::Value>, Vec<ConfigIssue>) {
+    let configuration = toml_table.get("Configuration");
+    let Some(settings) = toml_table.get("Settings") else {
+        return (configuration.cloned(), Vec::new());
+    };
+
+    let mut issues = vec![ConfigIssue::new(
+        "[Settings]",
+        "deprecated section name, rename it to [Configuration]",
+    )];
+
+    match (
+        configuration.and_then(toml::Value::as_table),
+        settings.as_table(),
+    ) {
+        (Some(configuration), Some(settings)) => {
+            let mut merged = settings.clone();
+            for (key, value) in configuration {
+                if let Some(legacy) = settings.get(key).filter(|legacy| *legacy != value) {
+                    issues.push(ConfigIssue::conflict(
+                        format!("[Settings] {key}"),
+                        format!(
+                            "{legacy} conflicts with {value} in [Configuration], which is used"
+                        ),
+                    ));
+                }
+                merged.insert(key.clone(), value.clone());
+            }
+            (Some(merged.into()), issues)
         }
-        config
+        (None, Some(_)) => (Some(settings.clone()), issues),
+        _ => (configuration.cloned(), issues),
     }
 }
 
+/// Checks whether an `ignore_users` entry is a wildcard, like `*` or `@*`.
+fn is_wildcard_entry(entry: &str) -> bool {
+    entry.strip_prefix('@').unwrap_or(entry).trim() == "*"
+}
+
+/// PAM services switching users, which never count as local console logins.
+const SWITCH_USER_SERVICES: [&str; 6] = ["su", "su-l", "sudo", "sudo-i", "runuser", "runuser-l"];
+
+/// Checks whether a terminal name is a virtual console of the physical console, `tty` followed by
+/// digits, with or without the `/dev/` prefix.
+fn is_console_tty(tty: &str) -> bool {
+    tty.strip_prefix("/dev/")
+        .unwrap_or(tty)
+        .strip_prefix("tty")
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Matches a name case-insensitively against a list of entries. An entry ending in `*` matches
+/// every name starting with the text before it.
+fn matches_any(entries: &[String], name: &str) -> bool {
+    let name = name.to_lowercase();
+    entries.iter().any(|entry| {
+        let entry = entry.to_lowercase();
+        match entry.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == entry,
+        }
+    })
+}
+
 // Unit Tests
 #[cfg(test)]
 mod tests {
+    use pam::PamHandle;
     use tempdir::TempDir;
 
     use super::*;
 
+    #[test]
+    fn test_format_time() {
+        let instant = "2024-01-01T13:30:05Z".parse().unwrap();
+        let cet = chrono::FixedOffset::east_opt(3600).unwrap();
+
+        assert_eq!(
+            format_time(instant, &cet, DEFAULT_MESSAGE_TIME_FORMAT),
+            "2024-01-01 02:30:05 PM"
+        );
+        assert_eq!(
+            format_time(instant, &cet, "%d.%m.%Y %H:%M %:z"),
+            "01.01.2024 14:30 +01:00"
+        );
+
+        // invalid formats fall back to the default
+        assert_eq!(
+            format_time(instant, &Utc, "%Y %Q"),
+            "2024-01-01 01:30:05 PM"
+        );
+    }
+
+    #[test]
+    fn test_message_time_local() {
+        // the only test depending on the local timezone
+        std::env::set_var("TZ", "CET-1");
+        let config = Config {
+            message_time_format: "%Y-%m-%d %H:%M:%S %:z".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.message_time("2024-01-01T13:30:05Z".parse().unwrap()),
+            "2024-01-01 14:30:05 +01:00"
+        );
+    }
+
+    #[test]
+    fn test_message_nul() {
+        // the conversation can't send a message with a NUL byte
+        for key in ["lockout_message", "countdown_message"] {
+            let issues = Config::check(&format!("[Configuration]\n{key} = \"locked\\u0000\""));
+            assert_eq!(issues.len(), 1, "{key}");
+            assert_eq!(issues[0].severity, Severity::Fatal);
+            assert!(
+                issues[0]
+                    .message
+                    .ends_with("expected a message without a NUL byte"),
+                "{}",
+                issues[0].message
+            );
+        }
+
+        let temp_dir = TempDir::new("test_message_nul").unwrap();
+        let conf_file = temp_dir.path().join("authramp.conf");
+        std::fs::write(
+            &conf_file,
+            "[Configuration]\nlockout_message = \"locked\\u0000\"",
+        )
+        .unwrap();
+        let config = Config::load_file::<PamHandle>(Some(conf_file.to_str().unwrap()), None);
+        assert_eq!(config.lockout_message, None);
+    }
+
+    #[test]
+    fn test_log_threshold() {
+        // debug messages are suppressed at the info threshold
+        assert!(!LogThreshold::Info.allows(pam::LogLevel::Debug));
+        assert!(LogThreshold::Info.allows(pam::LogLevel::Notice));
+        assert!(LogThreshold::Info.allows(pam::LogLevel::Info));
+        assert!(LogThreshold::Debug.allows(pam::LogLevel::Debug));
+        assert!(!LogThreshold::Error.allows(pam::LogLevel::Warning));
+        assert!(LogThreshold::Warn.allows(pam::LogLevel::Error));
+    }
+
     #[test]
     fn test_default_config() {
         let default_config = Config::default();
         assert_eq!(default_config.tally_dir, PathBuf::from("/var/run/authramp"));
+        assert!(!default_config.persist_across_reboot);
+        assert!(default_config.durable_writes);
+        assert!(!default_config.status_world_readable);
+        assert_eq!(
+            default_config.stats_file,
+            PathBuf::from("/var/lib/authramp/stats.toml")
+        );
         assert_eq!(default_config.free_tries, 6);
         assert_eq!(default_config.base_delay_seconds, 30);
-        assert_eq!(default_config.ramp_multiplier, 50);
+        assert!((default_config.ramp_multiplier - 50.0).abs() < f64::EPSILON);
+        assert_eq!(default_config.delay_algorithm, DelayAlgorithm::Ramp);
+        assert!((default_config.delay_factor - 2.0).abs() < f64::EPSILON);
         assert!(!default_config.countdown);
+        assert_eq!(
+            default_config.message_time_format,
+            DEFAULT_MESSAGE_TIME_FORMAT
+        );
+        assert_eq!(default_config.locale, "auto");
+        assert_eq!(default_config.lockout_message, None);
+        assert_eq!(default_config.countdown_message, None);
+        assert_eq!(default_config.countdown_style, CountdownStyle::Repeat);
+        assert_eq!(default_config.countdown_interval_seconds, 2);
+        assert_eq!(default_config.delay_mode, DelayMode::Sleep);
+        assert_eq!(default_config.soft_delay_seconds, 0);
+        assert_eq!(default_config.max_conversation_failures, 3);
+        assert_eq!(default_config.conversation_block_cap(), None);
+        assert_eq!(default_config.log_facility, LogFacility::AuthPriv);
+        assert_eq!(default_config.hook_command, None);
+        assert!(default_config.notify_email.is_empty());
+        assert_eq!(default_config.notify_email_template, None);
+        assert_eq!(
+            default_config.notify_sendmail,
+            PathBuf::from("/usr/sbin/sendmail")
+        );
+        assert_eq!(default_config.tally_hmac_key_file, None);
+        assert!(default_config.tally_hmac_fail_closed);
         assert!(!default_config.even_deny_root);
+        assert!(!default_config.even_deny_root_console);
+        assert_eq!(default_config.root_free_tries, None);
+        assert_eq!(default_config.root_max_lockout_seconds, None);
+        assert!(default_config.account_neutral);
+        assert!(!default_config.nodelay);
+        assert!(default_config.cli_permissions.is_empty());
+        assert!(default_config.exempt_services.is_empty());
+        assert!(default_config.exempt_groups.is_empty());
+        assert!(default_config.ignore_users.is_empty());
+        assert!(default_config.deny_local);
+        assert!(default_config.count_local_failures);
+        assert!(default_config.forgive_same_transaction_failures);
+        assert!(!default_config.count_while_locked);
+        assert!(default_config.recompute_on_config_change);
+        assert_eq!(default_config.user_lookup, UserLookup::Nss);
+        assert_eq!(default_config.normalize_user, NormalizeUser::default());
+        assert_eq!(default_config.log_backend, LogBackend::Syslog);
+        assert_eq!(default_config.log_repeat_interval_seconds, 60);
+        assert_eq!(default_config.log_level, LogThreshold::Info);
+        assert!(!default_config.audit_lockouts);
+        assert!(!default_config.dbus);
+        assert_eq!(default_config.tally_key, TallyKey::Name);
+        assert_eq!(default_config.tally_layout, TallyLayout::Flat);
+        assert!(default_config.user_overrides.is_empty());
+        assert_eq!(default_config.max_lockout_seconds, 86400);
+        assert_eq!(default_config.reset_after_seconds, 0);
+        assert_eq!(default_config.lockout_cap(), Some(Duration::hours(24)));
+        assert_eq!(default_config.policy_disclosure, PolicyDisclosure::Full);
+        assert_eq!(default_config.fail_mode, FailMode::Open);
+        assert_eq!(default_config.unknown_user, UnknownUser::Ignore);
+        assert!(!default_config.track_unknown_users);
+        assert_eq!(
+            default_config.unknown_user_salt_file,
+            PathBuf::from("/var/lib/authramp/unknown_user.salt")
+        );
+    }
+
+    #[test]
+    fn test_lockout_cap_limits_high_failure_count() {
+        use crate::settings::Settings;
+        use crate::tally::Tally;
+
+        let settings = |max_lockout_seconds: i64| Settings {
+            config: Config {
+                max_lockout_seconds,
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+        let tally = Tally {
+            failures_count: 99,
+            ..Tally::default()
+        };
+
+        // a 10-minute cap limits the delay of a high failure count
+        assert!(tally.get_delay(&settings(600)) > Duration::minutes(10));
+        assert_eq!(
+            tally.get_capped_delay(&settings(600)),
+            Duration::minutes(10)
+        );
+
+        // 0 means no cap
+        assert_eq!(settings(0).config.lockout_cap(), None);
+        assert_eq!(
+            tally.get_capped_delay(&settings(0)),
+            tally.get_delay(&settings(0))
+        );
+    }
+
+    #[test]
+    fn test_exempt_group() {
+        let config = Config {
+            exempt_groups: vec!["wheel".to_string(), "admins".to_string()],
+            ..Config::default()
+        };
+
+        let groups = |groups: &[&str]| groups.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        // primary group only
+        assert_eq!(config.exempt_group(&groups(&["wheel"])), Some("wheel"));
+        // match among many supplementary groups
+        let mut many: Vec<String> = (0..2000).map(|i| format!("group{i}")).collect();
+        many.push("admins".to_string());
+        assert_eq!(config.exempt_group(&many), Some("admins"));
+        // group names are case-sensitive
+        assert_eq!(config.exempt_group(&groups(&["users", "Wheel"])), None);
+        assert_eq!(config.exempt_group(&[]), None);
+        assert_eq!(Config::default().exempt_group(&groups(&["wheel"])), None);
+    }
+
+    #[test]
+    fn test_is_exempt_service_case_insensitive() {
+        let config = Config {
+            exempt_services: vec!["Dovecot".to_string(), "cron".to_string()],
+            ..Config::default()
+        };
+        assert!(config.is_exempt_service("dovecot"));
+        assert!(config.is_exempt_service("DOVECOT"));
+        assert!(config.is_exempt_service("Cron"));
+        assert!(!config.is_exempt_service("sshd"));
+        assert!(!config.is_exempt_service("dovecot-lda"));
+    }
+
+    #[test]
+    fn test_is_exempt_service_wildcard() {
+        let config = Config {
+            exempt_services: vec!["dovecot*".to_string(), "*".to_string()],
+            ..Config::default()
+        };
+        assert!(config.is_exempt_service("dovecot-lda"));
+        assert!(config.is_exempt_service("sshd"));
+
+        let config = Config {
+            exempt_services: vec!["Dove*".to_string()],
+            ..Config::default()
+        };
+        assert!(config.is_exempt_service("dovecot"));
+        assert!(config.is_exempt_service("DOVECOT-sasl"));
+        assert!(!config.is_exempt_service("sshd"));
+        assert!(!config.is_exempt_service("dov"));
+    }
+
+    #[test]
+    fn test_log_facility() {
+        assert_eq!(LogFacility::from_name("daemon"), Some(LogFacility::Daemon));
+        assert_eq!(
+            LogFacility::from_name("AUTHPRIV"),
+            Some(LogFacility::AuthPriv)
+        );
+        assert_eq!(LogFacility::from_name("local9"), None);
+        assert_eq!(LogFacility::User.code(), libc::LOG_USER);
+
+        // facility names are case-insensitive in the file too, unknown ones are an error
+        let (config, _) = Config::parse("[Configuration]\nlog_facility = \"Daemon\"").unwrap();
+        assert_eq!(config.log_facility, LogFacility::Daemon);
+        assert_eq!(
+            Config::check("[Configuration]\nlog_facility = \"kernel\""),
+            vec![ConfigIssue {
+                location: "line 2, column 16".to_string(),
+                message: "invalid value: string \"kernel\", expected one of \"auth\", \"authpriv\", \"daemon\", \"user\"".to_string(),
+                severity: Severity::Fatal,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_denies_root() {
+        assert!(is_console_tty("tty1"));
+        assert!(is_console_tty("/dev/tty63"));
+        assert!(!is_console_tty("tty"));
+        assert!(!is_console_tty("ttyS0"));
+        assert!(!is_console_tty("pts/0"));
+        assert!(!is_console_tty("ssh"));
+
+        let mut config = Config::default();
+        assert!(!config.denies_root(None));
+        assert!(!config.denies_root(Some("tty1")));
+
+        config.even_deny_root = true;
+        assert!(config.denies_root(None));
+        assert!(config.denies_root(Some("pts/0")));
+        assert!(!config.denies_root(Some("tty1")));
+
+        config.even_deny_root_console = true;
+        assert!(config.denies_root(Some("tty1")));
+    }
+
+    #[test]
+    fn test_ignored_user() {
+        let config = Config {
+            ignore_users: vec!["zabbix".to_string(), "@backup".to_string()],
+            ..Config::default()
+        };
+        let groups = ["users".to_string(), "backup".to_string()];
+
+        assert_eq!(config.ignored_user("zabbix", &[]), Some("zabbix"));
+        assert_eq!(config.ignored_user("bacula", &groups), Some("@backup"));
+        assert_eq!(config.ignored_user("Zabbix", &[]), None);
+        assert_eq!(config.ignored_user("backup", &[]), None);
+        assert_eq!(config.ignored_user("user", &groups[..1]), None);
+    }
+
+    #[test]
+    fn test_normalize_user() {
+        let none = NormalizeUser::default();
+        assert_eq!(none.apply("Alice@corp.example"), "Alice@corp.example");
+
+        let lowercase = NormalizeUser {
+            lowercase: true,
+            ..NormalizeUser::default()
+        };
+        assert_eq!(lowercase.apply("Alice"), "alice");
+        assert_eq!(lowercase.apply("ÄLICE"), "älice");
+        assert_eq!(lowercase.apply("Alice@CORP.EXAMPLE"), "alice@corp.example");
+
+        let strip_realm = NormalizeUser {
+            strip_realm: true,
+            ..NormalizeUser::default()
+        };
+        assert_eq!(strip_realm.apply("Alice@EXAMPLE.COM"), "Alice");
+        assert_eq!(strip_realm.apply("alice"), "alice");
+        assert_eq!(strip_realm.apply("a@b@corp.example"), "a@b");
+        assert_eq!(strip_realm.apply("@corp.example"), "@corp.example");
+
+        let both = NormalizeUser {
+            lowercase: true,
+            strip_realm: true,
+        };
+        assert_eq!(both.apply("Alice@corp.example"), "alice");
+        assert_eq!(both.apply("ALICE"), "alice");
+
+        // a mode or an array of modes
+        let value = |value: &str| {
+            toml::from_str::<toml::Table>(&format!("v = {value}")).unwrap()["v"].clone()
+        };
+        assert_eq!(NormalizeUser::from_value(&value("\"none\"")), Some(none));
+        assert_eq!(
+            NormalizeUser::from_value(&value("\"lowercase\"")),
+            Some(lowercase)
+        );
+        assert_eq!(
+            NormalizeUser::from_value(&value("[\"strip_realm\"]")),
+            Some(strip_realm)
+        );
+        assert_eq!(
+            NormalizeUser::from_value(&value("[\"lowercase\", \"strip_realm\"]")),
+            Some(both)
+        );
+        assert_eq!(NormalizeUser::from_value(&value("[]")), Some(none));
+        assert_eq!(NormalizeUser::from_value(&value("\"uppercase\"")), None);
+        assert_eq!(NormalizeUser::from_value(&value("true")), None);
+
+        for normalize in [none, lowercase, strip_realm, both] {
+            assert_eq!(
+                NormalizeUser::from_value(&normalize.to_value()),
+                Some(normalize)
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_ignore_users_wildcard() {
+        let issues = Config::check("[Configuration]\nignore_users = [\"zabbix\", \"*\", \"@*\"]");
+        assert_eq!(
+            issues,
+            vec![
+                ConfigIssue::conflict(
+                    "[Configuration] ignore_users",
+                    "\"*\" would exempt every user from the module and is ignored"
+                ),
+                ConfigIssue::conflict(
+                    "[Configuration] ignore_users",
+                    "\"@*\" would exempt every user from the module and is ignored"
+                ),
+            ]
+        );
+        assert!(Config::check("[Configuration]\nignore_users = [\"zabbix\"]").is_empty());
+    }
+
+    #[test]
+    fn test_is_exempt_local() {
+        let mut config = Config::default();
+        assert!(!config.is_exempt_local(Some("login"), Some("tty1"), None));
+
+        config.deny_local = false;
+        assert!(config.is_exempt_local(Some("login"), Some("tty1"), None));
+        assert!(config.is_exempt_local(Some("login"), Some("/dev/tty2"), Some("")));
+        assert!(config.is_exempt_local(None, Some("tty1"), None));
+
+        // remote hosts, other terminals and user switching services aren't local
+        assert!(!config.is_exempt_local(Some("login"), Some("tty1"), Some("10.0.0.1")));
+        assert!(!config.is_exempt_local(Some("sshd"), Some("ssh"), None));
+        assert!(!config.is_exempt_local(Some("su"), Some("pts/0"), None));
+        assert!(!config.is_exempt_local(Some("su"), Some("tty1"), None));
+        assert!(!config.is_exempt_local(Some("SUDO"), Some("tty1"), None));
+        assert!(!config.is_exempt_local(Some("login"), None, None));
+    }
+
+    #[test]
+    fn test_apply_root_policy() {
+        let mut config = Config {
+            free_tries: 3,
+            ..Config::default()
+        };
+        config.apply_root_policy();
+        assert_eq!(config.free_tries, 3);
+        assert_eq!(config.max_lockout_seconds, 86400);
+
+        config.root_free_tries = Some(10);
+        config.root_max_lockout_seconds = Some(0);
+        config.apply_root_policy();
+        assert_eq!(config.free_tries, 10);
+        assert_eq!(config.lockout_cap(), None);
+    }
+
+    #[test]
+    fn test_is_noninteractive() {
+        let config = Config::default();
+        assert!(config.is_noninteractive(Some("sshd"), None));
+        assert!(config.is_noninteractive(Some("SUDO"), Some("/dev/pts/0")));
+        assert!(config.is_noninteractive(Some("test-authramp"), Some("sshd")));
+        assert!(!config.is_noninteractive(Some("login"), Some("tty1")));
+        assert!(!config.is_noninteractive(None, None));
+
+        let config = Config {
+            noninteractive_services: Vec::new(),
+            ..Config::default()
+        };
+        assert!(!config.is_noninteractive(Some("sshd"), None));
     }
 
     #[test]
+    #[allow(clippy::too_many_lines)] // one assert per configuration key
     fn test_build_config() {
         let temp_dir = TempDir::new("test_build_settings_from_toml").unwrap();
         let conf_file_path = temp_dir.path().join("config.conf");
@@ -186,23 +1959,568 @@ mod tests {
         let toml_content = r#"
         [Configuration]
         tally_dir = "/tmp/tally_dir"
+        persist_across_reboot = true
+        durable_writes = false
+        status_world_readable = true
+        stats_file = "/tmp/stats.toml"
         free_tries = 10
         base_delay_seconds = 15
-        ramp_multiplier = 20.0
+        ramp_multiplier = 1.5
+        delay_algorithm = "exponential"
+        delay_factor = 3
+        max_lockout_seconds = 600
+        reset_after_seconds = 3600
         even_deny_root = true
+        even_deny_root_console = true
+        root_free_tries = 20
+        root_max_lockout_seconds = -5
         countdown = true
+        countdown_style = "single"
+        countdown_interval_seconds = 0
+        message_time_format = "%H:%M %Z"
+        locale = "de_DE.UTF-8"
+        lockout_message = "Konto gesperrt bis {unlock_time}."
+        countdown_message = ""
+        delay_mode = "pam_fail_delay"
+        soft_delay_seconds = 2
+        max_conversation_failures = 0
+        max_conversation_block_seconds = 300
+        log_facility = "Auth"
+        log_backend = "journald"
+        log_repeat_interval_seconds = 300
+        log_level = "debug"
+        audit_lockouts = true
+        dbus = true
+        hook_command = "/usr/local/bin/authramp-alert"
+        notify_email = ["root", "{user}"]
+        notify_email_template = "Subject: {user} locked"
+        notify_sendmail = "/usr/lib/sendmail"
+        tally_hmac_key_file = "/etc/security/authramp.key"
+        tally_hmac_fail_closed = false
+        account_neutral = false
+        nodelay = true
+        exempt_services = ["dovecot", "cron"]
+        noninteractive_services = ["ssh*"]
+        exempt_groups = ["wheel"]
+        ignore_users = ["zabbix", "@backup", "*"]
+        deny_local = false
+        count_local_failures = false
+        forgive_same_transaction_failures = false
+        count_while_locked = true
+        recompute_on_config_change = false
+        user_lookup = "none"
+        normalize_user = ["lowercase", "strip_realm"]
+        tally_key = "uid"
+        tally_layout = "sharded"
+        policy_disclosure = "minimal"
+        fail_mode = "closed"
+        unknown_user = "deny"
+        track_unknown_users = true
+        unknown_user_salt_file = "/tmp/unknown_user.salt"
+
+        [Cli.permissions]
+        reset = ["helpdesk", "security"]
     "#;
         std::fs::write(&conf_file_path, toml_content).unwrap();
 
         // Build settings from TOML
-        let config = Config::load_file(Some(conf_file_path.to_str().unwrap()), None);
+        let config = Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None);
 
         // Validate the result
         assert_eq!(config.tally_dir, PathBuf::from(&"/tmp/tally_dir"));
+        assert!(config.persist_across_reboot);
+        assert!(!config.durable_writes);
+        assert!(config.status_world_readable);
+        assert_eq!(config.stats_file, PathBuf::from(&"/tmp/stats.toml"));
         assert_eq!(config.free_tries, 10);
         assert_eq!(config.base_delay_seconds, 15);
-        assert_eq!(config.ramp_multiplier, 20);
+        assert!((config.ramp_multiplier - 1.5).abs() < f64::EPSILON);
+        assert_eq!(config.delay_algorithm, DelayAlgorithm::Exponential);
+        assert!((config.delay_factor - 3.0).abs() < f64::EPSILON);
+        assert_eq!(config.lockout_cap(), Some(Duration::minutes(10)));
+        assert_eq!(config.reset_after_seconds, 3600);
         assert!(config.even_deny_root);
+        assert!(config.even_deny_root_console);
+        assert_eq!(config.root_free_tries, Some(20));
+        assert_eq!(config.root_max_lockout_seconds, Some(0));
+        assert!(config.countdown);
+        assert_eq!(config.message_time_format, "%H:%M %Z");
+        assert_eq!(config.locale(), Locale::German);
+        assert_eq!(
+            config.lockout_message.as_deref(),
+            Some("Konto gesperrt bis {unlock_time}.")
+        );
+        assert_eq!(config.countdown_message.as_deref(), Some(""));
+        assert_eq!(config.countdown_style, CountdownStyle::Single);
+        assert_eq!(config.countdown_interval_seconds, 1);
+        assert_eq!(config.delay_mode, DelayMode::PamFailDelay);
+        assert_eq!(config.soft_delay_seconds, 2);
+        assert_eq!(config.max_conversation_failures, 1);
+        assert_eq!(config.conversation_block_cap(), Some(Duration::minutes(5)));
+        assert_eq!(config.log_facility, LogFacility::Auth);
+        assert_eq!(config.log_backend, LogBackend::Journald);
+        assert_eq!(config.log_repeat_interval_seconds, 300);
+        assert_eq!(config.log_level, LogThreshold::Debug);
+        assert!(config.audit_lockouts);
+        assert!(config.dbus);
+        assert_eq!(
+            config.hook_command,
+            Some(PathBuf::from("/usr/local/bin/authramp-alert"))
+        );
+        assert_eq!(config.notify_email, vec!["root", "{user}"]);
+        assert_eq!(
+            config.notify_email_template.as_deref(),
+            Some("Subject: {user} locked")
+        );
+        assert_eq!(config.notify_sendmail, PathBuf::from("/usr/lib/sendmail"));
+        assert_eq!(
+            config.tally_hmac_key_file,
+            Some(PathBuf::from("/etc/security/authramp.key"))
+        );
+        assert!(!config.tally_hmac_fail_closed);
+        assert!(!config.account_neutral);
+        assert!(config.nodelay);
+        assert_eq!(config.exempt_services, vec!["dovecot", "cron"]);
+        assert_eq!(config.noninteractive_services, vec!["ssh*"]);
+        assert_eq!(config.exempt_groups, vec!["wheel"]);
+        assert_eq!(config.ignore_users, vec!["zabbix", "@backup"]);
+        assert!(!config.deny_local);
+        assert!(!config.count_local_failures);
+        assert!(!config.forgive_same_transaction_failures);
+        assert!(config.count_while_locked);
+        assert!(!config.recompute_on_config_change);
+        assert_eq!(config.user_lookup, UserLookup::None);
+        assert_eq!(
+            config.normalize_user,
+            NormalizeUser {
+                lowercase: true,
+                strip_realm: true
+            }
+        );
+        assert_eq!(config.tally_key, TallyKey::Uid);
+        assert_eq!(config.tally_layout, TallyLayout::Sharded);
+        assert_eq!(config.policy_disclosure, PolicyDisclosure::Minimal);
+        assert_eq!(config.fail_mode, FailMode::Closed);
+        assert_eq!(config.unknown_user, UnknownUser::Deny);
+        assert!(config.track_unknown_users);
+        assert_eq!(
+            config.unknown_user_salt_file,
+            PathBuf::from("/tmp/unknown_user.salt")
+        );
+        assert_eq!(
+            config.cli_permissions.get("reset"),
+            Some(&vec!["helpdesk".to_string(), "security".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_fail_mode_result() {
+        let decisions = || {
+            [
+                PamResultCode::PAM_SUCCESS,
+                PamResultCode::PAM_IGNORE,
+                PamResultCode::PAM_AUTH_ERR,
+                PamResultCode::PAM_PERM_DENIED,
+                PamResultCode::PAM_SESSION_ERR,
+            ]
+        };
+
+        // decisions of the module are kept in both modes
+        for fail_mode in [FailMode::Open, FailMode::Closed] {
+            for (result, expected) in decisions().into_iter().zip(decisions()) {
+                assert_eq!(fail_mode.result("auth", result), expected);
+            }
+        }
+
+        // open mode lets the stack continue
+        for pam_hook in ["auth", "account"] {
+            assert_eq!(
+                FailMode::Open.result(pam_hook, PamResultCode::PAM_SYSTEM_ERR),
+                PamResultCode::PAM_IGNORE
+            );
+            assert_eq!(
+                FailMode::Open.result(pam_hook, PamResultCode::PAM_USER_UNKNOWN),
+                PamResultCode::PAM_IGNORE
+            );
+        }
+
+        // closed mode denies
+        assert_eq!(
+            FailMode::Closed.result("auth", PamResultCode::PAM_SYSTEM_ERR),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        assert_eq!(
+            FailMode::Closed.result("auth", PamResultCode::PAM_USER_UNKNOWN),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        assert_eq!(
+            FailMode::Closed.result("account", PamResultCode::PAM_SYSTEM_ERR),
+            PamResultCode::PAM_PERM_DENIED
+        );
+        assert_eq!(
+            FailMode::Closed.result("password", PamResultCode::PAM_SYSTEM_ERR),
+            PamResultCode::PAM_PERM_DENIED
+        );
+        assert_eq!(
+            FailMode::Closed.result("session", PamResultCode::PAM_SYSTEM_ERR),
+            PamResultCode::PAM_SESSION_ERR
+        );
+    }
+
+    #[test]
+    fn test_user_override_precedence() {
+        let temp_dir = TempDir::new("test_user_override_precedence").unwrap();
+        let conf_file_path = temp_dir.path().join("config.conf");
+
+        let toml_content = r"
+        [Configuration]
+        free_tries = 10
+        base_delay_seconds = 15
+
+        [user.breakglass]
+        free_tries = 2
+        even_deny_root = true
+
+        [user.kiosk]
+        free_tries = 20
+        ramp_multiplier = 5
+    ";
+        std::fs::write(&conf_file_path, toml_content).unwrap();
+
+        let config = |user: &str| {
+            let mut config =
+                Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None);
+            config.apply_user_override(user);
+            config
+        };
+
+        // user > global > default
+        let breakglass = config("breakglass");
+        assert_eq!(breakglass.free_tries, 2);
+        assert_eq!(breakglass.base_delay_seconds, 15);
+        assert!((breakglass.ramp_multiplier - 50.0).abs() < f64::EPSILON);
+        assert!(breakglass.even_deny_root);
+
+        let kiosk = config("kiosk");
+        assert_eq!(kiosk.free_tries, 20);
+        assert!((kiosk.ramp_multiplier - 5.0).abs() < f64::EPSILON);
+        assert!(!kiosk.even_deny_root);
+
+        // global > default
+        let other = config("other");
+        assert_eq!(other.free_tries, 10);
+        assert_eq!(other.base_delay_seconds, 15);
+        assert!((other.ramp_multiplier - 50.0).abs() < f64::EPSILON);
+
+        // only the keys that are set override
+        assert_eq!(
+            breakglass.user_overrides["breakglass"],
+            UserOverride {
+                free_tries: Some(2),
+                even_deny_root: Some(true),
+                ..UserOverride::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_config() {
+        let issues = |content: &str| {
+            Config::check(content)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        // the example configuration is valid
+        assert_eq!(
+            issues(include_str!("../../../examples/system-auth/authramp.conf")),
+            Vec::<String>::new()
+        );
+        assert!(issues("[Configuration]\nfree_tries = 3\ntally_key = \"uid\"").is_empty());
+
+        // the legacy section name is a warning, the values are used
+        assert_eq!(
+            issues("[Settings]\nfree_tries = 3"),
+            vec!["[Settings]: deprecated section name, rename it to [Configuration]"]
+        );
+
+        // unknown sections and keys and type mismatches void the file, reported with where they are
+        let fatal = |content: &str| {
+            let issues = Config::check(content);
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].severity, Severity::Fatal);
+            format!("{}: {}", issues[0].location, issues[0].message)
+        };
+        assert!(fatal("[Configuration]\nfree_trys = 3")
+            .starts_with("line 2, column 1: unknown field `free_trys`"));
+        assert!(fatal("[Configuratoin]\nfree_tries = 3")
+            .starts_with("line 1, column 2: unknown field `Configuratoin`"));
+        assert!(fatal("[user.kiosk]\nfree_tries = 20\ndelay = 1")
+            .starts_with("line 3, column 1: unknown field `delay`"));
+        assert!(fatal("[Configuration]\nbase_delay_seconds = \"30\"")
+            .starts_with("line 2, column 22: invalid type: string \"30\", expected i32"));
+        assert!(fatal("[Configuration]\nexempt_groups = [\"wheel\", 10]")
+            .starts_with("line 2, column 27: invalid type: integer `10`, expected a string"));
+        assert!(fatal("[Configuration]\nuser_lookup = \"ldap\"")
+            .starts_with("line 2, column 15: unknown variant `ldap`, expected `nss` or `none`"));
+        assert_eq!(
+            fatal("[Configuration]\nnormalize_user = [\"lowercase\", \"realm\"]"),
+            "line 2, column 18: expected one or an array of \"none\", \"lowercase\", \"strip_realm\", found [\"lowercase\", \"realm\"]"
+        );
+        assert!(
+            fatal("[Cli.permissions]\nreset = \"helpdesk\"").starts_with(
+                "line 2, column 9: invalid type: string \"helpdesk\", expected a sequence"
+            )
+        );
+
+        // syntax errors are reported with their line and column
+        let syntax = Config::check("[Configuration]\nfree_tries = = 3");
+        assert_eq!(syntax.len(), 1);
+        assert_eq!(syntax[0].location, "line 2, column 14");
+        assert_eq!(syntax[0].severity, Severity::Fatal);
+    }
+
+    #[test]
+    fn test_try_load_file_strict() {
+        let temp_dir = TempDir::new("test_try_load_file_strict").unwrap();
+        let path = temp_dir.path().join("authramp.conf");
+        let path_str = path.to_str().unwrap();
+
+        let error = |content: &str| {
+            fs::write(&path, content).unwrap();
+            let error = Config::try_load_file::<PamHandle>(path_str, None).unwrap_err();
+            assert!(matches!(error, AuthRampError::Invalid { .. }));
+            assert_eq!(
+                Config::load_file::<PamHandle>(Some(path_str), None).base_delay_seconds,
+                Config::default().base_delay_seconds
+            );
+            error.to_string()
+        };
+
+        // a typo'd key is an error instead of a silently ignored value
+        assert!(
+            error("[Configuration]\nfree_trys = 3\nbase_delay_seconds = 60\n").starts_with(
+                &format!(
+                    "Error parsing the configuration file {path_str}: line 2, column 1: unknown field `free_trys`"
+                )
+            )
+        );
+
+        // so is a value of the wrong type
+        assert!(
+            error("[Configuration]\nfree_tries = \"3\"\nbase_delay_seconds = 60\n").starts_with(
+                &format!(
+                    "Error parsing the configuration file {path_str}: line 2, column 14: invalid type: string \"3\", expected i32"
+                )
+            )
+        );
+
+        // and a stray quote
+        fs::write(
+            &path,
+            "[Configuration]\nfree_tries = \"3\nbase_delay_seconds = 60\n",
+        )
+        .unwrap();
+        let error = Config::try_load_file::<PamHandle>(path_str, None).unwrap_err();
+        assert!(matches!(error, AuthRampError::Invalid { .. }));
+        assert!(error.to_string().starts_with(&format!(
+            "Error parsing the configuration file {path_str}: line 2, column 16: "
+        )));
+        assert_eq!(
+            Config::load_file::<PamHandle>(Some(path_str), None).base_delay_seconds,
+            Config::default().base_delay_seconds
+        );
+    }
+
+    #[test]
+    fn test_unknown_delay_algorithm() {
+        let temp_dir = TempDir::new("test_unknown_delay_algorithm").unwrap();
+        let conf_file_path = temp_dir.path().join("config.conf");
+        let content = "[Configuration]\ndelay_algorithm = \"linear\"";
+        std::fs::write(&conf_file_path, content).unwrap();
+
+        // the file is rejected, so the ramp of the defaults is used
+        let config = Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None);
+        assert_eq!(config.delay_algorithm, DelayAlgorithm::Ramp);
+        assert_eq!(
+            Config::check(content),
+            vec![ConfigIssue {
+                severity: Severity::Fatal,
+                ..ConfigIssue::new(
+                    "line 2, column 19",
+                    "unknown variant `linear`, expected one of `ramp`, `exponential`, `fixed`"
+                )
+            }]
+        );
+    }
+
+    #[test]
+    fn test_persist_across_reboot() {
+        let temp_dir = TempDir::new("test_persist_across_reboot").unwrap();
+        let conf_file_path = temp_dir.path().join("config.conf");
+
+        let tally_dir = |toml_content: &str| {
+            std::fs::write(&conf_file_path, toml_content).unwrap();
+            Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None).tally_dir
+        };
+
+        // the switch selects the default directory
+        assert_eq!(
+            tally_dir("[Configuration]\npersist_across_reboot = false"),
+            PathBuf::from(VOLATILE_TALLY_DIR)
+        );
+        assert_eq!(
+            tally_dir("[Configuration]\npersist_across_reboot = true"),
+            PathBuf::from(PERSISTENT_TALLY_DIR)
+        );
+
+        // an explicit tally_dir always wins
+        assert_eq!(
+            tally_dir("[Configuration]\npersist_across_reboot = true\ntally_dir = \"/tmp/tally\""),
+            PathBuf::from("/tmp/tally")
+        );
+    }
+
+    #[test]
+    fn test_hook_override_precedence() {
+        let temp_dir = TempDir::new("test_hook_override_precedence").unwrap();
+        let conf_file_path = temp_dir.path().join("config.conf");
+
+        let toml_content = r"
+        [Configuration]
+        free_tries = 10
+        countdown = true
+
+        [account]
+        free_tries = 3
+        countdown = false
+
+        [user.kiosk]
+        free_tries = 20
+    ";
+        std::fs::write(&conf_file_path, toml_content).unwrap();
+
+        let config = |hook: &str, user: &str| {
+            let mut config =
+                Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None);
+            config.apply_hook_override(hook);
+            config.apply_user_override(user);
+            config
+        };
+
+        // hook > global, hooks without a table use the global values
+        let account = config("account", "other");
+        assert_eq!(account.free_tries, 3);
+        assert!(!account.countdown);
+        let auth = config("auth", "other");
+        assert_eq!(auth.free_tries, 10);
+        assert!(auth.countdown);
+
+        // user > hook
+        let kiosk = config("account", "kiosk");
+        assert_eq!(kiosk.free_tries, 20);
+        assert!(!kiosk.countdown);
+
+        // the hook tables are checked like the user tables
+        let issues = Config::check("[account]\nfree_tries = 3\ndelay_mode = \"sleep\"");
+        assert_eq!(issues[0].severity, Severity::Fatal);
+        assert!(issues[0].message.starts_with("unknown field `delay_mode`"));
+    }
+
+    #[test]
+    fn test_to_toml_round_trip() {
+        let temp_dir = TempDir::new("test_to_toml_round_trip").unwrap();
+        let conf_file_path = temp_dir.path().join("config.conf");
+
+        std::fs::write(
+            &conf_file_path,
+            r#"
+            [Configuration]
+            free_tries = 3
+            ramp_multiplier = 1.5
+            hook_command = "/usr/local/bin/authramp-alert"
+            log_facility = "Auth"
+            tally_key = "uid"
+
+            [Cli.permissions]
+            reset = ["helpdesk"]
+
+            [user.kiosk]
+            free_tries = 20
+
+            [account]
+            countdown = false
+        "#,
+        )
+        .unwrap();
+        let config = Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None);
+        let effective = config.to_toml();
+
+        // defaults are filled in
+        let configuration = effective["Configuration"].as_table().unwrap();
+        assert_eq!(configuration["free_tries"].as_integer(), Some(3));
+        assert_eq!(configuration["base_delay_seconds"].as_integer(), Some(30));
+        assert_eq!(configuration["log_facility"].as_str(), Some("auth"));
+        assert!(!configuration.contains_key("tally_hmac_key_file"));
+
+        // the rendered configuration is valid and loads the same
+        let rendered = effective.to_string();
+        assert!(Config::check(&rendered).is_empty());
+        std::fs::write(&conf_file_path, &rendered).unwrap();
+        let reloaded = Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None);
+        assert_eq!(reloaded.to_toml(), effective);
+    }
+
+    #[test]
+    fn test_legacy_settings_section() {
+        let temp_dir = TempDir::new("test_legacy_settings_section").unwrap();
+        let conf_file_path = temp_dir.path().join("config.conf");
+
+        let load = |content: &str| {
+            std::fs::write(&conf_file_path, content).unwrap();
+            Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None)
+        };
+        let severities = |content: &str| {
+            Config::check(content)
+                .into_iter()
+                .map(|issue| (issue.location, issue.severity))
+                .collect::<Vec<_>>()
+        };
+
+        // new only
+        let content = "[Configuration]\nfree_tries = 3";
+        assert_eq!(load(content).free_tries, 3);
+        assert!(Config::check(content).is_empty());
+
+        // legacy only
+        let content = "[Settings]\nfree_tries = 3";
+        assert_eq!(load(content).free_tries, 3);
+        assert_eq!(
+            severities(content),
+            vec![("[Settings]".to_string(), Severity::Warning)]
+        );
+
+        // both, [Configuration] takes precedence over conflicting values
+        let content = "[Settings]\nfree_tries = 3\ncountdown = true\n\n[Configuration]\nfree_tries = 5\nnodelay = true";
+        let config = load(content);
+        assert_eq!(config.free_tries, 5);
         assert!(config.countdown);
+        assert!(config.nodelay);
+        assert_eq!(
+            severities(content),
+            vec![
+                ("[Settings]".to_string(), Severity::Warning),
+                ("[Settings] free_tries".to_string(), Severity::Error),
+            ]
+        );
+
+        // equal values don't conflict
+        let content = "[Settings]\nfree_tries = 5\n\n[Configuration]\nfree_tries = 5";
+        assert_eq!(load(content).free_tries, 5);
+        assert_eq!(
+            severities(content),
+            vec![("[Settings]".to_string(), Severity::Warning)]
+        );
     }
 }
@@ -0,0 +1,161 @@
+//! # D-Bus Module
+//!
+//! The `dbus` module emits a signal on the D-Bus system bus when an account gets locked or a
+//! locked account is cleared, so lock screens and desktop applets can tell the user about the
+//! lockout, if the crate is built with the `dbus` feature and `dbus` is enabled.
+//!
+//! ## Signals
+//!
+//! The signals are emitted from the object [`DBUS_PATH`] with the interface [`DBUS_INTERFACE`]:
+//!
+//! - `Locked(s user, i failures, x unlock_timestamp)`: The failures crossed `free_tries`.
+//! - `Unlocked(s user, i failures, x unlock_timestamp)`: A success cleared a locked account.
+//!
+//! The unlock timestamp is in seconds since the Unix epoch, 0 if not locked. The connection to
+//! the bus is only opened for a signal and given up after [`DBUS_TIMEOUT_MILLISECONDS`], so an
+//! unavailable bus never delays the authentication.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+
+use crate::hook::HookEvent;
+
+/// The object path the signals are emitted from.
+pub const DBUS_PATH: &str = "/org/authramp/Lockout";
+
+/// The interface of the signals.
+pub const DBUS_INTERFACE: &str = "org.authramp.Lockout";
+
+/// Milliseconds after which emitting a signal is given up.
+pub const DBUS_TIMEOUT_MILLISECONDS: u64 = 500;
+
+/// A lockout event signaled on the system bus.
+#[derive(Debug)]
+pub struct LockoutSignal<'a> {
+    pub event: HookEvent,
+    pub user: &'a str,
+    pub failures: i32,
+    pub unlock_instant: Option<DateTime<Utc>>,
+}
+
+impl LockoutSignal<'_> {
+    /// The name of the signal of the event.
+    #[must_use]
+    pub fn member(&self) -> &'static str {
+        match self.event {
+            HookEvent::Lock => "Locked",
+            HookEvent::Unlock => "Unlocked",
+        }
+    }
+
+    /// The unlock instant in seconds since the Unix epoch, 0 if not locked.
+    #[must_use]
+    pub fn unlock_timestamp(&self) -> i64 {
+        self.unlock_instant
+            .map_or(0, |unlock_instant| unlock_instant.timestamp())
+    }
+}
+
+/// Emits a lockout signal on the system bus.
+///
+/// The signal is emitted by a thread that is waited for at most
+/// [`DBUS_TIMEOUT_MILLISECONDS`], so a bus that doesn't respond can't hold up the caller.
+///
+/// # Arguments
+///
+/// - `signal`: The lockout event.
+///
+/// # Errors
+///
+/// Returns a message if the system bus can't be connected to, the signal can't be emitted or
+/// the bus doesn't respond in time.
+#[cfg(feature = "dbus")]
+pub fn emit(signal: &LockoutSignal) -> Result<(), String> {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    let member = signal.member();
+    let body = (
+        signal.user.to_string(),
+        signal.failures,
+        signal.unlock_timestamp(),
+    );
+
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("authramp-dbus".to_string())
+        .spawn(move || {
+            let emitted = zbus::blocking::Connection::system()
+                .and_then(|connection| {
+                    connection.emit_signal(None::<&str>, DBUS_PATH, DBUS_INTERFACE, member, &body)
+                })
+                .map_err(|e| e.to_string());
+            let _ = sender.send(emitted);
+        })
+        .map_err(|e| format!("Error starting the D-Bus thread: {e}"))?;
+
+    receiver
+        .recv_timeout(Duration::from_millis(DBUS_TIMEOUT_MILLISECONDS))
+        .map_err(|_| "The system bus didn't respond in time".to_string())?
+}
+
+/// Emits a lockout signal on the system bus.
+///
+/// # Errors
+///
+/// Always returns a message, the module was built without the `dbus` feature.
+#[cfg(not(feature = "dbus"))]
+pub fn emit(_signal: &LockoutSignal) -> Result<(), String> {
+    Err("pam_authramp was built without the dbus feature".to_string())
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal() {
+        let mut signal = LockoutSignal {
+            event: HookEvent::Lock,
+            user: "user",
+            failures: 7,
+            unlock_instant: DateTime::from_timestamp(1_700_000_000, 0),
+        };
+        assert_eq!(signal.member(), "Locked");
+        assert_eq!(signal.unlock_timestamp(), 1_700_000_000);
+
+        signal.event = HookEvent::Unlock;
+        signal.unlock_instant = None;
+        assert_eq!(signal.member(), "Unlocked");
+        assert_eq!(signal.unlock_timestamp(), 0);
+    }
+
+    #[test]
+    fn test_emit_never_panics() {
+        let signal = LockoutSignal {
+            event: HookEvent::Lock,
+            user: "user",
+            failures: 7,
+            unlock_instant: None,
+        };
+        // the sandbox may have no system bus, only the error is reported
+        let _ = emit(&signal);
+    }
+}
@@ -0,0 +1,281 @@
+//! # Error Module
+//!
+//! The `error` module defines `AuthRampError`, the error type of the `Tally`, `Settings` and
+//! `Config` internals. Unlike a bare `PamResultCode` it keeps what failed and why, e.g. the tally
+//! file that couldn't be written together with the `io::Error`.
+//!
+//! The PAM module converts the error into a `PamResultCode` at the hook boundary, where
+//! `AuthRampError::log` logs the full context once. The CLI shows the same message.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{error::Error, fmt, io};
+
+use pam::{PamApi, PamResultCode};
+
+/// An error of the `AuthRamp` internals with its context.
+#[derive(Debug)]
+pub enum AuthRampError {
+    /// A file or directory can't be read or written.
+    Io { message: String, source: io::Error },
+    /// A file has content that can't be used, e.g. a tally that can't be parsed or fails the
+    /// integrity check.
+    Invalid { message: String, reason: String },
+    /// The PAM user can't be resolved or used.
+    User { message: String, reason: String },
+    /// An error returned to PAM as `code`, e.g. to deny instead of failing.
+    Mapped {
+        code: PamResultCode,
+        source: Box<AuthRampError>,
+    },
+    /// A result code passed through without context, e.g. of a failed log call. Outcomes like
+    /// `PAM_IGNORE` for an ignored unknown user are logged where they are decided.
+    Pam(PamResultCode),
+}
+
+impl AuthRampError {
+    /// Creates an `Io` error.
+    pub fn io(message: impl Into<String>, source: io::Error) -> Self {
+        Self::Io {
+            message: message.into(),
+            source,
+        }
+    }
+
+    /// Creates an `Invalid` error.
+    pub fn invalid(message: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::Invalid {
+            message: message.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Creates a `User` error.
+    pub fn user(message: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::User {
+            message: message.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Returns the error to PAM as `code` instead of the code of its kind.
+    #[must_use]
+    pub fn with_code(self, code: PamResultCode) -> Self {
+        Self::Mapped {
+            code,
+            source: Box::new(self),
+        }
+    }
+
+    /// The result code the error is returned to PAM with.
+    ///
+    /// # Returns
+    ///
+    /// `PAM_SYSTEM_ERR` for IO and invalid files, `PAM_USER_UNKNOWN` for users, otherwise the
+    /// code the error carries.
+    #[must_use]
+    pub fn code(&self) -> PamResultCode {
+        match self {
+            Self::Io { .. } | Self::Invalid { .. } => PamResultCode::PAM_SYSTEM_ERR,
+            Self::User { .. } => PamResultCode::PAM_USER_UNKNOWN,
+            Self::Mapped { code, .. } | Self::Pam(code) => *code,
+        }
+    }
+
+    /// Whether the error comes from storage that can't be written at all, like a read-only
+    /// filesystem or a tally directory that can't be created during early boot.
+    #[must_use]
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Io { source, .. } => source.kind() == io::ErrorKind::ReadOnlyFilesystem,
+            Self::Mapped { source, .. } => source.is_unavailable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the error comes from missing permissions, e.g. the CLI run without root.
+    #[must_use]
+    pub fn is_permission_denied(&self) -> bool {
+        match self {
+            Self::Io { source, .. } => {
+                source.kind() == io::ErrorKind::PermissionDenied
+                    // an unavailable tally directory keeps the original error
+                    || source
+                        .get_ref()
+                        .and_then(|inner| inner.downcast_ref::<io::Error>())
+                        .is_some_and(|inner| inner.kind() == io::ErrorKind::PermissionDenied)
+            }
+            Self::Mapped { source, .. } => source.is_permission_denied(),
+            _ => false,
+        }
+    }
+
+    /// Whether the error has a context worth logging, which passed through codes don't.
+    fn has_context(&self) -> bool {
+        match self {
+            Self::Mapped { source, .. } => source.has_context(),
+            Self::Pam(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Logs the error with its full context and converts it at the hook boundary.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_h`: The PAM handle to log with.
+    ///
+    /// # Returns
+    ///
+    /// The result code of the error.
+    pub fn log<P: PamApi>(self, pam_h: &P) -> PamResultCode {
+        let code = self.code();
+        if self.has_context() {
+            let _ = pam_h.log(pam::LogLevel::Error, format!("{code}: {self}"));
+        }
+        code
+    }
+}
+
+impl fmt::Display for AuthRampError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { message, source } => write!(f, "{message}: {source}"),
+            Self::Invalid { message, reason } | Self::User { message, reason } => {
+                write!(f, "{message}: {reason}")
+            }
+            Self::Mapped { source, .. } => write!(f, "{source}"),
+            Self::Pam(code) => write!(f, "{code}"),
+        }
+    }
+}
+
+impl Error for AuthRampError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Mapped { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<PamResultCode> for AuthRampError {
+    fn from(code: PamResultCode) -> Self {
+        Self::Pam(code)
+    }
+}
+
+impl From<AuthRampError> for PamResultCode {
+    fn from(error: AuthRampError) -> Self {
+        error.code()
+    }
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code() {
+        let io = AuthRampError::io("Error writing tally file", io::Error::other("disk full"));
+        assert_eq!(io.code(), PamResultCode::PAM_SYSTEM_ERR);
+        assert_eq!(
+            AuthRampError::invalid("Error parsing tally file", "[Fails] table does not exist")
+                .code(),
+            PamResultCode::PAM_SYSTEM_ERR
+        );
+        assert_eq!(
+            AuthRampError::user("Rejected the user name", "contains a slash").code(),
+            PamResultCode::PAM_USER_UNKNOWN
+        );
+        assert_eq!(
+            PamResultCode::from(io.with_code(PamResultCode::PAM_PERM_DENIED)),
+            PamResultCode::PAM_PERM_DENIED
+        );
+        assert_eq!(
+            AuthRampError::from(PamResultCode::PAM_IGNORE).code(),
+            PamResultCode::PAM_IGNORE
+        );
+    }
+
+    #[test]
+    fn test_is_unavailable() {
+        let read_only = || {
+            AuthRampError::io(
+                "Error writing tally file",
+                io::Error::from(io::ErrorKind::ReadOnlyFilesystem),
+            )
+        };
+        assert!(read_only().is_unavailable());
+        assert!(read_only()
+            .with_code(PamResultCode::PAM_PERM_DENIED)
+            .is_unavailable());
+        assert!(
+            !AuthRampError::io("Error writing tally file", io::Error::other("disk full"))
+                .is_unavailable()
+        );
+        assert!(!AuthRampError::from(PamResultCode::PAM_IGNORE).is_unavailable());
+    }
+
+    #[test]
+    fn test_is_permission_denied() {
+        let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(AuthRampError::io("Error deleting tally file", denied()).is_permission_denied());
+        assert!(AuthRampError::io(
+            "Error creating tally directory",
+            io::Error::new(io::ErrorKind::ReadOnlyFilesystem, denied()),
+        )
+        .with_code(PamResultCode::PAM_PERM_DENIED)
+        .is_permission_denied());
+        assert!(!AuthRampError::io(
+            "Error creating tally directory",
+            io::Error::from(io::ErrorKind::ReadOnlyFilesystem),
+        )
+        .is_permission_denied());
+        assert!(!AuthRampError::invalid("Error parsing tally", "bad").is_permission_denied());
+    }
+
+    #[test]
+    fn test_context() {
+        let error = AuthRampError::io(
+            "Error writing tally file /tmp/a",
+            io::Error::other("disk full"),
+        )
+        .with_code(PamResultCode::PAM_PERM_DENIED);
+        assert_eq!(
+            error.to_string(),
+            "Error writing tally file /tmp/a: disk full"
+        );
+        assert!(error.has_context());
+        assert_eq!(
+            error
+                .source()
+                .and_then(Error::source)
+                .map(ToString::to_string),
+            Some("disk full".to_string())
+        );
+
+        let passed = AuthRampError::from(PamResultCode::PAM_IGNORE);
+        assert_eq!(passed.to_string(), "PAM_IGNORE");
+        assert!(!passed.has_context());
+        assert!(!passed.with_code(PamResultCode::PAM_AUTH_ERR).has_context());
+    }
+}
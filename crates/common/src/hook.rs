@@ -0,0 +1,213 @@
+//! # Hook Module
+//!
+//! The `hook` module runs the `hook_command` of the configuration when an account gets locked or
+//! a locked account is cleared, e.g. to send alerts.
+//!
+//! ## Environment
+//!
+//! The command runs with an empty environment, except for `PATH` and these variables:
+//!
+//! - `AUTHRAMP_EVENT`: `lock` or `unlock`
+//! - `AUTHRAMP_USER`: The name of the account
+//! - `AUTHRAMP_FAILURES`: The number of recorded failures
+//! - `AUTHRAMP_SERVICE`: The PAM service, empty if unknown
+//! - `AUTHRAMP_RHOST`: The remote host, empty if unknown
+//! - `AUTHRAMP_UNLOCK_INSTANT`: The RFC 3339 unlock instant, empty if not locked
+//!
+//! The command is started in the background and killed after [`HOOK_TIMEOUT_SECONDS`], so it
+//! never delays the authentication.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+    io,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use chrono::{DateTime, Utc};
+
+/// Seconds after which a running hook command is terminated.
+pub const HOOK_TIMEOUT_SECONDS: u64 = 10;
+
+/// `PATH` of the hook command.
+pub(crate) const HOOK_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The lockout events a hook command is run for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookEvent {
+    /// The failures crossed `free_tries` and locked the account.
+    Lock,
+    /// A success cleared a locked account.
+    Unlock,
+}
+
+impl HookEvent {
+    /// The name of the event passed as `AUTHRAMP_EVENT`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::Lock => "lock",
+            HookEvent::Unlock => "unlock",
+        }
+    }
+}
+
+/// The details of an event passed to the hook command.
+#[derive(Debug)]
+pub struct HookContext<'a> {
+    pub event: HookEvent,
+    pub user: &'a str,
+    pub failures: i32,
+    pub service: Option<&'a str>,
+    pub rhost: Option<&'a str>,
+    pub unlock_instant: Option<DateTime<Utc>>,
+}
+
+impl HookContext<'_> {
+    /// Builds the environment variables of the hook command.
+    ///
+    /// # Returns
+    /// The variable names and values
+    #[must_use]
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("AUTHRAMP_EVENT", self.event.name().to_string()),
+            ("AUTHRAMP_USER", self.user.to_string()),
+            ("AUTHRAMP_FAILURES", self.failures.to_string()),
+            (
+                "AUTHRAMP_SERVICE",
+                self.service.unwrap_or_default().to_string(),
+            ),
+            ("AUTHRAMP_RHOST", self.rhost.unwrap_or_default().to_string()),
+            (
+                "AUTHRAMP_UNLOCK_INSTANT",
+                self.unlock_instant
+                    .map(|unlock_instant| unlock_instant.to_rfc3339())
+                    .unwrap_or_default(),
+            ),
+        ]
+    }
+}
+
+/// Starts the hook command in the background without waiting for it.
+///
+/// A shell detaches the command under `timeout`, so it is terminated after
+/// [`HOOK_TIMEOUT_SECONDS`] and never outlives the hard timeout. The event details are only
+/// passed through the environment, never through the shell command line.
+///
+/// # Arguments
+/// - `command`: Path of the executable to run
+/// - `context`: The event details
+///
+/// # Errors
+/// Returns an error if the shell can't be started or fails to start the command.
+pub fn spawn(command: &Path, context: &HookContext) -> io::Result<()> {
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("timeout -k 1 {HOOK_TIMEOUT_SECONDS} \"$0\" &"))
+        .arg(command)
+        .env_clear()
+        .env("PATH", HOOK_PATH)
+        .envs(context.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("hook shell exited with {status}")))
+    }
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, thread::sleep, time::Duration};
+    use tempdir::TempDir;
+
+    /// Writes an executable script that appends its environment to `out`.
+    fn env_script(dir: &Path, out: &Path) -> std::path::PathBuf {
+        let script = dir.join("hook.sh");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nenv >> '{}'\necho --- >> '{}'\n",
+                out.display(),
+                out.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script
+    }
+
+    /// Waits up to five seconds for the output file to contain `expected`.
+    fn wait_for(out: &Path, expected: &str) -> String {
+        for _ in 0..50 {
+            let content = fs::read_to_string(out).unwrap_or_default();
+            if content.contains(expected) {
+                return content;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("hook output never contained {expected}");
+    }
+
+    #[test]
+    fn test_spawn_passes_env() {
+        let temp_dir = TempDir::new("test_spawn_passes_env").unwrap();
+        let out = temp_dir.path().join("env.txt");
+        let script = env_script(temp_dir.path(), &out);
+
+        let context = HookContext {
+            event: HookEvent::Lock,
+            user: "test_user $(id)",
+            failures: 7,
+            service: Some("sshd"),
+            rhost: None,
+            unlock_instant: None,
+        };
+        spawn(&script, &context).unwrap();
+
+        let env = wait_for(&out, "---");
+        assert!(env.contains("AUTHRAMP_EVENT=lock"));
+        assert!(env.contains("AUTHRAMP_USER=test_user $(id)"));
+        assert!(env.contains("AUTHRAMP_FAILURES=7"));
+        assert!(env.contains("AUTHRAMP_SERVICE=sshd"));
+        assert!(env.contains("AUTHRAMP_RHOST=\n"));
+    }
+
+    #[test]
+    fn test_spawn_missing_command() {
+        // a missing command fails in the background without affecting the caller
+        let context = HookContext {
+            event: HookEvent::Unlock,
+            user: "test_user",
+            failures: 0,
+            service: None,
+            rhost: None,
+            unlock_instant: None,
+        };
+        assert!(spawn(Path::new("/nonexistent/hook"), &context).is_ok());
+    }
+}
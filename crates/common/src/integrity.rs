@@ -0,0 +1,197 @@
+//! # Integrity Module
+//!
+//! The `integrity` module authenticates tally files with an HMAC-SHA256, so a process that can
+//! write to the tally directory can't unlock an account by editing its tally. The key is read from
+//! the root-only `tally_hmac_key_file` of the configuration.
+//!
+//! The MAC covers the user name and the canonical serialization of the tally, so the tally of one
+//! user can't be copied over the tally of another. It doesn't prevent replaying an older tally
+//! file of the same user.
+//!
+//! Tally files without a MAC are accepted if they were last changed before the key file, so
+//! existing tallies are upgraded once after the feature is enabled. The change time of a file
+//! can't be set by its writer, so removing the MAC of a newer file is detected.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{fmt::Write, fs, os::unix::fs::MetadataExt, path::Path};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The outcome of checking the MAC of a tally file.
+#[derive(Debug, PartialEq)]
+pub enum Integrity {
+    /// No key is configured, the file is not checked.
+    Unchecked,
+    /// The MAC of the file verifies.
+    Verified,
+    /// The file has no MAC, but predates the key. It should be rewritten with a MAC.
+    Legacy,
+    /// The file can't be trusted, with the reason.
+    Invalid(String),
+}
+
+/// Reads the key from the key file.
+///
+/// # Arguments
+/// - `key_file`: Path of the key file
+///
+/// # Returns
+/// The key bytes
+///
+/// # Errors
+/// Returns a message if the key file can't be read, is accessible by group or others, or is empty.
+pub fn load_key(key_file: &Path) -> Result<Vec<u8>, String> {
+    let metadata =
+        fs::metadata(key_file).map_err(|e| format!("Error reading {}: {e}", key_file.display()))?;
+
+    if metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{} must not be accessible by group or others",
+            key_file.display()
+        ));
+    }
+
+    let key =
+        fs::read(key_file).map_err(|e| format!("Error reading {}: {e}", key_file.display()))?;
+
+    if key.is_empty() {
+        return Err(format!("{} is empty", key_file.display()));
+    }
+
+    Ok(key)
+}
+
+/// Creates the MAC state over a user name and the canonical tally content.
+fn mac(key: &[u8], user: &str, content: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(user.as_bytes());
+    mac.update(&[0]);
+    mac.update(content.as_bytes());
+    mac
+}
+
+/// Computes the MAC of a tally.
+///
+/// # Arguments
+/// - `key`: The key bytes
+/// - `user`: The user the tally belongs to
+/// - `content`: The canonical serialization of the tally
+///
+/// # Returns
+/// The MAC as lowercase hex
+#[must_use]
+pub fn sign(key: &[u8], user: &str, content: &str) -> String {
+    mac(key, user, content)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Verifies the MAC of a tally in constant time.
+///
+/// # Arguments
+/// - `key`: The key bytes
+/// - `user`: The user the tally belongs to
+/// - `content`: The canonical serialization of the tally
+/// - `hex`: The MAC stored in the tally file
+///
+/// # Returns
+/// `true` if the MAC is valid
+#[must_use]
+pub fn verify(key: &[u8], user: &str, content: &str, hex: &str) -> bool {
+    decode_hex(hex).is_some_and(|tag| mac(key, user, content).verify_slice(&tag).is_ok())
+}
+
+/// Decodes a hex string, `None` if it is malformed.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Checks whether a tally file was last changed before the key file was written.
+///
+/// # Arguments
+/// - `tally_file`: Path of the tally file
+/// - `key_file`: Path of the key file
+///
+/// # Returns
+/// `true` if the tally file predates the key, `false` if it doesn't or either can't be read
+#[must_use]
+pub fn predates_key(tally_file: &Path, key_file: &Path) -> bool {
+    match (fs::metadata(tally_file), fs::metadata(key_file)) {
+        (Ok(tally), Ok(key)) => {
+            (tally.ctime(), tally.ctime_nsec()) <= (key.mtime(), key.mtime_nsec())
+        }
+        _ => false,
+    }
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let content = "[Fails]\ncount = 7";
+        let mac = sign(b"secret", "test_user", content);
+        assert_eq!(mac.len(), 64);
+
+        assert!(verify(b"secret", "test_user", content, &mac));
+        assert!(!verify(b"secret", "test_user", "[Fails]\ncount = 0", &mac));
+        assert!(!verify(b"secret", "other_user", content, &mac));
+        assert!(!verify(b"other secret", "test_user", content, &mac));
+        assert!(!verify(b"secret", "test_user", content, "zz"));
+        assert!(!verify(b"secret", "test_user", content, &mac[..63]));
+    }
+
+    #[test]
+    fn test_load_key() {
+        let temp_dir = TempDir::new("test_load_key").unwrap();
+        let key_file = temp_dir.path().join("authramp.key");
+
+        assert!(load_key(&key_file).is_err());
+
+        fs::write(&key_file, "secret").unwrap();
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(load_key(&key_file).is_err());
+
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(load_key(&key_file), Ok(b"secret".to_vec()));
+
+        fs::write(&key_file, "").unwrap();
+        assert!(load_key(&key_file).is_err());
+    }
+}
@@ -1,7 +1,11 @@
 //! # `AuthRamp` Common Crate
 //!
 //! The `common` crate provides types, utility modules and functionality used across the `AuthRamp` library,
-//! including configuration management, settings handling, syslog initialization, and custom types.
+//! including configuration management, settings handling, and custom types.
+//!
+//! The crate keeps no logger state. The PAM module logs through `pam_syslog` on the PAM handle of
+//! each call. The only mutable state shared by concurrent PAM transactions in one process is the
+//! [`log_limit`] limiter collapsing repeated log lines, which is guarded by a `Mutex`.
 //!
 //! # Modules
 //!
@@ -11,17 +15,86 @@
 //! used by the `AuthRamp` PAM module and CLI binary. It includes a `Config` struct that represents
 //! the configuration settings for `AuthRamp`.
 //!
+//! ## `error`
+//!
+//! The `error` module defines `AuthRampError`, which keeps the context of a failure until it is
+//! logged and converted into a `PamResultCode` at the hook boundary.
+//!
 //! ## `settings`
 //!
 //! The `settings` module provides functionality for managing and accessing settings used by the
 //! `AuthRamp` PAM module. It includes a `Settings` struct that encapsulates configuration settings,
 //! user information, and other contextual information required for `AuthRamp`'s operation.
 //!
-//! ## `syslog`
+//! ## `tally`
+//!
+//! The `tally` module manages the account lockout status stored in the per-user tally files. It is
+//! shared between the PAM module, which updates the tallies, and the CLI binary, which inspects them.
+//!
+//! ## `state_file`
+//!
+//! The `state_file` module replaces the tally and stats files atomically, flushed to disk with
+//! `durable_writes`.
+//!
+//! ## `stats`
+//!
+//! The `stats` module keeps anonymous histograms of cleared tallies per PAM service, which help
+//! tuning the lockout policy. No user names are stored.
+//!
+//! ## `policy`
+//!
+//! The `policy` module snapshots the effective lockout policy of a user and renders it as a short
+//! summary.
+//!
+//! ## `query`
+//!
+//! The `query` module answers whether a user is locked out without a PAM handle, for the CLI and
+//! programs like desktop applets.
+//!
+//! ## `hook`
+//!
+//! The `hook` module runs the configured `hook_command` in the background when an account gets
+//! locked or unlocked.
+//!
+//! ## `audit`
+//!
+//! The `audit` module writes a Linux audit record when an account gets locked, if the crate is
+//! built with the `audit` feature and `audit_lockouts` is enabled.
+//!
+//! ## `dbus`
+//!
+//! The `dbus` module signals lockouts on the D-Bus system bus, if the crate is built with the
+//! `dbus` feature and `dbus` is enabled.
+//!
+//! ## `log_limit`
+//!
+//! The `log_limit` module collapses identical log lines, like the bounce of an account retried in
+//! a loop, into one line per `log_repeat_interval_seconds`.
+//!
+//! ## `messages`
+//!
+//! The `messages` module holds the translations of the messages to a locked user, selected with
+//! `locale`.
+//!
+//! ## `notify`
+//!
+//! The `notify` module emails the recipients of `notify_email` when an account gets locked or an
+//! admin resets it with the CLI.
+//!
+//! ## `template`
+//!
+//! The `template` module substitutes the `{name}` placeholders of the configurable user messages
+//! and notifications.
+//!
+//! ## `integrity`
+//!
+//! The `integrity` module authenticates the tally files with an HMAC if `tally_hmac_key_file` is
+//! configured, so editing a tally can't unlock an account.
+//!
+//! ## `unknown`
 //!
-//! The `syslog` module provides functionality for initializing syslog logging in both the PAM module
-//! and the CLI binary. It ensures that log messages are sent to the appropriate syslog facility,
-//! making it easy to monitor `AuthRamp` activity.
+//! The `unknown` module names the salted placeholders users missing from the user database are
+//! tallied under.
 //!
 //! ## `actions`
 //!
@@ -47,5 +120,20 @@
 //! along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod actions;
+pub mod audit;
 pub mod config;
+pub mod dbus;
+pub mod error;
+pub mod hook;
+pub mod integrity;
+pub mod log_limit;
+pub mod messages;
+pub mod notify;
+pub mod policy;
+pub mod query;
 pub mod settings;
+pub mod state_file;
+pub mod stats;
+pub mod tally;
+pub mod template;
+pub mod unknown;
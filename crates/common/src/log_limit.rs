@@ -0,0 +1,195 @@
+//! # Log Limit Module
+//!
+//! The `log_limit` module collapses repeated identical log lines. A client retrying a locked
+//! account in a loop is bounced on every attempt, and each bounce would log the same line again.
+//! Within `log_repeat_interval_seconds`, only the first occurrence of a line is logged. The next
+//! occurrence after the interval is logged with a "repeated N times" suffix counting the lines
+//! suppressed in between.
+//!
+//! The state lives in the process that loaded the module, so repeats are collapsed within
+//! long-running services and connections with several attempts, but not across processes.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Maximum number of distinct lines remembered, the oldest are forgotten first.
+pub const MAX_TRACKED_LINES: usize = 1024;
+
+/// When a line was last logged and how often it was suppressed since.
+#[derive(Debug, Clone, Copy)]
+struct Repeats {
+    logged: Instant,
+    suppressed: u64,
+}
+
+/// Collapses identical log lines per interval.
+#[derive(Debug, Default)]
+pub struct LogLimiter {
+    lines: HashMap<String, Repeats>,
+}
+
+impl LogLimiter {
+    /// Decides whether a line is logged.
+    ///
+    /// # Arguments
+    /// - `line`: The line to log
+    /// - `interval`: The interval identical lines are collapsed in, zero disables the limit
+    /// - `now`: The current instant
+    ///
+    /// # Returns
+    /// The line to log, with a "repeated N times" suffix if lines were suppressed since it was
+    /// last logged, or `None` if the line is suppressed
+    pub fn check(&mut self, line: &str, interval: Duration, now: Instant) -> Option<String> {
+        if interval.is_zero() {
+            return Some(line.to_string());
+        }
+
+        if let Some(repeats) = self.lines.get_mut(line) {
+            if now.saturating_duration_since(repeats.logged) < interval {
+                repeats.suppressed += 1;
+                return None;
+            }
+
+            let suppressed = repeats.suppressed;
+            *repeats = Repeats {
+                logged: now,
+                suppressed: 0,
+            };
+            return Some(if suppressed > 0 {
+                format!("{line} (repeated {suppressed} times)")
+            } else {
+                line.to_string()
+            });
+        }
+
+        self.forget_expired(interval, now);
+        self.lines.insert(
+            line.to_string(),
+            Repeats {
+                logged: now,
+                suppressed: 0,
+            },
+        );
+        Some(line.to_string())
+    }
+
+    /// Forgets lines whose interval passed without suppressed repeats, and the oldest lines if
+    /// still too many are remembered.
+    fn forget_expired(&mut self, interval: Duration, now: Instant) {
+        self.lines.retain(|_, repeats| {
+            repeats.suppressed > 0 || now.saturating_duration_since(repeats.logged) < interval
+        });
+
+        while self.lines.len() >= MAX_TRACKED_LINES {
+            let Some(oldest) = self
+                .lines
+                .iter()
+                .min_by_key(|(_, repeats)| repeats.logged)
+                .map(|(line, _)| line.clone())
+            else {
+                break;
+            };
+            self.lines.remove(&oldest);
+        }
+    }
+}
+
+/// Decides whether a line is logged with the limiter shared by the process.
+///
+/// # Arguments
+/// - `line`: The line to log
+/// - `interval`: The interval identical lines are collapsed in, zero disables the limit
+///
+/// # Returns
+/// The line to log or `None` if it is suppressed, see [`LogLimiter::check`]
+#[must_use]
+pub fn check(line: &str, interval: Duration) -> Option<String> {
+    static LIMITER: OnceLock<Mutex<LogLimiter>> = OnceLock::new();
+
+    // a poisoned limiter is still consistent, every update is a single assignment
+    let mut limiter = LIMITER
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    limiter.check(line, interval, Instant::now())
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str = "PAM_AUTH_ERR: Account \"user\" is getting bounced.";
+
+    #[test]
+    fn test_check_collapses_repeats() {
+        let mut limiter = LogLimiter::default();
+        let interval = Duration::from_mins(1);
+        let start = Instant::now();
+
+        // 1000 bounces within 100 seconds log at most one line per interval
+        let logged: Vec<String> = (0..1000)
+            .filter_map(|i| limiter.check(LINE, interval, start + Duration::from_millis(i * 100)))
+            .collect();
+        assert_eq!(logged.len(), 2);
+        assert_eq!(logged[0], LINE);
+        assert_eq!(logged[1], format!("{LINE} (repeated 599 times)"));
+
+        // the remaining repeats are reported with the next line, a quiet interval resets them
+        assert_eq!(
+            limiter.check(LINE, interval, start + Duration::from_mins(5)),
+            Some(format!("{LINE} (repeated 399 times)"))
+        );
+        assert_eq!(
+            limiter.check(LINE, interval, start + Duration::from_secs(400)),
+            Some(LINE.to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_distinct_lines() {
+        let mut limiter = LogLimiter::default();
+        let interval = Duration::from_mins(1);
+        let now = Instant::now();
+
+        assert!(limiter.check(LINE, interval, now).is_some());
+        assert!(limiter.check("other user", interval, now).is_some());
+        assert!(limiter.check(LINE, interval, now).is_none());
+
+        // zero disables the limit
+        assert!(limiter.check(LINE, Duration::ZERO, now).is_some());
+    }
+
+    #[test]
+    fn test_check_bounded() {
+        let mut limiter = LogLimiter::default();
+        let interval = Duration::from_mins(1);
+        let now = Instant::now();
+
+        for i in 0..2 * MAX_TRACKED_LINES {
+            assert!(limiter.check(&i.to_string(), interval, now).is_some());
+        }
+        assert!(limiter.lines.len() <= MAX_TRACKED_LINES);
+    }
+}
@@ -0,0 +1,187 @@
+//! # Messages Module
+//!
+//! The `messages` module holds the built-in translations of the messages to a locked user. The
+//! locale is configured with `locale`, or detected from `LC_ALL`, `LC_MESSAGES` and `LANG` of the
+//! authenticating service. Unknown locales and untranslated strings fall back to English.
+//!
+//! The strings are the default templates of `lockout_message` and `countdown_message` and the
+//! words of the remaining lock time, which carry their own plural forms per locale.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+
+/// The environment variables the locale is detected from, in order of precedence.
+pub const LOCALE_VARIABLES: [&str; 3] = ["LC_ALL", "LC_MESSAGES", "LANG"];
+
+/// The languages of the built-in translations.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Locale {
+    #[default]
+    English,
+    German,
+}
+
+/// The translated strings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message {
+    /// Default `lockout_message`
+    Lockout,
+    /// Default `countdown_message`
+    Countdown,
+    /// Remaining time below a minute, at minute granularity
+    LessThanAMinute,
+    /// Joins the last two parts of the remaining time
+    And,
+}
+
+/// The units of the remaining lock time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Hour,
+    Minute,
+    Second,
+}
+
+impl Locale {
+    /// Parses a locale name like "de", "de_DE.UTF-8" or "C" by its language.
+    ///
+    /// # Returns
+    ///
+    /// The locale, or `None` if there is no translation for the language.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        let language = name
+            .split(['_', '-', '.', '@'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match language.as_str() {
+            "en" | "c" | "posix" => Some(Locale::English),
+            "de" => Some(Locale::German),
+            _ => None,
+        }
+    }
+
+    /// Detects the locale from the first set variable of [`LOCALE_VARIABLES`].
+    ///
+    /// # Returns
+    ///
+    /// The locale, English if none is set or it has no translation.
+    #[must_use]
+    pub fn detect() -> Self {
+        LOCALE_VARIABLES
+            .iter()
+            .find_map(|variable| env::var(variable).ok().filter(|value| !value.is_empty()))
+            .and_then(|name| Locale::from_name(&name))
+            .unwrap_or_default()
+    }
+
+    /// The name of the locale in the configuration file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Locale::English => "en",
+            Locale::German => "de",
+        }
+    }
+
+    /// The translation of a string, English if it isn't translated.
+    #[must_use]
+    pub fn text(self, message: Message) -> &'static str {
+        self.translation(message)
+            .unwrap_or_else(|| english(message))
+    }
+
+    /// Formats a number of units with the plural form of the locale, e.g. "1 Stunde".
+    #[must_use]
+    pub fn unit(self, unit: Unit, value: i64) -> String {
+        let (singular, plural) = match (self, unit) {
+            (Locale::English, Unit::Hour) => ("hour", "hours"),
+            (Locale::English, Unit::Minute) => ("minute", "minutes"),
+            (Locale::English, Unit::Second) => ("second", "seconds"),
+            (Locale::German, Unit::Hour) => ("Stunde", "Stunden"),
+            (Locale::German, Unit::Minute) => ("Minute", "Minuten"),
+            (Locale::German, Unit::Second) => ("Sekunde", "Sekunden"),
+        };
+        format!("{value} {}", if value == 1 { singular } else { plural })
+    }
+
+    /// The translation of a string, `None` if it isn't translated or the locale is English.
+    fn translation(self, message: Message) -> Option<&'static str> {
+        match self {
+            Locale::English => None,
+            Locale::German => Some(match message {
+                Message::Lockout => "Konto gesperrt bis {unlock_time}.",
+                Message::Countdown => "Konto gesperrt! Entsperrung in {remaining}.",
+                Message::LessThanAMinute => "weniger als einer Minute",
+                Message::And => "und",
+            }),
+        }
+    }
+}
+
+/// The English strings, which every translation falls back to.
+fn english(message: Message) -> &'static str {
+    match message {
+        Message::Lockout => "Account locked until {unlock_time}.",
+        Message::Countdown => "Account locked! Unlocking in {remaining}.",
+        Message::LessThanAMinute => "less than a minute",
+        Message::And => "and",
+    }
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Locale::from_name("de"), Some(Locale::German));
+        assert_eq!(Locale::from_name("de_DE.UTF-8"), Some(Locale::German));
+        assert_eq!(Locale::from_name("de_AT@euro"), Some(Locale::German));
+        assert_eq!(Locale::from_name("en_US.UTF-8"), Some(Locale::English));
+        assert_eq!(Locale::from_name("C.UTF-8"), Some(Locale::English));
+        assert_eq!(Locale::from_name("POSIX"), Some(Locale::English));
+        assert_eq!(Locale::from_name("fr_FR"), None);
+        assert_eq!(Locale::from_name(""), None);
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            Locale::English.text(Message::Lockout),
+            "Account locked until {unlock_time}."
+        );
+        assert_eq!(
+            Locale::German.text(Message::Countdown),
+            "Konto gesperrt! Entsperrung in {remaining}."
+        );
+    }
+
+    #[test]
+    fn test_unit() {
+        assert_eq!(Locale::English.unit(Unit::Hour, 1), "1 hour");
+        assert_eq!(Locale::English.unit(Unit::Second, 0), "0 seconds");
+        assert_eq!(Locale::German.unit(Unit::Hour, 1), "1 Stunde");
+        assert_eq!(Locale::German.unit(Unit::Minute, 2), "2 Minuten");
+        assert_eq!(Locale::German.unit(Unit::Second, 1), "1 Sekunde");
+    }
+}
@@ -0,0 +1,278 @@
+//! # Notify Module
+//!
+//! The `notify` module emails the recipients of `notify_email` when an account gets locked or an
+//! admin resets it with the CLI. The message is rendered from `notify_email_template` and piped
+//! to `notify_sendmail`, `/usr/sbin/sendmail` by default.
+//!
+//! ## Template
+//!
+//! The template is the message after the `To` header, starting with its own headers like
+//! `Subject`. These placeholders are substituted:
+//!
+//! - `{event}`: `lock` or `reset`
+//! - `{user}`: The name of the account
+//! - `{failures}`: The number of recorded failures
+//! - `{service}`: The PAM service, `-` if unknown
+//! - `{rhost}`: The remote host of the last failure, `-` if unknown
+//! - `{unlock_time}`: The RFC 3339 unlock instant, `-` if not locked
+//!
+//! `{{` and `}}` are literal braces, unknown placeholders are kept as they are.
+//! `{user}` in a recipient is substituted as well, e.g. to notify the local mailbox of the
+//! account. Line breaks in substituted values are replaced, so they can't add headers.
+//!
+//! Like the hook command, sendmail is started in the background and killed after
+//! [`NOTIFY_TIMEOUT_SECONDS`], so it never delays the authentication.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+    io,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{hook::HOOK_PATH, template};
+
+/// Seconds after which a running sendmail is terminated.
+pub const NOTIFY_TIMEOUT_SECONDS: u64 = 10;
+
+/// Template used if `notify_email_template` isn't configured.
+pub const DEFAULT_TEMPLATE: &str = "Subject: AuthRamp {event} of the {user} account
+
+Event: {event}
+Account: {user}
+Failures: {failures}
+Service: {service}
+Remote host: {rhost}
+Unlock time: {unlock_time}
+";
+
+/// The events a notification is sent for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotifyEvent {
+    /// The failures crossed `free_tries` and locked the account.
+    Lock,
+    /// An admin reset the tally with the CLI.
+    Reset,
+}
+
+impl NotifyEvent {
+    /// The name of the event substituted for `{event}`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            NotifyEvent::Lock => "lock",
+            NotifyEvent::Reset => "reset",
+        }
+    }
+}
+
+/// The details of an event substituted into the template.
+#[derive(Debug)]
+pub struct Notification<'a> {
+    pub event: NotifyEvent,
+    pub user: &'a str,
+    pub failures: i32,
+    pub service: Option<&'a str>,
+    pub rhost: Option<&'a str>,
+    pub unlock_instant: Option<DateTime<Utc>>,
+}
+
+impl Notification<'_> {
+    /// The value of a placeholder, without line breaks or other control characters.
+    fn value(&self, placeholder: &str) -> Option<String> {
+        let value = match placeholder {
+            "event" => self.event.name().to_string(),
+            "user" => self.user.to_string(),
+            "failures" => self.failures.to_string(),
+            "service" => self.service.unwrap_or("-").to_string(),
+            "rhost" => self.rhost.unwrap_or("-").to_string(),
+            "unlock_time" => self.unlock_instant.map_or_else(
+                || "-".to_string(),
+                |unlock_instant| unlock_instant.to_rfc3339(),
+            ),
+            _ => return None,
+        };
+        Some(
+            value
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .collect(),
+        )
+    }
+
+    /// Substitutes the placeholders of a template, see [`template::render`].
+    ///
+    /// # Returns
+    /// The rendered template
+    #[must_use]
+    pub fn render(&self, template: &str) -> String {
+        template::render(template, |placeholder| self.value(placeholder))
+    }
+
+    /// Renders the complete message with the `To` header.
+    ///
+    /// # Returns
+    /// The recipients with `{user}` substituted and the message
+    #[must_use]
+    pub fn message(&self, recipients: &[String], template: &str) -> (Vec<String>, String) {
+        let recipients: Vec<String> = recipients
+            .iter()
+            .map(|recipient| self.render(recipient))
+            .collect();
+        let message = format!("To: {}\n{}", recipients.join(", "), self.render(template));
+        (recipients, message)
+    }
+}
+
+/// Pipes a notification to sendmail in the background without waiting for it.
+///
+/// A shell detaches sendmail under `timeout`, so it is terminated after
+/// [`NOTIFY_TIMEOUT_SECONDS`]. The message is only passed through the environment and the
+/// recipients as arguments, never through the shell command line.
+///
+/// # Arguments
+/// - `sendmail`: Path of the sendmail executable
+/// - `recipients`: The recipients, `{user}` is substituted
+/// - `template`: The template of the message
+/// - `notification`: The event details
+///
+/// # Errors
+/// Returns an error if the shell can't be started or fails to start sendmail.
+pub fn send(
+    sendmail: &Path,
+    recipients: &[String],
+    template: &str,
+    notification: &Notification,
+) -> io::Result<()> {
+    let (recipients, message) = notification.message(recipients, template);
+
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!(
+            "printf '%s' \"$AUTHRAMP_MAIL\" | timeout -k 1 {NOTIFY_TIMEOUT_SECONDS} \"$0\" -i -- \"$@\" &"
+        ))
+        .arg(sendmail)
+        .args(&recipients)
+        .env_clear()
+        .env("PATH", HOOK_PATH)
+        .env("AUTHRAMP_MAIL", message)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "sendmail shell exited with {status}"
+        )))
+    }
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, thread::sleep, time::Duration};
+    use tempdir::TempDir;
+
+    fn notification(user: &str) -> Notification<'_> {
+        Notification {
+            event: NotifyEvent::Lock,
+            user,
+            failures: 7,
+            service: Some("sshd"),
+            rhost: None,
+            unlock_instant: Some("2024-02-04T12:00:00Z".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            notification("test_user").render(DEFAULT_TEMPLATE),
+            "Subject: AuthRamp lock of the test_user account\n\nEvent: lock\nAccount: test_user\nFailures: 7\nService: sshd\nRemote host: -\nUnlock time: 2024-02-04T12:00:00+00:00\n"
+        );
+
+        // unknown and unclosed placeholders are kept, values are never substituted again
+        assert_eq!(
+            notification("{failures}").render("{user} {unknown} {failures"),
+            "{failures} {unknown} {failures"
+        );
+
+        // line breaks can't add headers
+        assert_eq!(
+            notification("a\nBcc: b").render("Subject: {user}\n"),
+            "Subject: a Bcc: b\n"
+        );
+    }
+
+    #[test]
+    fn test_send() {
+        let temp_dir = TempDir::new("test_notify_send").unwrap();
+        let out = temp_dir.path().join("mail.txt");
+        let sendmail = temp_dir.path().join("sendmail");
+        fs::write(
+            &sendmail,
+            format!(
+                "#!/bin/sh\necho \"$@\" > '{0}.tmp'\ncat >> '{0}.tmp'\nmv '{0}.tmp' '{0}'\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&sendmail, fs::Permissions::from_mode(0o755)).unwrap();
+
+        send(
+            &sendmail,
+            &["root".to_string(), "{user}".to_string()],
+            "Subject: {event} {user}\n\n{failures} failures\n",
+            &notification("test_user $(id)"),
+        )
+        .unwrap();
+
+        let mut mail = String::new();
+        for _ in 0..50 {
+            mail = fs::read_to_string(&out).unwrap_or_default();
+            if !mail.is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        assert_eq!(
+            mail,
+            "-i -- root test_user $(id)\nTo: root, test_user $(id)\nSubject: lock test_user $(id)\n\n7 failures\n"
+        );
+    }
+
+    #[test]
+    fn test_send_missing_sendmail() {
+        // a missing sendmail fails in the background without affecting the caller
+        assert!(send(
+            Path::new("/nonexistent/sendmail"),
+            &["root".to_string()],
+            DEFAULT_TEMPLATE,
+            &notification("test_user"),
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,188 @@
+//! # Policy Module
+//!
+//! The `policy` module describes the effective lockout policy of a user. A `Policy` is a snapshot
+//! of the resolved `Settings`, including per-user overrides and group and service exemptions, and
+//! can be rendered as a short summary for the user.
+//!
+//! The snapshot only holds configuration, never tally state, so it can't reveal the lock state
+//! of any account.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::settings::Settings;
+
+/// A snapshot of the lockout policy a user is subject to.
+#[derive(Debug, PartialEq)]
+pub struct Policy {
+    /// Failures allowed before delays apply.
+    pub free_tries: i32,
+    /// Delay applied to the first failure over the free tries.
+    pub base_delay_seconds: i32,
+    /// Upper bound of a single delay, `None` if uncapped.
+    pub max_delay_seconds: Option<i64>,
+    /// Whether the user is never locked out.
+    pub exempt: bool,
+}
+
+impl Policy {
+    /// Creates the policy snapshot of the user in the settings.
+    ///
+    /// # Arguments
+    /// - `settings`: The resolved settings, including per-user overrides.
+    ///
+    /// # Returns
+    /// The effective policy of the user.
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> Self {
+        let root_exempt = settings
+            .user
+            .as_ref()
+            .is_some_and(|user| user.uid() == 0 && !settings.config.even_deny_root);
+        let service_exempt = settings
+            .service
+            .as_deref()
+            .is_some_and(|service| settings.config.is_exempt_service(service));
+
+        Policy {
+            free_tries: settings.config.free_tries,
+            base_delay_seconds: settings.config.base_delay_seconds,
+            max_delay_seconds: settings.config.lockout_cap().map(|cap| cap.num_seconds()),
+            exempt: root_exempt || service_exempt || settings.exempt_group().is_some(),
+        }
+    }
+
+    /// Renders the policy as a short summary.
+    ///
+    /// # Returns
+    /// The summary, e.g. "6 free attempts, then increasing delays starting at 30s, max 24h".
+    #[must_use]
+    pub fn summary(&self) -> String {
+        if self.exempt {
+            return "This account is exempt from lockout.".to_string();
+        }
+
+        format!(
+            "{} free attempt{}, then increasing delays starting at {}, {}",
+            self.free_tries,
+            if self.free_tries == 1 { "" } else { "s" },
+            format_seconds(i64::from(self.base_delay_seconds)),
+            self.max_delay_seconds.map_or_else(
+                || "no maximum".to_string(),
+                |max_delay_seconds| format!("max {}", format_seconds(max_delay_seconds))
+            )
+        )
+    }
+}
+
+/// Formats seconds compactly, e.g. "90s" as "1m30s" and "86400s" as "24h".
+fn format_seconds(seconds: i64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+    [(hours, "h"), (minutes, "m"), (seconds, "s")]
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .reduce(|formatted, part| formatted + &part)
+        .unwrap_or_else(|| "0s".to_string())
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use uzers::User;
+
+    fn settings(config: Config, user: User, service: Option<&str>) -> Settings<'static> {
+        Settings {
+            user: Some(user),
+            service: service.map(str::to_string),
+            config,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn test_format_seconds() {
+        assert_eq!(format_seconds(0), "0s");
+        assert_eq!(format_seconds(30), "30s");
+        assert_eq!(format_seconds(90), "1m30s");
+        assert_eq!(format_seconds(3600), "1h");
+        assert_eq!(format_seconds(86400), "24h");
+    }
+
+    #[test]
+    fn test_policy_summary() {
+        let user = User::new(9999, "test_user", 9999);
+
+        // defaults
+        let policy = Policy::from_settings(&settings(Config::default(), user.clone(), None));
+        assert_eq!(
+            policy.summary(),
+            "6 free attempts, then increasing delays starting at 30s, max 24h"
+        );
+
+        // per-user override
+        let mut config = Config::default();
+        config.user_overrides.insert(
+            "test_user".to_string(),
+            crate::config::UserOverride {
+                free_tries: Some(1),
+                base_delay_seconds: Some(90),
+                ..Default::default()
+            },
+        );
+        config.apply_user_override("test_user");
+        let policy = Policy::from_settings(&settings(config, user.clone(), None));
+        assert_eq!(
+            policy.summary(),
+            "1 free attempt, then increasing delays starting at 1m30s, max 24h"
+        );
+
+        // uncapped lockouts
+        let config = Config {
+            max_lockout_seconds: 0,
+            ..Config::default()
+        };
+        let policy = Policy::from_settings(&settings(config, user.clone(), None));
+        assert_eq!(
+            policy.summary(),
+            "6 free attempts, then increasing delays starting at 30s, no maximum"
+        );
+
+        // exempt service
+        let config = Config {
+            exempt_services: vec!["whoami".to_string()],
+            ..Config::default()
+        };
+        let policy = Policy::from_settings(&settings(config, user, Some("whoami")));
+        assert_eq!(policy.summary(), "This account is exempt from lockout.");
+
+        // root unless even_deny_root
+        let root = User::new(0, "root", 0);
+        let policy = Policy::from_settings(&settings(Config::default(), root.clone(), None));
+        assert!(policy.exempt);
+        let config = Config {
+            even_deny_root: true,
+            ..Config::default()
+        };
+        let policy = Policy::from_settings(&settings(config, root, None));
+        assert!(!policy.exempt);
+    }
+}
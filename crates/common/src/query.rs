@@ -0,0 +1,253 @@
+//! # Query Module
+//!
+//! The `query` module answers whether a user is locked out, for programs like desktop applets
+//! and daemons that show the lockout state without a PAM handle. The tallies are read with the
+//! parser of the PAM module and the unlock instant is computed like the module does when it
+//! bounces an authentication, so a query agrees with the module and the `authramp status`
+//! command, which is built on it.
+//!
+//! Reading the tallies needs access to the tally directory, which only root has by default.
+//! With `status_world_readable` every user can read a tally by its file name.
+//!
+//! ```no_run
+//! use common::{config::Config, query};
+//! use pam::PamHandle;
+//!
+//! // no PAM handle to log with outside of the module
+//! let config = Config::load_file::<PamHandle>(None, None);
+//! if let Ok(Some(status)) = query::lock_status("alice", &config) {
+//!     if let Some(locked_until) = status.locked_until {
+//!         println!("alice is locked until {locked_until}");
+//!     }
+//! }
+//! ```
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+use std::path::Path;
+use uzers::{get_user_by_name, User};
+
+use crate::{
+    config::{Config, TallyKey},
+    error::AuthRampError,
+    settings::Settings,
+    tally::{find_tally_file, Tally},
+};
+
+/// The lockout state of a user at an instant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockStatus {
+    /// The failures recorded since the last reset.
+    pub failures: i32,
+    /// The instant of the last failure, `None` without failures.
+    pub last_failure: Option<DateTime<Utc>>,
+    /// The instant the user is locked until, `None` if the user isn't locked.
+    pub locked_until: Option<DateTime<Utc>>,
+    /// The instant the lock of the failures ends or ended, `None` within the free tries.
+    pub unlock_instant: Option<DateTime<Utc>>,
+    /// The instant of the last successful login, if the session hook recorded one.
+    pub last_success: Option<DateTime<Utc>>,
+    /// The user name remembered by uid-keyed tallies.
+    pub user_name: Option<String>,
+    /// The PAM service of the last failure.
+    pub service: Option<String>,
+    /// The remote host of the last failure.
+    pub rhost: Option<String>,
+    /// The terminal of the last failure.
+    pub tty: Option<String>,
+}
+
+impl LockStatus {
+    /// Whether the user is locked.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.locked_until.is_some()
+    }
+}
+
+/// Queries the lockout state of a user now.
+///
+/// The user name is normalized according to `normalize_user` and looked up in the user database,
+/// like the PAM module does. With `tally_key = "name"`, unknown users are queried by name, so the
+/// tallies of deleted accounts can still be inspected.
+///
+/// # Arguments
+/// - `user`: The user name.
+/// - `config`: The loaded configuration, with the tally directory.
+///
+/// # Returns
+/// The lockout state, or `None` if the user has no tally.
+///
+/// # Errors
+/// Returns an `AuthRampError` if the user is unknown with `tally_key = "uid"`, its name can't be
+/// used as a tally file name, or the tally can't be read, parsed or fails the integrity check.
+pub fn lock_status(user: &str, config: &Config) -> Result<Option<LockStatus>, AuthRampError> {
+    let user = config.normalize_user.apply(user);
+    let resolved = get_user_by_name(&user)
+        .or_else(|| (config.tally_key == TallyKey::Name).then(|| User::new(0, &user, 0)))
+        .ok_or_else(|| {
+            AuthRampError::user(
+                format!("Unknown user \"{user}\""),
+                "not in the user database",
+            )
+        })?;
+
+    let tally_file = find_tally_file(config, &resolved)
+        .map_err(|e| AuthRampError::user(format!("Rejected the user name \"{user}\""), e))?;
+
+    tally_lock_status(&tally_file, config, Utc::now())
+}
+
+/// Queries the lockout state stored in a tally file at an instant.
+///
+/// # Arguments
+/// - `path`: The path of the tally file.
+/// - `config`: The loaded configuration.
+/// - `now`: The instant the lock state is evaluated at.
+///
+/// # Returns
+/// The lockout state, or `None` if the tally file doesn't exist.
+///
+/// # Errors
+/// Returns an `AuthRampError` if the tally can't be accessed, read, parsed or fails the integrity
+/// check.
+pub fn tally_lock_status(
+    path: &Path,
+    config: &Config,
+    now: DateTime<Utc>,
+) -> Result<Option<LockStatus>, AuthRampError> {
+    let exists = path.try_exists().map_err(|e| {
+        AuthRampError::io(format!("Error accessing tally file {}", path.display()), e)
+    })?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let tally = Tally::read_trusted_tally_file(path, config)?;
+
+    let settings = Settings {
+        config: config.clone(),
+        ..Settings::default()
+    };
+
+    // same computation the module uses when bouncing an authentication
+    let unlock_instant = tally.effective_unlock_instant(&settings);
+
+    Ok(Some(LockStatus {
+        failures: tally.failures_count,
+        last_failure: (tally.failures_count > 0).then_some(tally.failure_instant),
+        locked_until: unlock_instant.filter(|unlock_instant| now < *unlock_instant),
+        unlock_instant,
+        last_success: tally.last_success,
+        user_name: tally.user_name,
+        service: tally.service,
+        rhost: tally.rhost,
+        tty: tally.tty,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NormalizeUser;
+    use chrono::Duration;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_tally_lock_status() {
+        let temp_dir = TempDir::new("test_tally_lock_status").unwrap();
+        let path = temp_dir.path().join("alice");
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let now = Utc::now();
+
+        assert_eq!(tally_lock_status(&path, &config, now).unwrap(), None);
+
+        fs::write(
+            &path,
+            format!(
+                "[Fails]\ncount = 7\ninstant = \"{now}\"\nunlock_instant = \"{}\"\nservice = \"sshd\"",
+                now + Duration::seconds(30)
+            ),
+        )
+        .unwrap();
+
+        let status = tally_lock_status(&path, &config, now).unwrap().unwrap();
+        assert!(status.is_locked());
+        assert_eq!(status.failures, 7);
+        assert_eq!(status.last_failure, Some(now));
+        assert_eq!(status.locked_until, Some(now + Duration::seconds(30)));
+        assert_eq!(status.service.as_deref(), Some("sshd"));
+
+        // the lock expired
+        let status = tally_lock_status(&path, &config, now + Duration::seconds(31))
+            .unwrap()
+            .unwrap();
+        assert!(!status.is_locked());
+        assert_eq!(status.unlock_instant, Some(now + Duration::seconds(30)));
+
+        // within the free tries
+        fs::write(&path, "[Fails]\ncount = 0").unwrap();
+        let status = tally_lock_status(&path, &config, now).unwrap().unwrap();
+        assert_eq!(status.last_failure, None);
+        assert_eq!(status.unlock_instant, None);
+
+        fs::write(&path, "not a tally").unwrap();
+        assert!(tally_lock_status(&path, &config, now).is_err());
+    }
+
+    #[test]
+    fn test_lock_status() {
+        let temp_dir = TempDir::new("test_lock_status").unwrap();
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+
+        assert_eq!(lock_status("query_user", &config).unwrap(), None);
+
+        fs::write(temp_dir.path().join("query_user"), "[Fails]\ncount = 3").unwrap();
+        let status = lock_status("query_user", &config).unwrap().unwrap();
+        assert_eq!(status.failures, 3);
+        assert!(!status.is_locked());
+
+        assert!(lock_status("../query_user", &config).is_err());
+
+        // variants of the name are normalized like the module does
+        assert_eq!(
+            lock_status("Query_User@corp.example", &config).unwrap(),
+            None
+        );
+        let config = Config {
+            normalize_user: NormalizeUser {
+                lowercase: true,
+                strip_realm: true,
+            },
+            ..config
+        };
+        let status = lock_status("Query_User@corp.example", &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.failures, 3);
+    }
+}
@@ -26,12 +26,47 @@
 //! along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::actions::Actions;
-use crate::config::Config;
-use pam::{PamFlag, PamHandle, PamResultCode};
+use crate::config::{deny_result, Config, LogBackend, UnknownUser, UserLookup};
+use crate::error::AuthRampError;
+use crate::tally::{ATTEMPT_MARKER, SUCCESS_MARKER, TRANSACTION_MARKER};
+use crate::unknown;
+use pam::items::{RHost, Service, Tty, User as UserItem};
+use pam::{PamApi, PamFlag, PamResultCode};
 use std::collections::HashMap;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Once;
 
-use uzers::User;
+use uzers::{get_group_by_gid, get_user_by_name, User};
+
+/// The uid and gid of users resolved without a user database, `(uid_t) -1`.
+pub(crate) const NAME_ONLY_ID: u32 = u32::MAX;
+
+/// Module arguments without a value.
+const FLAG_ARGUMENTS: [&str; 6] = [
+    "preauth", "authsucc", "authfail", "nodelay", "policy", "noclear",
+];
+
+/// Logs the features inactive without a user database once per process.
+static NAME_ONLY_NOTICE: Once = Once::new();
+
+/// Key of the PAM module data caching the configuration and user of earlier hooks.
+const SETTINGS_CACHE: &str = "pam_authramp_settings_cache";
+
+/// The configuration and user resolved by an earlier hook of the same PAM transaction, so later
+/// lines don't read the configuration file and the user database again.
+struct CachedSettings {
+    /// The `conf` and `conf_missing` arguments the configuration was loaded with.
+    conf_arguments: (Option<String>, Option<String>),
+    /// The configuration, without the user overrides and arguments.
+    config: Config,
+    /// The PAM user name the user was resolved from.
+    name: String,
+    /// The resolved user.
+    user: User,
+}
 
 // Settings struct represents the configuration loaded from default values, configuration file and parameters
 #[derive(Debug)]
@@ -42,18 +77,43 @@ pub struct Settings<'a> {
     pub action: Option<Actions>,
     // PAM user
     pub user: Option<User>,
+    // PAM service
+    pub service: Option<String>,
+    // PAM terminal name
+    pub tty: Option<String>,
+    // PAM remote host
+    pub rhost: Option<String>,
+    // Failures recorded earlier in the current PAM transaction
+    pub transaction_failures: i32,
+    // Whether a success already settled the tally in the current PAM transaction
+    pub transaction_succeeded: bool,
+    // Whether the failure of the current attempt was recorded, `None` without a preauth line
+    pub attempt_counted: Option<bool>,
+    // Show the effective policy instead of acting on the tally
+    pub policy: bool,
+    // Leave the tally untouched on success
+    pub noclear: bool,
     // Config
     pub config: Config,
 }
 
 impl Default for Settings<'_> {
-    /// Creates a default 'Settings' struct. Default configruation values are set here.
+    /// Creates a default 'Settings' struct with the default configuration, without reading the
+    /// configuration file.
     fn default() -> Self {
         Settings {
             action: Some(Actions::AUTHSUCC),
             user: None,
+            service: None,
+            tty: None,
+            rhost: None,
+            transaction_failures: 0,
+            transaction_succeeded: false,
+            attempt_counted: None,
+            policy: false,
+            noclear: false,
             pam_hook: "auth",
-            config: Config::load_file(None, None),
+            config: Config::default(),
         }
     }
 }
@@ -65,7 +125,7 @@ impl Settings<'_> {
     /// # Arguments
     ///
     /// * `user`: An optional `User` instance representing the user associated with
-    ///   the PAM session.
+    ///   the PAM session. If `None`, the PAM user is resolved according to `user_lookup`.
     /// * `args`: A vector of `CStr` references representing the PAM module arguments.
     /// * `_flags`: PAM flags indicating the context of the PAM operation (unused).
     /// * `config_file`: An optional `PathBuf` specifying the path to the TOML file. If
@@ -73,25 +133,87 @@ impl Settings<'_> {
     ///
     /// # Returns
     ///
-    /// A `Result` containing the constructed `Settings` instance or a `PamResultCode`
-    /// indicating an error during the construction process.
+    /// A `Result` containing the constructed `Settings` instance or an `AuthRampError`
+    /// describing an error during the construction process.
     ///
     /// # Errors
     ///
-    /// Returns a `PamResultCode` error.
-    pub fn build<'a>(
+    /// Returns an `AuthRampError` if the configuration file is missing with
+    /// `conf_missing=deny`, or the user can't be resolved. The latter is returned with the
+    /// result code of `fail_mode`.
+    pub fn build<'a, P: PamApi>(
         user: Option<User>,
         args: &[&CStr],
         _flags: PamFlag,
         pam_hook: &'a str,
-        pam_h: Option<&mut PamHandle>,
-    ) -> Result<Settings<'a>, PamResultCode> {
+        mut pam_h: Option<&mut P>,
+    ) -> Result<Settings<'a>, AuthRampError> {
+        // Get the PAM service name
+        let service = pam_h
+            .as_ref()
+            .and_then(|pam_h| pam_h.get_item::<Service>().ok().flatten())
+            .map(|service| service.0.to_string_lossy().into_owned());
+
+        // Get the PAM terminal name
+        let tty = pam_h
+            .as_ref()
+            .and_then(|pam_h| pam_h.get_item::<Tty>().ok().flatten())
+            .map(|tty| tty.0.to_string_lossy().into_owned());
+
+        // Get the PAM remote host
+        let rhost = pam_h
+            .as_ref()
+            .and_then(|pam_h| pam_h.get_item::<RHost>().ok().flatten())
+            .map(|rhost| rhost.0.to_string_lossy().into_owned());
+
+        // Get the failures recorded earlier in this transaction
+        let transaction_failures =
+            module_data::<i32, _>(pam_h.as_deref(), TRANSACTION_MARKER).unwrap_or_default();
+
+        // Check whether a success already settled the tally in this transaction
+        let transaction_succeeded =
+            module_data::<bool, _>(pam_h.as_deref(), SUCCESS_MARKER).unwrap_or_default();
+
+        // Check whether a line of the current attempt already recorded the failure
+        let attempt_counted = module_data::<bool, _>(pam_h.as_deref(), ATTEMPT_MARKER);
+
+        // Reuse the configuration of an earlier line with the same conf arguments
+        let conf_arguments = (
+            argument(args, "conf").map(str::to_string),
+            argument(args, "conf_missing").map(str::to_string),
+        );
+        let cached_config = pam_h
+            .as_ref()
+            .and_then(|pam_h| {
+                pam_h
+                    .get_data::<CachedSettings>(SETTINGS_CACHE)
+                    .ok()
+                    .flatten()
+            })
+            .filter(|cached| cached.conf_arguments == conf_arguments)
+            .map(|cached| cached.config.clone());
+        let config = match cached_config {
+            Some(config) => config,
+            None => Self::load_config(args, pam_hook, pam_h.as_deref_mut())?,
+        };
+
         // Init default settings.
         let mut settings = Settings {
-            config: Config::load_file(None, pam_h),
+            service,
+            tty,
+            rhost,
+            transaction_failures,
+            transaction_succeeded,
+            attempt_counted,
+            config,
             ..Settings::default()
         };
 
+        // Send the module's logs to the configured facility and backend
+        if let Some(pam_h) = pam_h.as_deref_mut() {
+            settings.set_up_logging(pam_h, pam_hook);
+        }
+
         // create possible action collection
         let action_map: HashMap<&str, Actions> = [
             ("preauth", Actions::PREAUTH),
@@ -112,15 +234,382 @@ impl Settings<'_> {
         // set default action if none is provided
         settings.action.get_or_insert(Actions::AUTHSUCC);
 
-        // get user
-        settings.user = Some(user.ok_or(PamResultCode::PAM_USER_UNKNOWN)?);
+        // get user, a failed lookup is an internal failure
+        let user = match user {
+            Some(user) => user,
+            None => Self::cached_user(pam_h.as_deref_mut(), &settings.config, conf_arguments)
+                .map_err(|e| {
+                    let code = settings.config.fail_mode.result(pam_hook, e.code());
+                    e.with_code(code)
+                })?,
+        };
+
+        // apply the [auth] or [account] overrides, then the [user.<name>] overrides
+        settings.config.apply_hook_override(pam_hook);
+        settings
+            .config
+            .apply_user_override(&user.name().to_string_lossy());
+        if let Some(pam_h) = pam_h.as_deref() {
+            pam_h.set_log_field("AUTHRAMP_USER", &user.name().to_string_lossy());
+        }
+        settings.user = Some(user);
+
+        // key=value arguments override the configuration, including the user overrides
+        for ignored in apply_arguments(&mut settings.config, args) {
+            if let Some(pam_h) = pam_h.as_deref() {
+                let _ = pam_h.log(pam::LogLevel::Warning, format!("Ignoring {ignored}"));
+            }
+        }
+
+        // the nodelay argument overrides the configuration
+        if args.iter().any(|&carg| carg.to_bytes() == b"nodelay") {
+            settings.config.nodelay = true;
+        }
+
+        // root has its own free tries and lockout cap, over the arguments
+        if settings.user.as_ref().is_some_and(|user| user.uid() == 0) {
+            settings.config.apply_root_policy();
+        }
+
+        // the policy argument requests the policy summary
+        settings.policy = args.iter().any(|&carg| carg.to_bytes() == b"policy");
+
+        // the noclear argument opts the line out of clearing the tally
+        settings.noclear = args.iter().any(|&carg| carg.to_bytes() == b"noclear");
 
         // pam hook
         settings.pam_hook = pam_hook;
 
+        if let Some(pam_h) = pam_h.as_deref() {
+            settings.log_resolved(pam_h);
+        }
+
         Ok(settings)
     }
 
+    /// Logs the resolved action and the configuration values the delay depends on at debug level.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_h`: The PAM handle the log is sent with.
+    fn log_resolved<P: PamApi>(&self, pam_h: &P) {
+        let config = &self.config;
+        let _ = pam_h.log(
+            pam::LogLevel::Debug,
+            format!(
+                "Resolved the {:?} action for the {} hook with delay_algorithm={}, free_tries={}, base_delay_seconds={}, ramp_multiplier={}, max_lockout_seconds={}, countdown={}, nodelay={}.",
+                self.action.unwrap_or(Actions::AUTHSUCC),
+                self.pam_hook,
+                config.delay_algorithm.name(),
+                config.free_tries,
+                config.base_delay_seconds,
+                config.ramp_multiplier,
+                config.max_lockout_seconds,
+                config.countdown,
+                config.nodelay
+            ),
+        );
+    }
+
+    /// Sends the later logs of the transaction to the configured facility and backend, dropping
+    /// messages below `log_level`.
+    ///
+    /// Syslog messages are prefixed with the PAM service and hook, plus the remote host and
+    /// terminal if set, e.g. `pam_authramp(sshd:auth rhost=1.2.3.4 tty=ssh)`.
+    ///
+    /// With the journald backend the PAM service is attached to the log entries as
+    /// `AUTHRAMP_SERVICE`. Failures are logged and the logs stay with `pam_syslog` then.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_h`: The PAM handle the logs are sent with.
+    /// * `pam_hook`: The PAM hook named in the syslog prefix.
+    fn set_up_logging<P: PamApi>(&self, pam_h: &mut P, pam_hook: &str) {
+        if let Err(pam_code) = pam_h.set_log_level(self.config.log_level.level()) {
+            let _ = pam_h.log(
+                pam::LogLevel::Error,
+                format!("{pam_code}: Error setting the log level."),
+            );
+        }
+
+        if let Err(pam_code) =
+            pam_h.set_log_facility("pam_authramp", pam_hook, self.config.log_facility.code())
+        {
+            let _ = pam_h.log(
+                pam::LogLevel::Error,
+                format!("{pam_code}: Error setting the log facility."),
+            );
+        }
+
+        if self.config.log_backend == LogBackend::Journald {
+            if let Err(pam_code) = pam_h.set_log_journal("pam_authramp") {
+                let _ = pam_h.log(
+                    pam::LogLevel::Error,
+                    format!("{pam_code}: Error setting the journald log backend."),
+                );
+            }
+            if let Some(service) = &self.service {
+                pam_h.set_log_field("AUTHRAMP_SERVICE", service);
+            }
+        }
+    }
+
+    /// Loads the configuration file, `conf=<path>` if the module argument is set.
+    ///
+    /// A missing or unreadable file of the `conf` argument is logged as an error. The defaults
+    /// are used then, unless the `conf_missing=deny` argument is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The PAM module arguments.
+    /// * `pam_hook`: The PAM hook, to deny with the matching result code.
+    /// * `pam_h`: An optional mutable reference to a PAM handle for logging.
+    ///
+    /// # Returns
+    ///
+    /// The loaded `Config`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the `AuthRampError` of the `conf` file with `PAM_AUTH_ERR`, or `PAM_PERM_DENIED`
+    /// in the account hook, if it can't be read or parsed and `conf_missing=deny` is set.
+    fn load_config<P: PamApi>(
+        args: &[&CStr],
+        pam_hook: &str,
+        mut pam_h: Option<&mut P>,
+    ) -> Result<Config, AuthRampError> {
+        let Some(path) = argument(args, "conf") else {
+            return Ok(Config::load_file(None, pam_h));
+        };
+
+        let deny = match argument(args, "conf_missing") {
+            None | Some("defaults") => false,
+            Some("deny") => true,
+            Some(value) => {
+                if let Some(pam_h) = pam_h.as_deref() {
+                    let _ = pam_h.log(
+                        pam::LogLevel::Warning,
+                        format!(
+                            "Ignoring invalid value in module argument \"conf_missing={value}\""
+                        ),
+                    );
+                }
+                false
+            }
+        };
+
+        Config::try_load_file(path, pam_h.as_deref_mut()).or_else(|e| {
+            if deny {
+                return Err(e.with_code(deny_result(pam_hook)));
+            }
+
+            if let Some(pam_h) = pam_h.as_deref() {
+                let _ = pam_h.log(pam::LogLevel::Error, format!("{e}. Using the defaults."));
+            }
+            Ok(Config::default())
+        })
+    }
+
+    /// Resolves the PAM user, reusing the user of an earlier hook of the same transaction if
+    /// neither the PAM user name nor the configuration changed.
+    ///
+    /// The PAM user name is normalized according to `normalize_user` first, and a changed name
+    /// replaces the PAM user. The resolved user is cached on the handle with the configuration it
+    /// was resolved with.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_h`: The PAM handle to get the PAM user name from and to cache the user on.
+    /// * `config`: The loaded configuration, without the user overrides and arguments.
+    /// * `conf_arguments`: The `conf` and `conf_missing` arguments the configuration was loaded
+    ///   with.
+    ///
+    /// # Returns
+    ///
+    /// The resolved user, see [`Settings::lookup_user`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AuthRampError` with `PAM_AUTH_ERR` if the PAM user name can't be read, and
+    /// the errors of [`Settings::lookup_user`].
+    fn cached_user<P: PamApi>(
+        pam_h: Option<&mut P>,
+        config: &Config,
+        conf_arguments: (Option<String>, Option<String>),
+    ) -> Result<User, AuthRampError> {
+        let pam_h = pam_h
+            .ok_or_else(|| AuthRampError::user("Error resolving the PAM user", "no PAM handle"))?;
+        let submitted = pam_h.get_cached_user(None).map_err(|e| {
+            AuthRampError::user("Error reading the PAM user name", e.to_string())
+                .with_code(PamResultCode::PAM_AUTH_ERR)
+        })?;
+        let name = Self::normalize_user(pam_h, config, &submitted);
+
+        if let Some(cached) = pam_h
+            .get_data::<CachedSettings>(SETTINGS_CACHE)
+            .ok()
+            .flatten()
+            .filter(|cached| cached.conf_arguments == conf_arguments && cached.name == name)
+        {
+            return Ok(cached.user.clone());
+        }
+
+        let user = Self::lookup_user(pam_h, config, &name)?;
+
+        // the submitted name of unknown users may be a mistyped password
+        let unknown = config.user_lookup == UserLookup::Nss && user.uid() == NAME_ONLY_ID;
+        if name != submitted && !unknown {
+            let _ = pam_h.log(
+                pam::LogLevel::Info,
+                format!("Normalized the PAM user \"{submitted}\" to \"{name}\"."),
+            );
+        }
+
+        let cached = CachedSettings {
+            conf_arguments,
+            config: config.clone(),
+            name,
+            user: user.clone(),
+        };
+        if let Err(pam_code) = pam_h.set_data(SETTINGS_CACHE, cached) {
+            let _ = pam_h.log(
+                pam::LogLevel::Warning,
+                format!("{pam_code}: Error caching the settings."),
+            );
+        }
+        Ok(user)
+    }
+
+    /// Normalizes the PAM user name according to `normalize_user` and replaces the PAM user with
+    /// a changed name, so the modules after this one see the same user.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_h`: The PAM handle to replace the PAM user on.
+    /// * `config`: The loaded configuration.
+    /// * `submitted`: The PAM user name.
+    ///
+    /// # Returns
+    ///
+    /// The normalized name, also if the PAM user can't be replaced.
+    fn normalize_user<P: PamApi>(pam_h: &mut P, config: &Config, submitted: &str) -> String {
+        let name = config.normalize_user.apply(submitted);
+        if name == submitted {
+            return name;
+        }
+
+        let replaced = CString::new(name.as_str())
+            .map_err(|_| PamResultCode::PAM_SYSTEM_ERR)
+            .and_then(|c_name| pam_h.set_item(UserItem(&c_name)));
+        if let Err(pam_code) = replaced {
+            let _ = pam_h.log(
+                pam::LogLevel::Warning,
+                format!("{pam_code}: Error replacing the PAM user with the normalized name."),
+            );
+        }
+        name
+    }
+
+    /// Resolves the PAM user according to `user_lookup`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_h`: The PAM handle for logging.
+    /// * `config`: The loaded configuration.
+    /// * `name`: The PAM user name.
+    ///
+    /// # Returns
+    ///
+    /// The user from the NSS user database, or a name-only user if `user_lookup` is `none`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AuthRampError` if the user can't be resolved. Users missing from the user
+    /// database are handled by `unknown_user`.
+    fn lookup_user<P: PamApi>(
+        pam_h: &P,
+        config: &Config,
+        name: &str,
+    ) -> Result<User, AuthRampError> {
+        match config.user_lookup {
+            UserLookup::Nss => {
+                get_user_by_name(name).map_or_else(|| Self::unknown_user(pam_h, config, name), Ok)
+            }
+            UserLookup::None => {
+                NAME_ONLY_NOTICE.call_once(|| {
+                    let _ = pam_h.log(
+                        pam::LogLevel::Info,
+                        "user_lookup is \"none\": Tallies are keyed by the PAM user name, root is matched by name and exempt_groups is inactive.".to_string(),
+                    );
+                });
+                name_only_user(name).ok_or_else(|| {
+                    AuthRampError::user(
+                        "Rejected the PAM user name",
+                        "it can't be used as a tally file name",
+                    )
+                })
+            }
+        }
+    }
+
+    /// Handles a PAM user missing from the user database according to `unknown_user` and
+    /// `track_unknown_users`.
+    ///
+    /// The submitted name is never logged, it may be a mistyped password.
+    ///
+    /// # Arguments
+    ///
+    /// * `pam_h`: The PAM handle for logging.
+    /// * `config`: The loaded configuration.
+    /// * `name`: The PAM user name.
+    ///
+    /// # Returns
+    ///
+    /// The placeholder user the attempts are tallied under.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_IGNORE` if unknown users are ignored without tracking them, and an
+    /// `AuthRampError` if the salt can't be read.
+    fn unknown_user<P: PamApi>(
+        pam_h: &P,
+        config: &Config,
+        name: &str,
+    ) -> Result<User, AuthRampError> {
+        let deny = config.unknown_user == UnknownUser::Deny;
+        if !deny && !config.track_unknown_users {
+            let _ = pam_h.log(
+                pam::LogLevel::Info,
+                "PAM_IGNORE: User not found in the user database. Ignoring.".to_string(),
+            );
+            return Err(PamResultCode::PAM_IGNORE.into());
+        }
+
+        let salt = unknown::load_salt(&config.unknown_user_salt_file)
+            .map_err(|e| AuthRampError::invalid("Error loading the unknown user salt", e))?;
+
+        let user = unknown::placeholder_user(&salt, name);
+        let _ = pam_h.log(
+            pam::LogLevel::Info,
+            format!(
+                "{}User not found in the user database. Tallying under \"{}\".",
+                if deny { "PAM_AUTH_ERR: Denying. " } else { "" },
+                user.name().display()
+            ),
+        );
+        Ok(user)
+    }
+
+    /// Whether the PAM user is missing from the user database and tallied under a placeholder.
+    #[must_use]
+    pub fn is_unknown_user(&self) -> bool {
+        self.config.user_lookup == UserLookup::Nss
+            && self
+                .user
+                .as_ref()
+                .is_some_and(|user| user.uid() == NAME_ONLY_ID)
+    }
+
     /// Gets the PAM action associated with the current settings.
     ///
     /// # Returns
@@ -148,12 +637,243 @@ impl Settings<'_> {
     pub fn get_user(&self) -> Result<&User, PamResultCode> {
         self.user.as_ref().ok_or(PamResultCode::PAM_USER_UNKNOWN)
     }
+
+    /// Finds the `ignore_users` entry matching the user.
+    ///
+    /// `@group` entries are only matched if the user database is used.
+    ///
+    /// # Returns
+    ///
+    /// The matching entry, or `None` if the module acts on the user.
+    #[must_use]
+    pub fn ignored_user(&self) -> Option<String> {
+        let user = self.user.as_ref()?;
+        let name = user.name().to_string_lossy();
+        let groups = if self.config.user_lookup == UserLookup::None
+            || self.is_unknown_user()
+            || !self
+                .config
+                .ignore_users
+                .iter()
+                .any(|entry| entry.starts_with('@'))
+        {
+            Vec::new()
+        } else {
+            user_groups(user)
+        };
+        self.config.ignored_user(&name, &groups).map(str::to_string)
+    }
+
+    /// Finds the group exempting the PAM user from lockout.
+    ///
+    /// The group memberships are only resolved if `exempt_groups` is configured and the user
+    /// database is used.
+    ///
+    /// # Returns
+    ///
+    /// The name of the first primary or supplementary group of the user listed in
+    /// `exempt_groups`, or `None` if the user isn't exempt.
+    #[must_use]
+    pub fn exempt_group(&self) -> Option<String> {
+        if self.config.exempt_groups.is_empty()
+            || self.config.user_lookup == UserLookup::None
+            || self.is_unknown_user()
+        {
+            return None;
+        }
+
+        let groups = user_groups(self.user.as_ref()?);
+        self.config.exempt_group(&groups).map(str::to_string)
+    }
+}
+
+/// Creates a user from the PAM user name alone, without a user database.
+///
+/// The name is trimmed and lowercased, so case variants share a tally. Only the literal name
+/// `root` gets uid 0.
+///
+/// # Arguments
+///
+/// * `name`: The PAM user name.
+///
+/// # Returns
+///
+/// The name-only user, or `None` if the name is empty or can't be used as a tally file name.
+#[must_use]
+pub fn name_only_user(name: &str) -> Option<User> {
+    let name = name.trim().to_lowercase();
+
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.chars().any(|c| c == '/' || c.is_control())
+    {
+        return None;
+    }
+
+    let id = if name == "root" { 0 } else { NAME_ONLY_ID };
+    Some(User::new(id, &name, id))
+}
+
+/// Reads module data stored on the PAM handle by an earlier line of the transaction.
+///
+/// # Arguments
+///
+/// * `pam_h`: The PAM handle, if any.
+/// * `key`: The key of the module data.
+///
+/// # Returns
+///
+/// A copy of the data, `None` if it isn't set.
+fn module_data<T: Copy + 'static, P: PamApi>(pam_h: Option<&P>, key: &str) -> Option<T> {
+    pam_h.and_then(|pam_h| pam_h.get_data::<T>(key).ok().flatten().copied())
+}
+
+/// Resolves the names of the primary and supplementary groups of a user.
+///
+/// `uzers::User::groups` gives up on users with more than 1024 groups, so the group list is
+/// fetched with `getgrouplist` and a buffer grown until every group fits.
+///
+/// # Arguments
+///
+/// * `user`: The user to resolve the groups of.
+///
+/// # Returns
+///
+/// The group names. The primary group is always included, even if the group database
+/// can't be queried.
+fn user_groups(user: &User) -> Vec<String> {
+    let primary_gid = user.primary_group_id();
+    let mut gids = vec![primary_gid];
+
+    if let Ok(name) = CString::new(user.name().as_bytes()) {
+        let mut buffer: Vec<libc::gid_t> = vec![0; 64];
+        loop {
+            let mut count = libc::c_int::try_from(buffer.len()).unwrap_or(libc::c_int::MAX);
+            let res = unsafe {
+                libc::getgrouplist(
+                    name.as_ptr(),
+                    primary_gid,
+                    buffer.as_mut_ptr(),
+                    &raw mut count,
+                )
+            };
+
+            if res >= 0 {
+                buffer.truncate(usize::try_from(count).unwrap_or_default());
+                gids.extend(buffer);
+                break;
+            }
+
+            // count holds the required size, fall back to doubling if it doesn't grow
+            let required = usize::try_from(count).unwrap_or_default();
+            let size = required.max(buffer.len() * 2);
+            if size > 65536 {
+                break;
+            }
+            buffer.resize(size, 0);
+        }
+    }
+
+    gids.sort_unstable();
+    gids.dedup();
+    gids.into_iter()
+        .filter_map(get_group_by_gid)
+        .map(|group| group.name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Applies the `key=value` module arguments over the configuration.
+///
+/// Supported keys are `free_tries`, `base_delay`, `ramp_multiplier`, `tally_dir` and
+/// `even_deny_root`, like the `deny=3` arguments of `pam_faillock`.
+///
+/// # Arguments
+///
+/// * `config`: The configuration to override.
+/// * `args`: The PAM module arguments.
+///
+/// # Returns
+///
+/// The descriptions of unknown arguments and invalid values, which are ignored.
+fn apply_arguments(config: &mut Config, args: &[&CStr]) -> Vec<String> {
+    let mut ignored = Vec::new();
+
+    for arg in args.iter().map(|carg| carg.to_string_lossy()) {
+        if FLAG_ARGUMENTS.contains(&arg.as_ref()) {
+            continue;
+        }
+
+        let Some((key, value)) = arg.split_once('=') else {
+            ignored.push(format!("unknown module argument \"{arg}\""));
+            continue;
+        };
+
+        let valid = match key {
+            "free_tries" => parse_into(value, &mut config.free_tries),
+            "base_delay" => parse_into(value, &mut config.base_delay_seconds),
+            "ramp_multiplier" => match value.parse::<f64>() {
+                Ok(ramp_multiplier) if ramp_multiplier.is_finite() => {
+                    config.ramp_multiplier = ramp_multiplier;
+                    true
+                }
+                _ => false,
+            },
+            "tally_dir" if !value.is_empty() => {
+                config.tally_dir = PathBuf::from(value);
+                true
+            }
+            "tally_dir" => false,
+            "even_deny_root" => parse_into(value, &mut config.even_deny_root),
+            // read before the configuration is loaded
+            "conf" | "conf_missing" => continue,
+            _ => {
+                ignored.push(format!("unknown module argument \"{arg}\""));
+                continue;
+            }
+        };
+
+        if !valid {
+            ignored.push(format!("invalid value in module argument \"{arg}\""));
+        }
+    }
+
+    ignored
+}
+
+/// Parses a module argument value into a configuration value.
+///
+/// # Returns
+///
+/// `true` if the value was valid and stored.
+fn parse_into<T: FromStr>(value: &str, target: &mut T) -> bool {
+    match value.parse() {
+        Ok(value) => {
+            *target = value;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Gets the value of a `key=value` module argument.
+///
+/// # Returns
+///
+/// The value of the first argument with the key, `None` if there is none.
+fn argument<'a>(args: &[&'a CStr], key: &str) -> Option<&'a str> {
+    args.iter().find_map(|&carg| {
+        carg.to_str()
+            .ok()
+            .and_then(|arg| arg.strip_prefix(key)?.strip_prefix('='))
+    })
 }
 
 // Unit Tests
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pam::PamHandle;
 
     #[test]
     fn test_default_settings() {
@@ -166,7 +886,7 @@ mod tests {
     fn test_build_settings_missing_action() {
         let args = vec![];
         let flags: PamFlag = 0;
-        let result = Settings::build(
+        let result = Settings::build::<PamHandle>(
             Some(User::new(9999, "test_user", 9999)),
             &args,
             flags,
@@ -176,12 +896,376 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_build_settings_nodelay_argument() {
+        let args = [
+            CStr::from_bytes_with_nul("preauth\0".as_bytes()).unwrap(),
+            CStr::from_bytes_with_nul("nodelay\0".as_bytes()).unwrap(),
+        ]
+        .to_vec();
+        let flags: PamFlag = 0;
+        let settings = Settings::build::<PamHandle>(
+            Some(User::new(9999, "test_user", 9999)),
+            &args,
+            flags,
+            "test",
+            None,
+        )
+        .unwrap();
+        assert_eq!(settings.action, Some(Actions::PREAUTH));
+        assert!(settings.config.nodelay);
+        assert!(!settings.noclear);
+    }
+
+    #[test]
+    fn test_build_settings_noclear_argument() {
+        let args = [CStr::from_bytes_with_nul("noclear\0".as_bytes()).unwrap()].to_vec();
+        let flags: PamFlag = 0;
+        let settings = Settings::build::<PamHandle>(
+            Some(User::new(9999, "test_user", 9999)),
+            &args,
+            flags,
+            "account",
+            None,
+        )
+        .unwrap();
+        assert_eq!(settings.action, Some(Actions::AUTHSUCC));
+        assert!(settings.noclear);
+    }
+
+    #[test]
+    fn test_apply_arguments_precedence() {
+        let temp_dir = tempdir::TempDir::new("test_apply_arguments_precedence").unwrap();
+        let conf_file_path = temp_dir.path().join("authramp.conf");
+        std::fs::write(
+            &conf_file_path,
+            "[Configuration]\nfree_tries = 10\nbase_delay_seconds = 15\ntally_dir = \"/tmp/conf\"",
+        )
+        .unwrap();
+        let mut config =
+            Config::load_file::<PamHandle>(Some(conf_file_path.to_str().unwrap()), None);
+
+        let args = [
+            c"preauth",
+            c"free_tries=3",
+            c"ramp_multiplier=1.5",
+            c"even_deny_root=true",
+            c"deny=3",
+            c"base_delay=soon",
+            c"ramp_multiplier=NaN",
+            c"tally_dir=",
+            c"verbose",
+        ];
+        let ignored = apply_arguments(&mut config, &args);
+
+        // module argument > config file > default
+        assert_eq!(config.free_tries, 3);
+        assert_eq!(config.base_delay_seconds, 15);
+        assert_eq!(config.tally_dir, PathBuf::from("/tmp/conf"));
+        assert!((config.ramp_multiplier - 1.5).abs() < f64::EPSILON);
+        assert!(config.even_deny_root);
+        assert_eq!(config.max_lockout_seconds, 86400);
+
+        // unknown arguments and invalid values are ignored
+        assert_eq!(
+            ignored,
+            vec![
+                "unknown module argument \"deny=3\"",
+                "invalid value in module argument \"base_delay=soon\"",
+                "invalid value in module argument \"ramp_multiplier=NaN\"",
+                "invalid value in module argument \"tally_dir=\"",
+                "unknown module argument \"verbose\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_settings_argument_overrides_user_override() {
+        let args = [c"authfail", c"free_tries=3", c"tally_dir=/tmp/authramp"];
+        let mut settings = Settings::build::<PamHandle>(
+            Some(User::new(9999, "test_user", 9999)),
+            &args,
+            0,
+            "auth",
+            None,
+        )
+        .unwrap();
+        assert_eq!(settings.action, Some(Actions::AUTHFAIL));
+        assert_eq!(settings.config.free_tries, 3);
+        assert_eq!(settings.config.tally_dir, PathBuf::from("/tmp/authramp"));
+
+        // the arguments apply after the [user.<name>] overrides
+        settings.config.user_overrides.insert(
+            "test_user".to_string(),
+            crate::config::UserOverride {
+                free_tries: Some(20),
+                ..Default::default()
+            },
+        );
+        settings.config.apply_user_override("test_user");
+        apply_arguments(&mut settings.config, &args);
+        assert_eq!(settings.config.free_tries, 3);
+    }
+
+    #[test]
+    fn test_build_settings_root_policy() {
+        let temp_dir = tempdir::TempDir::new("test_build_settings_root_policy").unwrap();
+        let conf_file_path = temp_dir.path().join("authramp.conf");
+        std::fs::write(
+            &conf_file_path,
+            "[Configuration]\nfree_tries = 2\nroot_free_tries = 10\nroot_max_lockout_seconds = 60",
+        )
+        .unwrap();
+        let conf_arg = CString::new(format!("conf={}", conf_file_path.display())).unwrap();
+        let build = |user: User, args: &[&CStr]| {
+            Settings::build::<PamHandle>(Some(user), args, 0, "auth", None)
+                .unwrap()
+                .config
+        };
+
+        // the root values apply to uid 0 only
+        let root = build(User::new(0, "root", 0), &[c"authfail", &conf_arg]);
+        assert_eq!(root.free_tries, 10);
+        assert_eq!(root.max_lockout_seconds, 60);
+        let user = build(
+            User::new(9999, "test_user", 9999),
+            &[c"authfail", &conf_arg],
+        );
+        assert_eq!(user.free_tries, 2);
+        assert_eq!(user.max_lockout_seconds, 86400);
+
+        // and take precedence over the module arguments
+        let root = build(
+            User::new(0, "root", 0),
+            &[c"authfail", &conf_arg, c"free_tries=3"],
+        );
+        assert_eq!(root.free_tries, 10);
+
+        // without root values root shares the global values
+        let root = build(User::new(0, "root", 0), &[c"authfail", c"free_tries=3"]);
+        assert_eq!(root.free_tries, 3);
+        assert_eq!(root.max_lockout_seconds, 86400);
+    }
+
+    #[test]
+    fn test_build_settings_hook_override() {
+        let temp_dir = tempdir::TempDir::new("test_build_settings_hook_override").unwrap();
+        let conf_file_path = temp_dir.path().join("authramp.conf");
+        std::fs::write(
+            &conf_file_path,
+            "[Configuration]\nfree_tries = 6\ncountdown = true\n\n[account]\nfree_tries = 2\ncountdown = false",
+        )
+        .unwrap();
+        let conf_arg = CString::new(format!("conf={}", conf_file_path.display())).unwrap();
+        let build = |hook: &'static str| {
+            Settings::build::<PamHandle>(
+                Some(User::new(9999, "test_user", 9999)),
+                &[c"preauth", &conf_arg],
+                0,
+                hook,
+                None,
+            )
+            .unwrap()
+        };
+        let auth = build("auth");
+        let account = build("account");
+        assert!(auth.config.countdown);
+        assert!(!account.config.countdown);
+
+        // 4 failures are free in the auth hook, the account hook enforces its stricter override
+        let tally = crate::tally::Tally {
+            failures_count: 4,
+            failure_instant: chrono::Utc::now(),
+            ..Default::default()
+        };
+        assert_eq!(tally.effective_unlock_instant(&auth), None);
+        assert!(tally
+            .effective_unlock_instant(&account)
+            .is_some_and(|unlock_instant| chrono::Utc::now() < unlock_instant));
+    }
+
+    #[test]
+    fn test_load_config_argument() {
+        let temp_dir = tempdir::TempDir::new("test_load_config_argument").unwrap();
+        let conf_file_path = temp_dir.path().join("authramp-sshd.conf");
+        std::fs::write(&conf_file_path, "[Configuration]\nfree_tries = 2").unwrap();
+        let conf_arg = CString::new(format!("conf={}", conf_file_path.display())).unwrap();
+
+        let settings = Settings::build::<PamHandle>(
+            Some(User::new(9999, "test_user", 9999)),
+            &[c"preauth", &conf_arg],
+            0,
+            "auth",
+            None,
+        )
+        .unwrap();
+        assert_eq!(settings.config.free_tries, 2);
+
+        // key=value arguments still take precedence
+        let settings = Settings::build::<PamHandle>(
+            Some(User::new(9999, "test_user", 9999)),
+            &[c"preauth", &conf_arg, c"free_tries=4"],
+            0,
+            "auth",
+            None,
+        )
+        .unwrap();
+        assert_eq!(settings.config.free_tries, 4);
+    }
+
+    #[test]
+    fn test_load_config_argument_missing() {
+        let temp_dir = tempdir::TempDir::new("test_load_config_argument_missing").unwrap();
+        let conf_arg = CString::new(format!(
+            "conf={}",
+            temp_dir.path().join("missing.conf").display()
+        ))
+        .unwrap();
+
+        // falls back to the defaults
+        let config = Settings::load_config::<PamHandle>(&[&conf_arg], "auth", None).unwrap();
+        assert_eq!(config.free_tries, Config::default().free_tries);
+        let config = Settings::load_config::<PamHandle>(
+            &[&conf_arg, c"conf_missing=defaults"],
+            "auth",
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.free_tries, Config::default().free_tries);
+
+        // or fails closed
+        assert_eq!(
+            Settings::load_config::<PamHandle>(&[&conf_arg, c"conf_missing=deny"], "auth", None)
+                .unwrap_err()
+                .code(),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        assert_eq!(
+            Settings::load_config::<PamHandle>(&[&conf_arg, c"conf_missing=deny"], "account", None)
+                .unwrap_err()
+                .code(),
+            PamResultCode::PAM_PERM_DENIED
+        );
+    }
+
+    #[test]
+    fn test_is_unknown_user() {
+        let user = unknown::placeholder_user(b"salt", "nobody-here");
+        let name = user.name().to_os_string();
+
+        let mut settings = Settings {
+            user: Some(user),
+            ..Settings::default()
+        };
+        assert!(settings.is_unknown_user());
+
+        // placeholders are keyed by name, even with tally_key = "uid"
+        settings.config.tally_key = crate::config::TallyKey::Uid;
+        assert_eq!(
+            crate::tally::user_tally_file(&settings.config, settings.get_user().unwrap()),
+            Ok(settings.config.tally_dir.join(&name))
+        );
+
+        // name-only users aren't unknown
+        settings.config.user_lookup = UserLookup::None;
+        assert!(!settings.is_unknown_user());
+        settings.config.user_lookup = UserLookup::Nss;
+        settings.user = Some(User::new(9999, "test_user", 9999));
+        assert!(!settings.is_unknown_user());
+    }
+
+    #[test]
+    fn test_exempt_group_primary_group() {
+        let root = uzers::get_user_by_uid(0).expect("root user");
+        let root_group = get_group_by_gid(root.primary_group_id())
+            .expect("root group")
+            .name()
+            .to_string_lossy()
+            .into_owned();
+
+        let mut settings = Settings {
+            user: Some(root),
+            ..Settings::default()
+        };
+        settings.config.exempt_groups = Vec::new();
+        assert_eq!(settings.exempt_group(), None);
+
+        settings.config.exempt_groups = vec!["no_such_group".to_string(), root_group.clone()];
+        assert_eq!(settings.exempt_group(), Some(root_group));
+
+        // unknown users are only matched by their primary group
+        settings.user = Some(User::new(9999, "test_user", 0));
+        assert!(settings.exempt_group().is_some());
+    }
+
+    #[test]
+    fn test_ignored_user() {
+        let root = uzers::get_user_by_uid(0).expect("root user");
+        let root_group = get_group_by_gid(root.primary_group_id())
+            .expect("root group")
+            .name()
+            .to_string_lossy()
+            .into_owned();
+
+        let mut settings = Settings {
+            user: Some(User::new(9999, "zabbix", 9999)),
+            ..Settings::default()
+        };
+        assert_eq!(settings.ignored_user(), None);
+
+        settings.config.ignore_users = vec!["zabbix".to_string(), format!("@{root_group}")];
+        assert_eq!(settings.ignored_user(), Some("zabbix".to_string()));
+
+        settings.user = Some(root);
+        assert_eq!(settings.ignored_user(), Some(format!("@{root_group}")));
+
+        // groups aren't resolved without the user database
+        settings.config.user_lookup = UserLookup::None;
+        assert_eq!(settings.ignored_user(), None);
+    }
+
+    #[test]
+    fn test_name_only_user() {
+        let user = name_only_user(" App_User ").unwrap();
+        assert_eq!(user.name(), "app_user");
+        assert_eq!(user.uid(), NAME_ONLY_ID);
+
+        // root is matched by name
+        assert_eq!(name_only_user("root").unwrap().uid(), 0);
+        assert_eq!(name_only_user("Root").unwrap().uid(), 0);
+
+        // names unusable as tally file names are rejected
+        for name in ["", "  ", ".", "..", "../etc/passwd", "a/b", "a\nb"] {
+            assert!(name_only_user(name).is_none(), "{name:?}");
+        }
+
+        // group features are inactive
+        let mut settings = Settings {
+            user: Some(name_only_user("root").unwrap()),
+            ..Settings::default()
+        };
+        settings.config.exempt_groups = vec!["root".to_string()];
+        settings.config.user_lookup = UserLookup::None;
+        assert_eq!(settings.exempt_group(), None);
+    }
+
     #[test]
     fn test_build_settings_missing_user() {
         let args = [CStr::from_bytes_with_nul("preauth\0".as_bytes()).unwrap()].to_vec();
         let flags: PamFlag = 0;
-        let result = Settings::build(None, &args, flags, "test", None);
+        let result = Settings::build::<PamHandle>(None, &args, flags, "test", None);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), PamResultCode::PAM_USER_UNKNOWN);
+        assert_eq!(result.unwrap_err().code(), PamResultCode::PAM_IGNORE);
+
+        // fail_mode = "closed" denies
+        let temp_dir = tempdir::TempDir::new("test_build_settings_missing_user").unwrap();
+        let conf_file_path = temp_dir.path().join("authramp.conf");
+        std::fs::write(&conf_file_path, "[Configuration]\nfail_mode = \"closed\"").unwrap();
+        let conf_arg = CString::new(format!("conf={}", conf_file_path.display())).unwrap();
+
+        let result = Settings::build::<PamHandle>(None, &[args[0], &conf_arg], flags, "auth", None);
+        assert_eq!(result.unwrap_err().code(), PamResultCode::PAM_AUTH_ERR);
+        let result = Settings::build::<PamHandle>(None, &[&conf_arg], flags, "account", None);
+        assert_eq!(result.unwrap_err().code(), PamResultCode::PAM_PERM_DENIED);
     }
 }
@@ -0,0 +1,145 @@
+//! # State File Module
+//!
+//! The `state_file` module writes the files `AuthRamp` keeps its state in, like the tallies and
+//! the stats file. A file is written to a hidden temporary file next to it and renamed over the
+//! old one, so a crash never leaves a truncated file behind.
+//!
+//! With `durable_writes`, the temporary file is flushed to disk before the rename and the
+//! directory after it, so a lockout survives a power loss right after it was recorded.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// The hidden temporary file a state file is written to before the rename.
+///
+/// The name starts with a dot, so listings of the tally directory skip it.
+fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no file name", path.display()),
+        )
+    })?;
+    Ok(path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        process::id()
+    )))
+}
+
+/// Replaces a state file atomically.
+///
+/// # Arguments
+/// - `path`: The file to write
+/// - `contents`: The new contents of the file
+/// - `mode`: The permissions of the file
+/// - `durable`: Whether the file and the directory entry are flushed to disk before returning
+///
+/// # Errors
+/// Returns an error if the file can't be written, synced or renamed. The temporary file is
+/// removed again then.
+pub fn write(path: &Path, contents: &[u8], mode: u32, durable: bool) -> io::Result<()> {
+    let temp_path = temp_path(path)?;
+
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&temp_path)
+        .and_then(|mut file| {
+            // the mode of open is masked by the umask
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+            file.write_all(contents)?;
+            if durable {
+                file.sync_all()?;
+            }
+            Ok(())
+        })
+        .and_then(|()| fs::rename(&temp_path, path));
+
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    // the rename is only durable once the directory is
+    if durable {
+        if let Some(parent_dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(parent_dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_write() {
+        let temp_dir = TempDir::new("test_state_file_write").unwrap();
+        let path = temp_dir.path().join("state");
+
+        for durable in [true, false] {
+            // created and replaced, without leaving the temporary file behind
+            write(&path, b"first", 0o600, durable).unwrap();
+            write(&path, b"second", 0o600, durable).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+            assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+        }
+
+        // the mode of the old file isn't kept
+        write(&path, b"third", 0o644, true).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+    }
+
+    #[test]
+    fn test_write_failure() {
+        let temp_dir = TempDir::new("test_state_file_write_failure").unwrap();
+
+        // a missing directory fails without creating anything
+        let path = temp_dir.path().join("missing").join("state");
+        assert!(write(&path, b"state", 0o600, true).is_err());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        // a directory in the way fails the rename, the temporary file is removed
+        let path = temp_dir.path().join("state");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("entry"), "").unwrap();
+        assert!(write(&path, b"state", 0o600, false).is_err());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,298 @@
+//! # Stats Module
+//!
+//! The `stats` module keeps anonymous usage statistics of the `AuthRamp` PAM module. When a tally
+//! with recorded failures is cleared, the number of failures and the time from the first failure
+//! to the eventual success are counted into bounded histograms per PAM service. Only bucket counts
+//! are stored, never user names.
+//!
+//! ## Stats File
+//!
+//! The statistics are stored in a TOML file, `stats_file` in the configuration:
+//!
+//! ```toml
+//! [Histograms.sshd]
+//! failures = [0, 3, 1, 0, 0, 0, 0, 0, 0, 0]
+//! seconds = [2, 1, 1, 0, 0, 0, 0, 0, 0, 0]
+//! ```
+//!
+//! Each array holds one count per bucket of [`FAILURE_BUCKETS`] and [`SECONDS_BUCKETS`], plus a
+//! final overflow bucket.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::state_file;
+
+/// Inclusive upper edges of the "failures before success" buckets.
+pub const FAILURE_BUCKETS: [i64; 9] = [1, 2, 3, 4, 6, 8, 12, 20, 50];
+
+/// Inclusive upper edges of the "seconds from first failure to success" buckets.
+pub const SECONDS_BUCKETS: [i64; 9] = [10, 30, 60, 300, 900, 3600, 14400, 86400, 604_800];
+
+/// Returns the index of the bucket a value falls into.
+///
+/// Values above the last edge fall into the overflow bucket at index `edges.len()`.
+#[must_use]
+pub fn bucket_index(edges: &[i64], value: i64) -> usize {
+    edges
+        .iter()
+        .position(|&edge| value <= edge)
+        .unwrap_or(edges.len())
+}
+
+/// A histogram holding one count per bucket plus an overflow bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Creates an empty histogram for the given bucket edges.
+    #[must_use]
+    pub fn new(edges: &[i64]) -> Self {
+        Histogram {
+            counts: vec![0; edges.len() + 1],
+        }
+    }
+
+    /// Counts a value into its bucket.
+    pub fn record(&mut self, edges: &[i64], value: i64) {
+        let index = bucket_index(edges, value);
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+    }
+
+    /// Adds the counts of another histogram with the same bucket edges.
+    pub fn merge(&mut self, other: &Histogram) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+    }
+
+    /// Returns the total number of recorded values.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// The histograms recorded for a single PAM service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceHistograms {
+    /// Failures cleared by a successful authentication.
+    pub failures: Histogram,
+    /// Seconds from the first failure to the successful authentication.
+    pub seconds: Histogram,
+}
+
+impl Default for ServiceHistograms {
+    fn default() -> Self {
+        ServiceHistograms {
+            failures: Histogram::new(&FAILURE_BUCKETS),
+            seconds: Histogram::new(&SECONDS_BUCKETS),
+        }
+    }
+}
+
+/// The `Stats` struct represents the content of the stats file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    /// Histograms keyed by PAM service.
+    pub histograms: BTreeMap<String, ServiceHistograms>,
+}
+
+impl Stats {
+    /// Loads the stats file. A missing file yields empty stats.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or parsed.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Stats::default()),
+            Err(e) => return Err(format!("{e:?}")),
+        };
+
+        let toml_stats = toml::from_str::<toml::Value>(&content).map_err(|e| format!("{e}"))?;
+
+        let counts = |value: &toml::Value, key: &str| Histogram {
+            counts: value
+                .get(key)
+                .and_then(toml::Value::as_array)
+                .map(|counts| {
+                    counts
+                        .iter()
+                        .map(|count| count.as_integer().unwrap_or_default().unsigned_abs())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+
+        let mut stats = Stats::default();
+        if let Some(histograms) = toml_stats.get("Histograms").and_then(|v| v.as_table()) {
+            for (service, value) in histograms {
+                let mut service_histograms = ServiceHistograms::default();
+                service_histograms
+                    .failures
+                    .merge(&counts(value, "failures"));
+                service_histograms.seconds.merge(&counts(value, "seconds"));
+                stats.histograms.insert(service.clone(), service_histograms);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Replaces the stats file atomically, creating the parent directory if needed.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be written.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let to_array = |histogram: &Histogram| {
+            toml::Value::Array(
+                histogram
+                    .counts
+                    .iter()
+                    .map(|&count| toml::Value::Integer(i64::try_from(count).unwrap_or(i64::MAX)))
+                    .collect(),
+            )
+        };
+
+        let mut histograms = toml::value::Table::new();
+        for (service, service_histograms) in &self.histograms {
+            let mut table = toml::value::Table::new();
+            table.insert("failures".into(), to_array(&service_histograms.failures));
+            table.insert("seconds".into(), to_array(&service_histograms.seconds));
+            histograms.insert(service.clone(), toml::Value::Table(table));
+        }
+
+        let mut toml_stats = toml::value::Table::new();
+        toml_stats.insert("Histograms".into(), toml::Value::Table(histograms));
+
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir).map_err(|e| format!("{e:?}"))?;
+        }
+
+        let content = toml::to_string(&toml_stats).map_err(|e| format!("{e}"))?;
+        state_file::write(path, content.as_bytes(), 0o644, false).map_err(|e| format!("{e:?}"))
+    }
+
+    /// Records a cleared tally into the histograms of a service.
+    ///
+    /// # Arguments
+    /// - `service`: The PAM service the successful authentication happened in.
+    /// - `failures`: The number of failures that got cleared.
+    /// - `seconds`: The seconds from the first failure to the successful authentication.
+    pub fn record_clear(&mut self, service: &str, failures: i64, seconds: i64) {
+        let service_histograms = self.histograms.entry(service.to_string()).or_default();
+        service_histograms
+            .failures
+            .record(&FAILURE_BUCKETS, failures);
+        service_histograms.seconds.record(&SECONDS_BUCKETS, seconds);
+    }
+
+    /// Adds the histograms of other stats.
+    pub fn merge(&mut self, other: &Stats) {
+        for (service, other_histograms) in &other.histograms {
+            let service_histograms = self.histograms.entry(service.clone()).or_default();
+            service_histograms
+                .failures
+                .merge(&other_histograms.failures);
+            service_histograms.seconds.merge(&other_histograms.seconds);
+        }
+    }
+}
+
+/// Records a cleared tally into the stats file.
+///
+/// # Errors
+/// Returns an error if the stats file cannot be read or written.
+pub fn record_clear(path: &Path, service: &str, failures: i64, seconds: i64) -> Result<(), String> {
+    let mut stats = Stats::load(path)?;
+    stats.record_clear(service, failures, seconds);
+    stats.save(path)
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_bucket_index() {
+        assert_eq!(bucket_index(&FAILURE_BUCKETS, 0), 0);
+        assert_eq!(bucket_index(&FAILURE_BUCKETS, 1), 0);
+        assert_eq!(bucket_index(&FAILURE_BUCKETS, 5), 4);
+        assert_eq!(bucket_index(&FAILURE_BUCKETS, 6), 4);
+        assert_eq!(bucket_index(&FAILURE_BUCKETS, 50), 8);
+        assert_eq!(bucket_index(&FAILURE_BUCKETS, 51), 9);
+        assert_eq!(bucket_index(&SECONDS_BUCKETS, 45), 2);
+        assert_eq!(bucket_index(&SECONDS_BUCKETS, 1_000_000), 9);
+    }
+
+    #[test]
+    fn test_histogram_merge() {
+        let mut a = Histogram::new(&FAILURE_BUCKETS);
+        a.record(&FAILURE_BUCKETS, 1);
+        a.record(&FAILURE_BUCKETS, 100);
+
+        let mut b = Histogram::new(&FAILURE_BUCKETS);
+        b.record(&FAILURE_BUCKETS, 1);
+        b.record(&FAILURE_BUCKETS, 7);
+
+        a.merge(&b);
+        assert_eq!(a.counts, vec![2, 0, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(a.total(), 4);
+
+        // merging a shorter histogram keeps the length
+        a.merge(&Histogram { counts: vec![1] });
+        assert_eq!(a.counts.len(), FAILURE_BUCKETS.len() + 1);
+        assert_eq!(a.counts[0], 3);
+
+        let mut stats = Stats::default();
+        stats.record_clear("sshd", 2, 20);
+        let mut other = Stats::default();
+        other.record_clear("sshd", 2, 20);
+        other.record_clear("login", 1, 5);
+        stats.merge(&other);
+        assert_eq!(stats.histograms["sshd"].failures.counts[1], 2);
+        assert_eq!(stats.histograms["login"].seconds.counts[0], 1);
+    }
+
+    #[test]
+    fn test_record_clear_roundtrip() {
+        let temp_dir = TempDir::new("test_record_clear_roundtrip").unwrap();
+        let stats_file = temp_dir.path().join("stats").join("stats.toml");
+
+        record_clear(&stats_file, "sshd", 3, 45).unwrap();
+        record_clear(&stats_file, "sshd", 3, 4000).unwrap();
+
+        let stats = Stats::load(&stats_file).unwrap();
+        assert_eq!(stats.histograms["sshd"].failures.counts[2], 2);
+        assert_eq!(stats.histograms["sshd"].seconds.counts[2], 1);
+        assert_eq!(stats.histograms["sshd"].seconds.counts[6], 1);
+    }
+}
@@ -0,0 +1,3602 @@
+//! # Tally Module
+//!
+//! The `tally` module manages the account lockout status, including the number of authentication
+//! failures, the timestamp of the last failure, and the unlock time. It provides functionality to open
+//! and update the tally based on authentication actions performed.
+//!
+//! ## Overview
+//!
+//! The `Tally` struct represents the account lockout information, and the module provides
+//! functionality to interact with this information, updating it based on authentication results.
+//!
+//! ## Tally Structure
+//!
+//! The `Tally` struct has the following fields:
+//!
+//! - `tally_file`: An optional `PathBuf` representing the path to the file storing tally information.
+//! - `failures_count`: An integer representing the number of authentication failures.
+//! - `failure_instant`: A `DateTime<Utc>` representing the timestamp of the last authentication failure.
+//! - `first_failure_instant`: An optional `DateTime<Utc>` representing the timestamp of the first failure since the last clear.
+//! - `unlock_instant`: An optional `DateTime<Utc>` representing the time when the account will be unlocked.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+    cmp::min,
+    ffi::OsStr,
+    fmt::Write,
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{chown, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use pam::{PamApi, PamResultCode};
+use sha2::{Digest, Sha256};
+use uzers::User;
+
+use crate::actions::Actions;
+use crate::audit;
+use crate::config::{Config, DelayAlgorithm, FailMode, TallyKey, TallyLayout, UserLookup};
+use crate::dbus::{self, LockoutSignal};
+use crate::error::AuthRampError;
+use crate::hook::{self, HookContext, HookEvent};
+use crate::integrity::{self, Integrity};
+use crate::notify::{self, Notification, NotifyEvent};
+use crate::settings::{Settings, NAME_ONLY_ID};
+use crate::state_file;
+use crate::stats;
+
+/// Key of the PAM module data counting the failures recorded in the current transaction.
+pub const TRANSACTION_MARKER: &str = "pam_authramp_transaction_failures";
+
+/// Key of the PAM module data marking that a success already settled the tally in the current transaction.
+pub const SUCCESS_MARKER: &str = "pam_authramp_transaction_success";
+
+/// Key of the PAM module data marking whether the failure of the current attempt was recorded.
+/// A `preauth` line starts the attempt.
+pub const ATTEMPT_MARKER: &str = "pam_authramp_attempt_counted";
+
+/// Version of the tally file format written by this release.
+pub const TALLY_VERSION: u32 = 1;
+
+/// Builds the path of the tally file of a user.
+///
+/// User names are used as file names, so names that could escape the tally directory are
+/// rejected: empty names, names starting with a dot, and names containing `/`, `..` or NUL.
+///
+/// # Arguments
+/// - `tally_dir`: The directory of the tally files.
+/// - `user`: The name of the user.
+///
+/// # Returns
+/// The path of the tally file.
+///
+/// # Errors
+/// Returns a message describing why the name was rejected.
+pub fn tally_file_path(tally_dir: &Path, user: &OsStr) -> Result<PathBuf, String> {
+    let name = user.as_bytes();
+
+    let rejected = if name.is_empty() {
+        Some("the name is empty")
+    } else if name.starts_with(b".") {
+        Some("the name starts with a dot")
+    } else if name.contains(&b'/') {
+        Some("the name contains a slash")
+    } else if name.contains(&0) {
+        Some("the name contains a NUL byte")
+    } else if name.windows(2).any(|pair| pair == b"..") {
+        Some("the name contains \"..\"")
+    } else {
+        None
+    };
+
+    match rejected {
+        Some(reason) => Err(reason.to_string()),
+        None => Ok(tally_dir.join(user)),
+    }
+}
+
+/// The subdirectory of a tally file in the `sharded` layout: the first two hex digits of the
+/// SHA-256 of the file name.
+///
+/// # Arguments
+/// - `file_name`: The name of the tally file.
+///
+/// # Returns
+/// The name of the shard directory.
+#[must_use]
+pub fn shard(file_name: &OsStr) -> String {
+    format!("{:02x}", Sha256::digest(file_name.as_bytes())[0])
+}
+
+/// Places a tally file name in the tally directory according to a layout.
+fn layout_path(tally_dir: &Path, file_name: &OsStr, layout: TallyLayout) -> PathBuf {
+    match layout {
+        TallyLayout::Flat => tally_dir.join(file_name),
+        TallyLayout::Sharded => tally_dir.join(shard(file_name)).join(file_name),
+    }
+}
+
+/// Builds the path of a tally file by its file name according to `tally_layout`.
+///
+/// # Arguments
+/// - `config`: The loaded configuration.
+/// - `file_name`: The name of the tally file, a user name or a uid.
+///
+/// # Returns
+/// The path of the tally file.
+///
+/// # Errors
+/// Returns a message if the name can't be used as a tally file name, see [`tally_file_path`].
+pub fn layout_tally_file(config: &Config, file_name: &OsStr) -> Result<PathBuf, String> {
+    tally_file_path(&config.tally_dir, file_name)?;
+    Ok(layout_path(
+        &config.tally_dir,
+        file_name,
+        config.tally_layout,
+    ))
+}
+
+/// The layout a tally may have been written in before `tally_layout` was switched.
+fn other_layout(layout: TallyLayout) -> TallyLayout {
+    match layout {
+        TallyLayout::Flat => TallyLayout::Sharded,
+        TallyLayout::Sharded => TallyLayout::Flat,
+    }
+}
+
+/// Builds the path of the tally file of a user according to `tally_key` and `tally_layout`.
+///
+/// Keying by uid needs the user database, so with `user_lookup = "none"` tallies are always keyed
+/// by name, like the placeholders of unknown users.
+///
+/// # Arguments
+/// - `config`: The loaded configuration.
+/// - `user`: The user.
+///
+/// # Returns
+/// The path of the tally file.
+///
+/// # Errors
+/// Returns a message if the user name can't be used as a tally file name.
+pub fn user_tally_file(config: &Config, user: &User) -> Result<PathBuf, String> {
+    let flat = if config.tally_key == TallyKey::Uid
+        && config.user_lookup == UserLookup::Nss
+        && user.uid() != NAME_ONLY_ID
+    {
+        config.tally_dir.join(user.uid().to_string())
+    } else {
+        tally_file_path(&config.tally_dir, user.name())?
+    };
+
+    Ok(match flat.file_name() {
+        Some(file_name) => layout_path(&config.tally_dir, file_name, config.tally_layout),
+        None => flat,
+    })
+}
+
+/// Finds the existing tally file of a user.
+///
+/// Like [`user_tally_file`], but falls back to a tally written before `tally_layout` was
+/// switched, and to a name-keyed tally written before `tally_key` was switched to `uid`, in either
+/// layout, as long as the configured one is missing.
+///
+/// # Arguments
+/// - `config`: The loaded configuration.
+/// - `user`: The user.
+///
+/// # Returns
+/// The path of the tally file.
+///
+/// # Errors
+/// Returns a message if the user name can't be used as a tally file name.
+pub fn find_tally_file(config: &Config, user: &User) -> Result<PathBuf, String> {
+    let tally_file = user_tally_file(config, user)?;
+
+    if tally_file.exists() {
+        return Ok(tally_file);
+    }
+
+    let mut file_names = tally_file
+        .file_name()
+        .map(OsStr::to_os_string)
+        .into_iter()
+        .collect::<Vec<_>>();
+    if tally_file_path(&config.tally_dir, user.name()).is_ok() {
+        file_names.push(user.name().to_os_string());
+    }
+    let layouts = [config.tally_layout, other_layout(config.tally_layout)];
+
+    let found = file_names
+        .iter()
+        .flat_map(|file_name| {
+            layouts.map(|layout| layout_path(&config.tally_dir, file_name, layout))
+        })
+        .find(|candidate| candidate.exists());
+    Ok(found.unwrap_or(tally_file))
+}
+
+/// Lists the tally files in the tally directory, in both layouts.
+///
+/// Hidden files, like the temporary files of interrupted writes, are skipped, and so are
+/// entries that can't be read.
+///
+/// # Arguments
+/// - `tally_dir`: The directory of the tally files.
+///
+/// # Returns
+/// The paths of the tally files.
+///
+/// # Errors
+/// Returns an error if the tally directory can't be read.
+pub fn tally_files(tally_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut tally_files = Vec::new();
+
+    let visible = |entry: &fs::DirEntry| !entry.file_name().as_bytes().starts_with(b".");
+    let is_file = |entry: &fs::DirEntry| entry.file_type().is_ok_and(|t| t.is_file());
+
+    for entry in fs::read_dir(tally_dir)?
+        .filter_map(Result::ok)
+        .filter(visible)
+    {
+        let name = entry.file_name();
+        let is_shard = name.len() == 2
+            && name.as_bytes().iter().all(u8::is_ascii_hexdigit)
+            && entry.file_type().is_ok_and(|t| t.is_dir());
+
+        if is_shard {
+            if let Ok(shard_entries) = fs::read_dir(entry.path()) {
+                tally_files.extend(
+                    shard_entries
+                        .filter_map(Result::ok)
+                        .filter(visible)
+                        .filter(is_file)
+                        .map(|shard_entry| shard_entry.path()),
+                );
+            }
+        } else if is_file(&entry) {
+            tally_files.push(entry.path());
+        }
+    }
+    Ok(tally_files)
+}
+
+/// Decides whether a failure count is over the free tries.
+///
+/// `free_tries` failures are free, the next failure locks the account. Every lock decision
+/// goes through this predicate.
+///
+/// # Arguments
+/// - `count`: The number of recorded failures.
+/// - `free_tries`: The number of failures allowed before delays apply.
+///
+/// # Returns
+/// `true` if the account is subject to a delay.
+#[must_use]
+pub fn is_over_threshold(count: i32, free_tries: i32) -> bool {
+    count > free_tries
+}
+
+/// Creates the tally directory with all intermediate directories and sets its permissions.
+///
+/// The directory gets the mode and, when running as root, root ownership. The module and the
+/// CLI create it with this on first use, with the mode of [`Config::tally_dir_mode`].
+///
+/// # Arguments
+/// - `tally_dir`: The tally directory.
+/// - `mode`: The permissions of the directory, `0o700` to make it only accessible by its owner.
+///
+/// # Errors
+/// Returns an error if the directory can't be created or its permissions can't be set. It is
+/// [unavailable](AuthRampError::is_unavailable) if the filesystem is read-only or a parent is
+/// missing and can't be created.
+pub fn create_tally_dir(tally_dir: &Path, mode: u32) -> Result<(), AuthRampError> {
+    fs::create_dir_all(tally_dir).map_err(|e| {
+        // a parent that is missing or can't be written makes the storage unavailable
+        let e = match e.kind() {
+            io::ErrorKind::NotFound
+            | io::ErrorKind::NotADirectory
+            | io::ErrorKind::PermissionDenied => {
+                io::Error::new(io::ErrorKind::ReadOnlyFilesystem, e)
+            }
+            _ => e,
+        };
+        AuthRampError::io(
+            format!("Error creating tally directory {}", tally_dir.display()),
+            e,
+        )
+    })?;
+
+    let owned = if unsafe { libc::getuid() } == 0 {
+        chown(tally_dir, Some(0), Some(0))
+    } else {
+        Ok(())
+    };
+    owned
+        .and_then(|()| fs::set_permissions(tally_dir, fs::Permissions::from_mode(mode)))
+        .map_err(|e| {
+            AuthRampError::io(
+                format!(
+                    "Error setting tally directory permissions of {}",
+                    tally_dir.display()
+                ),
+                e,
+            )
+        })
+}
+
+/// Creates the directory of a tally file with [`create_tally_dir`], and the tally directory above
+/// it in the `sharded` layout.
+///
+/// # Arguments
+/// - `tally_file`: The tally file.
+/// - `config`: The loaded configuration.
+///
+/// # Errors
+/// Returns an error if the tally file has no parent or a directory can't be created.
+pub fn create_tally_file_dir(tally_file: &Path, config: &Config) -> Result<(), AuthRampError> {
+    let Some(parent_dir) = tally_file.parent() else {
+        return Err(AuthRampError::invalid(
+            "Failed to get tally directory",
+            format!("{} has no parent", tally_file.display()),
+        ));
+    };
+
+    // the shard directories get the permissions of the tally directory
+    if parent_dir != config.tally_dir {
+        create_tally_dir(&config.tally_dir, config.tally_dir_mode())?;
+    }
+    create_tally_dir(parent_dir, config.tally_dir_mode())
+}
+
+/// Checks an existing tally directory for permissions that let others tamper with the tallies.
+///
+/// # Arguments
+/// - `tally_dir`: The tally directory.
+///
+/// # Returns
+/// The problem with the directory, `None` if it's safe or doesn't exist yet.
+#[must_use]
+pub fn tally_dir_issue(tally_dir: &Path) -> Option<String> {
+    let metadata = fs::symlink_metadata(tally_dir).ok()?;
+    let uid = unsafe { libc::getuid() };
+
+    if metadata.file_type().is_symlink() {
+        Some("is a symlink".to_string())
+    } else if metadata.mode() & 0o002 != 0 {
+        Some(format!(
+            "is world-writable (mode {:o})",
+            metadata.mode() & 0o7777
+        ))
+    } else if metadata.uid() != 0 && metadata.uid() != uid {
+        Some(format!(
+            "is owned by uid {} instead of root",
+            metadata.uid()
+        ))
+    } else {
+        None
+    }
+}
+
+/// The user a tally file belongs to, as authenticated by its MAC.
+fn file_user(tally_file: &Path) -> String {
+    tally_file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Loads the key of `tally_hmac_key_file`.
+///
+/// # Errors
+/// Returns an `AuthRampError` if the key can't be loaded, see [`integrity::load_key`].
+fn load_hmac_key(key_file: &Path) -> Result<Vec<u8>, AuthRampError> {
+    integrity::load_key(key_file)
+        .map_err(|reason| AuthRampError::invalid("Error loading the tally HMAC key", reason))
+}
+
+/// Reads the `version` key of a tally file.
+///
+/// # Returns
+/// The version, 0 for files written before the key existed.
+///
+/// # Errors
+/// Returns a message if the version isn't a known one.
+fn tally_version(root: &toml::Table) -> Result<u32, String> {
+    match root.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_integer()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| (1..=TALLY_VERSION).contains(version))
+            .ok_or_else(|| format!("unsupported tally version {version}")),
+    }
+}
+
+/// Parses a tally in the INI format written before tallies were TOML, e.g. `count=3`.
+///
+/// # Returns
+/// The sections as TOML tables, `None` if the content isn't INI either.
+fn parse_legacy_ini(content: &str) -> Option<toml::Table> {
+    let mut root = toml::Table::new();
+    let mut section = None;
+
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with([';', '#']))
+    {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            root.insert(name.to_string(), toml::Value::Table(toml::Table::new()));
+            section = Some(name.to_string());
+            continue;
+        }
+
+        let (key, value) = line.split_once('=')?;
+        let value = value.trim();
+        root.get_mut(section.as_ref()?)?.as_table_mut()?.insert(
+            key.trim().to_string(),
+            value.parse().map_or_else(
+                |_| toml::Value::String(value.to_string()),
+                toml::Value::Integer,
+            ),
+        );
+    }
+
+    Some(root)
+}
+
+/// Builds the path a corrupt tally file is moved to.
+///
+/// The name is hidden and has a `.corrupt-<timestamp>` suffix, so it is never the tally file of
+/// a user.
+fn quarantine_path(tally_file: &Path, now: DateTime<Utc>) -> PathBuf {
+    tally_file.with_file_name(format!(
+        ".{}.corrupt-{}",
+        file_user(tally_file),
+        now.format("%Y%m%dT%H%M%SZ")
+    ))
+}
+
+/// Reads an optional source item from the `[Fails]` table.
+fn source_item(fails_table: &toml::Table, key: &str) -> Option<String> {
+    fails_table
+        .get(key)
+        .and_then(toml::Value::as_str)
+        .map(str::to_string)
+}
+
+/// The `Tally` struct represents the account lockout information, including
+/// the number of authentication failures and the timestamp of the last failure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tally {
+    /// An optional `PathBuf` representing the path to the file storing tally information.
+    pub file: Option<PathBuf>,
+    /// The format version the tally was read with, 0 for files predating the `version` key.
+    pub version: u32,
+    /// An integer representing the number of authentication failures.
+    pub failures_count: i32,
+    /// A `DateTime<Utc>` representing the timestamp of the last authentication failure.
+    pub failure_instant: DateTime<Utc>,
+    /// An optional `DateTime<Utc>` representing the timestamp of the first failure since the last clear.
+    pub first_failure_instant: Option<DateTime<Utc>>,
+    /// An optional `DateTime<Utc>` representing the time when the account will be unlocked.
+    pub unlock_instant: Option<DateTime<Utc>>,
+    /// Whether the lock was set with `authramp lock`, which the current settings never shorten.
+    pub manual_lock: bool,
+    /// An optional `DateTime<Utc>` representing the time the last session was opened.
+    pub last_success: Option<DateTime<Utc>>,
+    /// Whether recorded failures have been cleared while opening the tally.
+    pub cleared: bool,
+    /// Failures recorded in the current PAM transaction, including this one.
+    pub transaction_failures: i32,
+    /// The PAM service of the most recent authentication, if known.
+    pub service: Option<String>,
+    /// The remote host of the most recent authentication, if known.
+    pub rhost: Option<String>,
+    /// The terminal of the most recent authentication, if known.
+    pub tty: Option<String>,
+    /// The user name last seen in a uid-keyed tally, if known.
+    pub user_name: Option<String>,
+    /// The MAC stored in the tally file, if any.
+    pub mac: Option<String>,
+}
+
+impl Default for Tally {
+    /// Creates a default `Tally` instance with zero failures and the current timestamp.
+    fn default() -> Self {
+        Tally {
+            file: None,
+            version: TALLY_VERSION,
+            failures_count: 0,
+            failure_instant: Utc::now(),
+            first_failure_instant: None,
+            unlock_instant: None,
+            manual_lock: false,
+            last_success: None,
+            cleared: false,
+            transaction_failures: 0,
+            service: None,
+            rhost: None,
+            tty: None,
+            user_name: None,
+            mac: None,
+        }
+    }
+}
+
+impl Tally {
+    /// Calculates the delay based on the number of authentication failures and settings.
+    /// Uses the formula of the `delay_algorithm`, with `over = fails − free_tries`:
+    ///
+    /// - `ramp`: `delay = ramp_multiplier × over × ln(over) + base_delay_seconds`
+    /// - `exponential`: `delay = base_delay_seconds × delay_factor^over`
+    /// - `fixed`: `delay = base_delay_seconds`
+    ///
+    /// The difference is clamped to at least 1, so counts at or below the threshold get the
+    /// delay of the first lock. The delay is never below `base_delay_seconds` nor negative, and
+    /// bounded so adding it to an instant can't overflow.
+    ///
+    /// # Arguments
+    /// - `fails`: Number of authentication failures
+    /// - `settings`: Settings for the authramp module
+    ///
+    /// # Returns
+    /// Calculated delay as a `Duration`
+    #[must_use]
+    pub fn get_delay(&self, settings: &Settings) -> Duration {
+        let base_delay = f64::from(settings.config.base_delay_seconds.max(0));
+        let over =
+            (f64::from(self.failures_count) - f64::from(settings.config.free_tries)).max(1.0);
+
+        let delay = match settings.config.delay_algorithm {
+            DelayAlgorithm::Ramp => settings.config.ramp_multiplier * over * over.ln() + base_delay,
+            DelayAlgorithm::Exponential => base_delay * settings.config.delay_factor.powf(over),
+            DelayAlgorithm::Fixed => base_delay,
+        };
+
+        // guard against non-finite results, a negative ramp and overflowing the unlock instant
+        let delay = if delay.is_nan() {
+            base_delay
+        } else {
+            delay.clamp(base_delay, f64::from(i32::MAX))
+        };
+
+        Duration::seconds(delay as i64)
+    }
+
+    /// Calculates the delay capped at `max_lockout_seconds`.
+    ///
+    /// # Arguments
+    /// - `settings`: Settings for the authramp module
+    ///
+    /// # Returns
+    /// The delay, at most the configured lockout cap
+    #[must_use]
+    pub fn get_capped_delay(&self, settings: &Settings) -> Duration {
+        let delay = self.get_delay(settings);
+        settings
+            .config
+            .lockout_cap()
+            .map_or(delay, |cap| min(delay, cap))
+    }
+
+    /// Calculates the instant the account gets unlocked.
+    ///
+    /// This is the single source for the unlock instant: the failure writer, the
+    /// bounce and the CLI all go through it. Legacy tallies without a stored
+    /// `unlock_instant` fall back to the capped delay from the last failure. With
+    /// `recompute_on_config_change`, a stored instant is shortened to that delay when
+    /// the settings became more lenient, but never extended. Manual locks are kept.
+    ///
+    /// # Arguments
+    /// - `settings`: Settings for the authramp module
+    ///
+    /// # Returns
+    /// The unlock instant if the failures exceed the free tries, `None` otherwise
+    #[must_use]
+    pub fn effective_unlock_instant(&self, settings: &Settings) -> Option<DateTime<Utc>> {
+        is_over_threshold(self.failures_count, settings.config.free_tries).then(|| {
+            let computed = self.computed_unlock_instant(settings);
+            self.unlock_instant.map_or(computed, |stored| {
+                if settings.config.recompute_on_config_change && !self.manual_lock {
+                    stored.min(computed)
+                } else {
+                    stored
+                }
+            })
+        })
+    }
+
+    /// Computes the unlock instant from the last failure and the capped delay.
+    fn computed_unlock_instant(&self, settings: &Settings) -> DateTime<Utc> {
+        self.failure_instant + self.get_capped_delay(settings)
+    }
+
+    /// Opens or creates the tally file based on the provided `Settings`.
+    ///
+    /// If the file exists, loads the values; if not, creates the file with default values.
+    /// Updates the tally based on authentication actions, such as successful or failed attempts.
+    /// Failures of members of `exempt_groups` are not recorded, nor failures of local console
+    /// logins with `deny_local = false` and `count_local_failures = false`.
+    ///
+    /// # Arguments
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` containing either the `Tally` struct or an `AuthRampError`.
+    ///
+    /// # Errors
+    /// Returns an `AuthRampError` if the tally file cannot be read, parsed or written, or the
+    /// user name can't be used as a tally file name.
+    pub fn new_from_tally_file<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        settings: &Settings,
+    ) -> Result<Self, AuthRampError> {
+        let mut tally = Tally::default();
+        let user = settings.get_user()?;
+
+        let tally_file = user_tally_file(&settings.config, user).map_err(|e| {
+            AuthRampError::user(
+                format!(
+                    "Rejected the user name \"{}\"",
+                    user.name().to_string_lossy().escape_debug()
+                ),
+                e,
+            )
+        })?;
+
+        Self::check_tally_dir(pam_h, settings)?;
+        Self::migrate_name_keyed(pam_h, &tally_file, user, settings)?;
+
+        // Members of exempt groups don't accumulate failures
+        if settings.action == Some(Actions::AUTHFAIL) {
+            if let Some(group) = settings.exempt_group() {
+                if let Some(pam_h) = &pam_h {
+                    pam_h.log(
+                        pam::LogLevel::Info,
+                        format!(
+                            "Account \"{}\" is exempt from lockout as a member of the \"{group}\" group. Failure not recorded.",
+                            user.name().display()
+                        ),
+                    )?;
+                }
+                tally.transaction_failures = settings.transaction_failures;
+                return Ok(tally);
+            }
+
+            // Local console logins exempt from lockout may not accumulate failures either
+            if !settings.config.count_local_failures
+                && settings.config.is_exempt_local(
+                    settings.service.as_deref(),
+                    settings.tty.as_deref(),
+                    settings.rhost.as_deref(),
+                )
+            {
+                if let Some(pam_h) = &pam_h {
+                    pam_h.log(
+                        pam::LogLevel::Info,
+                        format!(
+                            "Authentication of account \"{}\" failed on the local console {}. Failure not recorded.",
+                            user.name().display(),
+                            settings.tty.as_deref().unwrap_or_default()
+                        ),
+                    )?;
+                }
+                tally.transaction_failures = settings.transaction_failures;
+                return Ok(tally);
+            }
+        }
+
+        let loaded = tally_file.exists()
+            && Self::load_tally_from_file(pam_h, &mut tally, user, &tally_file, settings)?;
+        if !loaded && matches!(settings.action, Some(Actions::AUTHFAIL | Actions::SESSION)) {
+            Self::create_tally_file(pam_h, &mut tally, user, &tally_file, settings)?;
+        }
+
+        // Count this failure for the transaction marker, a success settles the transaction
+        tally.transaction_failures = match settings.action {
+            Some(Actions::AUTHFAIL) if settings.attempt_counted != Some(true) => {
+                settings.transaction_failures + 1
+            }
+            Some(Actions::AUTHSUCC) => 0,
+            _ => settings.transaction_failures,
+        };
+
+        tally.set_log_fields(pam_h, settings);
+        Ok(tally)
+    }
+
+    /// Loads tally information from an existing file.
+    ///
+    /// Tallies of older formats are migrated to [`TALLY_VERSION`], corrupt ones are quarantined.
+    ///
+    /// # Arguments
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `tally`: A mutable reference to the `Tally` struct.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` with whether the tally was loaded, `false` if the file was quarantined, or an
+    /// `AuthRampError` in case of errors.
+    fn load_tally_from_file<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<bool, AuthRampError> {
+        let (loaded, integrity) = match Self::read_verified_tally_file(tally_file, &settings.config)
+        {
+            Ok(loaded) => loaded,
+            Err(_) if Self::quarantine_corrupt(pam_h, user, tally_file, settings)? => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+        *tally = loaded;
+
+        Self::check_integrity(pam_h, tally, integrity, user, tally_file, settings)?;
+
+        if tally.version < TALLY_VERSION {
+            Self::migrate_version(pam_h, tally, user, tally_file, settings)?;
+        }
+
+        Self::correct_clock(pam_h, tally, user, settings)?;
+        Self::expire_failures(pam_h, tally, user, tally_file, settings)?;
+
+        Self::update_tally(pam_h, tally, user, tally_file, settings)?;
+        Ok(true)
+    }
+
+    /// Warns about a tally directory others could tamper with, see [`tally_dir_issue`].
+    ///
+    /// With `fail_mode = "closed"` the tally isn't used at all.
+    ///
+    /// # Arguments
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` if the directory is refused.
+    fn check_tally_dir<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        let tally_dir = &settings.config.tally_dir;
+        let Some(issue) = tally_dir_issue(tally_dir) else {
+            return Ok(());
+        };
+
+        if settings.config.fail_mode == FailMode::Closed {
+            return Err(AuthRampError::invalid(
+                format!("Refused the tally directory {}", tally_dir.display()),
+                issue,
+            ));
+        }
+        if let Some(pam_h) = &pam_h {
+            pam_h.log(
+                pam::LogLevel::Warning,
+                format!(
+                    "Tally directory {} {issue}. Other users may tamper with the tallies.",
+                    tally_dir.display()
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites a tally of an older format as [`TALLY_VERSION`].
+    ///
+    /// # Arguments
+    /// - `tally`: A mutable reference to the loaded `Tally` struct.
+    /// - `user`: The user the tally belongs to.
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` if the tally can't be written.
+    fn migrate_version<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        let from = tally.version;
+        tally.version = TALLY_VERSION;
+
+        tally.write_tally_file(tally_file, &settings.config)?;
+
+        if let Some(pam_h) = &pam_h {
+            pam_h.log(
+                pam::LogLevel::Info,
+                format!(
+                    "Migrated the tally of the \"{}\" account from version {from} to {TALLY_VERSION}.",
+                    user.name().display()
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Moves a tally file that can't be parsed out of the way, so a fresh tally is created.
+    ///
+    /// With a `tally_hmac_key_file` and `tally_hmac_fail_closed`, the file is kept and the
+    /// authentication fails, as replacing the tally would bypass the integrity check.
+    ///
+    /// # Arguments
+    /// - `user`: The user the tally belongs to.
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` with whether the file was quarantined, `false` if it parses, can't be read or
+    /// must be kept, or an `AuthRampError` if it can't be moved.
+    fn quarantine_corrupt<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<bool, AuthRampError> {
+        let Ok(content) = fs::read_to_string(tally_file) else {
+            return Ok(false);
+        };
+        let Err(reason) = Self::parse(&content) else {
+            return Ok(false);
+        };
+        if settings.config.tally_hmac_key_file.is_some() && settings.config.tally_hmac_fail_closed {
+            return Ok(false);
+        }
+
+        let quarantined = quarantine_path(tally_file, Utc::now());
+        fs::rename(tally_file, &quarantined).map_err(|e| {
+            AuthRampError::io(
+                format!("Error quarantining tally file {}", tally_file.display()),
+                e,
+            )
+        })?;
+
+        if let Some(pam_h) = &pam_h {
+            pam_h.log(
+                pam::LogLevel::Warning,
+                format!(
+                    "Quarantined the corrupt tally of the \"{}\" account as {}: {reason}",
+                    user.name().display(),
+                    quarantined.display()
+                ),
+            )?;
+        }
+        Ok(true)
+    }
+
+    /// Moves a name-keyed tally to its uid-keyed tally file, or a tally of the other layout to
+    /// the configured one.
+    ///
+    /// Only applies if the configured tally file doesn't exist yet. The tally is checked and
+    /// signed again, as the MAC covers the file name.
+    ///
+    /// # Arguments
+    /// - `tally_file`: The configured tally file.
+    /// - `user`: The user the tally belongs to.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` if the tally can't be migrated.
+    fn migrate_name_keyed<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally_file: &Path,
+        user: &User,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        let name_keyed = match find_tally_file(&settings.config, user) {
+            Ok(name_keyed) if name_keyed != tally_file => name_keyed,
+            _ => return Ok(()),
+        };
+
+        let (mut tally, integrity) = Self::read_verified_tally_file(&name_keyed, &settings.config)?;
+        Self::check_integrity(pam_h, &mut tally, integrity, user, tally_file, settings)?;
+        tally.user_name = Some(user.name().to_string_lossy().into_owned());
+
+        create_tally_file_dir(tally_file, &settings.config)?;
+        tally.write_tally_file(tally_file, &settings.config)?;
+        fs::remove_file(&name_keyed).map_err(|e| {
+            AuthRampError::io(
+                format!(
+                    "Error removing migrated tally file {}",
+                    name_keyed.display()
+                ),
+                e,
+            )
+        })?;
+
+        if let Some(pam_h) = &pam_h {
+            pam_h.log(
+                pam::LogLevel::Info,
+                format!(
+                    "Migrated the tally of the \"{}\" account to {}.",
+                    user.name().display(),
+                    tally_file.display()
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Acts on the outcome of the integrity check of a loaded tally.
+    ///
+    /// Tallies predating the key are upgraded with a MAC. Invalid tallies fail the
+    /// authentication, unless `tally_hmac_fail_closed` is disabled: then the untrusted tally is
+    /// discarded and signed again on the next write.
+    ///
+    /// # Arguments
+    /// - `tally`: A mutable reference to the loaded `Tally` struct.
+    /// - `integrity`: The outcome of the integrity check.
+    /// - `user`: The user the tally belongs to.
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` if the tally can't be trusted.
+    fn check_integrity<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        integrity: Integrity,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        match integrity {
+            Integrity::Unchecked | Integrity::Verified => Ok(()),
+            Integrity::Legacy => {
+                tally.write_tally_file(tally_file, &settings.config)?;
+                if let Some(pam_h) = &pam_h {
+                    pam_h.log(
+                        pam::LogLevel::Info,
+                        format!(
+                            "Upgraded the tally of the \"{}\" account with a MAC.",
+                            user.name().display()
+                        ),
+                    )?;
+                }
+                Ok(())
+            }
+            Integrity::Invalid(reason) => {
+                let message = format!(
+                    "The tally of the \"{}\" account failed the integrity check",
+                    user.name().display()
+                );
+                if settings.config.tally_hmac_fail_closed {
+                    return Err(AuthRampError::invalid(message, reason));
+                }
+                if let Some(pam_h) = &pam_h {
+                    pam_h.log(
+                        pam::LogLevel::Error,
+                        format!("{message}: {reason}. Discarding it."),
+                    )?;
+                }
+                *tally = Tally::default();
+                Ok(())
+            }
+        }
+    }
+
+    /// Resets failures older than `reset_after_seconds` and persists the reset.
+    ///
+    /// An active lock is never bypassed: the failures only expire once the unlock instant
+    /// has passed. With `reset_after_seconds = 0` failures never expire.
+    ///
+    /// # Arguments
+    /// - `tally`: A mutable reference to the loaded `Tally` struct.
+    /// - `user`: The user the tally belongs to.
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` if the reset can't be written.
+    fn expire_failures<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        let reset_after_seconds = settings.config.reset_after_seconds;
+        let now = Utc::now();
+
+        if reset_after_seconds <= 0
+            || tally.failures_count == 0
+            || now - tally.failure_instant < Duration::seconds(reset_after_seconds)
+            || tally
+                .effective_unlock_instant(settings)
+                .is_some_and(|unlock_instant| now < unlock_instant)
+        {
+            return Ok(());
+        }
+
+        let expired_failures = tally.failures_count;
+        tally.failures_count = 0;
+        tally.first_failure_instant = None;
+        tally.unlock_instant = None;
+
+        tally.write_tally_file(tally_file, &settings.config)?;
+
+        if let Some(pam_h) = &pam_h {
+            pam_h.log(
+                pam::LogLevel::Info,
+                format!(
+                    "Expired {expired_failures} failures older than {reset_after_seconds} seconds for the \"{}\" account.",
+                    user.name().display()
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Clamps the instants of a tally recorded before the system clock went backwards, e.g. by
+    /// an NTP correction after resume.
+    ///
+    /// Failures in the future are moved to now, and an unlock instant further away than
+    /// `max_lockout_seconds` is capped, so a clock going backwards never locks an account for
+    /// longer than the cap. Manual locks are kept. The corrected values are written with the
+    /// next update of the tally.
+    ///
+    /// # Arguments
+    /// - `tally`: A mutable reference to the loaded `Tally` struct.
+    /// - `user`: The user the tally belongs to.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` if logging fails.
+    fn correct_clock<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        user: &User,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        let now = Utc::now();
+        let mut corrected = false;
+
+        if tally.failure_instant > now {
+            tally.failure_instant = now;
+            corrected = true;
+        }
+        if tally
+            .first_failure_instant
+            .is_some_and(|first_failure_instant| first_failure_instant > now)
+        {
+            tally.first_failure_instant = Some(now);
+            corrected = true;
+        }
+        if let (Some(unlock_instant), Some(cap)) =
+            (tally.unlock_instant, settings.config.lockout_cap())
+        {
+            if !tally.manual_lock && unlock_instant > now + cap {
+                tally.unlock_instant = Some(now + cap);
+                corrected = true;
+            }
+        }
+
+        if corrected {
+            if let Some(pam_h) = &pam_h {
+                pam_h.log(
+                    pam::LogLevel::Warning,
+                    format!(
+                        "Tally of the \"{}\" account has instants in the future. Clamped them to the current time, the system clock may have gone backwards.",
+                        user.name().display()
+                    ),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and parses a tally file without modifying it.
+    ///
+    /// This is the parsing code used by the PAM module. It is public so the CLI can inspect
+    /// tallies exactly the way the module sees them.
+    ///
+    /// # Arguments
+    /// - `tally_file`: A reference to the tally file `Path`.
+    ///
+    /// # Returns
+    /// A `Result` containing the parsed `Tally` or an `AuthRampError` describing why it could not
+    /// be read.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or parsed.
+    pub fn read_tally_file(tally_file: &Path) -> Result<Self, AuthRampError> {
+        let content = fs::read_to_string(tally_file).map_err(|e| {
+            AuthRampError::io(
+                format!("Error reading tally file {}", tally_file.display()),
+                e,
+            )
+        })?;
+        let tally = Self::parse(&content).map_err(|reason| {
+            AuthRampError::invalid(
+                format!("Error parsing tally file {}", tally_file.display()),
+                reason,
+            )
+        })?;
+
+        Ok(Tally {
+            file: Some(tally_file.to_path_buf()),
+            ..tally
+        })
+    }
+
+    /// Parses the content of a tally file.
+    ///
+    /// Besides the current format, this accepts TOML without a `version` key and the INI format
+    /// of earlier releases. Both are read as version 0.
+    ///
+    /// # Arguments
+    /// - `content`: The content of the tally file.
+    ///
+    /// # Returns
+    /// A `Result` containing the parsed `Tally` without a file.
+    ///
+    /// # Errors
+    /// Returns an error if the content is neither TOML nor INI, has an unknown version or has no
+    /// `[Fails]` table.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let (root, version) = match toml::from_str::<toml::Table>(content) {
+            Ok(root) => {
+                let version = tally_version(&root)?;
+                (root, version)
+            }
+            Err(e) => (parse_legacy_ini(content).ok_or_else(|| format!("{e}"))?, 0),
+        };
+
+        // Extract values from the "Fails" table
+        let Some(fails_table) = root.get("Fails").and_then(|v| v.as_table()) else {
+            return Err("[Fails] table does not exist".to_string());
+        };
+
+        Ok(Tally {
+            file: None,
+            version,
+            failures_count: fails_table
+                .get("count")
+                .and_then(toml::Value::as_integer)
+                .map(|count| count as i32)
+                .unwrap_or_default(),
+            failure_instant: fails_table
+                .get("instant")
+                .and_then(|instant| instant.as_str())
+                .and_then(|instant| instant.parse().ok())
+                .unwrap_or_default(),
+            first_failure_instant: fails_table
+                .get("first_instant")
+                .and_then(|first_instant| first_instant.as_str())
+                .and_then(|first_instant| first_instant.parse().ok()),
+            unlock_instant: fails_table
+                .get("unlock_instant")
+                .and_then(|unlock_instant| unlock_instant.as_str())
+                .and_then(|unlock_instant| unlock_instant.parse().ok()),
+            manual_lock: fails_table
+                .get("manual_lock")
+                .and_then(toml::Value::as_bool)
+                .unwrap_or_default(),
+            last_success: fails_table
+                .get("last_success")
+                .and_then(|last_success| last_success.as_str())
+                .and_then(|last_success| last_success.parse().ok()),
+            cleared: false,
+            transaction_failures: 0,
+            service: source_item(fails_table, "service"),
+            rhost: source_item(fails_table, "rhost"),
+            tty: source_item(fails_table, "tty"),
+            user_name: source_item(fails_table, "user"),
+            mac: source_item(fails_table, "hmac"),
+        })
+    }
+
+    /// Serializes the tally into the tally file format read by [`Tally::read_tally_file`].
+    ///
+    /// This is the canonical form of a tally, which [`Tally::write_tally_file`] signs. Instants
+    /// that aren't set are omitted. Tallies read as version 0 serialize without the `version`
+    /// key, so the MAC of a file predating it still verifies.
+    ///
+    /// # Returns
+    /// The TOML content of the tally file.
+    #[must_use]
+    pub fn to_toml(&self) -> String {
+        self.to_toml_version(self.version)
+    }
+
+    /// Serializes the tally as a specific format version, see [`Tally::to_toml`].
+    fn to_toml_version(&self, version: u32) -> String {
+        let mut toml_str = if version > 0 {
+            format!("version = {version}\n\n")
+        } else {
+            String::new()
+        };
+        let _ = write!(
+            toml_str,
+            "[Fails]\ncount = {}\ninstant = \"{}\"",
+            self.failures_count, self.failure_instant
+        );
+        if let Some(first_failure_instant) = self.first_failure_instant {
+            let _ = write!(toml_str, "\nfirst_instant = \"{first_failure_instant}\"");
+        }
+        if let Some(unlock_instant) = self.unlock_instant {
+            let _ = write!(toml_str, "\nunlock_instant = \"{unlock_instant}\"");
+        }
+        if self.manual_lock {
+            toml_str.push_str("\nmanual_lock = true");
+        }
+        if let Some(last_success) = self.last_success {
+            let _ = write!(toml_str, "\nlast_success = \"{last_success}\"");
+        }
+        if let Some(user_name) = &self.user_name {
+            let _ = write!(
+                toml_str,
+                "\nuser = {}",
+                toml::Value::String(user_name.clone())
+            );
+        }
+        toml_str + &self.source_toml()
+    }
+
+    /// Writes the tally file as [`TALLY_VERSION`], with a MAC if `tally_hmac_key_file` is
+    /// configured.
+    ///
+    /// This is the only way the PAM module writes tallies. It is public so the CLI writes
+    /// tallies the module trusts.
+    ///
+    /// # Arguments
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `config`: The configuration with the key file.
+    ///
+    /// # Errors
+    /// Returns an error if the key can't be loaded or the file can't be written.
+    pub fn write_tally_file(
+        &self,
+        tally_file: &Path,
+        config: &Config,
+    ) -> Result<(), AuthRampError> {
+        let mut toml_str = self.to_toml_version(TALLY_VERSION);
+
+        if let Some(key_file) = &config.tally_hmac_key_file {
+            let key = load_hmac_key(key_file)?;
+            let mac = integrity::sign(&key, &file_user(tally_file), &toml_str);
+            let _ = write!(toml_str, "\nhmac = \"{mac}\"");
+        }
+
+        // Only the owner may read who failed and when unless status_world_readable is set,
+        // files of older releases or the other setting are adjusted
+        state_file::write(
+            tally_file,
+            toml_str.as_bytes(),
+            config.tally_file_mode(),
+            config.durable_writes,
+        )
+        .map_err(|e| {
+            AuthRampError::io(
+                format!("Error writing tally file {}", tally_file.display()),
+                e,
+            )
+        })
+    }
+
+    /// Reads a tally file and checks its MAC if `tally_hmac_key_file` is configured.
+    ///
+    /// The MAC is checked over the canonical serialization of the parsed tally, so the
+    /// formatting of the file doesn't matter. Callers must not trust a tally with an
+    /// [`Integrity::Invalid`] outcome.
+    ///
+    /// # Arguments
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `config`: The configuration with the key file.
+    ///
+    /// # Returns
+    /// The parsed `Tally` and the outcome of the integrity check.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or parsed, or the key can't be loaded.
+    pub fn read_verified_tally_file(
+        tally_file: &Path,
+        config: &Config,
+    ) -> Result<(Self, Integrity), AuthRampError> {
+        let tally = Self::read_tally_file(tally_file)?;
+
+        let Some(key_file) = &config.tally_hmac_key_file else {
+            return Ok((tally, Integrity::Unchecked));
+        };
+        let key = load_hmac_key(key_file)?;
+
+        let integrity = match &tally.mac {
+            Some(mac) if integrity::verify(&key, &file_user(tally_file), &tally.to_toml(), mac) => {
+                Integrity::Verified
+            }
+            Some(_) => Integrity::Invalid("the MAC doesn't verify".to_string()),
+            None if integrity::predates_key(tally_file, key_file) => Integrity::Legacy,
+            None => Integrity::Invalid("the MAC is missing".to_string()),
+        };
+
+        Ok((tally, integrity))
+    }
+
+    /// Reads a tally file that must pass the integrity check.
+    ///
+    /// # Arguments
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `config`: The configuration with the key file.
+    ///
+    /// # Returns
+    /// The parsed `Tally`.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or parsed, or fails the integrity check.
+    pub fn read_trusted_tally_file(
+        tally_file: &Path,
+        config: &Config,
+    ) -> Result<Self, AuthRampError> {
+        match Self::read_verified_tally_file(tally_file, config)? {
+            (_, Integrity::Invalid(reason)) => Err(AuthRampError::invalid(
+                format!(
+                    "Integrity check of tally file {} failed",
+                    tally_file.display()
+                ),
+                reason,
+            )),
+            (tally, _) => Ok(tally),
+        }
+    }
+
+    /// Serializes the known source items of the most recent authentication.
+    ///
+    /// The values are quoted as TOML strings, as they are controlled by the PAM client.
+    ///
+    /// # Returns
+    /// The TOML lines, each starting with a newline.
+    fn source_toml(&self) -> String {
+        [
+            ("service", &self.service),
+            ("rhost", &self.rhost),
+            ("tty", &self.tty),
+        ]
+        .iter()
+        .filter_map(|(key, value)| {
+            value
+                .as_ref()
+                .map(|value| format!("\n{key} = {}", toml::Value::String(value.clone())))
+        })
+        .collect()
+    }
+
+    /// Describes the source of the most recent authentication for log messages.
+    ///
+    /// # Returns
+    /// The known source items, e.g. "service sshd, rhost 192.0.2.1", `None` if none are known.
+    #[must_use]
+    pub fn source(&self) -> Option<String> {
+        let source = [
+            ("service", &self.service),
+            ("rhost", &self.rhost),
+            ("tty", &self.tty),
+        ]
+        .iter()
+        .filter_map(|(key, value)| value.as_ref().map(|value| format!("{key} {value}")))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+        (!source.is_empty()).then_some(source)
+    }
+
+    /// Records the source items of the current authentication, and the user name if the
+    /// tally is keyed by uid.
+    ///
+    /// # Arguments
+    /// - `settings`: A reference to the `Settings` struct.
+    fn record_source(&mut self, settings: &Settings) {
+        self.service.clone_from(&settings.service);
+        self.rhost.clone_from(&settings.rhost);
+        self.tty.clone_from(&settings.tty);
+        if settings.config.tally_key == TallyKey::Uid {
+            self.user_name = settings
+                .user
+                .as_ref()
+                .map(|user| user.name().to_string_lossy().into_owned());
+        }
+    }
+
+    /// Attaches the failures and the unlock time to the later journald log entries.
+    ///
+    /// # Arguments
+    /// - `settings`: A reference to the `Settings` struct.
+    fn set_log_fields<P: PamApi>(&self, pam_h: &Option<&mut P>, settings: &Settings) {
+        if let Some(pam_h) = pam_h {
+            pam_h.set_log_field("AUTHRAMP_FAILURES", &self.failures_count.to_string());
+            let unlock_time = self
+                .effective_unlock_instant(settings)
+                .filter(|unlock_instant| Utc::now() < *unlock_instant)
+                .map(|unlock_instant| unlock_instant.to_rfc3339())
+                .unwrap_or_default();
+            pam_h.set_log_field("AUTHRAMP_UNLOCK_TIME", &unlock_time);
+        }
+    }
+
+    /// Formats the source of the most recent authentication as a log sentence.
+    fn source_log(&self) -> String {
+        self.source()
+            .map(|source| format!(" Source: {source}."))
+            .unwrap_or_default()
+    }
+
+    /// Updates tally information based on a section from the tally file.
+    ///
+    /// AUTHSUCC deletes the tally, or only subtracts the failures of the current transaction
+    /// if `forgive_same_transaction_failures` is enabled
+    /// AUTHERR increases the tally
+    /// SESSION records the login in `last_success` without touching the failures
+    /// PREAUTH is ignored;
+    ///
+    /// # Arguments
+    /// - `fails_section`: A reference to the "Fails" section of the TOML file.
+    /// - `tally`: A mutable reference to the `Tally` struct.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` in case of errors. A failure that
+    /// can't be recorded is returned as `PAM_PERM_DENIED`.
+    fn update_tally<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        tally.set_log_fields(pam_h, settings);
+
+        // Handle specific actions based on settings.action
+        match settings.get_action()? {
+            Actions::PREAUTH => Ok(()),
+            Actions::SESSION => {
+                tally.last_success = Some(Utc::now());
+                tally.write_tally_file(tally_file, &settings.config)
+            }
+            Actions::AUTHSUCC => {
+                tally.record_source(settings);
+                Self::clear_tally(pam_h, tally, user, tally_file, settings)
+            }
+            Actions::AUTHFAIL => {
+                // Another line of this attempt already recorded the failure
+                if settings.attempt_counted == Some(true) {
+                    if let Some(pam_h) = &pam_h {
+                        pam_h.log(
+                            pam::LogLevel::Debug,
+                            format!(
+                                "Failure of the \"{}\" account already recorded in this attempt.",
+                                user.name().display()
+                            ),
+                        )?;
+                    }
+                    return Ok(());
+                }
+
+                tally.record_source(settings);
+
+                // Attempts during a lock can't succeed, so they don't ramp the delay further
+                if !settings.config.count_while_locked {
+                    if let Some(unlock_instant) = tally
+                        .effective_unlock_instant(settings)
+                        .filter(|unlock_instant| Utc::now() < *unlock_instant)
+                    {
+                        if let Some(pam_h) = &pam_h {
+                            pam_h.log(
+                                pam::LogLevel::Info,
+                                format!("PAM_AUTH_ERR: Ignored failure for the locked \"{}\" account. Account is locked until {unlock_instant}.{}",
+                                user.name().display(),
+                                tally.source_log()),
+                            )?;
+                        }
+                        return Ok(());
+                    }
+                }
+
+                let was_locked =
+                    is_over_threshold(tally.failures_count, settings.config.free_tries);
+
+                // If action is AUTHFAIL, update count and instant
+                tally.failures_count += 1;
+                tally.failure_instant = Utc::now();
+
+                // Start a new failure series after a clear
+                if tally.failures_count == 1 || tally.first_failure_instant.is_none() {
+                    tally.first_failure_instant = Some(tally.failure_instant);
+                }
+
+                // Cap unlock_instant at max_lockout_seconds from now
+                let unlock_instant = tally.computed_unlock_instant(settings);
+                let delay = unlock_instant - tally.failure_instant;
+                tally.unlock_instant = Some(unlock_instant);
+                tally.manual_lock = false;
+                if let Some(pam_h) = &pam_h {
+                    pam_h.log(
+                        pam::LogLevel::Debug,
+                        format!(
+                            "Computed a delay of {} seconds for {} failures (delay_algorithm={}, free_tries={}, base_delay_seconds={}, ramp_multiplier={}, delay_factor={}, max_lockout_seconds={}).",
+                            delay.num_seconds(),
+                            tally.failures_count,
+                            settings.config.delay_algorithm.name(),
+                            settings.config.free_tries,
+                            settings.config.base_delay_seconds,
+                            settings.config.ramp_multiplier,
+                            settings.config.delay_factor,
+                            settings.config.max_lockout_seconds
+                        ),
+                    )?;
+                }
+
+                // Write the updated values back to the file
+                tally
+                    .write_tally_file(tally_file, &settings.config)
+                    .map_err(|e| e.with_code(PamResultCode::PAM_PERM_DENIED))?;
+                tally.set_log_fields(pam_h, settings);
+
+                if is_over_threshold(tally.failures_count, settings.config.free_tries) {
+                    // log account unlock
+                    if let Some(pam_h) = &pam_h {
+                        pam_h.log(
+                            pam::LogLevel::Info,
+                            format!("PAM_AUTH_ERR: Added tally ({} failures) for the \"{}\" account. Account is locked until {}.{}",
+                            tally.failures_count,
+                            user.name().display(),
+                            tally.unlock_instant.unwrap(),
+                            tally.source_log()),
+                        )?;
+                    }
+
+                    // alert on the failure crossing the threshold
+                    if !was_locked {
+                        Self::run_hook(pam_h, HookEvent::Lock, tally, user, settings);
+                        Self::signal_lockout(pam_h, HookEvent::Lock, tally, user, settings);
+                        Self::audit_lockout(pam_h, user, settings);
+                        Self::notify_lockout(pam_h, tally, user, settings);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the audit record of an account crossing the threshold if `audit_lockouts` is
+    /// enabled.
+    ///
+    /// Errors are only logged as warnings, the audit trail never affects the PAM result.
+    ///
+    /// # Arguments
+    /// - `user`: The user the tally belongs to
+    /// - `settings`: A reference to the `Settings` struct
+    fn audit_lockout<P: PamApi>(pam_h: &Option<&mut P>, user: &User, settings: &Settings) {
+        if !settings.config.audit_lockouts {
+            return;
+        }
+
+        let name = user.name().to_string_lossy();
+        let record = audit::LockoutRecord {
+            uid: if settings.is_unknown_user() {
+                audit::AUDIT_UNSET_UID
+            } else {
+                user.uid()
+            },
+            user: &name,
+            service: settings.service.as_deref(),
+            rhost: settings.rhost.as_deref(),
+            tty: settings.tty.as_deref(),
+        };
+
+        if let Err(e) = audit::log_lockout(&record) {
+            if let Some(pam_h) = &pam_h {
+                let _ = pam_h.log(
+                    pam::LogLevel::Warning,
+                    format!("Error auditing the lockout of the \"{name}\" account: {e}"),
+                );
+            }
+        }
+    }
+
+    /// Emails the recipients of `notify_email` about an account crossing the threshold.
+    ///
+    /// Errors are only logged, the notification never affects the PAM result.
+    ///
+    /// # Arguments
+    /// - `tally`: The updated tally
+    /// - `user`: The user the tally belongs to
+    /// - `settings`: A reference to the `Settings` struct
+    fn notify_lockout<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &Tally,
+        user: &User,
+        settings: &Settings,
+    ) {
+        let config = &settings.config;
+        if config.notify_email.is_empty() {
+            return;
+        }
+
+        let name = user.name().to_string_lossy();
+        let notification = Notification {
+            event: NotifyEvent::Lock,
+            user: &name,
+            failures: tally.failures_count,
+            service: settings.service.as_deref(),
+            rhost: settings.rhost.as_deref(),
+            unlock_instant: tally.unlock_instant,
+        };
+        let template = config
+            .notify_email_template
+            .as_deref()
+            .unwrap_or(notify::DEFAULT_TEMPLATE);
+
+        if let Err(e) = notify::send(
+            &config.notify_sendmail,
+            &config.notify_email,
+            template,
+            &notification,
+        ) {
+            if let Some(pam_h) = &pam_h {
+                let _ = pam_h.log(
+                    pam::LogLevel::Error,
+                    format!("Error notifying about the lockout of the \"{name}\" account: {e}"),
+                );
+            }
+        }
+    }
+
+    /// Runs the configured `hook_command` for a lockout event.
+    ///
+    /// Errors are only logged, the hook never affects the PAM result.
+    ///
+    /// # Arguments
+    /// - `event`: The lockout event
+    /// - `tally`: The updated tally
+    /// - `user`: The user the tally belongs to
+    /// - `settings`: A reference to the `Settings` struct
+    fn run_hook<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        event: HookEvent,
+        tally: &Tally,
+        user: &User,
+        settings: &Settings,
+    ) {
+        let Some(command) = &settings.config.hook_command else {
+            return;
+        };
+
+        let name = user.name().to_string_lossy();
+        let context = HookContext {
+            event,
+            user: &name,
+            failures: tally.failures_count,
+            service: settings.service.as_deref(),
+            rhost: settings.rhost.as_deref(),
+            unlock_instant: (event == HookEvent::Lock)
+                .then_some(tally.unlock_instant)
+                .flatten(),
+        };
+
+        if let Err(e) = hook::spawn(command, &context) {
+            if let Some(pam_h) = &pam_h {
+                let _ = pam_h.log(
+                    pam::LogLevel::Error,
+                    format!(
+                        "Error running hook_command {} for the {} event: {e}",
+                        command.display(),
+                        event.name()
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Emits the D-Bus signal of a lockout event if `dbus` is enabled.
+    ///
+    /// Errors are only logged as warnings, the signal never affects the PAM result.
+    ///
+    /// # Arguments
+    /// - `event`: The lockout event
+    /// - `tally`: The updated tally
+    /// - `user`: The user the tally belongs to
+    /// - `settings`: A reference to the `Settings` struct
+    fn signal_lockout<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        event: HookEvent,
+        tally: &Tally,
+        user: &User,
+        settings: &Settings,
+    ) {
+        if !settings.config.dbus {
+            return;
+        }
+
+        let name = user.name().to_string_lossy();
+        let signal = LockoutSignal {
+            event,
+            user: &name,
+            failures: tally.failures_count,
+            unlock_instant: (event == HookEvent::Lock)
+                .then_some(tally.unlock_instant)
+                .flatten(),
+        };
+
+        if let Err(e) = dbus::emit(&signal) {
+            if let Some(pam_h) = &pam_h {
+                let _ = pam_h.log(
+                    pam::LogLevel::Warning,
+                    format!(
+                        "Error signaling the {} event of the \"{name}\" account on D-Bus: {e}",
+                        event.name()
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Clears the tally after a successful authentication.
+    ///
+    /// With `forgive_same_transaction_failures` enabled only the failures recorded earlier in the
+    /// current PAM transaction are subtracted. Failures of earlier transactions are kept, but
+    /// the lock is lifted.
+    ///
+    /// # Arguments
+    /// - `tally`: A mutable reference to the `Tally` struct.
+    /// - `user`: The user the tally belongs to.
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` returned as `PAM_PERM_DENIED` if the
+    /// tally can't be written.
+    fn clear_tally<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        // total failures for logging
+        let total_failures = tally.failures_count;
+
+        // Only forgive the failures of this transaction if configured, clear all otherwise
+        let forgiven_failures = if settings.config.forgive_same_transaction_failures
+            && settings.transaction_failures > 0
+        {
+            min(settings.transaction_failures, total_failures)
+        } else {
+            total_failures
+        };
+        tally.failures_count = total_failures - forgiven_failures;
+
+        // The successful authentication lifts the lock
+        tally.unlock_instant = (tally.failures_count > 0).then(Utc::now);
+
+        // Remember whether there was anything to clear
+        tally.cleared = forgiven_failures > 0;
+
+        // Record the anonymous clear statistics
+        if tally.cleared && tally.failures_count == 0 {
+            Self::record_clear_stats(pam_h, tally, total_failures, settings)?;
+        }
+
+        // Write the updated values back to the file
+        tally
+            .write_tally_file(tally_file, &settings.config)
+            .map_err(|e| e.with_code(PamResultCode::PAM_PERM_DENIED))?;
+
+        // log account unlock
+        if tally.failures_count > 0 {
+            if let Some(pam_h) = &pam_h {
+                pam_h.log(
+                    pam::LogLevel::Info,
+                    format!("PAM_SUCCESS: Forgave {forgiven_failures} failures of this transaction for the \"{}\" account. {} failures remain. Account is unlocked.{}",
+                    user.name().display(),
+                    tally.failures_count,
+                    tally.source_log()),
+                )?;
+            }
+        } else if total_failures > 0 {
+            if let Some(pam_h) = &pam_h {
+                pam_h.log(
+                    pam::LogLevel::Info,
+                    format!("PAM_SUCCESS: Clear tally ({} failures) for the \"{}\" account. Account is unlocked.{}",
+                    total_failures,
+                    user.name().display(),
+                    tally.source_log()),
+                )?;
+            }
+        }
+
+        // alert on lifting a lock
+        if tally.cleared && is_over_threshold(total_failures, settings.config.free_tries) {
+            Self::run_hook(pam_h, HookEvent::Unlock, tally, user, settings);
+            Self::signal_lockout(pam_h, HookEvent::Unlock, tally, user, settings);
+        }
+        Ok(())
+    }
+
+    /// Records a cleared tally into the anonymous stats file.
+    ///
+    /// Recording is best effort: errors are logged but never fail the authentication.
+    ///
+    /// # Arguments
+    /// - `tally`: A mutable reference to the `Tally` struct being cleared.
+    /// - `total_failures`: The number of failures being cleared.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or the `PamResultCode` of a failed log call.
+    fn record_clear_stats<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        total_failures: i32,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        let first_failure_instant = tally
+            .first_failure_instant
+            .take()
+            .unwrap_or(tally.failure_instant);
+
+        if let Err(e) = stats::record_clear(
+            &settings.config.stats_file,
+            settings.service.as_deref().unwrap_or("unknown"),
+            i64::from(total_failures),
+            (Utc::now() - first_failure_instant).num_seconds().max(0),
+        ) {
+            if let Some(pam_h) = &pam_h {
+                pam_h.log(pam::LogLevel::Error, format!("Error recording stats: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a new tally file with default values.
+    ///
+    /// A failure creates it with the failure counted, a session with the login recorded. Both
+    /// are recorded by [`Tally::update_tally`], so a first failure crossing the threshold locks
+    /// the account like any other.
+    ///
+    /// # Arguments
+    /// - `tally_file`: A reference to the tally file `Path`.
+    /// - `tally`: A mutable reference to the `Tally` struct.
+    /// - `user`: The user the tally belongs to.
+    /// - `settings`: A reference to the `Settings` struct.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or an `AuthRampError` in case of errors.
+    fn create_tally_file<P: PamApi>(
+        pam_h: &Option<&mut P>,
+        tally: &mut Tally,
+        user: &User,
+        tally_file: &Path,
+        settings: &Settings,
+    ) -> Result<(), AuthRampError> {
+        create_tally_file_dir(tally_file, &settings.config)?;
+
+        // written with mode 0600
+        Self::update_tally(pam_h, tally, user, tally_file, settings)?;
+
+        // another line of the attempt already recorded the failure
+        if !tally_file.exists() {
+            return Ok(());
+        }
+
+        // set tally file owner, root:root when running as root
+        let file_error = |e| {
+            AuthRampError::io(
+                format!(
+                    "Error setting the owner of tally file {}",
+                    tally_file.display()
+                ),
+                e,
+            )
+        };
+        let tally_file_meta = fs::metadata(tally_file).map_err(file_error)?;
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        if tally_file_meta.uid() != uid || tally_file_meta.gid() != gid {
+            chown(tally_file, Some(uid), Some(gid)).map_err(file_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pam::PamHandle;
+    use tempdir::TempDir;
+
+    use crate::config::Config;
+
+    #[test]
+    fn test_open_existing_tally_file() {
+        // Create a temporary directory
+        let temp_dir = TempDir::new("test_open_existing_tally_file").unwrap();
+        let tally_file_path = temp_dir.path().join("test_user_a");
+
+        // Create an existing TOML file
+        let toml_str = r#"
+            [Fails]
+            count = 42
+            instant = "2023-01-01T00:00:00Z"
+            unlock_instant = "2023-01-02T00:00:00Z"
+        "#;
+        std::fs::write(tally_file_path, toml_str).unwrap();
+
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+
+        // Create settings and call new_from_tally_file
+        let settings = Settings {
+            user: Some(User::new(9999, "test_user_a", 9999)),
+            config,
+            action: Some(Actions::PREAUTH),
+            ..Default::default()
+        };
+
+        // Test: Open existing tally file
+        let result = Tally::new_from_tally_file::<PamHandle>(&None, &settings);
+
+        // Check if the Tally struct is created with expected values
+        assert!(result.is_ok());
+        let tally = result.unwrap();
+        assert_eq!(tally.failures_count, 42);
+        assert_eq!(
+            tally.failure_instant,
+            DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z").unwrap()
+        );
+        assert_eq!(
+            tally.unlock_instant.unwrap(),
+            DateTime::parse_from_rfc3339("2023-01-02T00:00:00Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_open_nonexistent_tally_file() {
+        // Create a temporary directory
+        let temp_dir = TempDir::new("test_open_nonexistent_tally_file").unwrap();
+        let tally_file_path = temp_dir.path().join("test_user_b");
+
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            ..Config::default()
+        };
+
+        // Create settings and call open
+        let settings = Settings {
+            user: Some(User::new(1000, "test_user_b", 1000)),
+            action: Some(Actions::AUTHFAIL),
+            config,
+            ..Default::default()
+        };
+
+        // Test: Open nonexistent tally file
+        let result = Tally::new_from_tally_file::<PamHandle>(&None, &settings);
+
+        // println!("{result:?}");
+
+        // Check if the Tally struct is created with the failure counted
+        assert!(result.is_ok());
+        let tally = result.unwrap();
+        assert_eq!(tally.failures_count, 1);
+        assert_eq!(tally.effective_unlock_instant(&settings), None);
+
+        // Check if the TOML file has been created with the failure counted
+        let toml_content = fs::read_to_string(tally_file_path).unwrap();
+        // println!("{}", &toml_content);
+        assert!(toml_content.contains("[Fails]"));
+        assert!(toml_content.contains("count = 1"));
+    }
+
+    #[test]
+    fn test_open_auth_fail_updates_values() {
+        // Create a temporary directory
+        let temp_dir = TempDir::new("test_open_auth_fail_updates_values").unwrap();
+        let tally_file_path = temp_dir.path().join("test_user_c");
+
+        // Create an existing TOML file with some initial values
+        let toml_str = r#"
+        [Fails]
+        count = 2
+        instant = "2023-01-01T00:00:00Z"
+        unlock_instant = "2023-01-02T00:00:00Z"
+    "#;
+        std::fs::write(&tally_file_path, toml_str).unwrap();
+
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            stats_file: temp_dir.path().join("stats.toml"),
+            free_tries: 6,
+            ramp_multiplier: 50.0,
+            base_delay_seconds: 30,
+            even_deny_root: false,
+            countdown: true,
+            ..Config::default()
+        };
+
+        // Create settings and call new_from_tally_file with AUTHFAIL action
+        let settings = Settings {
+            user: Some(User::new(9999, "test_user_c", 9999)),
+            action: Some(Actions::AUTHFAIL),
+            service: None,
+            tty: None,
+            rhost: None,
+            transaction_failures: 0,
+            transaction_succeeded: false,
+            attempt_counted: None,
+            policy: false,
+            noclear: false,
+            pam_hook: "test",
+            config,
+        };
+
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings).unwrap();
+
+        // Check if the values are updated on AUTHFAIL
+        assert_eq!(tally.failures_count, 3); // Assuming you increment the count
+                                             // Also, assert that the instant is updated to the current time
+        assert!(tally.unlock_instant.is_some());
+        // Optionally, you can assert that the file is updated
+        let toml_content = fs::read_to_string(&tally_file_path).unwrap();
+        assert!(toml_content.contains("count = 3"));
+        // Also, assert the instant and unlock_instant values in the TOML file
+
+        // Additional assertions as needed
+    }
+
+    #[test]
+    fn test_open_auth_succ_resets_tally() {
+        // Create a temporary directory
+        let temp_dir = TempDir::new("test_open_auth_succ_deletes_file").unwrap();
+        let tally_file_path = temp_dir.path().join("test_user_d");
+
+        // Create an existing TOML file
+        let toml_str = r#"
+        [Fails]
+        count = 2
+        instant = "2023-01-01T00:00:00Z"
+        unlock_instant = "2023-01-02T00:00:00Z"
+    "#;
+        std::fs::write(&tally_file_path, toml_str).unwrap();
+
+        let config = Config {
+            tally_dir: temp_dir.path().to_path_buf(),
+            stats_file: temp_dir.path().join("stats.toml"),
+            free_tries: 6,
+            ramp_multiplier: 50.0,
+            base_delay_seconds: 30,
+            even_deny_root: false,
+            countdown: true,
+            ..Config::default()
+        };
+
+        // Create settings and call new_from_tally_file with AUTHSUCC action
+        let settings = Settings {
+            user: Some(User::new(9999, "test_user_d", 9999)),
+            action: Some(Actions::AUTHSUCC),
+            service: None,
+            tty: None,
+            rhost: None,
+            transaction_failures: 0,
+            transaction_succeeded: false,
+            attempt_counted: None,
+            policy: false,
+            noclear: false,
+            pam_hook: "test",
+            config,
+        };
+
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings).unwrap();
+
+        // Expect tally count to reset
+        let toml_content = fs::read_to_string(&tally_file_path).unwrap();
+        assert!(
+            toml_content.contains("count = 0"),
+            "Expected tally count = 0"
+        );
+        assert!(!toml_content.contains("unlock_instant = "));
+        assert!(tally.cleared);
+    }
+
+    #[test]
+    fn test_get_delay_fractional_ramp_multiplier() {
+        let settings = Settings {
+            config: Config {
+                free_tries: 6,
+                base_delay_seconds: 30,
+                ramp_multiplier: 1.5,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+        let tally = Tally {
+            failures_count: 16,
+            ..Tally::default()
+        };
+
+        // 1.5 × 10 × ln(10) + 30 = 64.54, truncating the multiplier to 1 would give 53
+        assert_eq!(tally.get_delay(&settings), Duration::seconds(64));
+
+        // the first delayed failure only gets the base delay
+        let tally = Tally {
+            failures_count: 7,
+            ..Tally::default()
+        };
+        assert_eq!(tally.get_delay(&settings), Duration::seconds(30));
+    }
+
+    #[test]
+    fn test_get_delay_algorithms() {
+        // algorithm, delay_factor, failures, expected seconds with free_tries = 6 and 30 seconds
+        let cases = [
+            (DelayAlgorithm::Ramp, 2.0, 7, 30),
+            (DelayAlgorithm::Ramp, 2.0, 8, 99),
+            (DelayAlgorithm::Ramp, 2.0, 10, 307),
+            (DelayAlgorithm::Exponential, 2.0, 0, 60),
+            (DelayAlgorithm::Exponential, 2.0, 7, 60),
+            (DelayAlgorithm::Exponential, 2.0, 8, 120),
+            (DelayAlgorithm::Exponential, 2.0, 10, 480),
+            (DelayAlgorithm::Exponential, 1.5, 8, 67),
+            (DelayAlgorithm::Exponential, 2.0, 100, i64::from(i32::MAX)),
+            (DelayAlgorithm::Exponential, 0.5, 10, 30),
+            (DelayAlgorithm::Fixed, 2.0, 7, 30),
+            (DelayAlgorithm::Fixed, 2.0, 100, 30),
+        ];
+
+        for (delay_algorithm, delay_factor, failures_count, expected) in cases {
+            let settings = Settings {
+                config: Config {
+                    free_tries: 6,
+                    base_delay_seconds: 30,
+                    ramp_multiplier: 50.0,
+                    delay_algorithm,
+                    delay_factor,
+                    ..Config::default()
+                },
+                ..Default::default()
+            };
+            let tally = Tally {
+                failures_count,
+                ..Tally::default()
+            };
+            assert_eq!(
+                tally.get_delay(&settings),
+                Duration::seconds(expected),
+                "{delay_algorithm:?} with delay_factor {delay_factor} at {failures_count} failures"
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_delay_monotonic() {
+        let configs = [
+            Config::default(),
+            Config {
+                ramp_multiplier: 1.5,
+                ..Config::default()
+            },
+            Config {
+                free_tries: 0,
+                base_delay_seconds: 0,
+                ..Config::default()
+            },
+            Config {
+                ramp_multiplier: -5.0,
+                ..Config::default()
+            },
+            Config {
+                ramp_multiplier: f64::INFINITY,
+                ..Config::default()
+            },
+            Config {
+                ramp_multiplier: f64::NAN,
+                base_delay_seconds: -10,
+                ..Config::default()
+            },
+            Config {
+                delay_algorithm: DelayAlgorithm::Exponential,
+                ..Config::default()
+            },
+            Config {
+                delay_algorithm: DelayAlgorithm::Exponential,
+                delay_factor: 0.5,
+                ..Config::default()
+            },
+            Config {
+                delay_algorithm: DelayAlgorithm::Fixed,
+                ..Config::default()
+            },
+        ];
+
+        for config in configs {
+            let base_delay = Duration::seconds(i64::from(config.base_delay_seconds.max(0)));
+            let settings = Settings {
+                config,
+                ..Default::default()
+            };
+
+            let mut previous = Duration::zero();
+            for failures_count in 0..100 {
+                let delay = Tally {
+                    failures_count,
+                    ..Tally::default()
+                }
+                .get_delay(&settings);
+
+                assert!(delay >= base_delay, "{failures_count}: {delay} below base");
+                assert!(delay >= previous, "{failures_count}: {delay} < {previous}");
+                previous = delay;
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_over_threshold_boundary() {
+        assert!(!is_over_threshold(0, 6));
+        assert!(!is_over_threshold(6, 6));
+        assert!(is_over_threshold(7, 6));
+        assert!(is_over_threshold(1, 0));
+        assert!(!is_over_threshold(0, 0));
+    }
+
+    #[test]
+    fn test_to_toml_round_trip() {
+        let temp_dir = TempDir::new("test_to_toml_round_trip").unwrap();
+        let tally_file = temp_dir.path().join("test_user");
+        let now = Utc::now();
+
+        // every instant set
+        let tally = Tally {
+            file: Some(tally_file.clone()),
+            failures_count: 7,
+            failure_instant: now,
+            first_failure_instant: Some(now - Duration::seconds(60)),
+            unlock_instant: Some(now + Duration::seconds(30)),
+            last_success: Some(now - Duration::seconds(3600)),
+            service: Some("sshd".to_string()),
+            rhost: Some("evil\"\ncount = 0".to_string()),
+            ..Tally::default()
+        };
+        fs::write(&tally_file, tally.to_toml()).unwrap();
+        assert_eq!(Tally::read_tally_file(&tally_file).unwrap(), tally);
+
+        // unset instants are omitted
+        let tally = Tally {
+            file: Some(tally_file.clone()),
+            failure_instant: now,
+            ..Tally::default()
+        };
+        assert!(!tally.to_toml().contains("unlock_instant"));
+        assert!(!tally.to_toml().contains("last_success"));
+        fs::write(&tally_file, tally.to_toml()).unwrap();
+        assert_eq!(Tally::read_tally_file(&tally_file).unwrap(), tally);
+    }
+
+    #[test]
+    fn test_auth_fail_records_source() {
+        let temp_dir = TempDir::new("test_auth_fail_records_source").unwrap();
+        let tally_file = temp_dir.path().join("test_user");
+
+        let settings = |action: Actions, rhost: &str| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            service: Some("sshd".to_string()),
+            rhost: Some(rhost.to_string()),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+
+        // created and updated tallies keep the most recent source
+        for rhost in ["192.0.2.1", "192.0.2.2"] {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL, rhost))
+                .unwrap();
+        }
+        let tally = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(tally.failures_count, 2);
+        assert_eq!(tally.service.as_deref(), Some("sshd"));
+        assert_eq!(tally.rhost.as_deref(), Some("192.0.2.2"));
+        assert_eq!(tally.tty, None);
+        assert_eq!(
+            tally.source().as_deref(),
+            Some("service sshd, rhost 192.0.2.2")
+        );
+
+        // a clear records the source of the success
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHSUCC, "192.0.2.3"))
+            .unwrap();
+        let tally = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(tally.failures_count, 0);
+        assert_eq!(tally.rhost.as_deref(), Some("192.0.2.3"));
+    }
+
+    #[test]
+    fn test_tally_hmac() {
+        let temp_dir = TempDir::new("test_tally_hmac").unwrap();
+        let tally_file = temp_dir.path().join("test_user");
+        let key_file = temp_dir.path().join("authramp.key");
+        fs::write(&key_file, "secret").unwrap();
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+        let set_key_modified = |modified: std::time::SystemTime| {
+            fs::File::options()
+                .write(true)
+                .open(&key_file)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let hour = std::time::Duration::from_hours(1);
+        set_key_modified(std::time::SystemTime::now() - hour);
+
+        let settings = |action: Actions, fail_closed: bool| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                tally_hmac_key_file: Some(key_file.clone()),
+                tally_hmac_fail_closed: fail_closed,
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+        let preauth = |fail_closed: bool| {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, fail_closed))
+        };
+
+        // written tallies are signed
+        for _ in 0..7 {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL, true))
+                .unwrap();
+        }
+        let signed = fs::read_to_string(&tally_file).unwrap();
+        assert!(signed.contains("\nhmac = \""));
+        let (tally, integrity) =
+            Tally::read_verified_tally_file(&tally_file, &settings(Actions::PREAUTH, true).config)
+                .unwrap();
+        assert_eq!(integrity, Integrity::Verified);
+        assert_eq!(tally.failures_count, 7);
+        assert_eq!(preauth(true).unwrap().failures_count, 7);
+
+        // tampered count
+        fs::write(&tally_file, signed.replace("count = 7", "count = 0")).unwrap();
+        assert_eq!(
+            preauth(true).unwrap_err().code(),
+            PamResultCode::PAM_SYSTEM_ERR
+        );
+        // fail open discards the untrusted tally
+        assert_eq!(preauth(false).unwrap().failures_count, 0);
+
+        // tampered unlock_instant
+        let tampered: String = signed
+            .lines()
+            .map(|line| {
+                if line.starts_with("unlock_instant") {
+                    "unlock_instant = \"2000-01-01T00:00:00Z\"".to_string()
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(&tally_file, tampered).unwrap();
+        assert_eq!(
+            preauth(true).unwrap_err().code(),
+            PamResultCode::PAM_SYSTEM_ERR
+        );
+
+        // missing MAC on a tally written after the key
+        let stripped: String = signed
+            .lines()
+            .filter(|line| !line.starts_with("hmac"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(&tally_file, &stripped).unwrap();
+        assert_eq!(
+            preauth(true).unwrap_err().code(),
+            PamResultCode::PAM_SYSTEM_ERR
+        );
+
+        // a tally predating the key is accepted once and upgraded
+        set_key_modified(std::time::SystemTime::now() + hour);
+        assert_eq!(preauth(true).unwrap().failures_count, 7);
+        assert!(fs::read_to_string(&tally_file).unwrap().contains("hmac = "));
+        set_key_modified(std::time::SystemTime::now() - hour);
+        assert_eq!(preauth(true).unwrap().failures_count, 7);
+    }
+
+    #[test]
+    fn test_tally_file_path() {
+        let tally_dir = Path::new("/var/run/authramp");
+
+        // traversal attempts
+        for user in [
+            "../../etc/cron.d/evil",
+            "..",
+            ".",
+            ".hidden",
+            "a/b",
+            "/etc/passwd",
+            "a..b",
+            "a\0b",
+            "",
+        ] {
+            assert!(
+                tally_file_path(tally_dir, OsStr::new(user)).is_err(),
+                "{user:?} not rejected"
+            );
+        }
+
+        // spaces and UTF-8 are kept as they are
+        for user in [
+            "test_user",
+            "John Doe",
+            "jöhn",
+            "用户",
+            "user.name",
+            "user@example.com",
+        ] {
+            assert_eq!(
+                tally_file_path(tally_dir, OsStr::new(user)),
+                Ok(tally_dir.join(user))
+            );
+        }
+    }
+
+    #[test]
+    fn test_traversal_user_is_unknown() {
+        let temp_dir = TempDir::new("test_traversal_user_is_unknown").unwrap();
+        let tally_dir = temp_dir.path().join("tally");
+
+        let settings = Settings {
+            user: Some(User::new(9999, "../evil", 9999)),
+            action: Some(Actions::AUTHFAIL),
+            config: Config {
+                tally_dir: tally_dir.clone(),
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings)
+                .unwrap_err()
+                .code(),
+            PamResultCode::PAM_USER_UNKNOWN
+        );
+        assert!(!temp_dir.path().join("evil").exists());
+        assert!(!tally_dir.exists());
+    }
+
+    #[test]
+    fn test_uid_keyed_tally() {
+        let temp_dir = TempDir::new("test_uid_keyed_tally").unwrap();
+        let tally_dir = temp_dir.path().to_path_buf();
+
+        let settings = |name: &str, key_file: Option<PathBuf>| Settings {
+            user: Some(User::new(9999, name, 9999)),
+            action: Some(Actions::AUTHFAIL),
+            config: Config {
+                tally_dir: tally_dir.clone(),
+                tally_key: TallyKey::Uid,
+                tally_hmac_key_file: key_file,
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+
+        // a signed name-keyed tally from before the switch is migrated and signed again
+        let key_file = tally_dir.join("authramp.key");
+        fs::write(&key_file, "secret").unwrap();
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+        let name_config = Config {
+            tally_hmac_key_file: Some(key_file.clone()),
+            ..settings("test_user", None).config
+        };
+        Tally {
+            failures_count: 3,
+            ..Tally::default()
+        }
+        .write_tally_file(&tally_dir.join("test_user"), &name_config)
+        .unwrap();
+
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings("test_user", Some(key_file)))
+                .unwrap();
+        assert_eq!(tally.failures_count, 4);
+        assert!(!tally_dir.join("test_user").exists());
+        let stored = Tally::read_tally_file(&tally_dir.join("9999")).unwrap();
+        assert_eq!(stored.failures_count, 4);
+        assert_eq!(stored.user_name.as_deref(), Some("test_user"));
+
+        // names resolving to the same uid share the tally
+        fs::remove_file(tally_dir.join("9999")).unwrap();
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings("test_user", None)).unwrap();
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings("Test_User", None)).unwrap();
+        assert_eq!(tally.failures_count, 2);
+        assert!(!tally_dir.join("Test_User").exists());
+        let stored = Tally::read_tally_file(&tally_dir.join("9999")).unwrap();
+        assert_eq!(stored.user_name.as_deref(), Some("Test_User"));
+
+        // without the user database tallies stay keyed by name
+        let mut name_only = settings("test_user", None);
+        name_only.config.user_lookup = UserLookup::None;
+        assert_eq!(
+            user_tally_file(&name_only.config, name_only.user.as_ref().unwrap()),
+            Ok(tally_dir.join("test_user"))
+        );
+    }
+
+    #[test]
+    fn test_tally_layout() {
+        let temp_dir = TempDir::new("test_tally_layout").unwrap();
+        let tally_dir = temp_dir.path().join("tally");
+        let shard_dir = tally_dir.join(shard(OsStr::new("test_user")));
+
+        let settings = |tally_layout: TallyLayout| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(Actions::AUTHFAIL),
+            config: Config {
+                tally_dir: tally_dir.clone(),
+                tally_layout,
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+        let user = User::new(9999, "test_user", 9999);
+
+        assert_eq!(shard(OsStr::new("test_user")).len(), 2);
+        assert_eq!(
+            user_tally_file(&settings(TallyLayout::Sharded).config, &user),
+            Ok(shard_dir.join("test_user"))
+        );
+        assert_eq!(
+            user_tally_file(&settings(TallyLayout::Flat).config, &user),
+            Ok(tally_dir.join("test_user"))
+        );
+
+        // a new tally is created in its shard, which is as private as the tally directory
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(TallyLayout::Sharded)).unwrap();
+        assert!(shard_dir.join("test_user").is_file());
+        assert_eq!(
+            fs::metadata(&shard_dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        // switching back finds the sharded tally and moves it
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(TallyLayout::Flat)).unwrap();
+        assert_eq!(tally.failures_count, 2);
+        assert!(!shard_dir.join("test_user").exists());
+        assert!(tally_dir.join("test_user").is_file());
+
+        // and the flat tally is moved to its shard
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings(TallyLayout::Sharded))
+            .unwrap();
+        assert_eq!(tally.failures_count, 3);
+        assert!(!tally_dir.join("test_user").exists());
+        assert_eq!(
+            find_tally_file(&settings(TallyLayout::Flat).config, &user),
+            Ok(shard_dir.join("test_user"))
+        );
+    }
+
+    #[test]
+    fn test_tally_files() {
+        let temp_dir = TempDir::new("test_tally_files").unwrap();
+        let tally_dir = temp_dir.path();
+
+        fs::write(tally_dir.join("flat_user"), "").unwrap();
+        fs::write(tally_dir.join(".flat_user.corrupt-1"), "").unwrap();
+        fs::create_dir(tally_dir.join("ab")).unwrap();
+        fs::write(tally_dir.join("ab").join("sharded_user"), "").unwrap();
+        fs::write(tally_dir.join("ab").join(".sharded_user.1.tmp"), "").unwrap();
+        // only two hex digit directories are shards
+        fs::create_dir(tally_dir.join("abc")).unwrap();
+        fs::write(tally_dir.join("abc").join("other"), "").unwrap();
+
+        let mut found = tally_files(tally_dir).unwrap();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                tally_dir.join("ab").join("sharded_user"),
+                tally_dir.join("flat_user")
+            ]
+        );
+
+        assert!(tally_files(&tally_dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_tally_versions() {
+        let temp_dir = TempDir::new("test_tally_versions").unwrap();
+        let tally_file = temp_dir.path().join("test_user");
+        let now = Utc::now();
+
+        let settings = |key_file: Option<PathBuf>| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(Actions::AUTHFAIL),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                tally_hmac_key_file: key_file,
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+        let quarantined = || {
+            fs::read_dir(temp_dir.path())
+                .unwrap()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_name().to_string_lossy().contains(".corrupt-"))
+                .count()
+        };
+
+        // the INI format and version-less TOML are migrated
+        for legacy in [
+            format!("[Fails]\ncount=3\ninstant={now}"),
+            format!("[Fails]\ncount = 3\ninstant = \"{now}\""),
+        ] {
+            fs::write(&tally_file, legacy).unwrap();
+            let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings(None)).unwrap();
+            assert_eq!(tally.failures_count, 4);
+            let stored = fs::read_to_string(&tally_file).unwrap();
+            assert!(stored.starts_with(&format!("version = {TALLY_VERSION}\n")));
+            assert_eq!(Tally::parse(&stored).unwrap().failures_count, 4);
+        }
+
+        // the MAC of a version-less tally still verifies
+        let key_file = temp_dir.path().join("authramp.key");
+        fs::write(&key_file, "secret").unwrap();
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+        let legacy = Tally {
+            version: 0,
+            failures_count: 3,
+            failure_instant: now,
+            ..Tally::default()
+        }
+        .to_toml();
+        let mac = integrity::sign(b"secret", "test_user", &legacy);
+        fs::write(&tally_file, format!("{legacy}\nhmac = \"{mac}\"")).unwrap();
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Some(key_file.clone())))
+                .unwrap();
+        assert_eq!(tally.failures_count, 4);
+        let (stored, integrity) =
+            Tally::read_verified_tally_file(&tally_file, &settings(Some(key_file.clone())).config)
+                .unwrap();
+        assert_eq!(integrity, Integrity::Verified);
+        assert_eq!(stored.version, TALLY_VERSION);
+
+        // corrupt tallies and unknown versions are quarantined and start over
+        for corrupt in ["not a tally", "version = 99\n\n[Fails]\ncount = 3", ""] {
+            fs::write(&tally_file, corrupt).unwrap();
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(None)).unwrap();
+            let tally = Tally::read_tally_file(&tally_file).unwrap();
+            assert_eq!(tally.failures_count, 1);
+            assert_eq!(quarantined(), 1);
+            for entry in fs::read_dir(temp_dir.path())
+                .unwrap()
+                .filter_map(Result::ok)
+            {
+                if entry.file_name().to_string_lossy().contains(".corrupt-") {
+                    assert!(entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(".test_user."));
+                    fs::remove_file(entry.path()).unwrap();
+                }
+            }
+        }
+
+        // unless a failed integrity check must block the user
+        fs::write(&tally_file, "not a tally").unwrap();
+        assert_eq!(
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Some(key_file)))
+                .unwrap_err()
+                .code(),
+            PamResultCode::PAM_SYSTEM_ERR
+        );
+        assert_eq!(quarantined(), 0);
+    }
+
+    #[test]
+    fn test_failures_while_locked() {
+        let temp_dir = TempDir::new("test_failures_while_locked").unwrap();
+        let tally_file = temp_dir.path().join("test_user");
+
+        let settings = |count_while_locked: bool| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(Actions::AUTHFAIL),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                free_tries: 2,
+                count_while_locked,
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+
+        for _ in 0..3 {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(false)).unwrap();
+        }
+        let locked = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(locked.failures_count, 3);
+        assert!(locked
+            .unlock_instant
+            .is_some_and(|unlock| Utc::now() < unlock));
+
+        // a burst of failures during the lock neither counts nor extends it
+        for _ in 0..5 {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(false)).unwrap();
+        }
+        let tally = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(tally.failures_count, 3);
+        assert_eq!(tally.unlock_instant, locked.unlock_instant);
+
+        // with count_while_locked every failure ramps the delay
+        for _ in 0..5 {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(true)).unwrap();
+        }
+        let tally = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(tally.failures_count, 8);
+        assert!(tally.unlock_instant > locked.unlock_instant);
+
+        // failures count again once the lock ended
+        let expired = Tally {
+            unlock_instant: Some(Utc::now() - Duration::seconds(1)),
+            ..tally
+        };
+        expired
+            .write_tally_file(&tally_file, &settings(false).config)
+            .unwrap();
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(false)).unwrap();
+        assert_eq!(
+            Tally::read_tally_file(&tally_file).unwrap().failures_count,
+            9
+        );
+    }
+
+    #[test]
+    fn test_session_records_last_success() {
+        let temp_dir = TempDir::new("test_session_records_last_success").unwrap();
+        let tally_file = temp_dir.path().join("root");
+
+        // root is exempt from lockout, but its logins are recorded
+        let settings = |action: Actions| Settings {
+            user: Some(User::new(0, "root", 0)),
+            action: Some(action),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                ..Config::default()
+            },
+            ..Settings::default()
+        };
+
+        // the first session creates the tally
+        let before = Utc::now();
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::SESSION)).unwrap();
+        let tally = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(tally.failures_count, 0);
+        let first_success = tally.last_success.unwrap();
+        assert!(before <= first_success);
+
+        // failures keep the last success
+        for _ in 0..2 {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL)).unwrap();
+        }
+        let failed = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(failed.failures_count, 2);
+        assert_eq!(failed.last_success, Some(first_success));
+
+        // a session updates it without touching the failures
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::SESSION)).unwrap();
+        let tally = Tally::read_tally_file(&tally_file).unwrap();
+        assert_eq!(tally.failures_count, 2);
+        assert_eq!(tally.failure_instant, failed.failure_instant);
+        assert_eq!(tally.unlock_instant, failed.unlock_instant);
+        assert!(tally.last_success > Some(first_success));
+    }
+
+    #[test]
+    fn test_free_tries_boundary() {
+        let temp_dir = TempDir::new("test_free_tries_boundary").unwrap();
+
+        let settings = |action: Actions| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            config: Config {
+                tally_dir: temp_dir.path().join("tally"),
+                stats_file: temp_dir.path().join("stats.toml"),
+                free_tries: 6,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        // the free tries never lock
+        for _ in 0..6 {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL)).unwrap();
+        }
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH)).unwrap();
+        assert_eq!(tally.failures_count, 6);
+        assert_eq!(
+            tally.effective_unlock_instant(&settings(Actions::PREAUTH)),
+            None
+        );
+
+        // the next failure locks
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL)).unwrap();
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH)).unwrap();
+        assert_eq!(tally.failures_count, 7);
+        assert!(tally
+            .effective_unlock_instant(&settings(Actions::PREAUTH))
+            .is_some_and(|unlock_instant| Utc::now() < unlock_instant));
+    }
+
+    #[test]
+    fn test_effective_unlock_instant() {
+        let temp_dir = TempDir::new("test_effective_unlock_instant").unwrap();
+
+        let settings = |action: Actions, max_lockout_seconds: i64| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                stats_file: temp_dir.path().join("stats.toml"),
+                free_tries: 6,
+                base_delay_seconds: 30,
+                ramp_multiplier: 50.0,
+                max_lockout_seconds,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        // a legacy tally without unlock_instant falls back to the capped delay
+        let failure_instant = Utc::now() - Duration::minutes(10);
+        std::fs::write(
+            temp_dir.path().join("test_user"),
+            format!(
+                "[Fails]\ncount = 9\ninstant = \"{}\"\n",
+                failure_instant.to_rfc3339()
+            ),
+        )
+        .unwrap();
+        let legacy =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, 60))
+                .unwrap();
+        assert_eq!(legacy.unlock_instant, None);
+        assert_eq!(
+            legacy.effective_unlock_instant(&settings(Actions::PREAUTH, 60)),
+            Some(legacy.failure_instant + Duration::seconds(60))
+        );
+
+        // once that lock passed, the writer records the same computation
+        let failed =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL, 60))
+                .unwrap();
+        assert_eq!(
+            failed.unlock_instant,
+            Some(failed.failure_instant + Duration::seconds(60))
+        );
+
+        // a harsher config keeps the recorded lock, a more lenient one shortens it
+        let stored =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, 120))
+                .unwrap();
+        assert_eq!(
+            stored.effective_unlock_instant(&settings(Actions::PREAUTH, 120)),
+            failed.unlock_instant
+        );
+        assert_eq!(
+            stored.effective_unlock_instant(&settings(Actions::PREAUTH, 20)),
+            Some(failed.failure_instant + Duration::seconds(20))
+        );
+
+        // failures during that lock aren't recorded
+        let ignored =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL, 20))
+                .unwrap();
+        assert_eq!(ignored.failures_count, failed.failures_count);
+
+        // and the next failure after it uses the new cap
+        std::fs::write(
+            temp_dir.path().join("test_user"),
+            format!(
+                "[Fails]\ncount = 10\ninstant = \"{}\"\nunlock_instant = \"{}\"\n",
+                failure_instant.to_rfc3339(),
+                (failure_instant + Duration::seconds(60)).to_rfc3339()
+            ),
+        )
+        .unwrap();
+        let failed =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL, 20))
+                .unwrap();
+        assert_eq!(
+            failed.effective_unlock_instant(&settings(Actions::PREAUTH, 20)),
+            Some(failed.failure_instant + Duration::seconds(20))
+        );
+    }
+
+    #[test]
+    fn test_recompute_on_config_change() {
+        let settings = |base_delay_seconds: i32, recompute_on_config_change: bool| Settings {
+            config: Config {
+                base_delay_seconds,
+                recompute_on_config_change,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        // locked for 10 hours under a harsh configuration
+        let now = Utc::now();
+        let tally = Tally {
+            failures_count: 10,
+            failure_instant: now,
+            unlock_instant: Some(now + Duration::hours(10)),
+            ..Tally::default()
+        };
+
+        // the current settings give 30 seconds plus the ramp of 4 failures over the free tries
+        let lenient = tally.effective_unlock_instant(&settings(30, true)).unwrap();
+        assert!(lenient - now < Duration::minutes(10));
+        assert_eq!(lenient, now + tally.get_capped_delay(&settings(30, true)));
+
+        // a harsher configuration never extends the lock
+        assert_eq!(
+            tally.effective_unlock_instant(&settings(86400, true)),
+            tally.unlock_instant
+        );
+
+        // the recorded lock is kept if disabled, or if it was set manually
+        assert_eq!(
+            tally.effective_unlock_instant(&settings(30, false)),
+            tally.unlock_instant
+        );
+        let manual = Tally {
+            manual_lock: true,
+            ..tally.clone()
+        };
+        assert_eq!(
+            manual.effective_unlock_instant(&settings(30, true)),
+            tally.unlock_instant
+        );
+
+        // the marker survives the tally file
+        assert!(Tally::parse(&manual.to_toml()).unwrap().manual_lock);
+        assert!(!tally.to_toml().contains("manual_lock"));
+    }
+
+    #[test]
+    fn test_attempt_counted_once() {
+        let temp_dir = TempDir::new("test_attempt_counted_once").unwrap();
+
+        let settings = |action: Actions, attempt_counted: Option<bool>| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            attempt_counted,
+            config: Config {
+                tally_dir: temp_dir.path().join("tally"),
+                stats_file: temp_dir.path().join("stats.toml"),
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+        let fail = |attempt_counted: Option<bool>| {
+            Tally::new_from_tally_file::<PamHandle>(
+                &None,
+                &settings(Actions::AUTHFAIL, attempt_counted),
+            )
+            .unwrap()
+        };
+        let failures = || {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, None))
+                .unwrap()
+                .failures_count
+        };
+
+        // the first authfail line of an attempt records the failure
+        assert_eq!(fail(Some(false)).transaction_failures, 1);
+        assert_eq!(failures(), 1);
+
+        // later lines of the same attempt don't
+        assert_eq!(fail(Some(true)).transaction_failures, 0);
+        assert_eq!(failures(), 1);
+
+        // stacks without preauth record every failure
+        fail(None);
+        fail(None);
+        assert_eq!(failures(), 3);
+    }
+
+    #[test]
+    fn test_clock_backwards() {
+        let temp_dir = TempDir::new("test_clock_backwards").unwrap();
+        let tally_file = temp_dir.path().join("test_user");
+
+        let settings = |recompute_on_config_change: bool| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(Actions::PREAUTH),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                stats_file: temp_dir.path().join("stats.toml"),
+                max_lockout_seconds: 3600,
+                recompute_on_config_change,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+        let write_tally = |extra: &str| {
+            let future = Utc::now() + Duration::days(2);
+            fs::write(
+                &tally_file,
+                format!("[Fails]\ncount = 10\ninstant = \"{future}\"\nfirst_instant = \"{future}\"\nunlock_instant = \"{}\"{extra}", future + Duration::days(30)),
+            )
+            .unwrap();
+        };
+
+        // instants in the future are clamped to now, the unlock to the cap
+        write_tally("");
+        let before = Utc::now();
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings(false)).unwrap();
+        let after = Utc::now();
+        assert!(before <= tally.failure_instant && tally.failure_instant <= after);
+        assert!(tally.first_failure_instant <= Some(after));
+        let unlock_instant = tally.effective_unlock_instant(&settings(false)).unwrap();
+        assert!(unlock_instant <= after + Duration::seconds(3600));
+
+        // the lock ends with the delay of the current settings, not in a month
+        let unlock_instant = tally.effective_unlock_instant(&settings(true)).unwrap();
+        assert!(unlock_instant <= after + tally.get_capped_delay(&settings(true)));
+
+        // a manual lock is kept, it only ends with its unlock instant
+        write_tally("\nmanual_lock = true");
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings(true)).unwrap();
+        assert!(tally.failure_instant <= Utc::now());
+        assert!(tally
+            .effective_unlock_instant(&settings(true))
+            .is_some_and(|unlock_instant| unlock_instant > Utc::now() + Duration::days(30)));
+    }
+
+    #[test]
+    fn test_create_tally_dir() {
+        let temp_dir = TempDir::new("test_create_tally_dir").unwrap();
+        let tally_dir = temp_dir.path().join("lib").join("authramp").join("tally");
+
+        // created with the intermediate directories on first use, only accessible by the owner
+        create_tally_dir(&tally_dir, 0o700).unwrap();
+        let metadata = fs::metadata(&tally_dir).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        assert_eq!(metadata.uid(), unsafe { libc::getuid() });
+
+        // an existing directory is tightened
+        fs::set_permissions(&tally_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        create_tally_dir(&tally_dir, 0o700).unwrap();
+        assert_eq!(
+            fs::metadata(&tally_dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+    }
+
+    #[test]
+    fn test_tally_permissions() {
+        let temp_dir = TempDir::new("test_tally_permissions").unwrap();
+        let tally_dir = temp_dir.path().join("tally");
+
+        let settings = |fail_mode: FailMode| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(Actions::AUTHFAIL),
+            config: Config {
+                tally_dir: tally_dir.clone(),
+                stats_file: temp_dir.path().join("stats.toml"),
+                fail_mode,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // the directory and the files are only accessible by their owner, whatever the umask
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(FailMode::Open)).unwrap();
+        let tally_file = tally_dir.join("test_user");
+        assert_eq!(mode(&tally_dir), 0o700);
+        assert_eq!(mode(&tally_file), 0o600);
+        let metadata = fs::metadata(&tally_file).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), unsafe {
+            (libc::getuid(), libc::getgid())
+        });
+
+        // files of older releases are tightened with the next write
+        fs::set_permissions(&tally_file, fs::Permissions::from_mode(0o644)).unwrap();
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(FailMode::Open)).unwrap();
+        assert_eq!(mode(&tally_file), 0o600);
+
+        // status_world_readable lets everyone read the tallies, but not list or change them
+        let mut world_readable = settings(FailMode::Open);
+        world_readable.config.status_world_readable = true;
+        Tally::new_from_tally_file::<PamHandle>(&None, &world_readable).unwrap();
+        assert_eq!(mode(&tally_dir), 0o700);
+        assert_eq!(mode(&tally_file), 0o644);
+        fs::remove_file(&tally_file).unwrap();
+        Tally::new_from_tally_file::<PamHandle>(&None, &world_readable).unwrap();
+        assert_eq!(mode(&tally_dir), 0o711);
+
+        // and turning it off again tightens them
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(FailMode::Open)).unwrap();
+        assert_eq!(mode(&tally_file), 0o600);
+
+        // a world-writable directory is only used in open mode
+        fs::set_permissions(&tally_dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(Tally::new_from_tally_file::<PamHandle>(&None, &settings(FailMode::Open)).is_ok());
+        assert!(
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(FailMode::Closed)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_tally_dir_issue() {
+        let temp_dir = TempDir::new("test_tally_dir_issue").unwrap();
+        let tally_dir = temp_dir.path().join("tally");
+
+        // a missing directory is created safely later
+        assert_eq!(tally_dir_issue(&tally_dir), None);
+
+        create_tally_dir(&tally_dir, 0o700).unwrap();
+        assert_eq!(tally_dir_issue(&tally_dir), None);
+
+        fs::set_permissions(&tally_dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        assert!(tally_dir_issue(&tally_dir).is_some_and(|issue| issue.contains("world-writable")));
+        fs::set_permissions(&tally_dir, fs::Permissions::from_mode(0o700)).unwrap();
+
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&tally_dir, &link).unwrap();
+        assert_eq!(tally_dir_issue(&link), Some("is a symlink".to_string()));
+
+        // only root or the user running the module may own it
+        if unsafe { libc::getuid() } == 0 {
+            chown(&tally_dir, Some(4242), None).unwrap();
+            assert_eq!(
+                tally_dir_issue(&tally_dir),
+                Some("is owned by uid 4242 instead of root".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_unavailable_tally_dir() {
+        let temp_dir = TempDir::new("test_unavailable_tally_dir").unwrap();
+
+        // a parent that isn't a directory can't hold the tallies, like a missing /var/run
+        let parent = temp_dir.path().join("run");
+        fs::write(&parent, "").unwrap();
+        let settings = |action: Actions| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            config: Config {
+                tally_dir: parent.join("authramp"),
+                stats_file: temp_dir.path().join("stats.toml"),
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        let error = Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL))
+            .unwrap_err();
+        assert!(error.is_unavailable());
+
+        // reading needs no directory
+        assert!(
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH)).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_local_failures() {
+        let temp_dir = TempDir::new("test_local_failures").unwrap();
+
+        let settings = |action: Actions, tty: &str, count_local_failures: bool| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            service: Some("login".to_string()),
+            tty: Some(tty.to_string()),
+            config: Config {
+                tally_dir: temp_dir.path().join("tally"),
+                stats_file: temp_dir.path().join("stats.toml"),
+                deny_local: false,
+                count_local_failures,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        let fail = |tty: &str, count_local_failures: bool| {
+            Tally::new_from_tally_file::<PamHandle>(
+                &None,
+                &settings(Actions::AUTHFAIL, tty, count_local_failures),
+            )
+            .unwrap()
+        };
+        let failures = || {
+            Tally::new_from_tally_file::<PamHandle>(
+                &None,
+                &settings(Actions::PREAUTH, "tty1", true),
+            )
+            .unwrap()
+            .failures_count
+        };
+
+        // local failures are recorded by default
+        fail("tty1", true);
+        assert_eq!(failures(), 1);
+
+        // unless count_local_failures is disabled, which still records remote failures
+        assert_eq!(fail("tty1", false).transaction_failures, 0);
+        assert_eq!(failures(), 1);
+        fail("pts/0", false);
+        assert_eq!(failures(), 2);
+    }
+
+    #[test]
+    fn test_reset_after_seconds() {
+        let temp_dir = TempDir::new("test_reset_after_seconds").unwrap();
+        let tally_dir = temp_dir.path().join("tally");
+        fs::create_dir_all(&tally_dir).unwrap();
+        let tally_file = tally_dir.join("test_user");
+        let now = Utc::now();
+
+        let settings = |action: Actions, reset_after_seconds: i64| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            config: Config {
+                tally_dir: tally_dir.clone(),
+                stats_file: temp_dir.path().join("stats.toml"),
+                reset_after_seconds,
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+        let write_tally = |count: i32, instant: DateTime<Utc>, unlock_instant: DateTime<Utc>| {
+            fs::write(
+                &tally_file,
+                format!("[Fails]\ncount = {count}\ninstant = \"{instant}\"\nunlock_instant = \"{unlock_instant}\""),
+            )
+            .unwrap();
+        };
+
+        // old failures expire before the action is applied and the reset is persisted
+        let a_week_ago = now - Duration::days(7);
+        write_tally(5, a_week_ago, a_week_ago);
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, 86400))
+                .unwrap();
+        assert_eq!(tally.failures_count, 0);
+        assert_eq!(
+            Tally::read_tally_file(&tally_file).unwrap().failures_count,
+            0
+        );
+
+        write_tally(5, a_week_ago, a_week_ago);
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL, 86400))
+                .unwrap();
+        assert_eq!(tally.failures_count, 1);
+
+        // recent failures are kept
+        let an_hour_ago = now - Duration::hours(1);
+        write_tally(5, an_hour_ago, an_hour_ago);
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, 86400))
+                .unwrap();
+        assert_eq!(tally.failures_count, 5);
+
+        // an active lock is never bypassed
+        write_tally(10, now - Duration::minutes(2), now + Duration::hours(1));
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, 60))
+            .unwrap();
+        assert_eq!(tally.failures_count, 10);
+
+        // 0 never expires
+        write_tally(5, a_week_ago, a_week_ago);
+        let tally =
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH, 0)).unwrap();
+        assert_eq!(tally.failures_count, 5);
+    }
+
+    #[test]
+    fn test_same_transaction_failures() {
+        for forgive in [true, false] {
+            // Create a temporary directory
+            let temp_dir = TempDir::new("test_same_transaction_failures").unwrap();
+            let tally_dir = temp_dir.path().join("tally");
+            fs::create_dir_all(&tally_dir).unwrap();
+
+            // One failure left over from an earlier transaction
+            std::fs::write(
+                tally_dir.join("test_user"),
+                "[Fails]\ncount = 1\ninstant = \"2023-01-01T00:00:00Z\"",
+            )
+            .unwrap();
+
+            let settings = |action: Actions, transaction_failures: i32| Settings {
+                user: Some(User::new(9999, "test_user", 9999)),
+                action: Some(action),
+                transaction_failures,
+                config: Config {
+                    tally_dir: tally_dir.clone(),
+                    stats_file: temp_dir.path().join("stats.toml"),
+                    forgive_same_transaction_failures: forgive,
+                    ..Config::default()
+                },
+                ..Default::default()
+            };
+
+            // fail, fail and succeed within one transaction
+            let tally =
+                Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL, 0))
+                    .unwrap();
+            assert_eq!(tally.transaction_failures, 1);
+            let tally = Tally::new_from_tally_file::<PamHandle>(
+                &None,
+                &settings(Actions::AUTHFAIL, tally.transaction_failures),
+            )
+            .unwrap();
+            assert_eq!(tally.failures_count, 3);
+            assert_eq!(tally.transaction_failures, 2);
+            let tally = Tally::new_from_tally_file::<PamHandle>(
+                &None,
+                &settings(Actions::AUTHSUCC, tally.transaction_failures),
+            )
+            .unwrap();
+            assert!(tally.cleared);
+            assert_eq!(tally.transaction_failures, 0);
+
+            // Only the earlier transaction's failure persists if forgiven
+            let persisted = Tally::read_tally_file(&tally_dir.join("test_user")).unwrap();
+            let expected = i32::from(forgive);
+            assert_eq!(persisted.failures_count, expected, "forgive = {forgive}");
+
+            // The account is unlocked either way
+            let unlock_instant = persisted.effective_unlock_instant(&settings(Actions::PREAUTH, 0));
+            assert!(unlock_instant.is_none_or(|unlock_instant| unlock_instant <= Utc::now()));
+        }
+    }
+
+    #[test]
+    fn test_name_only_lifecycle_matches_nss() {
+        use crate::config::UserLookup;
+        use crate::settings::name_only_user;
+
+        // (lookup, user): a user without passwd entry and an existing user
+        let cases = [
+            (UserLookup::None, name_only_user("No_Passwd_Entry").unwrap()),
+            (UserLookup::Nss, uzers::get_user_by_uid(0).unwrap()),
+        ];
+
+        let mut outcomes = Vec::new();
+        for (user_lookup, user) in cases {
+            let temp_dir = TempDir::new("test_name_only_lifecycle").unwrap();
+            let tally_dir = temp_dir.path().join("tally");
+
+            let settings = |action: Actions| Settings {
+                user: Some(user.clone()),
+                action: Some(action),
+                config: Config {
+                    tally_dir: tally_dir.clone(),
+                    stats_file: temp_dir.path().join("stats.toml"),
+                    free_tries: 2,
+                    user_lookup,
+                    ..Config::default()
+                },
+                ..Default::default()
+            };
+
+            let mut outcome = Vec::new();
+            for _ in 0..4 {
+                Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL))
+                    .unwrap();
+                let tally =
+                    Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::PREAUTH))
+                        .unwrap();
+                let locked = tally
+                    .effective_unlock_instant(&settings(Actions::PREAUTH))
+                    .is_some_and(|unlock_instant| Utc::now() < unlock_instant);
+                outcome.push((tally.failures_count, locked));
+            }
+
+            let tally =
+                Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHSUCC))
+                    .unwrap();
+            outcome.push((tally.failures_count, tally.cleared));
+
+            assert!(tally_dir.join(user.name()).exists());
+            outcomes.push(outcome);
+        }
+
+        assert_eq!(outcomes[0], outcomes[1]);
+        assert_eq!(
+            outcomes[0],
+            // the failure during the lock isn't counted
+            vec![(1, false), (2, false), (3, true), (3, true), (0, true)]
+        );
+    }
+
+    #[test]
+    fn test_auth_succ_records_anonymous_stats() {
+        // Create a temporary directory
+        let temp_dir = TempDir::new("test_auth_succ_records_anonymous_stats").unwrap();
+        let tally_dir = temp_dir.path().join("tally");
+        let stats_file = temp_dir.path().join("stats.toml");
+        fs::create_dir_all(&tally_dir).unwrap();
+
+        // Create an existing TOML file
+        let toml_str = r#"
+        [Fails]
+        count = 3
+        instant = "2023-01-01T00:00:00Z"
+        first_instant = "2023-01-01T00:00:00Z"
+    "#;
+        std::fs::write(tally_dir.join("secret_user_name"), toml_str).unwrap();
+
+        let settings = Settings {
+            user: Some(User::new(9999, "secret_user_name", 9999)),
+            action: Some(Actions::AUTHSUCC),
+            service: Some("sshd".to_string()),
+            config: Config {
+                tally_dir,
+                stats_file: stats_file.clone(),
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings).unwrap();
+        assert!(tally.cleared);
+
+        // Expect the clear to be counted without the user name
+        let stats_content = fs::read_to_string(&stats_file).unwrap();
+        assert!(stats_content.contains("sshd"));
+        assert!(!stats_content.contains("secret_user_name"));
+
+        let stats = crate::stats::Stats::load(&stats_file).unwrap();
+        assert_eq!(stats.histograms["sshd"].failures.total(), 1);
+        assert_eq!(stats.histograms["sshd"].failures.counts[2], 1);
+    }
+
+    #[test]
+    fn test_hook_command_lock_and_unlock() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new("test_hook_command_lock_and_unlock").unwrap();
+        let out = temp_dir.path().join("events.txt");
+
+        // The hook appends its event and user to the output file
+        let script = temp_dir.path().join("hook.sh");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$AUTHRAMP_EVENT $AUTHRAMP_USER $AUTHRAMP_FAILURES\" >> '{}'\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let settings = |action: Actions| Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(action),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                stats_file: temp_dir.path().join("stats.toml"),
+                free_tries: 1,
+                hook_command: Some(script.clone()),
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        // The second failure crosses the threshold, the third doesn't cross it again
+        for _ in 0..3 {
+            Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHFAIL)).unwrap();
+        }
+        Tally::new_from_tally_file::<PamHandle>(&None, &settings(Actions::AUTHSUCC)).unwrap();
+
+        // The hooks run in the background
+        for _ in 0..50 {
+            let events = fs::read_to_string(&out).unwrap_or_default();
+            if events.lines().count() >= 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        // Give a duplicate event the chance to show up
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        let events = fs::read_to_string(&out).unwrap_or_default();
+        let mut events: Vec<&str> = events.lines().collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["lock test_user 2", "unlock test_user 0"]);
+    }
+
+    #[test]
+    fn test_hook_command_first_failure() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new("test_hook_command_first_failure").unwrap();
+        let out = temp_dir.path().join("events.txt");
+
+        let script = temp_dir.path().join("hook.sh");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$AUTHRAMP_EVENT $AUTHRAMP_USER $AUTHRAMP_FAILURES\" >> '{}'\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let settings = Settings {
+            user: Some(User::new(9999, "test_user", 9999)),
+            action: Some(Actions::AUTHFAIL),
+            config: Config {
+                tally_dir: temp_dir.path().to_path_buf(),
+                stats_file: temp_dir.path().join("stats.toml"),
+                free_tries: 0,
+                hook_command: Some(script.clone()),
+                ..Config::default()
+            },
+            ..Default::default()
+        };
+
+        // Without free tries the failure creating the tally file locks the account
+        let tally = Tally::new_from_tally_file::<PamHandle>(&None, &settings).unwrap();
+        assert_eq!(tally.failures_count, 1);
+        assert!(tally.unlock_instant.is_some());
+        assert_eq!(
+            Tally::read_tally_file(&temp_dir.path().join("test_user"))
+                .unwrap()
+                .failures_count,
+            1
+        );
+
+        for _ in 0..50 {
+            if out.exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert_eq!(fs::read_to_string(&out).unwrap(), "lock test_user 1\n");
+    }
+}
@@ -0,0 +1,110 @@
+//! # Template Module
+//!
+//! The `template` module substitutes `{name}` placeholders in the configurable messages, like
+//! `lockout_message` and `notify_email_template`. It has no templating dependency and no logic
+//! beyond substitution:
+//!
+//! - `{name}` is replaced with the value of a known placeholder.
+//! - `{{` and `}}` are literal braces.
+//! - Unknown or unclosed placeholders are kept literally instead of failing.
+//!
+//! Substituted values are never scanned for placeholders again, so a user name like `{failures}`
+//! is shown as it is.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Substitutes the placeholders of a template.
+///
+/// # Arguments
+/// - `template`: The template with `{name}` placeholders
+/// - `value`: Returns the value of a placeholder name, `None` if it is unknown
+///
+/// # Returns
+/// The rendered template
+pub fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+
+        // escaped braces
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            rendered.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+
+        if let Some((end, value)) = tail
+            .starts_with('{')
+            .then(|| tail.find('}'))
+            .flatten()
+            .and_then(|end| Some((end, value(&tail[1..end])?)))
+        {
+            rendered.push_str(&value);
+            rest = &tail[end + 1..];
+        } else {
+            rendered.push_str(&tail[..1]);
+            rest = &tail[1..];
+        }
+    }
+
+    rendered + rest
+}
+
+// Unit Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(placeholder: &str) -> Option<String> {
+        match placeholder {
+            "user" => Some("alice".to_string()),
+            "failures" => Some("7".to_string()),
+            "nested" => Some("{user}".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("{user} failed {failures} times", value),
+            "alice failed 7 times"
+        );
+        assert_eq!(render("no placeholders", value), "no placeholders");
+        assert_eq!(render("", value), "");
+
+        // values are never substituted again
+        assert_eq!(render("{nested}", value), "{user}");
+    }
+
+    #[test]
+    fn test_render_literal() {
+        // escaped braces
+        assert_eq!(render("{{user}} is {user}", value), "{user} is alice");
+        assert_eq!(render("a }} b {{", value), "a } b {");
+
+        // unknown, unclosed and empty placeholders are kept
+        assert_eq!(render("{unknown} {user", value), "{unknown} {user");
+        assert_eq!(render("{} }", value), "{} }");
+        assert_eq!(render("{{{user}}}", value), "{alice}");
+    }
+}
@@ -0,0 +1,183 @@
+//! # Unknown Module
+//!
+//! The `unknown` module names the placeholders users missing from the user database are tallied
+//! under, so attempts against nonexistent accounts accumulate delays like any other account.
+//!
+//! A placeholder is named after an HMAC-SHA256 of the submitted user name. The salt is read from
+//! the root-only `unknown_user_salt_file` of the configuration and generated on first use, so
+//! nobody without the salt can tell which names were tried from the tally directory.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write as _},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uzers::User;
+
+use crate::integrity;
+use crate::settings::NAME_ONLY_ID;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The name prefix of the placeholders. User names can't contain a colon, so placeholders never
+/// collide with an account.
+pub const UNKNOWN_USER_PREFIX: &str = "unknown:";
+
+/// The number of salt bytes generated.
+const SALT_LENGTH: usize = 32;
+
+/// The number of hash bytes in a placeholder name.
+const HASH_LENGTH: usize = 8;
+
+/// Reads the salt, generating the salt file on first use.
+///
+/// # Arguments
+/// - `salt_file`: Path of the salt file
+///
+/// # Returns
+/// The salt bytes
+///
+/// # Errors
+/// Returns a message if the salt file can't be created or read, or is accessible by group or
+/// others.
+pub fn load_salt(salt_file: &Path) -> Result<Vec<u8>, String> {
+    if !salt_file.exists() {
+        create_salt(salt_file).or_else(|e| match e.kind() {
+            // another process created it first
+            io::ErrorKind::AlreadyExists => Ok(()),
+            _ => Err(format!("Error creating {}: {e}", salt_file.display())),
+        })?;
+    }
+
+    integrity::load_key(salt_file)
+}
+
+/// Writes a random salt to a new root-only file.
+fn create_salt(salt_file: &Path) -> io::Result<()> {
+    if let Some(parent) = salt_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut salt = [0u8; SALT_LENGTH];
+    File::open("/dev/urandom")?.read_exact(&mut salt)?;
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(salt_file)?
+        .write_all(&salt)
+}
+
+/// Creates the placeholder an unknown user is tallied under.
+///
+/// # Arguments
+/// - `salt`: The salt bytes
+/// - `name`: The PAM user name
+///
+/// # Returns
+/// The placeholder user, named `unknown:<hash>`
+#[must_use]
+pub fn placeholder_user(salt: &[u8], name: &str) -> User {
+    let placeholder = mac(salt, name)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .take(HASH_LENGTH)
+        .fold(String::from(UNKNOWN_USER_PREFIX), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+    User::new(NAME_ONLY_ID, &placeholder, NAME_ONLY_ID)
+}
+
+/// Creates the MAC state over a user name.
+fn mac(salt: &[u8], name: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(salt).expect("HMAC takes keys of any length");
+    mac.update(name.as_bytes());
+    mac
+}
+
+/// Gets the hash of a placeholder name.
+///
+/// # Arguments
+/// - `name`: A user or tally file name
+///
+/// # Returns
+/// The hash, `None` if the name isn't a placeholder
+#[must_use]
+pub fn placeholder_hash(name: &str) -> Option<&str> {
+    name.strip_prefix(UNKNOWN_USER_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_load_salt() {
+        let temp_dir = TempDir::new("test_load_salt").unwrap();
+        let salt_file = temp_dir.path().join("authramp").join("unknown_user.salt");
+
+        // generated on first use, root-only
+        let salt = load_salt(&salt_file).unwrap();
+        assert_eq!(salt.len(), SALT_LENGTH);
+        assert_eq!(
+            fs::metadata(&salt_file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // and kept afterwards
+        assert_eq!(load_salt(&salt_file).unwrap(), salt);
+
+        // a salt readable by others is refused
+        fs::set_permissions(&salt_file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(load_salt(&salt_file).is_err());
+    }
+
+    #[test]
+    fn test_placeholder_user() {
+        let user = placeholder_user(b"salt", "nobody-here");
+        let name = user.name().to_string_lossy().into_owned();
+
+        // the same name shares a placeholder, without storing the name
+        assert_eq!(placeholder_user(b"salt", "nobody-here").name(), user.name());
+        assert_ne!(placeholder_user(b"salt", "nobody-else").name(), user.name());
+        assert!(!name.contains("nobody"));
+        assert_eq!(user.uid(), NAME_ONLY_ID);
+
+        // the salt changes the placeholder
+        assert_ne!(
+            placeholder_user(b"pepper", "nobody-here").name(),
+            user.name()
+        );
+
+        assert_eq!(placeholder_hash(&name).map(str::len), Some(HASH_LENGTH * 2));
+        assert_eq!(placeholder_hash("test_user"), None);
+    }
+}
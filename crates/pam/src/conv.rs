@@ -33,6 +33,9 @@ use crate::{items::Item, items::ItemType, PamMessageStyle, PamResult, PamResultC
 
 pub type PamItemType = c_int;
 
+/// Maximum number of messages that clients accept in one conversation.
+pub const PAM_MAX_NUM_MSG: usize = 32;
+
 #[repr(C)]
 struct PamMessage {
     msg_style: PamMessageStyle,
@@ -54,10 +57,10 @@ struct PamResponse {
 pub struct Inner {
     conv: extern "C" fn(
         num_msg: c_int,
-        pam_message: &&PamMessage,
+        pam_message: *const *const PamMessage,
         pam_response: &mut *const PamResponse,
         appdata_ptr: *const libc::c_void,
-    ) -> PamResultCode,
+    ) -> c_int,
     appdata_ptr: *const libc::c_void,
 }
 
@@ -84,30 +87,109 @@ impl<'a> Conv<'a> {
     /// Note that the user experience will depend on how the client implements
     /// these message styles - and not all applications implement all message
     /// styles.
-    pub fn send(&self, style: PamMessageStyle, msg: &str) -> PamResult<Option<&CStr>> {
-        let mut resp_ptr: *const PamResponse = ptr::null();
-        let msg_cstr = CString::new(msg).unwrap();
-        let msg = PamMessage {
-            msg_style: style,
-            msg: msg_cstr.as_ptr(),
-        };
+    ///
+    /// # Returns
+    ///
+    /// A copy of the user's response, or `None` if the client returned none, e.g. for
+    /// `PAM_TEXT_INFO`. The response allocated by the client is wiped and freed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_CONV_ERR` if `msg` contains a NUL byte, otherwise the result code of the
+    /// conversation function if it failed.
+    pub fn send(&self, style: PamMessageStyle, msg: &str) -> PamResult<Option<CString>> {
+        self.send_all(&[(style, msg)])
+            .map(|mut responses| responses.pop().flatten())
+    }
+
+    /// Sends several messages to the pam client in a single conversation.
+    ///
+    /// Clients like graphical greeters show every conversation as a separate dialog, so messages
+    /// that belong together should be sent together. At most `PAM_MAX_NUM_MSG` messages can be
+    /// sent at once.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the response to every message, in the order of `msgs`. See `send`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_CONV_ERR` if there are too many messages or a message contains a NUL byte,
+    /// otherwise the result code of the conversation function if it failed.
+    pub fn send_all(&self, msgs: &[(PamMessageStyle, &str)]) -> PamResult<Vec<Option<CString>>> {
+        if msgs.is_empty() {
+            return Ok(Vec::new());
+        }
+        if msgs.len() > PAM_MAX_NUM_MSG {
+            return Err(PamResultCode::PAM_CONV_ERR);
+        }
+
+        let msg_cstrs = msgs
+            .iter()
+            .map(|(_, msg)| CString::new(*msg))
+            .collect::<Result<Vec<CString>, _>>()
+            .map_err(|_| PamResultCode::PAM_CONV_ERR)?;
+        let messages: Vec<PamMessage> = msgs
+            .iter()
+            .zip(&msg_cstrs)
+            .map(|((style, _), msg_cstr)| PamMessage {
+                msg_style: *style,
+                msg: msg_cstr.as_ptr(),
+            })
+            .collect();
+        // Linux-PAM reads the messages through an array of pointers, Solaris through a pointer to
+        // a contiguous array. Pointers into a contiguous array work for both.
+        let message_ptrs: Vec<*const PamMessage> = messages.iter().map(ptr::from_ref).collect();
 
-        let ret = (self.0.conv)(1, &&msg, &mut resp_ptr, self.0.appdata_ptr);
+        let mut resp_ptr: *const PamResponse = ptr::null();
+        let ret = PamResultCode::from_ffi((self.0.conv)(
+            c_int::try_from(msgs.len()).map_err(|_| PamResultCode::PAM_CONV_ERR)?,
+            message_ptrs.as_ptr(),
+            &mut resp_ptr,
+            self.0.appdata_ptr,
+        ));
 
+        // Some clients return no response array at all for styles without user input
+        let responses = unsafe { take_responses(resp_ptr, msgs.len()) };
         if PamResultCode::PAM_SUCCESS == ret {
-            // PamResponse.resp is null for styles that don't return user input like PAM_TEXT_INFO
-            let response = unsafe { (*resp_ptr).resp };
-            if response.is_null() {
-                Ok(None)
-            } else {
-                Ok(Some(unsafe { CStr::from_ptr(response) }))
-            }
+            Ok(responses)
         } else {
             Err(ret)
         }
     }
 }
 
+/// Copies the responses to `num_msg` messages and frees the memory allocated by the client.
+///
+/// The client allocates both the response array and its strings with `malloc`, and the module is
+/// responsible for freeing them. The strings are wiped first, as they may hold a password.
+///
+/// # Safety
+///
+/// `resp_ptr` must be null or point to a `malloc`ed array of `num_msg` responses whose `resp` is
+/// null or a `malloc`ed, NUL terminated string. All are freed and must not be used afterwards.
+unsafe fn take_responses(resp_ptr: *const PamResponse, num_msg: usize) -> Vec<Option<CString>> {
+    if resp_ptr.is_null() {
+        return vec![None; num_msg];
+    }
+
+    let mut responses = Vec::with_capacity(num_msg);
+    for index in 0..num_msg {
+        // PamResponse.resp is null for styles that don't return user input like PAM_TEXT_INFO
+        let resp = (*resp_ptr.add(index)).resp.cast_mut();
+        if resp.is_null() {
+            responses.push(None);
+        } else {
+            responses.push(Some(CStr::from_ptr(resp).to_owned()));
+            let len = libc::strlen(resp);
+            crate::zeroize(std::slice::from_raw_parts_mut(resp.cast::<u8>(), len));
+            libc::free(resp.cast());
+        }
+    }
+    libc::free(resp_ptr.cast_mut().cast());
+    responses
+}
+
 /// Provides implementations for the `Item` trait for `Conv`.
 /// This allows a `Conv` to be used as an item in the PAM conversation
 /// model.
@@ -126,3 +208,169 @@ impl<'a> Item for Conv<'a> {
         self.0 as _
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    type ConvFn = extern "C" fn(
+        c_int,
+        *const *const PamMessage,
+        &mut *const PamResponse,
+        *const libc::c_void,
+    ) -> c_int;
+
+    type Recorded = RefCell<Vec<(PamMessageStyle, String)>>;
+
+    /// Allocates the response array the way a client does, with `malloc`.
+    unsafe fn alloc_responses(answers: &[Option<&str>]) -> *const PamResponse {
+        let resp_ptr =
+            libc::malloc(answers.len() * std::mem::size_of::<PamResponse>()).cast::<PamResponse>();
+        for (index, answer) in answers.iter().enumerate() {
+            let resp = answer.map_or(ptr::null_mut(), |answer| {
+                let resp = libc::malloc(answer.len() + 1).cast::<u8>();
+                ptr::copy_nonoverlapping(answer.as_ptr(), resp, answer.len());
+                *resp.add(answer.len()) = 0;
+                resp
+            });
+            resp_ptr.add(index).write(PamResponse {
+                resp: resp.cast(),
+                resp_retcode: 0,
+            });
+        }
+        resp_ptr
+    }
+
+    extern "C" fn null_array_conv(
+        _: c_int,
+        _: *const *const PamMessage,
+        pam_response: &mut *const PamResponse,
+        _: *const libc::c_void,
+    ) -> c_int {
+        *pam_response = ptr::null();
+        PamResultCode::PAM_SUCCESS as c_int
+    }
+
+    extern "C" fn null_resp_conv(
+        num_msg: c_int,
+        _: *const *const PamMessage,
+        pam_response: &mut *const PamResponse,
+        _: *const libc::c_void,
+    ) -> c_int {
+        let answers = vec![None; usize::try_from(num_msg).unwrap()];
+        *pam_response = unsafe { alloc_responses(&answers) };
+        PamResultCode::PAM_SUCCESS as c_int
+    }
+
+    /// Records the messages in the `Recorded` passed as appdata, and answers every message with
+    /// its text.
+    extern "C" fn recording_conv(
+        num_msg: c_int,
+        pam_message: *const *const PamMessage,
+        pam_response: &mut *const PamResponse,
+        appdata_ptr: *const libc::c_void,
+    ) -> c_int {
+        let recorded = unsafe { &*appdata_ptr.cast::<Recorded>() };
+        let mut answers = Vec::new();
+        for index in 0..usize::try_from(num_msg).unwrap() {
+            // Read the way Linux-PAM does, and check that the Solaris way agrees
+            let msg = unsafe { &**pam_message.add(index) };
+            assert!(ptr::eq(msg, unsafe { (*pam_message).add(index) }));
+
+            let text = unsafe { CStr::from_ptr(msg.msg) }.to_str().unwrap();
+            recorded
+                .borrow_mut()
+                .push((msg.msg_style, text.to_string()));
+            answers.push(Some(text));
+        }
+        *pam_response = unsafe { alloc_responses(&answers) };
+        PamResultCode::PAM_SUCCESS as c_int
+    }
+
+    extern "C" fn failing_conv(
+        _: c_int,
+        _: *const *const PamMessage,
+        pam_response: &mut *const PamResponse,
+        _: *const libc::c_void,
+    ) -> c_int {
+        *pam_response = ptr::null();
+        PamResultCode::PAM_CONV_ERR as c_int
+    }
+
+    fn mock_conv(conv: ConvFn, appdata_ptr: *const libc::c_void) -> Inner {
+        Inner { conv, appdata_ptr }
+    }
+
+    #[test]
+    fn test_send_null_response() {
+        for conv in [null_array_conv as ConvFn, null_resp_conv] {
+            let inner = mock_conv(conv, ptr::null());
+            assert_eq!(Conv(&inner).send(crate::PAM_TEXT_INFO, "info"), Ok(None));
+            assert_eq!(
+                Conv(&inner).send_all(&[(crate::PAM_TEXT_INFO, "a"), (crate::PAM_TEXT_INFO, "b")]),
+                Ok(vec![None, None])
+            );
+        }
+    }
+
+    #[test]
+    fn test_send_response() {
+        let recorded = Recorded::default();
+        let inner = mock_conv(recording_conv, ptr::from_ref(&recorded).cast());
+        assert_eq!(
+            Conv(&inner).send(crate::PAM_TEXT_INFO, "secret"),
+            Ok(Some(CString::new("secret").unwrap()))
+        );
+
+        let inner = mock_conv(failing_conv, ptr::null());
+        assert_eq!(
+            Conv(&inner).send(crate::PAM_ERROR_MSG, "error"),
+            Err(PamResultCode::PAM_CONV_ERR)
+        );
+    }
+
+    #[test]
+    fn test_send_all() {
+        let recorded = Recorded::default();
+        let inner = mock_conv(recording_conv, ptr::from_ref(&recorded).cast());
+        let conv = Conv(&inner);
+
+        let responses = conv.send_all(&[
+            (crate::PAM_ERROR_MSG, "header"),
+            (crate::PAM_TEXT_INFO, "first"),
+            (crate::PAM_TEXT_INFO, "second"),
+        ]);
+        assert_eq!(
+            responses,
+            Ok(vec![
+                Some(CString::new("header").unwrap()),
+                Some(CString::new("first").unwrap()),
+                Some(CString::new("second").unwrap()),
+            ])
+        );
+        assert_eq!(
+            *recorded.borrow(),
+            vec![
+                (crate::PAM_ERROR_MSG, "header".to_string()),
+                (crate::PAM_TEXT_INFO, "first".to_string()),
+                (crate::PAM_TEXT_INFO, "second".to_string()),
+            ]
+        );
+
+        // Nothing to send, and more than clients accept
+        assert_eq!(conv.send_all(&[]), Ok(Vec::new()));
+        let too_many = vec![(crate::PAM_TEXT_INFO, "line"); PAM_MAX_NUM_MSG + 1];
+        assert_eq!(conv.send_all(&too_many), Err(PamResultCode::PAM_CONV_ERR));
+
+        // A NUL byte fails the conversation without sending anything
+        assert_eq!(
+            conv.send_all(&[
+                (crate::PAM_TEXT_INFO, "fine"),
+                (crate::PAM_TEXT_INFO, "nul\0")
+            ]),
+            Err(PamResultCode::PAM_CONV_ERR)
+        );
+        assert_eq!(recorded.borrow().len(), 3);
+    }
+}
@@ -10,8 +10,8 @@
 //! Each item type corresponds to a specific piece of data, such as the user's username, password,
 //! or the PAM conversation function.
 //!
-//! This module also provides implementations of the `Item` trait for several types, including
-//! `CString`, `Conv`, and `Option<CString>`.
+//! This module also provides implementations of the `Item` trait for `Conv` and for the string
+//! items `Service`, `User`, `Tty`, `RHost` and `RUser`.
 //!
 //! ## License
 //!
@@ -22,9 +22,22 @@
 //! https://opensource.org/licenses/MIT.
 
 #[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ItemType {
+    /// The service name
+    Service = 1,
+    /// The user name
+    User = 2,
+    /// The tty name
+    Tty = 3,
+    /// The remote host name
+    RHost = 4,
     /// The pam_conv structure
     Conv = 5,
+    /// The authentication token, only read through `PamHandle::get_authtok`
+    AuthTok = 6,
+    /// The remote user name
+    RUser = 8,
 }
 
 // A type that can be requested by `pam::Handle::get_item`.
@@ -45,3 +58,34 @@ pub trait Item {
     /// The function to convert from this wrapper type to a C-compatible pointer.
     fn into_raw(self) -> *const Self::Raw;
 }
+
+/// Defines a string item wrapping the `CStr` returned by `pam_get_item`.
+macro_rules! cstr_item {
+    ($name:ident, $doc:literal) => {
+        #[doc = $doc]
+        #[derive(Debug)]
+        pub struct $name<'s>(pub &'s std::ffi::CStr);
+
+        impl<'s> Item for $name<'s> {
+            type Raw = libc::c_char;
+
+            fn type_id() -> ItemType {
+                ItemType::$name
+            }
+
+            unsafe fn from_raw(raw: *const Self::Raw) -> Self {
+                Self(std::ffi::CStr::from_ptr(raw))
+            }
+
+            fn into_raw(self) -> *const Self::Raw {
+                self.0.as_ptr()
+            }
+        }
+    };
+}
+
+cstr_item!(Service, "The name of the PAM service invoking the module.");
+cstr_item!(User, "The name of the user being authenticated.");
+cstr_item!(Tty, "The terminal name of the session.");
+cstr_item!(RHost, "The name of the remote host requesting the service.");
+cstr_item!(RUser, "The name of the remote user requesting the service.");
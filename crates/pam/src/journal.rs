@@ -0,0 +1,102 @@
+//! # Journal module
+//!
+//! This module sends log entries to journald with its native protocol, see
+//! https://systemd.io/JOURNAL_NATIVE_PROTOCOL/
+//!
+//! Unlike syslog, the native protocol keeps structured fields, e.g. the user an entry is about,
+//! as separate fields of the entry. An entry is a single datagram sent to the journal socket,
+//! holding one field per line. Values with a line feed are sent in the binary form, prefixed with
+//! their length.
+//!
+//! ## License
+//!
+//! Copyright 2023 34n0
+//!
+//! Use of this source code is governed by an MIT-style
+//! license that can be found in the LICENSE file or at
+//! https://opensource.org/licenses/MIT.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+/// The socket journald receives native protocol entries on.
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Encodes the fields of an entry in the native protocol.
+///
+/// Field names must consist of uppercase letters, digits and underscores and must not start with
+/// an underscore, journald drops other fields.
+pub fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    for (name, value) in fields {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// Sends an entry to the journal socket at `socket`.
+///
+/// # Errors
+///
+/// Returns an error if the socket can't be reached, e.g. in a container without journald, or the
+/// entry exceeds the datagram size limit.
+pub fn send_to(socket: &Path, fields: &[(&str, &str)]) -> io::Result<()> {
+    UnixDatagram::unbound()?
+        .send_to(&encode(fields), socket)
+        .map(|_| ())
+}
+
+/// Sends an entry to journald.
+///
+/// # Errors
+///
+/// Returns an error if journald can't be reached or the entry exceeds the datagram size limit.
+pub fn send(fields: &[(&str, &str)]) -> io::Result<()> {
+    send_to(Path::new(JOURNAL_SOCKET), fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode(&[("MESSAGE", "Account locked"), ("PRIORITY", "6")]),
+            b"MESSAGE=Account locked\nPRIORITY=6\n"
+        );
+
+        let mut binary = b"MESSAGE\n".to_vec();
+        binary.extend_from_slice(&5u64.to_le_bytes());
+        binary.extend_from_slice(b"a\nb=c\n");
+        assert_eq!(encode(&[("MESSAGE", "a\nb=c")]), binary);
+    }
+
+    #[test]
+    fn test_send_to() {
+        let dir = std::env::temp_dir().join(format!("pam_journal_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("socket");
+        let _ = std::fs::remove_file(&socket);
+        let journal = UnixDatagram::bind(&socket).unwrap();
+
+        send_to(&socket, &[("MESSAGE", "Account locked")]).unwrap();
+        let mut buf = [0u8; 64];
+        let len = journal.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"MESSAGE=Account locked\n");
+
+        // journald not running
+        std::fs::remove_file(&socket).unwrap();
+        assert!(send_to(&socket, &[("MESSAGE", "Account locked")]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -25,10 +25,14 @@
 
 pub mod conv;
 pub mod items;
+pub mod journal;
 pub mod macros;
 
 use libc::c_char;
+use std::any::TypeId;
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
+use std::sync::atomic::{compiler_fence, Ordering};
 
 use libc::{c_int, c_uint};
 
@@ -38,20 +42,128 @@ pub type PamMessageStyle = c_int;
 pub const PAM_ERROR_MSG: PamMessageStyle = 3;
 pub const PAM_TEXT_INFO: PamMessageStyle = 4;
 
+/// `pam_sm_chauthtok` flag of the first pass, checking whether the token can be changed
+pub const PAM_PRELIM_CHECK: PamFlag = 0x4000;
+/// `pam_sm_chauthtok` flag of the second pass, changing the token
+pub const PAM_UPDATE_AUTHTOK: PamFlag = 0x2000;
+
+/// The result codes of Linux-PAM, see `_pam_types.h`.
 #[allow(non_camel_case_types, dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub enum PamResultCode {
     PAM_SUCCESS = 0,
+    PAM_OPEN_ERR = 1,
+    PAM_SYMBOL_ERR = 2,
+    PAM_SERVICE_ERR = 3,
     PAM_SYSTEM_ERR = 4,
+    PAM_BUF_ERR = 5,
     PAM_PERM_DENIED = 6,
     PAM_AUTH_ERR = 7,
+    PAM_CRED_INSUFFICIENT = 8,
+    PAM_AUTHINFO_UNAVAIL = 9,
     PAM_USER_UNKNOWN = 10,
+    PAM_MAXTRIES = 11,
+    PAM_NEW_AUTHTOK_REQD = 12,
+    PAM_ACCT_EXPIRED = 13,
+    PAM_SESSION_ERR = 14,
+    PAM_CRED_UNAVAIL = 15,
+    PAM_CRED_EXPIRED = 16,
+    PAM_CRED_ERR = 17,
+    PAM_NO_MODULE_DATA = 18,
     PAM_CONV_ERR = 19,
+    PAM_AUTHTOK_ERR = 20,
+    PAM_AUTHTOK_RECOVERY_ERR = 21,
+    PAM_AUTHTOK_LOCK_BUSY = 22,
+    PAM_AUTHTOK_DISABLE_AGING = 23,
+    PAM_TRY_AGAIN = 24,
     PAM_IGNORE = 25,
     PAM_ABORT = 26,
+    PAM_AUTHTOK_EXPIRED = 27,
+    PAM_MODULE_UNKNOWN = 28,
+    PAM_BAD_ITEM = 29,
+    PAM_CONV_AGAIN = 30,
+    PAM_INCOMPLETE = 31,
+}
+
+/// The result codes in the order of their values.
+const RESULT_CODES: [PamResultCode; 32] = [
+    PamResultCode::PAM_SUCCESS,
+    PamResultCode::PAM_OPEN_ERR,
+    PamResultCode::PAM_SYMBOL_ERR,
+    PamResultCode::PAM_SERVICE_ERR,
+    PamResultCode::PAM_SYSTEM_ERR,
+    PamResultCode::PAM_BUF_ERR,
+    PamResultCode::PAM_PERM_DENIED,
+    PamResultCode::PAM_AUTH_ERR,
+    PamResultCode::PAM_CRED_INSUFFICIENT,
+    PamResultCode::PAM_AUTHINFO_UNAVAIL,
+    PamResultCode::PAM_USER_UNKNOWN,
+    PamResultCode::PAM_MAXTRIES,
+    PamResultCode::PAM_NEW_AUTHTOK_REQD,
+    PamResultCode::PAM_ACCT_EXPIRED,
+    PamResultCode::PAM_SESSION_ERR,
+    PamResultCode::PAM_CRED_UNAVAIL,
+    PamResultCode::PAM_CRED_EXPIRED,
+    PamResultCode::PAM_CRED_ERR,
+    PamResultCode::PAM_NO_MODULE_DATA,
+    PamResultCode::PAM_CONV_ERR,
+    PamResultCode::PAM_AUTHTOK_ERR,
+    PamResultCode::PAM_AUTHTOK_RECOVERY_ERR,
+    PamResultCode::PAM_AUTHTOK_LOCK_BUSY,
+    PamResultCode::PAM_AUTHTOK_DISABLE_AGING,
+    PamResultCode::PAM_TRY_AGAIN,
+    PamResultCode::PAM_IGNORE,
+    PamResultCode::PAM_ABORT,
+    PamResultCode::PAM_AUTHTOK_EXPIRED,
+    PamResultCode::PAM_MODULE_UNKNOWN,
+    PamResultCode::PAM_BAD_ITEM,
+    PamResultCode::PAM_CONV_AGAIN,
+    PamResultCode::PAM_INCOMPLETE,
+];
+
+/// A result code outside the Linux-PAM set, e.g. returned by another PAM implementation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnknownResultCode(pub c_int);
+
+impl TryFrom<c_int> for PamResultCode {
+    type Error = UnknownResultCode;
+
+    fn try_from(code: c_int) -> Result<Self, Self::Error> {
+        usize::try_from(code)
+            .ok()
+            .and_then(|index| RESULT_CODES.get(index))
+            .copied()
+            .ok_or(UnknownResultCode(code))
+    }
+}
+
+impl PamResultCode {
+    /// Converts a result code returned by libpam. Unknown codes are internal failures.
+    ///
+    /// # Returns
+    ///
+    /// The result code, `PAM_SYSTEM_ERR` if it's unknown.
+    #[must_use]
+    pub fn from_ffi(code: c_int) -> Self {
+        Self::try_from(code).unwrap_or(PamResultCode::PAM_SYSTEM_ERR)
+    }
+}
+
+impl std::fmt::Display for PamResultCode {
+    /// Writes the canonical name, e.g. `PAM_AUTH_ERR`, which the variants are named after.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::fmt::Display for UnknownResultCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown PAM result code {}", self.0)
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LogLevel {
     /// system is unusable, corresponds to LOG_EMERG
     Emergency = 0,
@@ -71,6 +183,14 @@ pub enum LogLevel {
     Debug = 7,
 }
 
+impl LogLevel {
+    /// Whether the level is at least as severe as `threshold`.
+    #[must_use]
+    pub fn is_within(self, threshold: LogLevel) -> bool {
+        self as c_int <= threshold as c_int
+    }
+}
+
 /// Opaque type, used as a pointer when making pam API calls.
 ///
 /// A module is invoked via an external function such as `pam_sm_authenticate`.
@@ -83,28 +203,182 @@ pub struct PamHandle {
 
 #[link(name = "pam")]
 extern "C" {
-    fn pam_get_user(
-        pamh: *const PamHandle,
-        user: &*mut c_char,
-        prompt: *const c_char,
-    ) -> PamResultCode;
+    fn pam_get_user(pamh: *const PamHandle, user: &*mut c_char, prompt: *const c_char) -> c_int;
 
     fn pam_get_item(
         pamh: *const PamHandle,
         item_type: items::ItemType,
         item: &mut *const libc::c_void,
-    ) -> PamResultCode;
+    ) -> c_int;
 
-    fn pam_syslog(
+    fn pam_set_item(
+        pamh: *mut PamHandle,
+        item_type: items::ItemType,
+        item: *const libc::c_void,
+    ) -> c_int;
+
+    fn pam_set_data(
+        pamh: *const PamHandle,
+        module_data_name: *const c_char,
+        data: *mut libc::c_void,
+        cleanup: extern "C" fn(
+            pamh: *const PamHandle,
+            data: *mut libc::c_void,
+            error_status: c_int,
+        ),
+    ) -> c_int;
+
+    fn pam_get_data(
+        pamh: *const PamHandle,
+        module_data_name: *const c_char,
+        data: &mut *const libc::c_void,
+    ) -> c_int;
+
+    fn pam_syslog(pamh: *const PamHandle, priority: libc::c_int, format: *const c_char, ...);
+
+    fn pam_fail_delay(pamh: *const PamHandle, musec_delay: libc::c_uint) -> c_int;
+
+    fn pam_putenv(pamh: *const PamHandle, name_value: *const c_char) -> c_int;
+
+    fn pam_getenv(pamh: *const PamHandle, name: *const c_char) -> *const c_char;
+
+    fn pam_get_authtok(
         pamh: *const PamHandle,
-        priority: libc::c_int,
-        format: *const c_char,
-        ...
-    ) -> PamResultCode;
+        item: items::ItemType,
+        authtok: &mut *const c_char,
+        prompt: *const c_char,
+    ) -> c_int;
 }
 
 pub type PamResult<T> = Result<T, PamResultCode>;
 
+/// Key of the module data holding the syslog state set with `PamHandle::set_log_facility`.
+const LOG_FACILITY_KEY: &str = "pam_authramp_log_facility";
+
+/// Key of the module data holding the least severe priority set with `PamHandle::set_log_level`.
+const LOG_LEVEL_KEY: &str = "pam_authramp_log_level";
+
+/// Key of the module data holding the journal state set with `PamHandle::set_log_journal`.
+const LOG_JOURNAL_KEY: &str = "pam_authramp_log_journal";
+
+/// Key of the module data holding the user name resolved with `PamHandle::get_cached_user`.
+const USER_KEY: &str = "pam_authramp_cached_user";
+
+/// How `PamHandle::get_cached_user` resolves the user name.
+#[derive(Debug, PartialEq)]
+enum UserLookup {
+    /// `PAM_USER` is the cached name
+    Cached(String),
+    /// `PAM_USER` is set but not cached yet, or changed since it was cached
+    Item(String),
+    /// `PAM_USER` was cleared after the user was prompted for
+    Cleared,
+    /// Neither `PAM_USER` nor a cached name is set, the user is prompted for
+    Prompt,
+}
+
+impl UserLookup {
+    /// Decides how the user name is resolved from the `PAM_USER` item and the cached name.
+    fn decide(item: Option<String>, cached: Option<&str>) -> Self {
+        match (item, cached) {
+            (Some(name), Some(cached)) if name == cached => UserLookup::Cached(name),
+            (Some(name), _) => UserLookup::Item(name),
+            (None, Some(_)) => UserLookup::Cleared,
+            (None, None) => UserLookup::Prompt,
+        }
+    }
+}
+
+/// The ident, PAM hook and facility of the messages sent to the syslog.
+struct SyslogLog {
+    ident: String,
+    hook: String,
+    facility: c_int,
+}
+
+/// Formats the prefix of a syslog message, e.g. `pam_authramp(sshd:auth rhost=1.2.3.4 tty=ssh)`.
+///
+/// Like the prefix of `pam_syslog`, it starts with the ident, the PAM service and the hook. The
+/// remote host and terminal are appended if they are set. Whitespace and control characters in
+/// the values are replaced with `?`, so the prefix always parses the same way.
+pub fn log_prefix(
+    ident: &str,
+    service: Option<&str>,
+    hook: &str,
+    rhost: Option<&str>,
+    tty: Option<&str>,
+) -> String {
+    let clean = |value: &str| -> String {
+        value
+            .chars()
+            .map(|c| {
+                if c.is_whitespace() || c.is_control() || c == ')' {
+                    '?'
+                } else {
+                    c
+                }
+            })
+            .collect()
+    };
+
+    let mut prefix = format!("{ident}({}:{hook}", clean(service.unwrap_or_default()));
+    for (name, value) in [("rhost", rhost), ("tty", tty)] {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            prefix.push_str(&format!(" {name}={}", clean(value)));
+        }
+    }
+    prefix.push(')');
+    prefix
+}
+
+/// The ident and structured fields of the log entries sent to journald.
+struct JournalLog {
+    ident: String,
+    fields: RefCell<Vec<(String, String)>>,
+}
+
+/// Overwrites a buffer that held a secret. The volatile writes can't be optimized away.
+fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Module data stored with `PamHandle::set_data`, tagged with its type. The tag comes first in
+/// every instantiation, so `get_data` can check it before the value is read as a `T`.
+#[repr(C)]
+struct TypedData<T> {
+    type_id: TypeId,
+    value: T,
+}
+
+/// Drops module data stored with `PamHandle::set_data` when PAM releases it.
+extern "C" fn cleanup<T: 'static>(_: *const PamHandle, data: *mut libc::c_void, _: c_int) {
+    unsafe {
+        drop(Box::from_raw(data.cast::<TypedData<T>>()));
+    }
+}
+
+/// Reads module data stored with `PamHandle::set_data` as a `T`.
+///
+/// # Safety
+///
+/// `data` must be null or point to a `TypedData` stored by `PamHandle::set_data` that outlives
+/// `'a`. Module data is shared by all modules of the stack and only this crate tags it, so the
+/// keys passed to `set_data` and `get_data` are prefixed with `pam_authramp_`, which no other
+/// module uses.
+///
+/// # Returns
+///
+/// The value, `None` if `data` is null or the value was stored with another type.
+unsafe fn downcast_data<'a, T: 'static>(data: *const libc::c_void) -> Option<&'a T> {
+    if data.is_null() || unsafe { *data.cast::<TypeId>() } != TypeId::of::<T>() {
+        return None;
+    }
+    Some(unsafe { &(*data.cast::<TypedData<T>>()).value })
+}
+
 impl PamHandle {
     /// Retrieves the name of the user who is authenticating or logging in.
     ///
@@ -130,7 +404,7 @@ impl PamHandle {
             }
             None => std::ptr::null(),
         };
-        let res = unsafe { pam_get_user(self, &ptr, c_prompt) };
+        let res = PamResultCode::from_ffi(unsafe { pam_get_user(self, &ptr, c_prompt) });
         if PamResultCode::PAM_SUCCESS == res && !ptr.is_null() {
             let const_ptr = ptr as *const c_char;
             let bytes = unsafe { CStr::from_ptr(const_ptr).to_bytes() };
@@ -140,6 +414,84 @@ impl PamHandle {
         }
     }
 
+    /// Retrieves the name of the user like `get_user`, but prompts for it at most once per
+    /// transaction.
+    ///
+    /// The name is cached as module data. A `PAM_USER` item that is set always wins over the
+    /// cache, so a user changed by the application or another module between hooks replaces the
+    /// cached name. If the user was prompted for earlier and `PAM_USER` has been cleared since,
+    /// the user isn't prompted again.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_USER_UNKNOWN` if `PAM_USER` was cleared after the user was prompted for, and
+    /// the errors of `get_user`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provided prompt string contains a nul byte
+    pub fn get_cached_user(&mut self, prompt: Option<&str>) -> PamResult<String> {
+        let item = self
+            .get_item::<items::User>()?
+            .map(|user| String::from_utf8(user.0.to_bytes().to_vec()))
+            .transpose()
+            .map_err(|_| PamResultCode::PAM_CONV_ERR)?;
+        // applications may not read module data, there is no cache then
+        let cached = self.get_data::<String>(USER_KEY).ok().flatten();
+
+        let name = match UserLookup::decide(item, cached.map(String::as_str)) {
+            UserLookup::Cached(name) => return Ok(name),
+            UserLookup::Item(name) => name,
+            UserLookup::Cleared => return Err(PamResultCode::PAM_USER_UNKNOWN),
+            UserLookup::Prompt => self.get_user(prompt)?,
+        };
+
+        // without the cache the item still spares the prompt
+        let _ = self.set_data(USER_KEY, name.clone());
+        Ok(name)
+    }
+
+    /// Retrieves the authentication token of the user. If no earlier module stored one, the user
+    /// is prompted through the conversation, with `prompt` or the default "Password: ".
+    ///
+    /// The token is copied out of the buffer owned by PAM. The copy is wiped if it can't be
+    /// returned.
+    ///
+    /// See `pam_get_authtok` in `man 3 pam_get_authtok`.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(None)` if no token is available, e.g. the conversation didn't return one, which
+    /// libpam reports as `PAM_AUTHTOK_ERR`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the prompt contains a nul byte, the token isn't valid UTF-8 or the
+    /// underlying PAM function call fails.
+    pub fn get_authtok(&self, prompt: Option<&str>) -> PamResult<Option<String>> {
+        let prompt = prompt
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let c_prompt = prompt.as_ref().map_or(std::ptr::null(), |p| p.as_ptr());
+
+        let mut ptr: *const c_char = std::ptr::null();
+        let res = PamResultCode::from_ffi(unsafe {
+            pam_get_authtok(self, items::ItemType::AuthTok, &mut ptr, c_prompt)
+        });
+        match res {
+            PamResultCode::PAM_SUCCESS if !ptr.is_null() => {
+                let bytes = unsafe { CStr::from_ptr(ptr) }.to_bytes().to_vec();
+                String::from_utf8(bytes).map(Some).map_err(|e| {
+                    zeroize(&mut e.into_bytes());
+                    PamResultCode::PAM_CONV_ERR
+                })
+            }
+            PamResultCode::PAM_SUCCESS | PamResultCode::PAM_AUTHTOK_ERR => Ok(None),
+            _ => Err(res),
+        }
+    }
+
     /// Retrieves a value that has been set, possibly by the pam client.  This is
     /// particularly useful for getting a `PamConv` reference.
     ///
@@ -152,7 +504,7 @@ impl PamHandle {
     pub fn get_item<T: items::Item>(&self) -> PamResult<Option<T>> {
         let mut ptr: *const libc::c_void = std::ptr::null();
         let (res, item) = unsafe {
-            let r = pam_get_item(self, T::type_id(), &mut ptr);
+            let r = PamResultCode::from_ffi(pam_get_item(self, T::type_id(), &mut ptr));
             let typed_ptr = ptr.cast::<T::Raw>();
             let t = if typed_ptr.is_null() {
                 None
@@ -168,18 +520,461 @@ impl PamHandle {
         }
     }
 
+    /// Sets a value for later modules and the application, e.g. to replace the user name with a
+    /// normalized one.
+    ///
+    /// libpam copies string items and the conversation, so the item only has to outlive the call.
+    ///
+    /// See `pam_set_item` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails, e.g. with `PAM_BAD_ITEM` for
+    /// an item the caller may not set.
+    pub fn set_item<T: items::Item>(&mut self, item: T) -> PamResult<()> {
+        let res = PamResultCode::from_ffi(unsafe {
+            pam_set_item(self, T::type_id(), item.into_raw().cast::<libc::c_void>())
+        });
+        if PamResultCode::PAM_SUCCESS == res {
+            Ok(())
+        } else {
+            Err(res)
+        }
+    }
+
+    /// Stores module data on the handle. The data lives until it is replaced or the PAM
+    /// transaction ends, so it can carry state between the hooks of a single transaction.
+    ///
+    /// The data is tagged with its type, which `get_data` checks. The keys are shared with the
+    /// other modules of the stack, which don't tag their data, so every key starts with
+    /// `pam_authramp_`.
+    ///
+    /// See `pam_set_data` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key contains a nul byte or the underlying PAM function call fails.
+    pub fn set_data<T: 'static>(&mut self, key: &str, data: T) -> PamResult<()> {
+        let c_key = CString::new(key).map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let ptr = Box::into_raw(Box::new(TypedData {
+            type_id: TypeId::of::<T>(),
+            value: data,
+        }))
+        .cast::<libc::c_void>();
+        let res = PamResultCode::from_ffi(unsafe {
+            pam_set_data(self, c_key.as_ptr(), ptr, cleanup::<T>)
+        });
+        if PamResultCode::PAM_SUCCESS == res {
+            Ok(())
+        } else {
+            // PAM didn't take ownership
+            unsafe { drop(Box::from_raw(ptr.cast::<TypedData<T>>())) };
+            Err(res)
+        }
+    }
+
+    /// Retrieves module data stored with `set_data` earlier in the same transaction.
+    ///
+    /// Data stored with another type is treated like missing data. The key must start with
+    /// `pam_authramp_`, data of other modules isn't tagged and can't be read, see `set_data`.
+    ///
+    /// See `pam_get_data` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key contains a nul byte or the underlying PAM function call fails.
+    pub fn get_data<T: 'static>(&self, key: &str) -> PamResult<Option<&T>> {
+        let c_key = CString::new(key).map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let mut ptr: *const libc::c_void = std::ptr::null();
+        let res = PamResultCode::from_ffi(unsafe { pam_get_data(self, c_key.as_ptr(), &mut ptr) });
+        match res {
+            // the data lives until it is replaced, which needs the handle mutably
+            PamResultCode::PAM_SUCCESS => Ok(unsafe { downcast_data(ptr) }),
+            PamResultCode::PAM_NO_MODULE_DATA => Ok(None),
+            _ => Err(res),
+        }
+    }
+
+    /// Sets, replaces or removes a variable of the PAM environment, which later modules and the
+    /// session of the application can read. `"NAME=value"` sets the variable, `"NAME="` sets it
+    /// to an empty value and `"NAME"` removes it.
+    ///
+    /// See `pam_putenv` in `man 3 pam_putenv`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the argument contains a nul byte or the underlying PAM function call
+    /// fails, e.g. with `PAM_BAD_ITEM` when removing a variable that isn't set.
+    pub fn putenv(&self, name_value: &str) -> PamResult<()> {
+        let c_name_value = CString::new(name_value).map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let res = PamResultCode::from_ffi(unsafe { pam_putenv(self, c_name_value.as_ptr()) });
+        if PamResultCode::PAM_SUCCESS == res {
+            Ok(())
+        } else {
+            Err(res)
+        }
+    }
+
+    /// Retrieves a variable of the PAM environment.
+    ///
+    /// See `pam_getenv` in `man 3 pam_getenv`.
+    ///
+    /// # Returns
+    ///
+    /// The value of the variable, `None` if it isn't set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name contains a nul byte or the value isn't valid UTF-8.
+    pub fn getenv(&self, name: &str) -> PamResult<Option<String>> {
+        let c_name = CString::new(name).map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let ptr = unsafe { pam_getenv(self, c_name.as_ptr()) };
+        if ptr.is_null() {
+            return Ok(None);
+        }
+        let bytes = unsafe { CStr::from_ptr(ptr) }.to_bytes();
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|_| PamResultCode::PAM_CONV_ERR)
+    }
+
+    /// Sets the syslog facility of later `log` calls in the same transaction.
+    ///
+    /// The messages are sent with `syslog` directly instead of `pam_syslog`, prefixed with
+    /// `log_prefix`. Unlike the prefix of `pam_syslog`, it includes the remote host and terminal.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn set_log_facility(&mut self, ident: &str, hook: &str, facility: c_int) -> PamResult<()> {
+        self.set_data(
+            LOG_FACILITY_KEY,
+            SyslogLog {
+                ident: ident.to_string(),
+                hook: hook.to_string(),
+                facility,
+            },
+        )
+    }
+
+    /// Drops `log` calls less severe than `level` for the rest of the transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn set_log_level(&mut self, level: LogLevel) -> PamResult<()> {
+        self.set_data(LOG_LEVEL_KEY, level as c_int)
+    }
+
+    /// Whether `log` calls of a level are logged, see `set_log_level`.
+    pub fn is_logged(&self, level: LogLevel) -> bool {
+        self.get_data::<c_int>(LOG_LEVEL_KEY)
+            .ok()
+            .flatten()
+            .is_none_or(|threshold| level as c_int <= *threshold)
+    }
+
+    /// Sends the `log` calls of the same transaction to journald instead of the syslog, with the
+    /// fields set with `set_log_field`. Messages journald doesn't accept are logged to the syslog.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn set_log_journal(&mut self, ident: &str) -> PamResult<()> {
+        self.set_data(
+            LOG_JOURNAL_KEY,
+            JournalLog {
+                ident: ident.to_string(),
+                fields: RefCell::new(Vec::new()),
+            },
+        )
+    }
+
+    /// Sets or replaces a structured field of the later journald log entries, e.g.
+    /// `AUTHRAMP_USER`. Without `set_log_journal` the field is dropped.
+    pub fn set_log_field(&self, name: &str, value: &str) {
+        if let Ok(Some(journal)) = self.get_data::<JournalLog>(LOG_JOURNAL_KEY) {
+            let mut fields = journal.fields.borrow_mut();
+            match fields.iter_mut().find(|(field, _)| field == name) {
+                Some((_, field_value)) => *field_value = value.to_string(),
+                None => fields.push((name.to_string(), value.to_string())),
+            }
+        }
+    }
+
+    /// Requests the application to delay a failed authentication, instead of blocking in the
+    /// module. PAM applies the longest delay requested in the transaction after the stack failed.
+    ///
+    /// See `pam_fail_delay` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn fail_delay(&self, usec: u32) -> PamResult<()> {
+        let res = PamResultCode::from_ffi(unsafe { pam_fail_delay(self, usec) });
+        if PamResultCode::PAM_SUCCESS == res {
+            Ok(())
+        } else {
+            Err(res)
+        }
+    }
+
     /// Log a message with the specified level to the syslog.
     ///
     /// This method wraps pam_syslog, which prefixes the message with a string indicating
-    /// the relevant PAM context.
+    /// the relevant PAM context. A facility set with `set_log_facility` takes precedence and
+    /// adds the remote host and terminal to the prefix, the journal set with `set_log_journal`
+    /// takes precedence over both.
     pub fn log(&self, level: LogLevel, message: String) -> Result<(), PamResultCode> {
+        if !self.is_logged(level) {
+            return Ok(());
+        }
+
         let percent_s = CString::new("%s").map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let priority = level as c_int;
+        let log_facility = self.get_data::<SyslogLog>(LOG_FACILITY_KEY).ok().flatten();
+
+        if let Ok(Some(journal)) = self.get_data::<JournalLog>(LOG_JOURNAL_KEY) {
+            let facility = log_facility.map_or(libc::LOG_AUTHPRIV, |syslog| syslog.facility);
+            let (priority, facility) = (priority.to_string(), (facility >> 3).to_string());
+            let fields = journal.fields.borrow();
+            let entry: Vec<(&str, &str)> = [
+                ("MESSAGE", message.as_str()),
+                ("PRIORITY", priority.as_str()),
+                ("SYSLOG_FACILITY", facility.as_str()),
+                ("SYSLOG_IDENTIFIER", journal.ident.as_str()),
+            ]
+            .into_iter()
+            .chain(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            )
+            .collect();
+            if journal::send(&entry).is_ok() {
+                return Ok(());
+            }
+        }
+
+        if let Some(syslog) = log_facility {
+            let item =
+                |value: Option<&CStr>| value.map(|value| value.to_string_lossy().into_owned());
+            let service = item(
+                self.get_item::<items::Service>()
+                    .ok()
+                    .flatten()
+                    .map(|service| service.0),
+            );
+            let rhost = item(
+                self.get_item::<items::RHost>()
+                    .ok()
+                    .flatten()
+                    .map(|rhost| rhost.0),
+            );
+            let tty = item(
+                self.get_item::<items::Tty>()
+                    .ok()
+                    .flatten()
+                    .map(|tty| tty.0),
+            );
+            let prefix = log_prefix(
+                &syslog.ident,
+                service.as_deref(),
+                &syslog.hook,
+                rhost.as_deref(),
+                tty.as_deref(),
+            );
+            let message = CString::new(format!("{prefix}: {message}"))
+                .map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+            unsafe {
+                libc::syslog(
+                    syslog.facility | priority,
+                    percent_s.as_ptr(),
+                    message.as_ptr(),
+                );
+            };
+            return Ok(());
+        }
+
         let message = CString::new(message).map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
-        let res = unsafe { pam_syslog(self, level as i32, percent_s.as_ptr(), message.as_ptr()) };
-        if PamResultCode::PAM_SUCCESS == res {
-            Ok(())
-        } else {
-            Err(res)
+        // pam_syslog doesn't report errors
+        unsafe { pam_syslog(self, priority, percent_s.as_ptr(), message.as_ptr()) };
+        Ok(())
+    }
+}
+
+/// The parts of a `PamHandle` the module logic talks to.
+///
+/// Functions that are generic over `PamApi` instead of taking a `PamHandle` can run against a
+/// mock in unit tests, without installing the module and running a PAM transaction.
+pub trait PamApi {
+    /// See `PamHandle::get_user`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the user name can't be retrieved.
+    fn get_user(&self, prompt: Option<&str>) -> PamResult<String>;
+
+    /// See `PamHandle::get_cached_user`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the user name can't be retrieved.
+    fn get_cached_user(&mut self, prompt: Option<&str>) -> PamResult<String>;
+
+    /// See `PamHandle::get_item`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the item can't be retrieved.
+    fn get_item<T: items::Item>(&self) -> PamResult<Option<T>>;
+
+    /// See `PamHandle::set_item`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the item can't be set.
+    fn set_item<T: items::Item>(&mut self, item: T) -> PamResult<()>;
+
+    /// See `PamHandle::get_data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data can't be retrieved.
+    fn get_data<T: 'static>(&self, key: &str) -> PamResult<Option<&T>>;
+
+    /// See `PamHandle::set_data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data can't be stored.
+    fn set_data<T: 'static>(&mut self, key: &str, data: T) -> PamResult<()>;
+
+    /// See `PamHandle::putenv`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the variable can't be set.
+    fn putenv(&self, name_value: &str) -> PamResult<()>;
+
+    /// See `PamHandle::fail_delay`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the delay can't be requested.
+    fn fail_delay(&self, usec: u32) -> PamResult<()>;
+
+    /// See `PamHandle::log`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message can't be logged.
+    fn log(&self, level: LogLevel, message: String) -> PamResult<()>;
+
+    /// See `PamHandle::set_log_level`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the level can't be stored.
+    fn set_log_level(&mut self, level: LogLevel) -> PamResult<()>;
+
+    /// See `PamHandle::set_log_facility`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the facility can't be stored.
+    fn set_log_facility(&mut self, ident: &str, hook: &str, facility: c_int) -> PamResult<()>;
+
+    /// See `PamHandle::set_log_journal`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the journal can't be stored.
+    fn set_log_journal(&mut self, ident: &str) -> PamResult<()>;
+
+    /// See `PamHandle::set_log_field`.
+    fn set_log_field(&self, name: &str, value: &str);
+
+    /// Sends messages to the user in a single conversation, see `Conv::send_all`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_CONV_ERR` if there is no conversation function, otherwise the result code of
+    /// the conversation if it failed.
+    fn send_messages(&self, msgs: &[(PamMessageStyle, &str)]) -> PamResult<Vec<Option<CString>>>;
+
+    /// Sends a single message to the user, see `Conv::send`.
+    ///
+    /// # Errors
+    ///
+    /// See `send_messages`.
+    fn send_message(&self, style: PamMessageStyle, msg: &str) -> PamResult<Option<CString>> {
+        self.send_messages(&[(style, msg)])
+            .map(|mut responses| responses.pop().flatten())
+    }
+}
+
+impl PamApi for PamHandle {
+    fn get_user(&self, prompt: Option<&str>) -> PamResult<String> {
+        PamHandle::get_user(self, prompt)
+    }
+
+    fn get_cached_user(&mut self, prompt: Option<&str>) -> PamResult<String> {
+        PamHandle::get_cached_user(self, prompt)
+    }
+
+    fn get_item<T: items::Item>(&self) -> PamResult<Option<T>> {
+        PamHandle::get_item(self)
+    }
+
+    fn set_item<T: items::Item>(&mut self, item: T) -> PamResult<()> {
+        PamHandle::set_item(self, item)
+    }
+
+    fn get_data<T: 'static>(&self, key: &str) -> PamResult<Option<&T>> {
+        PamHandle::get_data(self, key)
+    }
+
+    fn set_data<T: 'static>(&mut self, key: &str, data: T) -> PamResult<()> {
+        PamHandle::set_data(self, key, data)
+    }
+
+    fn putenv(&self, name_value: &str) -> PamResult<()> {
+        PamHandle::putenv(self, name_value)
+    }
+
+    fn fail_delay(&self, usec: u32) -> PamResult<()> {
+        PamHandle::fail_delay(self, usec)
+    }
+
+    fn log(&self, level: LogLevel, message: String) -> PamResult<()> {
+        PamHandle::log(self, level, message)
+    }
+
+    fn set_log_level(&mut self, level: LogLevel) -> PamResult<()> {
+        PamHandle::set_log_level(self, level)
+    }
+
+    fn set_log_facility(&mut self, ident: &str, hook: &str, facility: c_int) -> PamResult<()> {
+        PamHandle::set_log_facility(self, ident, hook, facility)
+    }
+
+    fn set_log_journal(&mut self, ident: &str) -> PamResult<()> {
+        PamHandle::set_log_journal(self, ident)
+    }
+
+    fn set_log_field(&self, name: &str, value: &str) {
+        PamHandle::set_log_field(self, name, value);
+    }
+
+    fn send_messages(&self, msgs: &[(PamMessageStyle, &str)]) -> PamResult<Vec<Option<CString>>> {
+        match self.get_item::<conv::Conv>() {
+            Ok(Some(conv)) => conv.send_all(msgs),
+            Ok(None) | Err(_) => Err(PamResultCode::PAM_CONV_ERR),
         }
     }
 }
@@ -205,8 +1000,354 @@ pub trait PamHooks {
         PamResultCode::PAM_IGNORE
     }
 
-    /// Is not actually implemented, but still needs to be exposed to fix some instabilitry issues.
+    /// This function is used to (re-)set the authentication token of the user. It is called twice,
+    /// first with `PAM_PRELIM_CHECK` to check whether the token can be changed, then with
+    /// `PAM_UPDATE_AUTHTOK` to change it.
+    fn sm_chauthtok(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        PamResultCode::PAM_IGNORE
+    }
+
+    /// Is not actually implemented, but still needs to be exposed to fix some instability issues.
     fn sm_setcred(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
         PamResultCode::PAM_IGNORE
     }
+
+    /// This function is called to commence a session.
+    fn sm_open_session(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        PamResultCode::PAM_IGNORE
+    }
+
+    /// This function is called to terminate a session.
+    fn sm_close_session(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        PamResultCode::PAM_IGNORE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[repr(C)]
+    struct PamConvT {
+        conv: extern "C" fn(
+            c_int,
+            *const *const libc::c_void,
+            *mut *mut libc::c_void,
+            *mut libc::c_void,
+        ) -> c_int,
+        appdata_ptr: *mut libc::c_void,
+    }
+
+    extern "C" fn refuse_conv(
+        _: c_int,
+        _: *const *const libc::c_void,
+        _: *mut *mut libc::c_void,
+        _: *mut libc::c_void,
+    ) -> c_int {
+        PamResultCode::PAM_CONV_ERR as c_int
+    }
+
+    #[link(name = "pam")]
+    extern "C" {
+        fn pam_start(
+            service_name: *const c_char,
+            user: *const c_char,
+            pam_conversation: *const PamConvT,
+            pamh: *mut *mut PamHandle,
+        ) -> c_int;
+
+        fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+    }
+
+    /// Starts a transaction of the host libpam, end it with `pam_end`.
+    fn start_handle() -> *mut PamHandle {
+        // pam_start copies the conversation
+        let conv = PamConvT {
+            conv: refuse_conv,
+            appdata_ptr: std::ptr::null_mut(),
+        };
+        let mut pamh: *mut PamHandle = std::ptr::null_mut();
+
+        let res = unsafe {
+            pam_start(
+                c"authramp-test".as_ptr(),
+                c"user".as_ptr(),
+                &conv,
+                &mut pamh,
+            )
+        };
+        assert_eq!(PamResultCode::from_ffi(res), PamResultCode::PAM_SUCCESS);
+        pamh
+    }
+
+    #[test]
+    fn test_result_code_conversion() {
+        // every code converts back to itself
+        for code in RESULT_CODES {
+            assert_eq!(PamResultCode::try_from(code as c_int), Ok(code));
+        }
+        assert_eq!(
+            PamResultCode::try_from(31),
+            Ok(PamResultCode::PAM_INCOMPLETE)
+        );
+
+        // codes outside the Linux-PAM set are reported, and internal failures at the boundary
+        assert_eq!(PamResultCode::try_from(32), Err(UnknownResultCode(32)));
+        assert_eq!(PamResultCode::try_from(-1), Err(UnknownResultCode(-1)));
+        assert_eq!(PamResultCode::from_ffi(32), PamResultCode::PAM_SYSTEM_ERR);
+
+        assert_eq!(PamResultCode::PAM_MAXTRIES.to_string(), "PAM_MAXTRIES");
+        assert_eq!(
+            UnknownResultCode(32).to_string(),
+            "unknown PAM result code 32"
+        );
+    }
+
+    #[test]
+    fn test_fail_delay() {
+        let pamh = start_handle();
+
+        assert_eq!(unsafe { &*pamh }.fail_delay(2_000_000), Ok(()));
+
+        unsafe { pam_end(pamh, 0) };
+    }
+
+    #[test]
+    fn test_user_lookup() {
+        let name = |name: &str| Some(name.to_string());
+
+        // the first hook prompts or caches the item set by the application
+        assert_eq!(UserLookup::decide(None, None), UserLookup::Prompt);
+        assert_eq!(
+            UserLookup::decide(name("alice"), None),
+            UserLookup::Item("alice".to_string())
+        );
+
+        // later hooks reuse the cache
+        assert_eq!(
+            UserLookup::decide(name("alice"), Some("alice")),
+            UserLookup::Cached("alice".to_string())
+        );
+
+        // a user changed in between replaces the cached one
+        assert_eq!(
+            UserLookup::decide(name("bob"), Some("alice")),
+            UserLookup::Item("bob".to_string())
+        );
+
+        // a cleared user isn't prompted for again
+        assert_eq!(UserLookup::decide(None, Some("alice")), UserLookup::Cleared);
+    }
+
+    #[test]
+    fn test_get_cached_user() {
+        // libpam refuses module data from the application, so this only covers the item
+        let pamh = start_handle();
+        let handle = unsafe { &mut *pamh };
+
+        // the user of pam_start is returned without a prompt, every time
+        assert_eq!(handle.get_cached_user(None), Ok("user".to_string()));
+        assert_eq!(handle.get_cached_user(None), Ok("user".to_string()));
+        assert_eq!(
+            handle.get_cached_user(Some("Login: ")),
+            Ok("user".to_string())
+        );
+
+        // a user changed in between is returned
+        handle.set_item(items::User(c"other")).unwrap();
+        assert_eq!(handle.get_cached_user(None), Ok("other".to_string()));
+
+        unsafe { pam_end(pamh, 0) };
+    }
+
+    #[test]
+    fn test_set_item() {
+        let pamh = start_handle();
+        let handle = unsafe { &mut *pamh };
+
+        // libpam keeps a copy, the string can be dropped right away
+        let user = CString::new("alice").unwrap();
+        handle.set_item(items::User(&user)).unwrap();
+        drop(user);
+        assert_eq!(
+            handle
+                .get_item::<items::User>()
+                .unwrap()
+                .map(|user| user.0.to_owned()),
+            Some(c"alice".to_owned())
+        );
+        assert_eq!(handle.get_user(None), Ok("alice".to_string()));
+
+        handle.set_item(items::RHost(c"10.0.0.1")).unwrap();
+        assert_eq!(
+            handle
+                .get_item::<items::RHost>()
+                .unwrap()
+                .map(|rhost| rhost.0.to_owned()),
+            Some(c"10.0.0.1".to_owned())
+        );
+
+        unsafe { pam_end(pamh, 0) };
+    }
+
+    #[test]
+    fn test_get_authtok() {
+        let pamh = start_handle();
+        let handle = unsafe { &*pamh };
+
+        // the conversation refuses to prompt, so no token is available
+        assert_eq!(handle.get_authtok(None), Ok(None));
+        assert_eq!(handle.get_authtok(Some("Token: ")), Ok(None));
+
+        // prompts with a nul byte are refused
+        assert_eq!(
+            handle.get_authtok(Some("To\0ken: ")),
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+
+        unsafe { pam_end(pamh, 0) };
+    }
+
+    #[test]
+    fn test_env() {
+        let pamh = start_handle();
+        let handle = unsafe { &*pamh };
+
+        assert_eq!(handle.getenv("AUTHRAMP_TEST"), Ok(None));
+
+        // set and replace
+        assert_eq!(handle.putenv("AUTHRAMP_TEST=1"), Ok(()));
+        assert_eq!(handle.getenv("AUTHRAMP_TEST"), Ok(Some("1".to_string())));
+        assert_eq!(handle.putenv("AUTHRAMP_TEST=2"), Ok(()));
+        assert_eq!(handle.getenv("AUTHRAMP_TEST"), Ok(Some("2".to_string())));
+
+        // remove, which fails once the variable is gone
+        assert_eq!(handle.putenv("AUTHRAMP_TEST"), Ok(()));
+        assert_eq!(handle.getenv("AUTHRAMP_TEST"), Ok(None));
+        assert_eq!(
+            handle.putenv("AUTHRAMP_TEST"),
+            Err(PamResultCode::PAM_BAD_ITEM)
+        );
+
+        // nul bytes are refused
+        assert_eq!(
+            handle.putenv("AUTHRAMP_TEST=1\0AUTHRAMP_OTHER=2"),
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+        assert_eq!(
+            handle.getenv("AUTHRAMP\0TEST"),
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+
+        unsafe { pam_end(pamh, 0) };
+    }
+
+    #[test]
+    fn test_log_level() {
+        // debug messages are suppressed at the info threshold
+        assert!(!LogLevel::Debug.is_within(LogLevel::Info));
+        assert!(LogLevel::Info.is_within(LogLevel::Info));
+        assert!(LogLevel::Error.is_within(LogLevel::Info));
+
+        // everything is logged without a threshold
+        let pamh = start_handle();
+        let handle = unsafe { &*pamh };
+        assert!(handle.is_logged(LogLevel::Debug));
+        unsafe { pam_end(pamh, 0) };
+    }
+
+    #[test]
+    fn test_log_prefix() {
+        assert_eq!(
+            log_prefix(
+                "pam_authramp",
+                Some("sshd"),
+                "auth",
+                Some("1.2.3.4"),
+                Some("ssh")
+            ),
+            "pam_authramp(sshd:auth rhost=1.2.3.4 tty=ssh)"
+        );
+
+        // unset items are left out
+        assert_eq!(
+            log_prefix("pam_authramp", Some("sudo"), "account", None, Some("")),
+            "pam_authramp(sudo:account)"
+        );
+        assert_eq!(
+            log_prefix("pam_authramp", None, "auth", None, Some("/dev/pts/0")),
+            "pam_authramp(:auth tty=/dev/pts/0)"
+        );
+
+        // values can't break the format
+        assert_eq!(
+            log_prefix("pam_authramp", Some("sshd"), "auth", Some("a b) x\n"), None),
+            "pam_authramp(sshd:auth rhost=a?b??x?)"
+        );
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut secret = b"secret".to_vec();
+        zeroize(&mut secret);
+        assert_eq!(secret, [0; 6]);
+    }
+
+    #[test]
+    fn test_data_ownership() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Tracked;
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        // libpam releases stored data through the cleanup callback
+        let ptr = Box::into_raw(Box::new(TypedData {
+            type_id: TypeId::of::<Tracked>(),
+            value: Tracked,
+        }))
+        .cast::<libc::c_void>();
+        cleanup::<Tracked>(std::ptr::null(), ptr, 0);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+        // libpam refuses module data from the application, which keeps ownership
+        let pamh = start_handle();
+        let handle = unsafe { &mut *pamh };
+        assert_eq!(
+            handle.set_data("pam_authramp_key", Tracked),
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+        assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+
+        // keys with a nul byte are refused before the data is boxed
+        assert_eq!(
+            handle.set_data("k\0ey", Tracked),
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+        assert_eq!(DROPS.load(Ordering::SeqCst), 3);
+        assert!(handle.get_data::<Tracked>("k\0ey").is_err());
+
+        unsafe { pam_end(pamh, 0) };
+    }
+
+    #[test]
+    fn test_downcast_data() {
+        let data = Box::into_raw(Box::new(TypedData {
+            type_id: TypeId::of::<u32>(),
+            value: 7_u32,
+        }))
+        .cast::<libc::c_void>();
+
+        assert_eq!(unsafe { downcast_data::<u32>(data) }, Some(&7));
+        // data stored with another type isn't reinterpreted
+        assert_eq!(unsafe { downcast_data::<String>(data) }, None);
+        assert_eq!(unsafe { downcast_data::<u64>(data) }, None);
+        assert_eq!(unsafe { downcast_data::<u32>(std::ptr::null()) }, None);
+
+        cleanup::<u32>(std::ptr::null(), data, 0);
+    }
 }
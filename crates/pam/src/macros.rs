@@ -3,8 +3,11 @@
 //! This module provides useful macros for working with PAM.
 //!
 //! The `pam_hooks!` macro is used to define the hooks that the PAM module provides for
-//! various PAM operations, such as account management (`pam_sm_acct_mgmt`) and
-//! authentication (`pam_sm_authenticate`). The macro takes the name of a struct that
+//! various PAM operations, such as account management (`pam_sm_acct_mgmt`),
+//! authentication (`pam_sm_authenticate`) and password changes (`pam_sm_chauthtok`).
+//! Credentials (`pam_sm_setcred`) and sessions (`pam_sm_open_session`, `pam_sm_close_session`)
+//! are exported as well, so libpam never misses a symbol. They return `PAM_IGNORE` unless the
+//! module overrides them. The macro takes the name of a struct that
 //! implements the `PamHooks` trait, and generates the necessary extern "C" functions
 //! that the PAM library will call.
 //!
@@ -58,6 +61,17 @@ macro_rules! pam_hooks {
                 super::$ident::sm_authenticate(pamh, args, flags)
             }
 
+            #[no_mangle]
+            pub extern "C" fn pam_sm_chauthtok(
+                pamh: &mut PamHandle,
+                flags: PamFlag,
+                argc: c_int,
+                argv: *const *const c_char,
+            ) -> PamResultCode {
+                let args = extract_argv(argc, argv);
+                super::$ident::sm_chauthtok(pamh, args, flags)
+            }
+
             #[no_mangle]
             pub extern "C" fn pam_sm_setcred(
                 pamh: &mut PamHandle,
@@ -68,6 +82,28 @@ macro_rules! pam_hooks {
                 let args = extract_argv(argc, argv);
                 super::$ident::sm_setcred(pamh, args, flags)
             }
+
+            #[no_mangle]
+            pub extern "C" fn pam_sm_open_session(
+                pamh: &mut PamHandle,
+                flags: PamFlag,
+                argc: c_int,
+                argv: *const *const c_char,
+            ) -> PamResultCode {
+                let args = extract_argv(argc, argv);
+                super::$ident::sm_open_session(pamh, args, flags)
+            }
+
+            #[no_mangle]
+            pub extern "C" fn pam_sm_close_session(
+                pamh: &mut PamHandle,
+                flags: PamFlag,
+                argc: c_int,
+                argv: *const *const c_char,
+            ) -> PamResultCode {
+                let args = extract_argv(argc, argv);
+                super::$ident::sm_close_session(pamh, args, flags)
+            }
         }
     };
 }
@@ -87,3 +123,39 @@ macro_rules! pam_try {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{PamFlag, PamHandle, PamHooks, PamResultCode};
+    use std::os::raw::{c_char, c_int};
+
+    struct TestHooks;
+    impl PamHooks for TestHooks {}
+
+    pam_hooks!(TestHooks);
+
+    type PamSmFn =
+        extern "C" fn(&mut PamHandle, PamFlag, c_int, *const *const c_char) -> PamResultCode;
+
+    #[test]
+    fn test_pam_hooks_exports() {
+        // fails to compile if the macro doesn't emit a symbol with the libpam signature
+        let exports: [(&str, PamSmFn); 6] = [
+            ("pam_sm_acct_mgmt", pam_sm_acct_mgmt),
+            ("pam_sm_authenticate", pam_sm_authenticate),
+            ("pam_sm_chauthtok", pam_sm_chauthtok),
+            ("pam_sm_setcred", pam_sm_setcred),
+            ("pam_sm_open_session", pam_sm_open_session),
+            ("pam_sm_close_session", pam_sm_close_session),
+        ];
+
+        for (i, (name, export)) in exports.iter().enumerate() {
+            for (other_name, other) in &exports[i + 1..] {
+                assert_ne!(
+                    *export as usize, *other as usize,
+                    "{name} and {other_name} share an entry point"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! # `AuthRamp` xtask
+//!
+//! The `xtask` binary bundles the development workflows of the `AuthRamp` workspace. It is run
+//! through the `cargo xtask` alias defined in `.cargo/config.toml`.
+//!
+//! # Example
+//!
+//! ```bash
+//! # Run the local CI gate
+//! cargo xtask ci
+//!
+//! # Stop at the first failing step
+//! cargo xtask ci --fail-fast
+//! ```
+//!
+//! # Commands
+//!
+//! - `ci`: Runs fmt, clippy, the feature matrix builds and unit tests, and the doc build.
+//!
+//! The feature matrix is declared in `crates/xtask/matrix.toml`.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use clap::{Parser, Subcommand};
+use colored::Colorize;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command as Process},
+};
+
+#[derive(Parser, Debug)]
+#[command(arg_required_else_help = true, author = "34n0")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Run the local CI gate")]
+    Ci {
+        #[clap(long)]
+        fail_fast: bool,
+    },
+}
+
+/// A feature combination of a single package in the matrix.
+#[derive(Debug, PartialEq)]
+struct MatrixCell {
+    package: String,
+    features: Vec<String>,
+    no_default_features: bool,
+}
+
+impl MatrixCell {
+    /// Returns the cargo arguments selecting this cell's package and features.
+    fn cargo_args(&self) -> Vec<String> {
+        let mut args = vec!["--package".to_string(), self.package.clone()];
+        if self.no_default_features {
+            args.push("--no-default-features".to_string());
+        }
+        if !self.features.is_empty() {
+            args.push("--features".to_string());
+            args.push(self.features.join(","));
+        }
+        args
+    }
+
+    /// Returns a short description of the cell for the summary table.
+    fn describe(&self) -> String {
+        match (self.no_default_features, self.features.is_empty()) {
+            (false, true) => format!("{} [default]", self.package),
+            (true, true) => format!("{} [no-default]", self.package),
+            (false, false) => format!("{} [{}]", self.package, self.features.join(",")),
+            (true, false) => format!("{} [no-default {}]", self.package, self.features.join(",")),
+        }
+    }
+}
+
+/// A single step of the CI gate consisting of one or more cargo invocations.
+struct Step {
+    name: String,
+    invocations: Vec<Vec<String>>,
+}
+
+/// Parses the feature matrix and expands it into the cells to build.
+///
+/// Every package gets its default features, each feature alone and all features together.
+/// Packages declaring `no_default_features = true` are also built without default features.
+/// Duplicate cells are dropped.
+///
+/// # Arguments
+///
+/// - `content`: The content of the matrix TOML file.
+///
+/// # Returns
+///
+/// The expanded matrix cells or a message describing why the matrix is invalid.
+fn parse_matrix(content: &str) -> Result<Vec<MatrixCell>, String> {
+    let table = toml::from_str::<toml::value::Table>(content).map_err(|e| format!("{e}"))?;
+
+    let mut cells: Vec<MatrixCell> = Vec::new();
+    let mut push = |cell: MatrixCell| {
+        if !cells.contains(&cell) {
+            cells.push(cell);
+        }
+    };
+
+    for (package, value) in &table {
+        let features: Vec<String> = value
+            .get("features")
+            .and_then(toml::Value::as_array)
+            .map(|features| {
+                features
+                    .iter()
+                    .filter_map(|feature| feature.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let no_default_features = value
+            .get("no_default_features")
+            .and_then(toml::Value::as_bool)
+            .unwrap_or_default();
+
+        let cell = |features: Vec<String>, no_default_features: bool| MatrixCell {
+            package: package.clone(),
+            features,
+            no_default_features,
+        };
+
+        push(cell(Vec::new(), false));
+        for feature in &features {
+            push(cell(vec![feature.clone()], false));
+        }
+        push(cell(features.clone(), false));
+        if no_default_features {
+            push(cell(Vec::new(), true));
+        }
+    }
+
+    Ok(cells)
+}
+
+/// Builds the list of steps the CI gate runs.
+///
+/// # Arguments
+///
+/// - `cells`: The expanded feature matrix.
+///
+/// # Returns
+///
+/// The steps in the order they are run.
+fn ci_steps(cells: &[MatrixCell]) -> Vec<Step> {
+    let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+    let mut steps = vec![
+        Step {
+            name: "fmt".to_string(),
+            invocations: vec![args(&["fmt", "--all", "--check"])],
+        },
+        Step {
+            name: "clippy".to_string(),
+            invocations: vec![args(&[
+                "clippy",
+                "--workspace",
+                "--all-targets",
+                "--",
+                "-D",
+                "warnings",
+            ])],
+        },
+    ];
+
+    for cell in cells {
+        let mut build = args(&["build"]);
+        build.extend(cell.cargo_args());
+        let mut test = args(&["test"]);
+        test.extend(cell.cargo_args());
+        steps.push(Step {
+            name: cell.describe(),
+            invocations: vec![build, test],
+        });
+    }
+
+    steps.push(Step {
+        name: "doc".to_string(),
+        invocations: vec![args(&["doc", "--workspace", "--no-deps"])],
+    });
+
+    steps
+}
+
+/// Runs the CI gate and prints a summary table.
+///
+/// # Arguments
+///
+/// - `root`: The workspace root directory.
+/// - `fail_fast`: Stop at the first failing step.
+///
+/// # Returns
+///
+/// `true` if every step passed.
+fn ci(root: &Path, fail_fast: bool) -> bool {
+    let matrix_path = root.join("crates/xtask/matrix.toml");
+    let cells = match fs::read_to_string(&matrix_path)
+        .map_err(|e| format!("{e}"))
+        .and_then(|content| parse_matrix(&content))
+    {
+        Ok(cells) => cells,
+        Err(e) => {
+            eprintln!("{} {}: {e}", "error:".red().bold(), matrix_path.display());
+            return false;
+        }
+    };
+
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut results: Vec<(String, Option<bool>)> = Vec::new();
+    let mut failed = false;
+
+    for step in ci_steps(&cells) {
+        if failed && fail_fast {
+            results.push((step.name, None));
+            continue;
+        }
+
+        println!("{} {}", "running:".yellow().bold(), step.name);
+        let passed = step.invocations.iter().all(|args| {
+            Process::new(&cargo)
+                .args(args)
+                .current_dir(root)
+                .status()
+                .is_ok_and(|status| status.success())
+        });
+
+        failed |= !passed;
+        results.push((step.name, Some(passed)));
+    }
+
+    println!("\n{:<48} RESULT", "STEP");
+    for (name, passed) in &results {
+        let result = match passed {
+            Some(true) => "ok".green().bold(),
+            Some(false) => "failed".red().bold(),
+            None => "skipped".yellow().bold(),
+        };
+        println!("{name:<48} {result}");
+    }
+
+    !failed
+}
+
+/// Main entry point for the `xtask` binary.
+fn main() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../..");
+
+    let passed = match Cli::parse().command {
+        Some(Command::Ci { fail_fast }) => ci(&root, fail_fast),
+        None => true,
+    };
+
+    if !passed {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_matrix() {
+        let cells = parse_matrix(
+            r#"
+            [pam-authramp]
+            features = ["a", "b"]
+            no_default_features = true
+
+            [cli]
+            "#,
+        )
+        .unwrap();
+
+        let described: Vec<String> = cells.iter().map(MatrixCell::describe).collect();
+        assert_eq!(
+            described,
+            vec![
+                "cli [default]",
+                "pam-authramp [default]",
+                "pam-authramp [a]",
+                "pam-authramp [b]",
+                "pam-authramp [a,b]",
+                "pam-authramp [no-default]",
+            ]
+        );
+        assert_eq!(
+            cells[4].cargo_args(),
+            vec!["--package", "pam-authramp", "--features", "a,b"]
+        );
+    }
+}
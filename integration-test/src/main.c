@@ -11,7 +11,19 @@ int main() {
     // run integration tests
     test_valid_auth();
     test_invalid_auth();
+    test_consecutive_invalid_adds_tally();
     test_bounce_auth();
+    test_account_neutral();
+    test_nodelay();
+    test_policy();
+    test_noninteractive();
+    test_account_clears_tally();
+    test_locked_preauth();
+    test_conf_argument();
+    test_unknown_user();
+    test_chauthtok();
+    test_open_session();
+    test_settings_cache();
 
     printf("------ \n");
     return 0;
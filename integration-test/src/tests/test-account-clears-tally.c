@@ -0,0 +1,140 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+// Records two failures for the user in a transaction of their own
+static void record_failures(const char *user_name) {
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        [default=die]                                libpam_authramp.so authfail";
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  if (pam_start(PAM_SRV, user_name, &conv, &pamh) == PAM_SUCCESS) {
+    pam_authenticate(pamh, 0);
+    pam_authenticate(pamh, 0);
+    pam_end(pamh, PAM_AUTH_ERR);
+  }
+
+  remove_pam_service_file();
+}
+
+// Authenticates and runs the account stack with the given service, returns the PAM result
+static int login(const char *srv, const char *user_name) {
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    retval = pam_authenticate(pamh, 0);
+  }
+
+  if (retval == PAM_SUCCESS) {
+    retval = pam_acct_mgmt(pamh, 0);
+  }
+
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+  return retval;
+}
+
+// Checks whether the tally file of the user holds no failures
+static int tally_cleared(const char *user_name) {
+  char tallyFilePath[FILE_PATH_MAX];
+  snprintf(tallyFilePath, sizeof(tallyFilePath), "%s%s", TALLY_DIR, user_name);
+
+  FILE *file = fopen(tallyFilePath, "r");
+  if (file == NULL) {
+    return 1;
+  }
+
+  char line[256];
+  int cleared = 0;
+  while (fgets(line, sizeof(line), file) != NULL) {
+    if (strncmp(line, "count = 0", 9) == 0) {
+      cleared = 1;
+    }
+  }
+  fclose(file);
+  return cleared;
+}
+
+int test_account_clears_tally() {
+  printf("------ \n");
+  printf("test_account_clears_tally: \n\n");
+
+  char user_name[] = "user";
+  int result = 0;
+
+  clear_tally_dir();
+
+  // The account hook clears the tally without an authsucc line
+  record_failures(user_name);
+  int retval = login(
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        required                                     pam_permit.so \n\
+      account     required                                     libpam_authramp.so \n\
+      account     required                                     pam_permit.so",
+      user_name);
+
+  if (retval == PAM_SUCCESS && tally_cleared(user_name)) {
+    printf("Account hook cleared the tally.\n");
+  } else {
+    print_error("account hook did not clear the tally");
+    result = 1;
+  }
+
+  // With both stages, the authsucc line clears and the account hook leaves the tally alone
+  record_failures(user_name);
+  retval = login(
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        required                                     pam_permit.so \n\
+      auth        required                                     libpam_authramp.so authsucc \n\
+      account     required                                     libpam_authramp.so \n\
+      account     required                                     pam_permit.so",
+      user_name);
+
+  if (retval == PAM_SUCCESS && tally_cleared(user_name)) {
+    printf("Both stages cleared the tally once.\n");
+  } else {
+    print_error("auth and account stages did not clear the tally");
+    result = 1;
+  }
+
+  // The noclear argument keeps the account hook from clearing
+  clear_tally_dir();
+  record_failures(user_name);
+  retval = login(
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        required                                     pam_permit.so \n\
+      account     optional                                     libpam_authramp.so noclear \n\
+      account     required                                     pam_permit.so",
+      user_name);
+
+  if (retval == PAM_SUCCESS && !tally_cleared(user_name)) {
+    printf("noclear kept the tally.\n");
+  } else {
+    print_error("noclear account hook cleared the tally");
+    result = 1;
+  }
+
+  if (result == 0) {
+    print_success("test_account_clears_tally");
+  }
+  clear_tally_dir();
+  return result;
+}
@@ -0,0 +1,68 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <unistd.h>
+
+int test_account_neutral() {
+  printf("------ \n");
+  printf("test_account_neutral: \n\n");
+
+  // authramp is 'sufficient' on the account stack. pam_deny stands in for
+  // the account checks of pam_unix, which must not be short-circuited.
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth \n\
+      account     sufficient                                   libpam_authramp.so \n\
+      account     required                                     pam_deny.so";
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval;
+
+  char user_name[] = "user";
+
+  clear_tally_dir();
+
+  retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  // Are the credentials correct?
+  if (retval == PAM_SUCCESS) {
+    printf("PAM module initialized\n");
+    retval = pam_authenticate(pamh, 0);
+  }
+
+  // Can the accound be used at this time?
+  if (retval == PAM_SUCCESS) {
+    printf("Credentials accepted.\n");
+    retval = pam_acct_mgmt(pamh, 0);
+  } else {
+    print_error("Authentication failed before account management");
+  }
+
+  // The account stack has to fall through to pam_deny
+  if (retval == PAM_SUCCESS) {
+    print_error("Account management short-circuited by authramp");
+  } else {
+    printf("Account denied:  %d\n", retval);
+  }
+
+  // close PAM (end session)
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    pamh = NULL;
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+
+  if (retval != PAM_SUCCESS) {
+    print_success("test_account_neutral");
+  }
+  return retval;
+}
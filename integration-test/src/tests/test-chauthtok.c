@@ -0,0 +1,98 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <unistd.h>
+
+#define CONF_FILE "/etc/security/authramp-test.conf"
+
+// Changes the password with a stack accepting any new password
+static int change_password(const char *user_name) {
+  char srv[] =
+      "password    requisite                                    libpam_authramp.so conf=" CONF_FILE " \n\
+      password    required                                     pam_permit.so";
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    retval = pam_chauthtok(pamh, 0);
+  }
+
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+  return retval;
+}
+
+int test_chauthtok() {
+  printf("------ \n");
+  printf("test_chauthtok: \n\n");
+
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth conf=" CONF_FILE " \n\
+      auth        [default=die]                                libpam_authramp.so authfail conf=" CONF_FILE;
+
+  char user_name[] = "user";
+  char tally_file[FILE_PATH_MAX];
+  snprintf(tally_file, sizeof(tally_file), "%s%s", TALLY_DIR, user_name);
+  int result = 0;
+
+  clear_tally_dir();
+
+  // deny locked accounts without blocking
+  if (writeToFile(CONF_FILE, "[Configuration]\nnodelay = true\n") != 0) {
+    print_error("could not write " CONF_FILE);
+    return 1;
+  }
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  if (pam_start(PAM_SRV, user_name, &conv, &pamh) == PAM_SUCCESS) {
+    // free_tries + 1 failures lock the account for base_delay_seconds
+    for (int i = 0; i < 7; ++i) {
+      pam_authenticate(pamh, 0);
+    }
+    pam_end(pamh, PAM_AUTH_ERR);
+  }
+
+  remove_pam_service_file();
+
+  // A locked account can't change the password
+  int retval = change_password(user_name);
+  if (retval == PAM_PERM_DENIED && access(tally_file, F_OK) == 0) {
+    printf("Locked account denied: %d\n", retval);
+  } else {
+    print_error("locked account was not denied the password change");
+    result = 1;
+  }
+
+  // Once unlocked the password change succeeds and clears the tally
+  sleep(31);
+  retval = change_password(user_name);
+  if (retval == PAM_SUCCESS && access(tally_file, F_OK) != 0) {
+    printf("Password changed and tally cleared.\n");
+  } else {
+    print_error("password change of the unlocked account failed");
+    result = 1;
+  }
+
+  remove(CONF_FILE);
+
+  if (result == 0) {
+    print_success("test_chauthtok");
+  }
+  clear_tally_dir();
+  return result;
+}
@@ -0,0 +1,108 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <unistd.h>
+
+#define CONF_FILE "/etc/security/authramp-test.conf"
+
+// Authenticates once with a stack accepting any password
+static int authenticate_correct_password(const char *user_name,
+                                         const char *args) {
+  char srv[512];
+  snprintf(srv, sizeof(srv),
+           "auth        requisite                                    libpam_authramp.so preauth nodelay %s \n\
+      auth        sufficient                                   pam_permit.so \n\
+      auth        [default=die]                                libpam_authramp.so authfail nodelay %s",
+           args, args);
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    retval = pam_authenticate(pamh, 0);
+  }
+
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+  return retval;
+}
+
+int test_conf_argument() {
+  printf("------ \n");
+  printf("test_conf_argument: \n\n");
+
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth nodelay conf=" CONF_FILE " \n\
+      auth        [default=die]                                libpam_authramp.so authfail nodelay conf=" CONF_FILE;
+
+  char user_name[] = "user";
+  int result = 0;
+
+  // The alternate configuration locks after a single free try
+  if (writeToFile(CONF_FILE, "[Configuration]\nfree_tries = 1\n") != 0) {
+    print_error("could not write " CONF_FILE);
+    return 1;
+  }
+
+  clear_tally_dir();
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  if (pam_start(PAM_SRV, user_name, &conv, &pamh) == PAM_SUCCESS) {
+    // free_tries + 1 failures lock the account
+    for (int i = 0; i < 2; ++i) {
+      pam_authenticate(pamh, 0);
+    }
+    pam_end(pamh, PAM_AUTH_ERR);
+  }
+
+  remove_pam_service_file();
+
+  int retval = authenticate_correct_password(user_name, "conf=" CONF_FILE);
+  if (retval == PAM_AUTH_ERR) {
+    printf("Locked after the free tries of " CONF_FILE ": %d\n", retval);
+  } else {
+    print_error("the free tries of the conf argument were not applied");
+    result = 1;
+  }
+
+  clear_tally_dir();
+  remove(CONF_FILE);
+
+  // A missing file falls back to the defaults
+  retval = authenticate_correct_password(user_name, "conf=" CONF_FILE);
+  if (retval == PAM_SUCCESS) {
+    printf("Missing conf file fell back to the defaults: %d\n", retval);
+  } else {
+    print_error("missing conf file did not fall back to the defaults");
+    result = 1;
+  }
+
+  // Or fails closed with conf_missing=deny
+  retval = authenticate_correct_password(user_name,
+                                         "conf=" CONF_FILE " conf_missing=deny");
+  if (retval == PAM_AUTH_ERR) {
+    printf("Missing conf file denied: %d\n", retval);
+  } else {
+    print_error("missing conf file with conf_missing=deny was not denied");
+    result = 1;
+  }
+
+  if (result == 0) {
+    print_success("test_conf_argument");
+  }
+  clear_tally_dir();
+  return result;
+}
@@ -0,0 +1,52 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+
+int test_consecutive_invalid_adds_tally() {
+  printf("------ \n");
+  printf("test_consecutive_invalid_adds_tally: \n\n");
+
+  // The documented stack, with a second authfail line a failed attempt also traverses
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        required                                     libpam_authramp.so authfail \n\
+      auth        [default=die]                                libpam_authramp.so authfail \n\
+      account     required                                     libpam_authramp.so";
+
+  int result = 0;
+
+  clear_tally_dir();
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, "user", &conv, &pamh);
+
+  // Every attempt of the transaction is recorded exactly once
+  const char *counts[] = {"count = 1", "count = 2", "count = 3"};
+  for (int i = 0; i < 3 && result == 0; i++) {
+    retval = pam_authenticate(pamh, 0);
+
+    if (tally_contains("user", counts[i])) {
+      printf("Attempt %d counted once: %d\n", i + 1, retval);
+    } else {
+      print_error("attempt was not counted exactly once");
+      result = 1;
+    }
+  }
+
+  pam_end(pamh, retval);
+  remove_pam_service_file();
+
+  if (result == 0) {
+    print_success("test_consecutive_invalid_adds_tally");
+  }
+  clear_tally_dir();
+  return result;
+}
@@ -0,0 +1,86 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <unistd.h>
+
+// Authenticates once with a stack accepting any password
+static int authenticate_correct_password(const char *user_name) {
+  char srv[] =
+      "auth        requisite                                    libpam_authramp.so preauth \n\
+      auth        sufficient                                   pam_permit.so \n\
+      auth        [default=die]                                libpam_authramp.so authfail";
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    retval = pam_authenticate(pamh, 0);
+  }
+
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+  return retval;
+}
+
+int test_locked_preauth() {
+  printf("------ \n");
+  printf("test_locked_preauth: \n\n");
+
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        [default=die]                                libpam_authramp.so authfail";
+
+  char user_name[] = "user";
+  int result = 0;
+
+  clear_tally_dir();
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  if (pam_start(PAM_SRV, user_name, &conv, &pamh) == PAM_SUCCESS) {
+    // free_tries + 1 failures lock the account for base_delay_seconds
+    for (int i = 0; i < 7; ++i) {
+      pam_authenticate(pamh, 0);
+    }
+    pam_end(pamh, PAM_AUTH_ERR);
+  }
+
+  remove_pam_service_file();
+
+  // The correct password must not get past preauth while locked
+  int retval = authenticate_correct_password(user_name);
+  if (retval == PAM_AUTH_ERR) {
+    printf("Locked account denied: %d\n", retval);
+  } else {
+    print_error("locked account was not denied by preauth");
+    result = 1;
+  }
+
+  // Once the unlock instant passed the correct password is accepted again
+  sleep(31);
+  retval = authenticate_correct_password(user_name);
+  if (retval == PAM_SUCCESS) {
+    printf("Unlocked account authenticated.\n");
+  } else {
+    print_error("unlocked account was denied");
+    result = 1;
+  }
+
+  if (result == 0) {
+    print_success("test_locked_preauth");
+  }
+  clear_tally_dir();
+  return result;
+}
@@ -0,0 +1,80 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <string.h>
+#include <time.h>
+#include <unistd.h>
+
+int test_nodelay() {
+  printf("------ \n");
+  printf("test_nodelay: \n\n");
+
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth nodelay \n\
+      auth        [default=die]                                libpam_authramp.so authfail nodelay \n\
+      account     required                                     libpam_authramp.so";
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval;
+
+  char user_name[] = "user";
+
+  clear_tally_dir();
+
+  retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    printf("PAM module initialized\n");
+    // authenticate 7 times to cause lock
+    for (int i = 0; i < 7; ++i) {
+      retval = pam_authenticate(pamh, 0);
+    }
+  }
+
+  // The locked account has to be denied without sleeping
+  time_t start = time(NULL);
+  retval = pam_authenticate(pamh, 0);
+  double elapsed = difftime(time(NULL), start);
+
+  if (retval == PAM_SUCCESS) {
+    print_error("Locked account authenticated");
+  } else {
+    printf("Not Authenticated:  %d after %.0f seconds\n", retval, elapsed);
+  }
+
+  // The bounce exports the lock to the PAM environment
+  const char *failures = pam_getenv(pamh, "AUTHRAMP_FAILURES");
+  const char *locked_until = pam_getenv(pamh, "AUTHRAMP_LOCKED_UNTIL");
+  int exported = failures != NULL && strcmp(failures, "7") == 0 &&
+                 locked_until != NULL;
+  if (exported) {
+    printf("Exported lock until %s after %s failures\n", locked_until, failures);
+  } else {
+    print_error("lock was not exported to the PAM environment");
+  }
+
+  // close PAM (end session)
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    pamh = NULL;
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+
+  if (retval != PAM_SUCCESS && elapsed < 2 && exported) {
+    print_success("test_nodelay");
+  } else if (elapsed >= 2) {
+    print_error("nodelay bounce did not return promptly");
+  }
+  clear_tally_dir();
+  return retval;
+}
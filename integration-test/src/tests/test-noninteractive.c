@@ -0,0 +1,72 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <time.h>
+#include <unistd.h>
+
+int test_noninteractive() {
+  printf("------ \n");
+  printf("test_noninteractive: \n\n");
+
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        [default=die]                                libpam_authramp.so authfail \n\
+      account     required                                     libpam_authramp.so";
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval;
+
+  char user_name[] = "user";
+
+  clear_tally_dir();
+
+  retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    printf("PAM module initialized\n");
+    // sshd-style session: matches the default noninteractive_services
+    pam_set_item(pamh, PAM_TTY, "sshd");
+    // authenticate 7 times to cause lock
+    for (int i = 0; i < 7; ++i) {
+      retval = pam_authenticate(pamh, 0);
+    }
+  }
+
+  // The locked account has to be reported once without sleeping
+  time_t start = time(NULL);
+  retval = pam_authenticate(pamh, 0);
+  double elapsed = difftime(time(NULL), start);
+
+  if (retval == PAM_SUCCESS) {
+    print_error("Locked account authenticated");
+  } else {
+    printf("Not Authenticated:  %d after %.0f seconds\n", retval, elapsed);
+  }
+
+  // close PAM (end session)
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    pamh = NULL;
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+
+  if (retval == PAM_AUTH_ERR && elapsed < 2) {
+    print_success("test_noninteractive");
+  } else if (elapsed >= 2) {
+    print_error("noninteractive bounce did not return promptly");
+  } else {
+    print_error("noninteractive bounce did not return PAM_AUTH_ERR");
+  }
+  clear_tally_dir();
+  return retval;
+}
@@ -0,0 +1,61 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+
+int test_open_session() {
+  printf("------ \n");
+  printf("test_open_session: \n\n");
+
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        [default=die]                                libpam_authramp.so authfail \n\
+      session     optional                                     libpam_authramp.so";
+
+  char user_name[] = "user";
+  int result = 0;
+
+  clear_tally_dir();
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, user_name, &conv, &pamh);
+
+  // A session records the login
+  if (retval == PAM_SUCCESS) {
+    retval = pam_open_session(pamh, 0);
+  }
+
+  if (retval == PAM_SUCCESS && tally_contains(user_name, "last_success") &&
+      tally_contains(user_name, "count = 0")) {
+    printf("Session recorded: %d\n", retval);
+  } else {
+    print_error("session was not recorded");
+    result = 1;
+  }
+
+  // Failures are counted besides the recorded login
+  if (pam_authenticate(pamh, 0) == PAM_AUTH_ERR &&
+      tally_contains(user_name, "last_success") &&
+      tally_contains(user_name, "count = 1")) {
+    printf("Failure counted after the session.\n");
+  } else {
+    print_error("failure after the session was not counted");
+    result = 1;
+  }
+
+  pam_end(pamh, retval);
+  remove_pam_service_file();
+
+  if (result == 0) {
+    print_success("test_open_session");
+  }
+  clear_tally_dir();
+  return result;
+}
@@ -0,0 +1,79 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+static char policy_msg[512];
+
+// Records info messages instead of printing them
+static int policy_conv(int num_msg, const struct pam_message **msg,
+                       struct pam_response **resp, void *appdata_ptr) {
+  (void)appdata_ptr;
+  *resp = calloc(num_msg, sizeof(struct pam_response));
+  if (*resp == NULL) {
+    return PAM_CONV_ERR;
+  }
+  for (int i = 0; i < num_msg; ++i) {
+    if (msg[i]->msg_style == PAM_TEXT_INFO) {
+      snprintf(policy_msg, sizeof(policy_msg), "%s", msg[i]->msg);
+    }
+  }
+  return PAM_SUCCESS;
+}
+
+int test_policy() {
+  printf("------ \n");
+  printf("test_policy: \n\n");
+
+  char srv[] =
+      "auth        optional                                     libpam_authramp.so policy \n\
+      auth        required                                     pam_permit.so";
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  struct pam_conv recording_conv = {policy_conv, NULL};
+  int retval;
+
+  char user_name[] = "user";
+  char tally_file[FILE_PATH_MAX];
+  snprintf(tally_file, sizeof(tally_file), "%s%s", TALLY_DIR, user_name);
+
+  clear_tally_dir();
+  policy_msg[0] = '\0';
+
+  retval = pam_start(PAM_SRV, user_name, &recording_conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    printf("PAM module initialized\n");
+    retval = pam_authenticate(pamh, 0);
+  }
+
+  printf("Policy message: %s\n", policy_msg);
+
+  // close PAM (end session)
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    pamh = NULL;
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+
+  if (strstr(policy_msg, "free attempts") == NULL) {
+    print_error("Policy message missing");
+  } else if (access(tally_file, F_OK) == 0) {
+    print_error("Policy request created a tally");
+  } else {
+    print_success("test_policy");
+  }
+  clear_tally_dir();
+  return retval;
+}
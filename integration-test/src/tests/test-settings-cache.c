@@ -0,0 +1,60 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+
+int test_settings_cache() {
+  printf("------ \n");
+  printf("test_settings_cache: \n\n");
+
+  char srv[] =
+      "auth        required                                     libpam_authramp.so preauth \n\
+      auth        [default=die]                                libpam_authramp.so authfail";
+
+  int result = 0;
+
+  clear_tally_dir();
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, "user", &conv, &pamh);
+
+  // The lines of a transaction share the resolved user
+  if (retval == PAM_SUCCESS) {
+    retval = pam_authenticate(pamh, 0);
+  }
+
+  if (retval == PAM_AUTH_ERR && tally_contains("user", "count = 1")) {
+    printf("Failure counted: %d\n", retval);
+  } else {
+    print_error("failure was not counted");
+    result = 1;
+  }
+
+  // A changed PAM user is resolved again instead of reusing the cached one
+  pam_set_item(pamh, PAM_USER, "root");
+  retval = pam_authenticate(pamh, 0);
+
+  if (retval == PAM_AUTH_ERR && tally_contains("root", "count = 1") &&
+      tally_contains("user", "count = 1")) {
+    printf("Failure counted for the changed user: %d\n", retval);
+  } else {
+    print_error("failure of the changed user was not counted");
+    result = 1;
+  }
+
+  pam_end(pamh, retval);
+  remove_pam_service_file();
+
+  if (result == 0) {
+    print_success("test_settings_cache");
+  }
+  clear_tally_dir();
+  return result;
+}
@@ -0,0 +1,122 @@
+// Copyright 2023 34n0
+// 
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#include "../utils/utils.h"
+#include <dirent.h>
+#include <security/pam_appl.h>
+#include <security/pam_misc.h>
+#include <stdio.h>
+#include <string.h>
+
+#define CONF_FILE "/etc/security/authramp-test.conf"
+
+// Counts the placeholder tallies of unknown users
+static int count_placeholder_tallies() {
+  DIR *dir = opendir(TALLY_DIR);
+  if (dir == NULL) {
+    return 0;
+  }
+
+  int count = 0;
+  struct dirent *entry;
+  while ((entry = readdir(dir)) != NULL) {
+    if (strncmp(entry->d_name, "unknown:", 8) == 0) {
+      ++count;
+    }
+  }
+  closedir(dir);
+  return count;
+}
+
+// Authenticates a nonexistent user with a stack accepting or refusing any password
+static int authenticate_unknown_user(const char *conf, const char *module) {
+  char srv[512];
+  snprintf(srv, sizeof(srv),
+           "auth        required                                     libpam_authramp.so preauth conf=%s \n\
+      auth        sufficient                                   %s \n\
+      auth        [default=die]                                libpam_authramp.so authfail conf=%s",
+           conf, module, conf);
+
+  create_pam_service_file(srv);
+
+  pam_handle_t *pamh = NULL;
+  int retval = pam_start(PAM_SRV, "no-such-user-authramp", &conv, &pamh);
+
+  if (retval == PAM_SUCCESS) {
+    retval = pam_authenticate(pamh, 0);
+  }
+
+  if (pam_end(pamh, retval) != PAM_SUCCESS) {
+    printf("Check_user: failed to release authenticator\n");
+  }
+
+  remove_pam_service_file();
+  return retval;
+}
+
+int test_unknown_user() {
+  printf("------ \n");
+  printf("test_unknown_user: \n\n");
+
+  int result = 0;
+
+  clear_tally_dir();
+
+  // unknown_user = "ignore" steps aside
+  if (writeToFile(CONF_FILE, "[Configuration]\nunknown_user = \"ignore\"\n") !=
+      0) {
+    print_error("could not write " CONF_FILE);
+    return 1;
+  }
+
+  int retval = authenticate_unknown_user(CONF_FILE, "pam_permit.so");
+  if (retval == PAM_SUCCESS && count_placeholder_tallies() == 0) {
+    printf("Unknown user ignored: %d\n", retval);
+  } else {
+    print_error("unknown user was not ignored");
+    result = 1;
+  }
+
+  // unknown_user = "deny" denies and tallies under a placeholder
+  if (writeToFile(CONF_FILE, "[Configuration]\nunknown_user = \"deny\"\n") !=
+      0) {
+    print_error("could not write " CONF_FILE);
+    return 1;
+  }
+
+  retval = authenticate_unknown_user(CONF_FILE, "pam_permit.so");
+  if (retval == PAM_AUTH_ERR && count_placeholder_tallies() == 1) {
+    printf("Unknown user denied and tallied: %d\n", retval);
+  } else {
+    print_error("unknown user was not denied and tallied");
+    result = 1;
+  }
+
+  clear_tally_dir();
+
+  // track_unknown_users = true tallies failures of ignored unknown users
+  if (writeToFile(CONF_FILE, "[Configuration]\ntrack_unknown_users = true\n") !=
+      0) {
+    print_error("could not write " CONF_FILE);
+    return 1;
+  }
+
+  retval = authenticate_unknown_user(CONF_FILE, "pam_deny.so");
+  if (retval == PAM_AUTH_ERR && count_placeholder_tallies() == 1) {
+    printf("Unknown user failure tallied: %d\n", retval);
+  } else {
+    print_error("unknown user failure was not tallied");
+    result = 1;
+  }
+
+  remove(CONF_FILE);
+
+  if (result == 0) {
+    print_success("test_unknown_user");
+  }
+  clear_tally_dir();
+  return result;
+}
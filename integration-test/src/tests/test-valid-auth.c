@@ -16,7 +16,8 @@ int test_valid_auth() {
 
   char srv[] =
       "auth        required                                     libpam_authramp.so preauth \n\
-      account     required                                     libpam_authramp.so";
+      account     required                                     libpam_authramp.so \n\
+      account     required                                     pam_permit.so";
 
   create_pam_service_file(srv);
 
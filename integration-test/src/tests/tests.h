@@ -13,6 +13,18 @@
 
 int test_valid_auth();
 int test_invalid_auth();
+int test_consecutive_invalid_adds_tally();
 int test_bounce_auth();
+int test_account_neutral();
+int test_nodelay();
+int test_policy();
+int test_noninteractive();
+int test_account_clears_tally();
+int test_locked_preauth();
+int test_conf_argument();
+int test_unknown_user();
+int test_chauthtok();
+int test_open_session();
+int test_settings_cache();
 
 #endif  // TESTS_H
\ No newline at end of file
@@ -87,6 +87,28 @@ int clear_tally_dir() {
   return 0;
 }
 
+// Checks whether the tally file of a user contains a string
+int tally_contains(const char *user_name, const char *needle) {
+  char tally_file[FILE_PATH_MAX];
+  snprintf(tally_file, sizeof(tally_file), "%s%s", TALLY_DIR, user_name);
+
+  FILE *file = fopen(tally_file, "r");
+  if (file == NULL) {
+    return 0;
+  }
+
+  char line[256];
+  int found = 0;
+  while (fgets(line, sizeof(line), file) != NULL) {
+    if (strstr(line, needle) != NULL) {
+      found = 1;
+      break;
+    }
+  }
+  fclose(file);
+  return found;
+}
+
 void print_error(const char *message) {
   printf(RED_TEXT "Error: %s" RESET_TEXT "\n", message);
 }
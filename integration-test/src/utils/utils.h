@@ -18,9 +18,11 @@ extern char PAM_SRV[];
 extern char TALLY_DIR[];
 extern struct pam_conv conv;
 
+int writeToFile(const char *filePath, const char *content);
 int create_pam_service_file(const char *srv_content);
 int remove_pam_service_file();
 int clear_tally_dir();
+int tally_contains(const char *user_name, const char *needle);
 void print_error(const char *message);
 void print_success(const char *message);
 
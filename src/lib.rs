@@ -8,13 +8,18 @@
 //!
 //! To use the `AuthRamp` PAM module, integrate it with the PAM system by configuring the `/etc/pam.d/`
 //! configuration files for the desired PAM-aware services. This module is designed for the
-//! `sm_authenticate` and `acct_mgmt` hooks.
+//! `sm_authenticate`, `acct_mgmt`, `sm_chauthtok` and `sm_open_session` hooks.
+//!
+//! Bounced accounts are exported to the PAM environment as `AUTHRAMP_FAILURES` and
+//! `AUTHRAMP_LOCKED_UNTIL`, the unlock instant in RFC 3339 format.
 //!
 //! ## Configuration
 //!
 //! The behavior of the `AuthRamp` module is configurable through an TOML file located at
 //! `/etc/security/authramp.conf` by default. The configuration file can be customized with settings
-//! such as the tally directory, free tries threshold, base delay, and multiplier.
+//! such as the tally directory, free tries threshold, base delay, and multiplier. A file with a
+//! syntax error, an unknown key or a value of the wrong type is logged and ignored as a whole, so
+//! every value falls back to its default.
 //!
 //! ```ini
 //! [Configuration]
@@ -25,9 +30,92 @@
 //! ```
 //!
 //! - `tally_dir`: Directory where tally information is stored.
-//! - `free_tries`: Number of allowed free authentication attempts before applying delays.
+//! - `persist_across_reboot`: Store tallies in `/var/lib/authramp/tally` instead of the tmpfs
+//!   `/var/run/authramp` if `tally_dir` isn't set.
+//! - `durable_writes`: Flush tally files to disk when they're written, `true` by default.
+//! - `status_world_readable`: Let every user read the tally files for `authramp remaining`.
+//! - `stats_file`: File where anonymous statistics of cleared tallies are stored.
+//! - `free_tries`: Number of allowed free authentication attempts before applying delays. The
+//!   failure after the free tries locks the account.
 //! - `base_delay_seconds`: Base delay applied to each authentication failure.
 //! - `ramp_multiplier`: Multiplier for the delay calculation based on the number of failures.
+//! - `delay_algorithm`: `"ramp"`, `"exponential"` growing by `delay_factor` per failure, or
+//!   `"fixed"` at `base_delay_seconds`.
+//! - `max_lockout_seconds`: Maximum duration of a single lockout. 0 means no cap.
+//! - `reset_after_seconds`: Failures older than this expire, unless the account is locked.
+//!   0 means failures never expire.
+//! - `even_deny_root_console`: Lock out root on the physical console too, with `even_deny_root`.
+//! - `root_free_tries`, `root_max_lockout_seconds`: `free_tries` and `max_lockout_seconds` of
+//!   root, the global values by default.
+//! - `message_time_format`: strftime format of the unlock time in user messages, shown in the
+//!   local timezone.
+//! - `locale`: Language of the user messages, detected from the environment by default.
+//! - `lockout_message`, `countdown_message`: Templates of the messages to a locked user with
+//!   `{user}`, `{failures}`, `{remaining}` and `{unlock_time}` placeholders. Empty ones aren't sent.
+//! - `countdown_style`: `"repeat"` sends the countdown whenever it changes, `"single"` sends it
+//!   once.
+//! - `countdown_interval_seconds`: Granularity of the repeated countdown in its last two minutes.
+//!   Longer locks count down in minutes, locks over two hours in hours.
+//! - `delay_mode`: `"sleep"` blocks in the module, `"pam_fail_delay"` asks the application to delay
+//!   the failure instead.
+//! - `soft_delay_seconds`: Delay of every failure within the free tries, without a message.
+//! - `max_conversation_failures`: Consecutive failed countdown messages until the countdown stops.
+//! - `max_conversation_block_seconds`: Maximum time the countdown blocks. 0 means no cap.
+//! - `account_neutral`: Return `PAM_IGNORE` from the account hook when there is nothing to clear.
+//! - `nodelay`: Deny locked accounts immediately without sleeping. Also available as module argument.
+//! - `exempt_services`: PAM services the module doesn't act on. Supports a trailing `*` wildcard.
+//! - `noninteractive_services`: PAM services or terminals that can't display the countdown. Locked
+//!   accounts are reported once instead. Defaults to `["sshd", "sudo"]`.
+//! - `exempt_groups`: Members of these groups, primary or supplementary, are never locked out.
+//! - `ignore_users`: Users, or members of `@group` entries, the module doesn't act on. Their tally
+//!   is never touched.
+//! - `deny_local`: Lock out logins without a remote host on a console terminal. With false they
+//!   are never delayed.
+//! - `count_local_failures`: Record the failures of local logins exempt with `deny_local = false`.
+//! - `hook_command`: Executable run in the background when an account gets locked or unlocked.
+//! - `notify_email`: Recipients emailed when an account gets locked or reset with the CLI.
+//! - `notify_email_template`: Template of the notification with `{user}`-style placeholders.
+//! - `notify_sendmail`: Executable the notifications are piped to, `/usr/sbin/sendmail` by default.
+//! - `tally_hmac_key_file`: Root-only key the tally files are authenticated with.
+//! - `tally_hmac_fail_closed`: Refuse users whose tally fails the integrity check, `true` by default.
+//! - `log_facility`: Syslog facility of the module and CLI logs, `"authpriv"` by default.
+//! - `log_backend`: `"syslog"` logs to the syslog socket, `"journald"` to the journal with
+//!   structured `AUTHRAMP_*` fields.
+//! - `log_level`: Least severe messages logged, `"info"` by default. `"debug"` adds the delay
+//!   calculation and the resolved configuration.
+//! - `log_repeat_interval_seconds`: Interval identical bounce log lines are collapsed in, `60` by
+//!   default.
+//! - `audit_lockouts`: Write a Linux audit record when an account gets locked. Requires the `audit`
+//!   feature.
+//! - `dbus`: Signal lockouts and unlocks on the D-Bus system bus. Requires the `dbus` feature.
+//! - `user_lookup`: `"nss"` resolves users in the user database, `"none"` keys everything by the
+//!   PAM user name for deployments without one.
+//! - `normalize_user`: `"lowercase"`, `"strip_realm"` or both in an array normalize the PAM user
+//!   name, so case and realm variants share a tally.
+//! - `tally_key`: `"name"` keys tally files by user name, `"uid"` by uid.
+//! - `tally_layout`: `"flat"` keeps tally files in `tally_dir`, `"sharded"` in hashed subdirectories.
+//! - `forgive_same_transaction_failures`: A success only subtracts the failures of its own PAM
+//!   transaction instead of clearing the tally.
+//! - `count_while_locked`: Record failures while the account is locked, `false` by default.
+//! - `recompute_on_config_change`: Shorten recorded locks to the delay of the current settings.
+//! - `fail_mode`: `"open"` returns `PAM_IGNORE` on internal failures, `"closed"` denies. An unsafe
+//!   tally directory is only a warning in open mode.
+//! - `unknown_user`: `"ignore"` returns `PAM_IGNORE` for users missing from the user database,
+//!   `"deny"` denies them and tallies the attempts under a hashed placeholder.
+//! - `track_unknown_users`: Tally ignored unknown users under their placeholder, `false` by default.
+//! - `unknown_user_salt_file`: Root-only salt of the placeholder names, generated on first use.
+//! - `policy_disclosure`: `"full"` shows the policy to lines with the `policy` argument,
+//!   `"minimal"` refuses and logs the request.
+//!
+//! `[user.<name>]` tables override `free_tries`, `base_delay_seconds`, `ramp_multiplier`,
+//! `even_deny_root`, `countdown` and `nodelay` for a single user. `[auth]` and `[account]` tables
+//! override the same keys for a PAM hook, below the user tables.
+//!
+//! The module arguments `free_tries`, `base_delay`, `ramp_multiplier`, `tally_dir` and
+//! `even_deny_root`, e.g. `free_tries=3`, override the configuration file and the user tables.
+//!
+//! The `conf=<path>` module argument loads another configuration file. If it can't be read, the
+//! defaults are used, or the module denies with the `conf_missing=deny` argument.
 //!
 //! ## License
 //!
@@ -47,21 +135,27 @@
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-mod tally;
-
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use common::actions::Actions;
+use common::config::{
+    deny_result, Config, CountdownStyle, DelayMode, FailMode, PolicyDisclosure, UnknownUser,
+};
+use common::error::AuthRampError;
+use common::log_limit;
+use common::messages::{Locale, Message, Unit};
+use common::policy::Policy;
 use common::settings::Settings;
-use pam::conv::Conv;
+use common::tally::{is_over_threshold, Tally, ATTEMPT_MARKER, SUCCESS_MARKER, TRANSACTION_MARKER};
+use common::template;
 use pam::pam_try;
-use pam::{PamFlag, PamResultCode, PAM_TEXT_INFO};
-use pam::{PamHandle, PamHooks};
-use std::cmp::min;
+use pam::{PamApi, PamHandle, PamHooks};
+use pam::{
+    PamFlag, PamMessageStyle, PamResultCode, PAM_ERROR_MSG, PAM_PRELIM_CHECK, PAM_TEXT_INFO,
+};
 use std::ffi::CStr;
+use std::fmt::Write;
 use std::thread::sleep;
-use uzers::get_user_by_name;
-
-use tally::Tally;
+use uzers::User;
 
 pub struct Pamauthramp;
 
@@ -77,7 +171,13 @@ impl PamHooks for Pamauthramp {
     ///
     /// It can also be called with the AUTHFAIL action argument:
     /// auth        [default=die]                                `libpam_authramp.so` authfail
-    /// It then locks the account and increments the delay.
+    /// It then locks the account and increments the delay. The failure is also counted on the
+    /// PAM handle, so a success later in the same transaction can forgive it.
+    /// After a `preauth` line, the failure of an attempt is recorded once, even if the stack
+    /// runs several `authfail` lines.
+    ///
+    /// Adding the `nodelay` argument denies a locked account immediately with a single error
+    /// message instead of holding the conversation open.
     ///
     /// # Arguments
     /// - `pam_h`: `PamHandle` instance for interacting with PAM
@@ -87,15 +187,7 @@ impl PamHooks for Pamauthramp {
     /// # Returns
     /// `PAM_SUCCESS` OR `PAM_AUTH_ERR`
     fn sm_authenticate(pam_h: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
-        init_authramp(pam_h, &args, flags, "auth", |pam_h, settings, tally| {
-            // match action parameter
-            match settings.get_action()? {
-                Actions::PREAUTH => Ok(bounce_auth(pam_h, settings, tally)),
-                Actions::AUTHFAIL => Err(bounce_auth(pam_h, settings, tally)),
-                Actions::AUTHSUCC => Ok(PamResultCode::PAM_SUCCESS),
-            }
-        })
-        .unwrap_or_else(|e| e)
+        init_authramp(pam_h, &args, flags, "auth", authenticate).unwrap_or_else(|e| e)
     }
 
     /// Handles the `acct_mgmt` PAM hook, which is invoked during the account management process.
@@ -105,282 +197,2002 @@ impl PamHooks for Pamauthramp {
     /// This hook is only called on sucessful authentication and clears the tally to unlock the account:
     /// account     required                                     `libpam_authramp.so`
     ///
+    /// If an `authsucc` line already settled the tally earlier in the same transaction, the hook
+    /// leaves it untouched. Adding the `noclear` argument opts the line out of clearing entirely.
+    ///
+    /// With `account_neutral` enabled the hook only returns `PAM_SUCCESS` if it actually cleared
+    /// recorded failures. Otherwise it returns `PAM_IGNORE`, so it can't vouch for an account
+    /// it never validated on stacks using a `sufficient` control.
+    ///
     /// # Arguments
     /// - `pam_h`: `PamHandle` instance for interacting with PAM
     /// - `args`: PAM arguments provided during account management
     /// - `flags`: PAM flags indicating the context of the PAM operation
     ///
     /// # Returns
-    /// `PAM_SUCESS` OR `PAM_SYS_ERR`
+    /// `PAM_SUCESS`, `PAM_IGNORE` OR `PAM_PERM_DENIED`
     fn acct_mgmt(pam_h: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
         pam_try!(init_authramp(
             pam_h,
             &args,
             flags,
             "account",
-            |_pam_h, _settings, _tally| { Ok(PamResultCode::PAM_SUCCESS) }
+            |_pam_h, settings, tally| {
+                if tally.cleared {
+                    Ok(PamResultCode::PAM_SUCCESS)
+                } else {
+                    Ok(neutral_result(settings))
+                }
+            }
         ))
     }
 
+    /// Handles the `sm_chauthtok` PAM hook, which is invoked when the user changes the password.
+    ///
+    /// Libpam runs the password stack twice. The `PAM_PRELIM_CHECK` pass bounces locked accounts
+    /// like the `preauth` action, whatever action the line has, and never touches the tally.
+    /// The `PAM_UPDATE_AUTHTOK` pass only runs once every module accepted the preliminary check,
+    /// including the one verifying the old password. It acts like the auth hook, so the default
+    /// action counts the change as a success and clears the tally:
+    /// password    requisite                                    `libpam_authramp.so`
+    ///
+    /// # Arguments
+    /// - `pam_h`: `PamHandle` instance for interacting with PAM
+    /// - `args`: PAM arguments provided during the password change
+    /// - `flags`: PAM flags indicating the pass of the password change
+    ///
+    /// # Returns
+    /// `PAM_SUCCESS` OR `PAM_PERM_DENIED`
+    fn sm_chauthtok(pam_h: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        let mut settings =
+            pam_try!(Settings::build(None, &args, flags, "password", Some(pam_h))
+                .map_err(|e| e.log(pam_h)));
+
+        // The preliminary check only bounces, the tally is updated once the password changed
+        if flags & PAM_PRELIM_CHECK != 0 {
+            settings.action = Some(Actions::PREAUTH);
+        }
+
+        let result = run_hook(pam_h, &settings, "password", authenticate)
+            .map_err(|result| fail_result(pam_h, &settings, result))
+            .unwrap_or_else(|e| e);
+        password_result(result)
+    }
+
+    /// Handles the `sm_open_session` PAM hook, which is invoked when a session is opened after a
+    /// successful login.
+    ///
+    /// The instant of the login is recorded as `last_success` in the tally, without touching the
+    /// failures. Users exempt from lockout, like root without `even_deny_root`, are recorded too:
+    /// session     optional                                     `libpam_authramp.so`
+    ///
+    /// # Arguments
+    /// - `pam_h`: `PamHandle` instance for interacting with PAM
+    /// - `args`: PAM arguments provided during the session opening
+    /// - `flags`: PAM flags indicating the context of the PAM operation
+    ///
+    /// # Returns
+    /// `PAM_SUCCESS`, internal failures are mapped according to `fail_mode`
+    fn sm_open_session(pam_h: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        let mut settings =
+            pam_try!(Settings::build(None, &args, flags, "session", Some(pam_h))
+                .map_err(|e| e.log(pam_h)));
+
+        // Sessions only record the login, whatever action the line has
+        settings.action = Some(Actions::SESSION);
+
+        run_hook(pam_h, &settings, "session", |_pam_h, _settings, _tally| {
+            Ok(PamResultCode::PAM_SUCCESS)
+        })
+        .map_err(|result| fail_result(pam_h, &settings, result))
+        .unwrap_or_else(|e| e)
+    }
+
     fn sm_setcred(_pam_h: &mut PamHandle, _args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
         PamResultCode::PAM_SUCCESS
     }
 }
 
+/// Runs the action of an auth or password line on the tally.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for interacting with PAM
+/// - `settings`: Settings for the authramp module
+/// - `tally`: The tally of this hook
+///
+/// # Returns
+/// `PAM_SUCCESS` OR `PAM_AUTH_ERR`, as error if the line recorded a failure
+fn authenticate<P: PamApi>(
+    pam_h: &mut P,
+    settings: &Settings,
+    tally: &Tally,
+) -> Result<PamResultCode, PamResultCode> {
+    // match action parameter
+    match settings.get_action()? {
+        Actions::PREAUTH => {
+            // a new attempt starts, its failure isn't recorded yet
+            mark_attempt(pam_h, false)?;
+            Ok(bounce_auth(pam_h, settings, tally))
+        }
+        Actions::AUTHFAIL => {
+            // mark the failure for the rest of this transaction
+            mark_transaction(pam_h, tally, false)?;
+
+            // later authfail lines of an attempt started by preauth don't record it again
+            match settings.attempt_counted {
+                Some(true) => {}
+                Some(false) => {
+                    mark_attempt(pam_h, true)?;
+                    soft_delay(pam_h, settings, tally);
+                }
+                None => soft_delay(pam_h, settings, tally),
+            }
+            Err(bounce_auth(pam_h, settings, tally))
+        }
+        Actions::AUTHSUCC | Actions::SESSION => Ok(PamResultCode::PAM_SUCCESS),
+    }
+}
+
+/// Maps the result of the password hook to the codes `pam_sm_chauthtok` may return.
+///
+/// A locked account is bounced with `PAM_AUTH_ERR`, which isn't a result of the password
+/// hook, so it's denied with `PAM_PERM_DENIED` instead.
+///
+/// # Arguments
+/// - `result`: The result code of the hook
+///
+/// # Returns
+/// The result code passed to PAM
+fn password_result(result: PamResultCode) -> PamResultCode {
+    match result {
+        PamResultCode::PAM_AUTH_ERR => PamResultCode::PAM_PERM_DENIED,
+        result => result,
+    }
+}
+
 /// Initializes the authramp module by setting up user information and loading settings.
 /// Calls the provided `pam_hook` function with the initialized variables.
 ///
 /// # Arguments
-/// - `pam_h`: `PamHandle` instance for interacting with PAM
+/// - `pam_h`: PAM handle for interacting with PAM
 /// - `_args`: PAM arguments provided during authentication
 /// - `_flags`: PAM flags indicating the context of the PAM operation
 /// - `pam_hook`: Function to be called with the initialized variables
 ///
+/// Services listed in `exempt_services`, users listed in `ignore_users`, lines with the `policy`
+/// argument and successes that must not clear the tally are short-circuited before the tally is
+/// touched.
+///
+/// Internal failures are mapped according to `fail_mode`.
+///
 /// # Returns
 /// Result from the `pam_hook` function or PAM error code if initialization fails
-fn init_authramp<F, R>(
-    pam_h: &mut PamHandle,
+fn init_authramp<P: PamApi, F>(
+    pam_h: &mut P,
     args: &[&CStr],
     flags: PamFlag,
     pam_hook_desc: &str,
     pam_hook: F,
-) -> Result<R, PamResultCode>
+) -> Result<PamResultCode, PamResultCode>
+where
+    F: FnOnce(&mut P, &Settings, &Tally) -> Result<PamResultCode, PamResultCode>,
+{
+    // Resolve the PAM user and read the configuration file
+    let settings =
+        Settings::build(None, args, flags, pam_hook_desc, Some(pam_h)).map_err(|e| e.log(pam_h))?;
+
+    run_hook(pam_h, &settings, pam_hook_desc, pam_hook)
+        .map_err(|result| fail_result(pam_h, &settings, result))
+}
+
+/// Runs the `pam_hook` function with the tally of the settings.
+///
+/// # Returns
+/// Result from the `pam_hook` function or PAM error code if the tally can't be loaded
+fn run_hook<P: PamApi, F>(
+    pam_h: &mut P,
+    settings: &Settings,
+    pam_hook_desc: &str,
+    pam_hook: F,
+) -> Result<PamResultCode, PamResultCode>
 where
-    F: FnOnce(&mut PamHandle, &Settings, &Tally) -> Result<R, PamResultCode>,
+    F: FnOnce(&mut P, &Settings, &Tally) -> Result<PamResultCode, PamResultCode>,
 {
-    // Try to get PAM user
-    let user = get_user_by_name(pam_try!(
-        &pam_h.get_user(None),
-        Err(PamResultCode::PAM_AUTH_ERR)
-    ));
+    // Show the policy without touching the tally
+    if settings.policy {
+        return show_policy(pam_h, settings);
+    }
+
+    // Skip exempt services
+    if let Some(service) = settings
+        .service
+        .as_deref()
+        .filter(|service| settings.config.is_exempt_service(service))
+    {
+        pam_h.log(
+            pam::LogLevel::Debug,
+            format!("Service \"{service}\" is exempt. Skipping the {pam_hook_desc} hook."),
+        )?;
+        return Ok(neutral_result(settings));
+    }
+
+    // Skip ignored users without touching the tally
+    if let Some(entry) = settings.ignored_user() {
+        pam_h.log(
+            pam::LogLevel::Info,
+            format!(
+                "Account \"{}\" matches \"{entry}\" in ignore_users. Skipping the {pam_hook_desc} hook.",
+                settings.get_user()?.name().display()
+            ),
+        )?;
+        return Ok(if settings.action == Some(Actions::AUTHFAIL) {
+            PamResultCode::PAM_IGNORE
+        } else {
+            neutral_result(settings)
+        });
+    }
+
+    // Tracked unknown users are tallied like any other user, denied ones never pass
+    let deny_unknown =
+        settings.is_unknown_user() && settings.config.unknown_user == UnknownUser::Deny;
 
-    // Read configuration file
-    let settings = Settings::build(user.clone(), args, flags, pam_hook_desc, Some(pam_h))?;
+    if settings.action == Some(Actions::AUTHSUCC) {
+        // Denied unknown users never pass, so their tally is never cleared
+        if deny_unknown {
+            return Err(deny_result(settings.pam_hook));
+        }
+
+        // Leave the tally untouched if the line opts out of clearing
+        if settings.noclear {
+            pam_h.log(
+                pam::LogLevel::Debug,
+                format!(
+                    "noclear argument set. Not clearing the tally in the {pam_hook_desc} hook."
+                ),
+            )?;
+            return Ok(neutral_result(settings));
+        }
 
-    // common::util::syslog::init_pam_log(pam_h, &settings)?;
+        // An earlier success in this transaction, e.g. an authsucc line, already settled the tally
+        if settings.transaction_succeeded {
+            pam_h.log(
+                pam::LogLevel::Debug,
+                format!(
+                    "Tally already settled in this transaction. Skipping the {pam_hook_desc} hook."
+                ),
+            )?;
+            return Ok(neutral_result(settings));
+        }
+    }
 
     // Get and Set tally
-    let tally = Tally::new_from_tally_file(&Some(pam_h), &settings)?;
+    let tally = match Tally::new_from_tally_file(&Some(pam_h), settings) {
+        Ok(tally) => tally,
+        Err(e) => return unavailable_result(pam_h, settings, pam_hook_desc, e),
+    };
+
+    // mark the success for the rest of this transaction
+    if settings.action == Some(Actions::AUTHSUCC) {
+        mark_transaction(pam_h, &tally, true)?;
+    }
 
-    pam_hook(pam_h, &settings, &tally)
+    let result = pam_hook(pam_h, settings, &tally);
+
+    if deny_unknown {
+        return Err(deny_result(settings.pam_hook));
+    }
+    result
 }
 
-/// Formats a Duration into a human-readable string representation.
-/// The format includes hours, minutes, and seconds, excluding zero values.
+/// Handles a tally that can't be loaded or updated.
+///
+/// On early boot, e.g. in an emergency shell, the tally directory may be on a read-only
+/// filesystem or not exist yet. In open mode that storage is logged as a warning and the hook is
+/// skipped without tallying, so recovery logins aren't blocked. Any other error, or closed mode,
+/// fails the hook.
 ///
 /// # Arguments
-/// - `remaining_time`: Duration representing the remaining time
+/// - `pam_h`: PAM handle for logging
+/// - `settings`: Settings for the authramp module
+/// - `pam_hook_desc`: The name of the hook, for the log
+/// - `error`: The error loading the tally
 ///
 /// # Returns
-/// Formatted string indicating the remaining time in the countdown
-fn format_remaining_countdown_time(remaining_time: Duration) -> String {
-    if remaining_time.num_seconds() == 0 {
-        return "..".to_string();
+/// `PAM_IGNORE` for a failure, the neutral result otherwise, or the code of the error
+fn unavailable_result<P: PamApi>(
+    pam_h: &P,
+    settings: &Settings,
+    pam_hook_desc: &str,
+    error: AuthRampError,
+) -> Result<PamResultCode, PamResultCode> {
+    if !error.is_unavailable() || settings.config.fail_mode != FailMode::Open {
+        return Err(error.log(pam_h));
     }
 
-    let mut formatted_time = String::new();
+    pam_h.log(
+        pam::LogLevel::Warning,
+        format!("{error}. The tally storage is unavailable. Skipping the {pam_hook_desc} hook without tallying."),
+    )?;
+    Ok(if settings.action == Some(Actions::AUTHFAIL) {
+        PamResultCode::PAM_IGNORE
+    } else {
+        neutral_result(settings)
+    })
+}
 
-    let mut t_val = remaining_time.num_hours();
-    let mut t_desc = "hours";
+/// Maps the result of a hook according to `fail_mode`.
+///
+/// Decisions of the module are returned unchanged. Any other code is an internal failure, which
+/// is logged and returns `PAM_IGNORE` in open mode, or denies in closed mode.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for logging
+/// - `settings`: Settings for the authramp module
+/// - `result`: The result code of the hook
+///
+/// # Returns
+/// The result code passed to PAM
+fn fail_result<P: PamApi>(pam_h: &P, settings: &Settings, result: PamResultCode) -> PamResultCode {
+    let fail_mode = settings.config.fail_mode;
+    let mapped = fail_mode.result(settings.pam_hook, result);
 
-    if t_val > 0 {
-        if t_val == 1 {
-            t_desc = t_desc.trim_end_matches('s');
-        }
-        formatted_time += &format!("{t_val} {t_desc}, ");
+    if mapped != result {
+        let _ = pam_h.log(
+            pam::LogLevel::Error,
+            format!(
+                "{result}: Internal failure in the {} hook. Returning {mapped}, fail_mode is {}.",
+                settings.pam_hook,
+                fail_mode.name()
+            ),
+        );
     }
+    mapped
+}
 
-    t_val = remaining_time.num_minutes() % 60;
-    t_desc = "minutes";
-
-    if t_val > 0 {
-        if t_val == 1 {
-            t_desc = t_desc.trim_end_matches('s');
-        }
-        formatted_time += &format!("{t_val} {t_desc} and ");
+/// Stores the state of the current transaction on the PAM handle for later hooks.
+///
+/// Failures of this transaction can be forgiven by a later success, and a success keeps later
+/// stages of the same transaction from clearing the tally again. A failure lifts that mark.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for interacting with PAM
+/// - `tally`: The tally of this hook
+/// - `succeeded`: Whether this hook settled the tally after a success
+///
+/// # Returns
+/// `Ok` even if the markers can't be stored, errors only come from logging
+fn mark_transaction<P: PamApi>(
+    pam_h: &mut P,
+    tally: &Tally,
+    succeeded: bool,
+) -> Result<(), PamResultCode> {
+    if let Err(pam_code) = pam_h
+        .set_data(TRANSACTION_MARKER, tally.transaction_failures)
+        .and_then(|()| pam_h.set_data(SUCCESS_MARKER, succeeded))
+    {
+        pam_h.log(
+            pam::LogLevel::Error,
+            format!("{pam_code}: Error setting the transaction marker."),
+        )?;
     }
+    Ok(())
+}
 
-    t_val = remaining_time.num_seconds() % 60;
-    t_desc = "seconds";
-
-    if t_val == 1 {
-        t_desc = t_desc.trim_end_matches('s');
+/// Stores on the PAM handle whether the failure of the current attempt was recorded.
+///
+/// A `preauth` line starts an attempt, so the failure of one password is recorded once even if
+/// the stack runs several `authfail` lines. Without a `preauth` line every failure is recorded.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for interacting with PAM
+/// - `counted`: Whether the failure of the attempt was recorded
+///
+/// # Returns
+/// `Ok` even if the marker can't be stored, errors only come from logging
+fn mark_attempt<P: PamApi>(pam_h: &mut P, counted: bool) -> Result<(), PamResultCode> {
+    if let Err(pam_code) = pam_h.set_data(ATTEMPT_MARKER, counted) {
+        pam_h.log(
+            pam::LogLevel::Error,
+            format!("{pam_code}: Error setting the attempt marker."),
+        )?;
     }
+    Ok(())
+}
 
-    formatted_time += &format!("{t_val} {t_desc}");
+/// Sends the effective policy of the authenticating user as a PAM info message.
+///
+/// With `policy_disclosure = "minimal"` the request is refused and logged instead.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for interacting with PAM
+/// - `settings`: Settings for the authramp module
+///
+/// # Returns
+/// `PAM_IGNORE` so the line never influences the stack
+fn show_policy<P: PamApi>(
+    pam_h: &mut P,
+    settings: &Settings,
+) -> Result<PamResultCode, PamResultCode> {
+    if settings.config.policy_disclosure == PolicyDisclosure::Minimal {
+        pam_h.log(
+            pam::LogLevel::Info,
+            format!(
+                "Refused to show the policy of the \"{}\" account: policy_disclosure is minimal.",
+                settings.get_user()?.name().display()
+            ),
+        )?;
+        return Ok(PamResultCode::PAM_IGNORE);
+    }
 
-    formatted_time
+    pam_message(
+        pam_h,
+        PAM_TEXT_INFO,
+        &Policy::from_settings(settings).summary(),
+    )?;
+    Ok(PamResultCode::PAM_IGNORE)
 }
 
-/// Sends a message to the PAM conversation function and logs errors if they occur.
+/// Returns the result of a hook that has nothing to enforce.
 ///
-/// This function retrieves the conversation function from the PAM handle and sends
-/// a specified message to it. If any errors occur during this process, they are logged
-/// appropriately.
+/// The account hook returns `PAM_IGNORE` when `account_neutral` is enabled, so it can't vouch
+/// for an account it never validated. Every other case returns `PAM_SUCCESS`.
 ///
 /// # Arguments
-/// - `pam_h`: Mutable reference to the `PamHandle`
-/// - `msg`: String slice containing the message to be sent
+/// - `settings`: Settings for the authramp module
 ///
 /// # Returns
-/// `Result<(), PamResultCode>` - Returns `Ok(())` if the message is sent successfully.
-/// Returns `Err(PamResultCode)` if an error occurs, with the appropriate PAM result code.
-///
-/// # Errors
-/// - If the conversation function cannot be accessed from the PAM handle.
-/// - If sending the message to the conversation function fails.
-/// - If logging the error fails.
-fn pam_message(pam_h: &mut PamHandle, msg: &str) -> Result<(), PamResultCode> {
-    if let Ok(Some(conv)) = pam_h.get_item::<Conv>() {
-        // Send a message to the conversation function
-        let conv_res = conv.send(PAM_TEXT_INFO, msg);
-
-        // Log error
-        match conv_res {
-            Ok(_) => Ok(()),
-            Err(pam_code) => {
-                match pam_h.log(
-                    pam::LogLevel::Error,
-                    format!("{pam_code:?}: Error starting PAM conversation."),
-                ) {
-                    Ok(()) => Ok(()),
-                    Err(result_code) => Err(result_code),
-                }
-            }
-        }
+/// `PAM_IGNORE` OR `PAM_SUCCESS`
+fn neutral_result(settings: &Settings) -> PamResultCode {
+    if settings.pam_hook == "account" && settings.config.account_neutral {
+        PamResultCode::PAM_IGNORE
     } else {
-        match pam_h.log(
-            pam::LogLevel::Error,
-            "Error accessing conversation in PAM library.".to_string(),
-        ) {
-            Ok(()) => Ok(()),
-            Err(result_code) => Err(result_code),
-        }
+        PamResultCode::PAM_SUCCESS
     }
 }
 
-/// Handles the account lockout mechanism based on the number of failures and settings.
-/// If the account is locked, it sends periodic messages to the user until the account is unlocked.
+/// Decides which countdown message, if any, is sent for the remaining lock time.
+///
+/// Clients like ssh print every message on a new line, so a message is only sent when it differs
+/// from the last one. With `CountdownStyle::Repeat` the remaining time is rounded, see
+/// [`format_remaining_countdown_step`], with `CountdownStyle::Single` only the first message is
+/// sent.
 ///
 /// # Arguments
-/// - `pam_h`: `PamHandle` instance for interacting with PAM
-/// - `settings`: Settings for the authramp module
-/// - `tally`: Tally information containing failure count and timestamps
+/// - `lock`: The lock the message is about, with the configured `countdown_message`
+/// - `remaining_time`: Duration until the account is unlocked
+/// - `last_message`: The last message sent, `None` if nothing was sent yet
 ///
 /// # Returns
-/// `PAM_SUCCESS` if the account is successfully unlocked, `PAM_AUTH_ERR` otherwise
-fn bounce_auth(pam_h: &mut PamHandle, settings: &Settings, tally: &Tally) -> PamResultCode {
-    // get user
-    let user = match settings.get_user() {
-        Ok(user) => user,
-        Err(res) => return res,
+/// The message to send, `None` if nothing should be sent
+fn countdown_message(
+    lock: &LockMessage,
+    remaining_time: Duration,
+    last_message: Option<&str>,
+) -> Option<String> {
+    let remaining = match lock.config.countdown_style {
+        CountdownStyle::Single if last_message.is_some() => return None,
+        CountdownStyle::Single => format_remaining_countdown_time(lock.locale, remaining_time),
+        CountdownStyle::Repeat => format_remaining_countdown_step(
+            lock.locale,
+            remaining_time,
+            lock.config.countdown_interval_seconds,
+        ),
     };
+    let message = lock.render(lock.config.countdown_message(lock.locale), &remaining)?;
 
-    // ignore root except when configured
-    if user.uid().eq(&0) && !settings.config.even_deny_root {
-        return PamResultCode::PAM_SUCCESS;
+    (last_message != Some(message.as_str())).then_some(message)
+}
+
+/// The values of the placeholders in the messages to a locked user.
+struct LockMessage<'a> {
+    config: &'a Config,
+    locale: Locale,
+    user: String,
+    failures: i32,
+    unlock_instant: DateTime<Utc>,
+}
+
+impl<'a> LockMessage<'a> {
+    /// Collects the placeholder values of a locked account.
+    fn new(config: &'a Config, user: &User, tally: &Tally, unlock_instant: DateTime<Utc>) -> Self {
+        LockMessage {
+            config,
+            locale: config.locale(),
+            user: user.name().to_string_lossy().into_owned(),
+            failures: tally.failures_count,
+            unlock_instant,
+        }
+    }
+
+    /// Renders a message template, see `common::template`.
+    ///
+    /// # Arguments
+    /// - `template`: The template with `{user}`, `{failures}`, `{remaining}` and `{unlock_time}`
+    ///   placeholders
+    /// - `remaining`: The formatted remaining lock time
+    ///
+    /// # Returns
+    /// The message, `None` if the template is empty
+    fn render(&self, template: &str, remaining: &str) -> Option<String> {
+        if template.is_empty() {
+            return None;
+        }
+        Some(template::render(
+            template,
+            |placeholder| match placeholder {
+                "user" => Some(self.user.clone()),
+                "failures" => Some(self.failures.to_string()),
+                "remaining" => Some(remaining.to_string()),
+                "unlock_time" => Some(self.config.message_time(self.unlock_instant)),
+                _ => None,
+            },
+        ))
     }
 
-    if tally.failures_count > settings.config.free_tries {
-        let delay = tally.get_delay(settings);
+    /// Renders the `lockout_message` telling when the account is unlocked, in the locale.
+    ///
+    /// # Arguments
+    /// - `remaining_time`: Duration until the account is unlocked
+    ///
+    /// # Returns
+    /// The message in the local timezone, e.g. "Account locked until 2024-01-01 01:30:05 PM.",
+    /// `None` if the template is empty
+    fn lockout(&self, remaining_time: Duration) -> Option<String> {
+        self.render(
+            self.config.lockout_message(self.locale),
+            &format_remaining_countdown_time(self.locale, remaining_time),
+        )
+    }
+}
 
-        // Calculate the time when the account will be unlocked
-        let unlock_instant = tally
-            .unlock_instant
-            .unwrap_or(tally.failure_instant + delay);
+/// Exports the lock of a bounced account to the PAM environment, so later modules and the
+/// session, e.g. a motd script, can tell the user what happened.
+///
+/// Sets `AUTHRAMP_FAILURES` to the recorded failures and `AUTHRAMP_LOCKED_UNTIL` to the unlock
+/// instant in RFC 3339 format. Errors are only logged.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for interacting with PAM
+/// - `tally`: Tally of the bounced account
+/// - `unlock_instant`: Instant the account is unlocked
+fn export_lock_state<P: PamApi>(pam_h: &P, tally: &Tally, unlock_instant: DateTime<Utc>) {
+    for name_value in [
+        format!("AUTHRAMP_FAILURES={}", tally.failures_count),
+        format!("AUTHRAMP_LOCKED_UNTIL={}", unlock_instant.to_rfc3339()),
+    ] {
+        if let Err(pam_code) = pam_h.putenv(&name_value) {
+            let _ = pam_h.log(
+                pam::LogLevel::Warning,
+                format!("{pam_code}: Error exporting \"{name_value}\" to the PAM environment."),
+            );
+        }
+    }
+}
 
-        match pam_h.log(
+/// Counts down the lock of an account with messages until it is unlocked.
+///
+/// The countdown stops early if the conversation keeps failing or it blocked for
+/// `max_conversation_block_seconds`.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for interacting with PAM
+/// - `settings`: Settings for the authramp module
+/// - `user`: The locked user
+/// - `lock`: The lock the messages are about
+///
+/// # Returns
+/// `PAM_CONV_ERR` if the conversation kept failing, `PAM_AUTH_ERR` otherwise
+fn countdown<P: PamApi>(
+    pam_h: &mut P,
+    settings: &Settings,
+    user: &User,
+    lock: &LockMessage,
+) -> PamResultCode {
+    let unlock_instant = lock.unlock_instant;
+    let mut control = CountdownControl::new(&settings.config, Utc::now());
+    let mut header_sent = false;
+    let mut last_message: Option<String> = None;
+    while Utc::now() < unlock_instant {
+        // Stop blocking after max_conversation_block_seconds
+        if let Some(result_code) = control.check_deadline(Utc::now()) {
+            let _ = pam_h.log(
                 pam::LogLevel::Info,
                 format!(
-                    "PAM_AUTH_ERR: Account {user:?} is getting bounced. Account still locked until {unlock_instant}"
+                    "PAM_AUTH_ERR: Stopped the countdown of account {user:?} after max_conversation_block_seconds."
                 ),
-            ) {
-                Ok(()) => (),
-                Err(result_code) => return result_code,
-            }
+            );
+            return result_code;
+        }
+
+        // Calculate remaining time until unlock, the lock was capped when it was recorded
+        let remaining_time = (unlock_instant - Utc::now()).max(Duration::zero());
+
+        // The first message comes with a header telling when the account is unlocked
+        let header = if header_sent {
+            None
+        } else {
+            lock.lockout(remaining_time)
+        };
+        let message = countdown_message(lock, remaining_time, last_message.as_deref());
+        let messages: Vec<_> = header
+            .iter()
+            .chain(message.iter())
+            .map(|message| (PAM_TEXT_INFO, message.as_str()))
+            .collect();
 
-        // Don't loop and return timestamp if configured
-        if !settings.config.countdown {
-            // If account is locked, keep user locked out
-            if Utc::now() < unlock_instant {
-                if let Err(result_code) = pam_message(
-                    pam_h,
-                    &format!(
-                        "Account locked until {}.",
-                        unlock_instant.format("%Y-%m-%d %I:%M:%S %p")
+        if !messages.is_empty() {
+            let sent = pam_messages(pam_h, &messages);
+            let delivered = sent.is_ok();
+
+            // Stop if the conversation keeps failing, e.g. the client disconnected
+            if let Some(result_code) = control.record_message(sent) {
+                let _ = pam_h.log(
+                    pam::LogLevel::Warning,
+                    format!(
+                        "PAM_CONV_ERR: Stopped the countdown of account {user:?} after {} failed messages.",
+                        control.failures
                     ),
-                ) {
-                    return result_code;
+                );
+                return result_code;
+            }
+
+            // Failed messages are sent again
+            if delivered {
+                header_sent = true;
+                if message.is_some() {
+                    last_message = message;
                 }
-                return PamResultCode::PAM_AUTH_ERR;
             }
-            return PamResultCode::PAM_SUCCESS;
         }
 
-        while Utc::now() < unlock_instant {
-            // Calculate remaining time until unlock
-            let remaining_time = unlock_instant - Utc::now();
+        // Wait for one second
+        sleep(std::time::Duration::from_secs(1));
+    }
 
-            // Cap remaining time at 24 hours
-            let capped_remaining_time = min(remaining_time, Duration::hours(24));
+    // The password may have been entered while the account was locked, e.g. by a screen locker
+    // collecting it up front, so it is never checked in this attempt
+    PamResultCode::PAM_AUTH_ERR
+}
 
-            // Only send a message every two seconds to help with latency
-            if capped_remaining_time.num_seconds() % 2 == 0 {
-                if let Err(result_code) = pam_message(
-                    pam_h,
-                    &format!(
-                        "Account locked! Unlocking in {}.",
-                        format_remaining_countdown_time(capped_remaining_time)
-                    ),
-                ) {
-                    return result_code;
-                }
-            }
+/// Delays a failure within the free tries by `soft_delay_seconds`, without any message.
+///
+/// The delay blocks in the module, or is requested with `pam_fail_delay` in that `delay_mode`.
+/// Failures past the free tries are delayed by the lock instead, and `nodelay` and local console
+/// logins exempt with `deny_local = false` are never delayed.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle requesting the fail delay
+/// - `settings`: Settings for the authramp module
+/// - `tally`: Tally of the failed account
+fn soft_delay<P: PamApi>(pam_h: &P, settings: &Settings, tally: &Tally) {
+    let config = &settings.config;
+    if config.soft_delay_seconds == 0
+        || config.nodelay
+        || is_over_threshold(tally.failures_count, config.free_tries)
+        || config.is_exempt_local(
+            settings.service.as_deref(),
+            settings.tty.as_deref(),
+            settings.rhost.as_deref(),
+        )
+    {
+        return;
+    }
 
-            // Wait for one second
-            sleep(std::time::Duration::from_secs(1));
+    if config.delay_mode == DelayMode::PamFailDelay {
+        let delay = Duration::seconds(config.soft_delay_seconds);
+        if let Err(pam_code) = pam_h.fail_delay(fail_delay_usec(delay)) {
+            let _ = pam_h.log(
+                pam::LogLevel::Warning,
+                format!("{pam_code}: Error requesting the soft delay."),
+            );
         }
+    } else {
+        sleep(std::time::Duration::from_secs(
+            u64::try_from(config.soft_delay_seconds).unwrap_or_default(),
+        ));
     }
-    PamResultCode::PAM_SUCCESS
 }
 
-// Unit tests
-#[cfg(test)]
-mod tests {
-    use chrono::TimeDelta;
+/// Converts the remaining lock time into the delay requested with `pam_fail_delay`.
+///
+/// # Arguments
+/// - `remaining_time`: Duration until the account is unlocked
+///
+/// # Returns
+/// The delay in microseconds, saturating at `u32::MAX`, about 71 minutes
+fn fail_delay_usec(remaining_time: Duration) -> u32 {
+    remaining_time.num_microseconds().map_or(u32::MAX, |usec| {
+        u32::try_from(usec.max(0)).unwrap_or(u32::MAX)
+    })
+}
 
-    use super::*;
-    use std::time::Duration;
+/// Formats the remaining lock time of the repeated countdown, coarser the longer it is.
+///
+/// More than two hours are rounded up to hours and more than two minutes to minutes, so a long
+/// lock doesn't send a message every few seconds. The last two minutes are rounded up to
+/// `interval_seconds`.
+///
+/// # Arguments
+/// - `locale`: Language of the words and plural forms
+/// - `remaining_time`: Duration representing the remaining time
+/// - `interval_seconds`: Granularity of the last two minutes, at least one second
+///
+/// # Returns
+/// Formatted string indicating the remaining time in the countdown
+fn format_remaining_countdown_step(
+    locale: Locale,
+    remaining_time: Duration,
+    interval_seconds: i64,
+) -> String {
+    let seconds = remaining_time.num_seconds();
+    if seconds > 2 * 3600 {
+        return locale.unit(Unit::Hour, (seconds + 3599) / 3600);
+    }
+    if seconds > 2 * 60 {
+        return format_remaining_countdown_minutes(locale, remaining_time);
+    }
 
-    #[test]
-    fn test_format_remaining_time() {
-        let cast_error = &"bad time delta!";
+    let interval = interval_seconds.max(1);
+    let rounded = (seconds + interval - 1) / interval * interval;
+    if rounded > 0 && rounded % 60 == 0 {
+        locale.unit(Unit::Minute, rounded / 60)
+    } else {
+        format_remaining_countdown_time(locale, Duration::seconds(rounded))
+    }
+}
 
-        // Test with duration of 2 hours, 24 minutes, and 5 seconds
-        let duration =
-            TimeDelta::from_std(Duration::new(2 * 3600 + 24 * 60 + 5, 0)).expect(cast_error);
-        assert_eq!(
-            format_remaining_countdown_time(duration),
-            "2 hours, 24 minutes and 5 seconds"
-        );
+/// Formats a Duration into a human-readable string representation at minute granularity.
+/// Partial minutes are rounded up and durations below a minute are shown as such.
+///
+/// # Arguments
+/// - `locale`: Language of the words and plural forms
+/// - `remaining_time`: Duration representing the remaining time
+///
+/// # Returns
+/// Formatted string indicating the remaining time in the countdown
+fn format_remaining_countdown_minutes(locale: Locale, remaining_time: Duration) -> String {
+    let minutes = (remaining_time.num_seconds() + 59) / 60;
+    if minutes <= 1 {
+        return locale.text(Message::LessThanAMinute).to_string();
+    }
 
-        // Test with duration of 1 hour, 1 minute, and 0 seconds
-        let duration = TimeDelta::from_std(Duration::new(3600 + 60, 0)).expect(cast_error);
-        assert_eq!(
-            format_remaining_countdown_time(duration),
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => locale.unit(Unit::Minute, minutes),
+        (hours, 0) => locale.unit(Unit::Hour, hours),
+        (hours, minutes) => format!(
+            "{} {} {}",
+            locale.unit(Unit::Hour, hours),
+            locale.text(Message::And),
+            locale.unit(Unit::Minute, minutes)
+        ),
+    }
+}
+
+/// Formats a Duration into a human-readable string representation.
+/// The format includes hours, minutes, and seconds, excluding zero values.
+///
+/// # Arguments
+/// - `locale`: Language of the words and plural forms
+/// - `remaining_time`: Duration representing the remaining time
+///
+/// # Returns
+/// Formatted string indicating the remaining time in the countdown
+fn format_remaining_countdown_time(locale: Locale, remaining_time: Duration) -> String {
+    if remaining_time.num_seconds() == 0 {
+        return "..".to_string();
+    }
+
+    let mut formatted_time = String::new();
+
+    let hours = remaining_time.num_hours();
+    if hours > 0 {
+        let _ = write!(formatted_time, "{}, ", locale.unit(Unit::Hour, hours));
+    }
+
+    let minutes = remaining_time.num_minutes() % 60;
+    if minutes > 0 {
+        let _ = write!(
+            formatted_time,
+            "{} {} ",
+            locale.unit(Unit::Minute, minutes),
+            locale.text(Message::And)
+        );
+    }
+
+    let seconds = remaining_time.num_seconds() % 60;
+    formatted_time.push_str(&locale.unit(Unit::Second, seconds));
+
+    formatted_time
+}
+
+/// Sends a message to the PAM conversation function and logs errors if they occur.
+///
+/// This function retrieves the conversation function from the PAM handle and sends
+/// a specified message to it. If any errors occur during this process, they are logged
+/// appropriately.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle sending the messages
+/// - `style`: PAM message style, e.g. `PAM_TEXT_INFO` or `PAM_ERROR_MSG`
+/// - `msg`: String slice containing the message to be sent
+///
+/// # Returns
+/// `Result<(), PamResultCode>` - Returns `Ok(())` if the message is sent successfully.
+/// Returns `Err(PamResultCode)` if an error occurs, with the appropriate PAM result code.
+///
+/// # Errors
+/// - `PAM_CONV_ERR` if the PAM handle has no conversation function.
+/// - The result of the conversation function if sending the message fails.
+/// - If logging the error fails.
+fn pam_message<P: PamApi>(
+    pam_h: &mut P,
+    style: PamMessageStyle,
+    msg: &str,
+) -> Result<(), PamResultCode> {
+    pam_messages(pam_h, &[(style, msg)])
+}
+
+/// Sends several messages in a single conversation, so clients that show every conversation as
+/// a separate dialog show them together. Errors are logged like in `pam_message`.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle sending the messages
+/// - `msgs`: Styles and texts of the messages, in the order they are shown
+///
+/// # Returns
+/// `Ok(())` if the messages are sent successfully
+///
+/// # Errors
+/// See `pam_message`.
+fn pam_messages<P: PamApi>(
+    pam_h: &mut P,
+    msgs: &[(PamMessageStyle, &str)],
+) -> Result<(), PamResultCode> {
+    // Send the messages to the conversation function
+    if let Err(pam_code) = pam_h.send_messages(msgs) {
+        pam_h.log(
+            pam::LogLevel::Error,
+            format!("{pam_code}: Error starting PAM conversation."),
+        )?;
+        return Err(pam_code);
+    }
+    Ok(())
+}
+
+/// Decides when the countdown stops before the account is unlocked.
+///
+/// A conversation that keeps failing, e.g. because the user closed the SSH connection, stops the
+/// countdown after `max_conversation_failures` consecutive failed messages. The countdown also
+/// stops after `max_conversation_block_seconds`, however long the lock is.
+struct CountdownControl {
+    // Consecutive failed messages until the countdown stops
+    max_failures: i32,
+    // Consecutive failed messages so far
+    failures: i32,
+    // Instant the countdown stops blocking, if capped
+    deadline: Option<DateTime<Utc>>,
+}
+
+impl CountdownControl {
+    /// Starts controlling a countdown.
+    ///
+    /// # Arguments
+    /// - `config`: Loaded configuration
+    /// - `start`: Instant the countdown starts
+    fn new(config: &Config, start: DateTime<Utc>) -> Self {
+        CountdownControl {
+            max_failures: config.max_conversation_failures.max(1),
+            failures: 0,
+            deadline: config.conversation_block_cap().map(|cap| start + cap),
+        }
+    }
+
+    /// Records the result of sending a countdown message.
+    ///
+    /// # Arguments
+    /// - `sent`: Result of sending the message
+    ///
+    /// # Returns
+    /// `PAM_CONV_ERR` once too many consecutive messages failed, `None` to continue
+    fn record_message(&mut self, sent: Result<(), PamResultCode>) -> Option<PamResultCode> {
+        if sent.is_ok() {
+            self.failures = 0;
+            return None;
+        }
+
+        self.failures += 1;
+        (self.failures >= self.max_failures).then_some(PamResultCode::PAM_CONV_ERR)
+    }
+
+    /// Checks whether the countdown blocked for `max_conversation_block_seconds`.
+    ///
+    /// # Arguments
+    /// - `now`: The current instant
+    ///
+    /// # Returns
+    /// `PAM_AUTH_ERR` once the countdown has to stop, `None` to continue
+    fn check_deadline(&self, now: DateTime<Utc>) -> Option<PamResultCode> {
+        self.deadline
+            .is_some_and(|deadline| now >= deadline)
+            .then_some(PamResultCode::PAM_AUTH_ERR)
+    }
+}
+
+/// Handles the account lockout mechanism based on the number of failures and settings.
+/// If the account is locked, it sends periodic messages to the user until the account is unlocked.
+///
+/// A locked account is always denied, even after the countdown ran out. The next attempt is the
+/// first one to reach the password module.
+///
+/// # Arguments
+/// - `pam_h`: PAM handle for interacting with PAM
+/// - `settings`: Settings for the authramp module
+/// - `tally`: Tally information containing failure count and timestamps
+///
+/// # Returns
+/// `PAM_SUCCESS` if the account isn't locked, `PAM_AUTH_ERR` otherwise
+fn bounce_auth<P: PamApi>(pam_h: &mut P, settings: &Settings, tally: &Tally) -> PamResultCode {
+    // get user
+    let user = match settings.get_user() {
+        Ok(user) => user,
+        Err(res) => return fail_result(pam_h, settings, res),
+    };
+
+    // ignore root except when configured, on the console only with even_deny_root_console
+    if user.uid().eq(&0) && !settings.config.denies_root(settings.tty.as_deref()) {
+        return PamResultCode::PAM_SUCCESS;
+    }
+
+    // Calculate the time when the account will be unlocked, nothing to bounce if it passed
+    let Some(unlock_instant) = tally
+        .effective_unlock_instant(settings)
+        .filter(|unlock_instant| Utc::now() < *unlock_instant)
+    else {
+        return PamResultCode::PAM_SUCCESS;
+    };
+
+    // never lock out members of exempt groups
+    if let Some(group) = settings.exempt_group() {
+        if let Err(result_code) = pam_h.log(
+            pam::LogLevel::Info,
+            format!(
+                "PAM_SUCCESS: Account \"{}\" is exempt from lockout as a member of the \"{group}\" group.",
+                user.name().display()
+            ),
+        ) {
+            return fail_result(pam_h, settings, result_code);
+        }
+        return PamResultCode::PAM_SUCCESS;
+    }
+
+    // never lock out local console logins with deny_local = false
+    if settings.config.is_exempt_local(
+        settings.service.as_deref(),
+        settings.tty.as_deref(),
+        settings.rhost.as_deref(),
+    ) {
+        if let Err(result_code) = pam_h.log(
+            pam::LogLevel::Info,
+            format!(
+                "PAM_SUCCESS: Account \"{}\" is exempt from lockout on the local console {}.",
+                user.name().display(),
+                settings.tty.as_deref().unwrap_or_default()
+            ),
+        ) {
+            return fail_result(pam_h, settings, result_code);
+        }
+        return PamResultCode::PAM_SUCCESS;
+    }
+
+    // a client retrying in a loop logs the bounce once per interval
+    let bounce_line = format!(
+        "PAM_AUTH_ERR: Account {user:?} is getting bounced. Account still locked until {unlock_instant}"
+    );
+    let repeat_interval = std::time::Duration::from_secs(
+        u64::try_from(settings.config.log_repeat_interval_seconds).unwrap_or_default(),
+    );
+    if let Some(line) = log_limit::check(&bounce_line, repeat_interval) {
+        if let Err(result_code) = pam_h.log(pam::LogLevel::Info, line) {
+            return fail_result(pam_h, settings, result_code);
+        }
+    }
+
+    export_lock_state(pam_h, tally, unlock_instant);
+
+    // the messages and the delay show the same instant that is enforced, never a negative time
+    let remaining_time = (unlock_instant - Utc::now()).max(Duration::zero());
+    let lock = LockMessage::new(&settings.config, user, tally, unlock_instant);
+
+    // Let the application delay the failure instead of blocking in the module
+    let fail_delay =
+        settings.config.delay_mode == DelayMode::PamFailDelay && !settings.config.nodelay;
+    if fail_delay {
+        if let Err(pam_code) = pam_h.fail_delay(fail_delay_usec(remaining_time)) {
+            let _ = pam_h.log(
+                pam::LogLevel::Warning,
+                format!("{pam_code}: Error requesting the fail delay."),
+            );
+        }
+    }
+
+    // Don't loop and return timestamp if configured or the client can't display the countdown
+    let noninteractive = settings
+        .config
+        .is_noninteractive(settings.service.as_deref(), settings.tty.as_deref());
+    if settings.config.nodelay || !settings.config.countdown || noninteractive || fail_delay {
+        // nodelay reports the lock as an error so non-interactive clients see it
+        let style = if settings.config.nodelay {
+            PAM_ERROR_MSG
+        } else {
+            PAM_TEXT_INFO
+        };
+
+        // a failing conversation never lifts the lock, the error is logged
+        if let Some(message) = lock.lockout(remaining_time) {
+            let _ = pam_message(pam_h, style, &message);
+        }
+        return PamResultCode::PAM_AUTH_ERR;
+    }
+
+    countdown(pam_h, settings, user, &lock)
+}
+
+// Mock of the PAM handle for unit tests
+#[cfg(test)]
+mod mock;
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::mock::MockPamHandle;
+    use pam::items::ItemType;
+    use std::ffi::CString;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_remaining_time() {
+        let cast_error = &"bad time delta!";
+
+        // Test with duration of 2 hours, 24 minutes, and 5 seconds
+        let duration =
+            TimeDelta::from_std(Duration::new(2 * 3600 + 24 * 60 + 5, 0)).expect(cast_error);
+        assert_eq!(
+            format_remaining_countdown_time(Locale::English, duration),
+            "2 hours, 24 minutes and 5 seconds"
+        );
+
+        // Test with duration of 1 hour, 1 minute, and 0 seconds
+        let duration = TimeDelta::from_std(Duration::new(3600 + 60, 0)).expect(cast_error);
+        assert_eq!(
+            format_remaining_countdown_time(Locale::English, duration),
             "1 hour, 1 minute and 0 seconds"
         );
 
         // Test with duration of 35 seconds
         let duration = TimeDelta::from_std(Duration::new(35, 0)).expect(cast_error);
-        assert_eq!(format_remaining_countdown_time(duration), "35 seconds");
+        assert_eq!(
+            format_remaining_countdown_time(Locale::English, duration),
+            "35 seconds"
+        );
 
         // Test with duration of 35 seconds
         let duration = TimeDelta::from_std(Duration::new(1, 0)).expect(cast_error);
-        assert_eq!(format_remaining_countdown_time(duration), "1 second");
+        assert_eq!(
+            format_remaining_countdown_time(Locale::English, duration),
+            "1 second"
+        );
 
         // Test with duration of 0 seconds
         let duration = TimeDelta::from_std(Duration::new(0, 0)).expect(cast_error);
-        assert_eq!(format_remaining_countdown_time(duration), "..");
+        assert_eq!(
+            format_remaining_countdown_time(Locale::English, duration),
+            ".."
+        );
+    }
+
+    #[test]
+    fn test_countdown_control_conversation_failures() {
+        let config = Config {
+            max_conversation_failures: 3,
+            ..Config::default()
+        };
+        let mut control = CountdownControl::new(&config, Utc::now());
+
+        // a conversation that works for two messages, then starts failing
+        let mock_conversation = |message: usize| {
+            if message < 2 {
+                Ok(())
+            } else {
+                Err(PamResultCode::PAM_CONV_ERR)
+            }
+        };
+
+        let stopped_at = (0..10)
+            .find_map(|message| {
+                control
+                    .record_message(mock_conversation(message))
+                    .map(|result_code| (message, result_code))
+            })
+            .unwrap();
+        assert_eq!(stopped_at, (4, PamResultCode::PAM_CONV_ERR));
+
+        // only consecutive failures count
+        let mut control = CountdownControl::new(&config, Utc::now());
+        for sent in [
+            Err(PamResultCode::PAM_CONV_ERR),
+            Err(PamResultCode::PAM_CONV_ERR),
+            Ok(()),
+            Err(PamResultCode::PAM_CONV_ERR),
+            Err(PamResultCode::PAM_CONV_ERR),
+        ] {
+            assert_eq!(control.record_message(sent), None);
+        }
+        assert_eq!(
+            control.record_message(Err(PamResultCode::PAM_CONV_ERR)),
+            Some(PamResultCode::PAM_CONV_ERR)
+        );
+    }
+
+    #[test]
+    fn test_countdown_control_deadline() {
+        let start = Utc::now();
+
+        // uncapped by default
+        let control = CountdownControl::new(&Config::default(), start);
+        assert_eq!(control.check_deadline(start + TimeDelta::days(7)), None);
+
+        let config = Config {
+            max_conversation_block_seconds: 60,
+            ..Config::default()
+        };
+        let control = CountdownControl::new(&config, start);
+        assert_eq!(control.check_deadline(start + TimeDelta::seconds(59)), None);
+        assert_eq!(
+            control.check_deadline(start + TimeDelta::seconds(60)),
+            Some(PamResultCode::PAM_AUTH_ERR)
+        );
+    }
+
+    #[test]
+    fn test_password_result() {
+        assert_eq!(
+            password_result(PamResultCode::PAM_AUTH_ERR),
+            PamResultCode::PAM_PERM_DENIED
+        );
+        assert_eq!(
+            password_result(PamResultCode::PAM_SUCCESS),
+            PamResultCode::PAM_SUCCESS
+        );
+        assert_eq!(
+            password_result(PamResultCode::PAM_CONV_ERR),
+            PamResultCode::PAM_CONV_ERR
+        );
+    }
+
+    #[test]
+    fn test_fail_delay_usec() {
+        assert_eq!(fail_delay_usec(TimeDelta::seconds(30)), 30_000_000);
+        assert_eq!(fail_delay_usec(TimeDelta::milliseconds(-5)), 0);
+        assert_eq!(fail_delay_usec(TimeDelta::hours(2)), u32::MAX);
+        assert_eq!(fail_delay_usec(TimeDelta::max_value()), u32::MAX);
+    }
+
+    #[test]
+    fn test_format_remaining_time_german() {
+        assert_eq!(
+            format_remaining_countdown_time(
+                Locale::German,
+                TimeDelta::seconds(2 * 3600 + 24 * 60 + 5)
+            ),
+            "2 Stunden, 24 Minuten und 5 Sekunden"
+        );
+        assert_eq!(
+            format_remaining_countdown_time(Locale::German, TimeDelta::seconds(3600 + 60 + 1)),
+            "1 Stunde, 1 Minute und 1 Sekunde"
+        );
+        assert_eq!(
+            format_remaining_countdown_time(Locale::German, TimeDelta::seconds(35)),
+            "35 Sekunden"
+        );
+
+        assert_eq!(
+            format_remaining_countdown_minutes(Locale::German, TimeDelta::seconds(30)),
+            "weniger als einer Minute"
+        );
+        assert_eq!(
+            format_remaining_countdown_minutes(Locale::German, TimeDelta::hours(1)),
+            "1 Stunde"
+        );
+        assert_eq!(
+            format_remaining_countdown_minutes(
+                Locale::German,
+                TimeDelta::seconds(2 * 3600 + 24 * 60 + 5)
+            ),
+            "2 Stunden und 25 Minuten"
+        );
+    }
+
+    #[test]
+    fn test_format_remaining_minutes() {
+        assert_eq!(
+            format_remaining_countdown_minutes(Locale::English, TimeDelta::seconds(30)),
+            "less than a minute"
+        );
+        assert_eq!(
+            format_remaining_countdown_minutes(Locale::English, TimeDelta::seconds(90)),
+            "2 minutes"
+        );
+        assert_eq!(
+            format_remaining_countdown_minutes(Locale::English, TimeDelta::hours(1)),
+            "1 hour"
+        );
+        assert_eq!(
+            format_remaining_countdown_minutes(
+                Locale::English,
+                TimeDelta::seconds(2 * 3600 + 24 * 60 + 5)
+            ),
+            "2 hours and 25 minutes"
+        );
+    }
+
+    /// Collects the remaining times sent while counting a lock down second by second.
+    fn countdown_sequence(style: CountdownStyle, interval: i64, lock_seconds: i64) -> Vec<String> {
+        let config = Config {
+            countdown_style: style,
+            countdown_interval_seconds: interval,
+            countdown_message: Some("{remaining}".to_string()),
+            ..Config::default()
+        };
+        let lock = lock_message(&config);
+        let mut sent: Vec<String> = Vec::new();
+        for remaining in (1..=lock_seconds).rev() {
+            if let Some(message) = countdown_message(
+                &lock,
+                TimeDelta::seconds(remaining),
+                sent.last().map(String::as_str),
+            ) {
+                sent.push(message);
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn test_countdown_sequence_seconds() {
+        assert_eq!(
+            countdown_sequence(CountdownStyle::Repeat, 2, 10),
+            [
+                "10 seconds",
+                "8 seconds",
+                "6 seconds",
+                "4 seconds",
+                "2 seconds"
+            ]
+        );
+        assert_eq!(
+            countdown_sequence(CountdownStyle::Repeat, 10, 30),
+            ["30 seconds", "20 seconds", "10 seconds"]
+        );
+        assert_eq!(
+            countdown_sequence(CountdownStyle::Repeat, 30, 150),
+            [
+                "3 minutes",
+                "2 minutes",
+                "1 minute and 30 seconds",
+                "1 minute",
+                "30 seconds"
+            ]
+        );
+        assert_eq!(
+            countdown_sequence(CountdownStyle::Single, 2, 30),
+            ["30 seconds"]
+        );
+    }
+
+    #[test]
+    fn test_countdown_sequence_coarse() {
+        let sequence = countdown_sequence(CountdownStyle::Repeat, 2, 3 * 3600 + 1);
+        assert_eq!(
+            sequence[..4],
+            ["4 hours", "3 hours", "2 hours", "1 hour and 59 minutes"]
+        );
+        assert_eq!(sequence[119], "3 minutes");
+        assert_eq!(sequence[120], "2 minutes");
+        assert_eq!(sequence.last().map(String::as_str), Some("2 seconds"));
+
+        // hours, then minutes, and only the last two minutes at the interval
+        assert_eq!(sequence.len(), 2 + 118 + 60);
+    }
+
+    /// Builds the settings of a user with the given configuration.
+    fn bounce_settings(user: User, config: Config) -> Settings<'static> {
+        Settings {
+            action: Some(Actions::PREAUTH),
+            user: Some(user),
+            config,
+            ..Settings::default()
+        }
+    }
+
+    /// Builds a tally over the default free tries, locked manually until `unlock_instant`, so
+    /// the settings don't shorten the lock.
+    fn locked_tally(unlock_instant: DateTime<Utc>) -> Tally {
+        Tally {
+            failures_count: 10,
+            failure_instant: Utc::now(),
+            unlock_instant: Some(unlock_instant),
+            manual_lock: true,
+            ..Tally::default()
+        }
+    }
+
+    #[test]
+    fn test_bounce_auth_unlocked() {
+        let settings = bounce_settings(User::new(1000, "user", 1000), Config::default());
+        let mut pam_h = MockPamHandle::default();
+
+        // no failures
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &Tally::default()),
+            PamResultCode::PAM_SUCCESS
+        );
+
+        // the lock already expired
+        let tally = locked_tally(Utc::now() - TimeDelta::seconds(10));
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &tally),
+            PamResultCode::PAM_SUCCESS
+        );
+        assert!(pam_h.messages.borrow().is_empty());
+        assert!(pam_h.env.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_bounce_auth_locked() {
+        let config = Config {
+            nodelay: true,
+            ..Config::default()
+        };
+        let settings = bounce_settings(User::new(1000, "user", 1000), config);
+        let unlock_instant = Utc::now() + TimeDelta::minutes(10);
+        let mut pam_h = MockPamHandle::default();
+
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &locked_tally(unlock_instant)),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        assert_eq!(
+            *pam_h.messages.borrow(),
+            vec![(
+                PAM_ERROR_MSG,
+                lockout_message(&settings.config, unlock_instant)
+            )]
+        );
+        assert_eq!(pam_h.getenv("AUTHRAMP_FAILURES").as_deref(), Some("10"));
+        assert_eq!(
+            pam_h.getenv("AUTHRAMP_LOCKED_UNTIL"),
+            Some(unlock_instant.to_rfc3339())
+        );
+        assert!(pam_h.fail_delays.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_bounce_auth_recomputed() {
+        let config = Config {
+            nodelay: true,
+            ..Config::default()
+        };
+        let settings = bounce_settings(User::new(1000, "user", 1000), config);
+        let mut pam_h = MockPamHandle::default();
+
+        // a lock recorded under a harsher configuration ends with the current delay
+        let tally = Tally {
+            manual_lock: false,
+            ..locked_tally(Utc::now() + TimeDelta::hours(10))
+        };
+        let unlock_instant = tally.failure_instant + tally.get_capped_delay(&settings);
+        assert!(unlock_instant - Utc::now() < TimeDelta::minutes(10));
+
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &tally),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        assert_eq!(
+            *pam_h.messages.borrow(),
+            vec![(
+                PAM_ERROR_MSG,
+                lockout_message(&settings.config, unlock_instant)
+            )]
+        );
+        assert_eq!(
+            pam_h.getenv("AUTHRAMP_LOCKED_UNTIL"),
+            Some(unlock_instant.to_rfc3339())
+        );
+    }
+
+    #[test]
+    fn test_bounce_auth_root_exempt() {
+        let tally = locked_tally(Utc::now() + TimeDelta::minutes(10));
+        let config = Config {
+            nodelay: true,
+            ..Config::default()
+        };
+
+        // root is never locked by default
+        let settings = bounce_settings(User::new(0, "root", 0), config.clone());
+        let mut pam_h = MockPamHandle::default();
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &tally),
+            PamResultCode::PAM_SUCCESS
+        );
+        assert!(pam_h.messages.borrow().is_empty());
+
+        // unless even_deny_root is enabled
+        let config = Config {
+            even_deny_root: true,
+            ..config
+        };
+        let settings = bounce_settings(User::new(0, "root", 0), config);
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &tally),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        assert_eq!(pam_h.messages.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_bounce_auth_root_console() {
+        let tally = locked_tally(Utc::now() + TimeDelta::minutes(10));
+
+        for even_deny_root in [false, true] {
+            for even_deny_root_console in [false, true] {
+                for tty in [
+                    None,
+                    Some("tty1"),
+                    Some("/dev/tty12"),
+                    Some("pts/0"),
+                    Some("ssh"),
+                ] {
+                    let config = Config {
+                        nodelay: true,
+                        even_deny_root,
+                        even_deny_root_console,
+                        ..Config::default()
+                    };
+                    let settings = Settings {
+                        tty: tty.map(str::to_string),
+                        ..bounce_settings(User::new(0, "root", 0), config)
+                    };
+                    let console = matches!(tty, Some("tty1" | "/dev/tty12"));
+                    let expected = if even_deny_root && (even_deny_root_console || !console) {
+                        PamResultCode::PAM_AUTH_ERR
+                    } else {
+                        PamResultCode::PAM_SUCCESS
+                    };
+                    assert_eq!(
+                        bounce_auth(&mut MockPamHandle::default(), &settings, &tally),
+                        expected,
+                        "even_deny_root={even_deny_root} even_deny_root_console={even_deny_root_console} tty={tty:?}"
+                    );
+                }
+            }
+        }
+
+        // the console exemption is for root only
+        let settings = Settings {
+            tty: Some("tty1".to_string()),
+            ..bounce_settings(
+                User::new(1000, "user", 1000),
+                Config {
+                    nodelay: true,
+                    ..Config::default()
+                },
+            )
+        };
+        assert_eq!(
+            bounce_auth(&mut MockPamHandle::default(), &settings, &tally),
+            PamResultCode::PAM_AUTH_ERR
+        );
+    }
+
+    #[test]
+    fn test_bounce_auth_local() {
+        let tally = locked_tally(Utc::now() + TimeDelta::minutes(10));
+        let settings = |deny_local: bool, tty: &str, rhost: Option<&str>| Settings {
+            service: Some("login".to_string()),
+            tty: Some(tty.to_string()),
+            rhost: rhost.map(str::to_string),
+            ..bounce_settings(
+                User::new(1000, "user", 1000),
+                Config {
+                    nodelay: true,
+                    deny_local,
+                    ..Config::default()
+                },
+            )
+        };
+
+        // local logins are locked out by default
+        let mut pam_h = MockPamHandle::default();
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings(true, "tty1", None), &tally),
+            PamResultCode::PAM_AUTH_ERR
+        );
+
+        // without a remote host on a console terminal they are exempt with deny_local = false
+        let mut pam_h = MockPamHandle::default();
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings(false, "tty1", None), &tally),
+            PamResultCode::PAM_SUCCESS
+        );
+        assert!(pam_h.messages.borrow().is_empty());
+
+        // remote sessions are still locked out
+        for (tty, rhost) in [("tty1", Some("10.0.0.1")), ("pts/0", None)] {
+            assert_eq!(
+                bounce_auth(
+                    &mut MockPamHandle::default(),
+                    &settings(false, tty, rhost),
+                    &tally
+                ),
+                PamResultCode::PAM_AUTH_ERR
+            );
+        }
+        let su = Settings {
+            service: Some("su".to_string()),
+            ..settings(false, "tty1", None)
+        };
+        assert_eq!(
+            bounce_auth(&mut MockPamHandle::default(), &su, &tally),
+            PamResultCode::PAM_AUTH_ERR
+        );
+    }
+
+    #[test]
+    fn test_bounce_auth_fail_delay() {
+        let config = Config {
+            delay_mode: DelayMode::PamFailDelay,
+            ..Config::default()
+        };
+        let settings = bounce_settings(User::new(1000, "user", 1000), config);
+        let unlock_instant = Utc::now() + TimeDelta::minutes(10);
+        let mut pam_h = MockPamHandle::default();
+
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &locked_tally(unlock_instant)),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        assert_eq!(pam_h.fail_delays.borrow().len(), 1);
+        assert_eq!(
+            *pam_h.messages.borrow(),
+            vec![(
+                PAM_TEXT_INFO,
+                lockout_message(&settings.config, unlock_instant)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_bounce_auth_countdown() {
+        let config = Config {
+            countdown: true,
+            countdown_style: CountdownStyle::Single,
+            ..Config::default()
+        };
+        let settings = bounce_settings(User::new(1000, "user", 1000), config);
+        let unlock_instant = Utc::now() + TimeDelta::seconds(1);
+        let mut pam_h = MockPamHandle::default();
+
+        // the header and the first countdown line are sent together
+        assert_eq!(
+            bounce_auth(&mut pam_h, &settings, &locked_tally(unlock_instant)),
+            PamResultCode::PAM_AUTH_ERR
+        );
+        let messages = pam_h.messages.borrow();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0],
+            (
+                PAM_TEXT_INFO,
+                lockout_message(&settings.config, unlock_instant)
+            )
+        );
+        assert!(messages[1].1.starts_with("Account locked! Unlocking in"));
+    }
+
+    #[test]
+    fn test_bounce_auth_countdown_conversation_failure() {
+        let config = Config {
+            countdown: true,
+            max_conversation_failures: 1,
+            ..Config::default()
+        };
+        let settings = bounce_settings(User::new(1000, "user", 1000), config);
+        let mut pam_h = MockPamHandle {
+            conv_error: Some(PamResultCode::PAM_CONV_ERR),
+            ..MockPamHandle::default()
+        };
+
+        assert_eq!(
+            bounce_auth(
+                &mut pam_h,
+                &settings,
+                &locked_tally(Utc::now() + TimeDelta::minutes(10))
+            ),
+            PamResultCode::PAM_CONV_ERR
+        );
+        assert!(pam_h
+            .logs
+            .borrow()
+            .iter()
+            .any(|log| log.contains("Stopped the countdown")));
+    }
+
+    #[test]
+    fn test_authenticate_authfail() {
+        let config = Config {
+            nodelay: true,
+            ..Config::default()
+        };
+        let settings = Settings {
+            action: Some(Actions::AUTHFAIL),
+            ..bounce_settings(User::new(1000, "user", 1000), config)
+        };
+        let tally = Tally {
+            transaction_failures: 10,
+            ..locked_tally(Utc::now() + TimeDelta::minutes(10))
+        };
+        let mut pam_h = MockPamHandle::default();
+
+        // the failure is marked for the rest of the transaction and the account bounced
+        assert_eq!(
+            authenticate(&mut pam_h, &settings, &tally),
+            Err(PamResultCode::PAM_AUTH_ERR)
+        );
+        assert_eq!(
+            pam_h.get_data::<i32>(TRANSACTION_MARKER).unwrap(),
+            Some(&10)
+        );
+        assert_eq!(
+            pam_h.get_data::<bool>(SUCCESS_MARKER).unwrap(),
+            Some(&false)
+        );
+        assert_eq!(pam_h.messages.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_authenticate_soft_delay() {
+        let settings = |delay_mode: DelayMode, soft_delay_seconds: i64| Settings {
+            action: Some(Actions::AUTHFAIL),
+            ..bounce_settings(
+                User::new(1000, "user", 1000),
+                Config {
+                    delay_mode,
+                    soft_delay_seconds,
+                    ..Config::default()
+                },
+            )
+        };
+        let early = Tally {
+            failures_count: 1,
+            ..Tally::default()
+        };
+
+        // an early failure is delayed without any message
+        let mut pam_h = MockPamHandle::default();
+        let start = std::time::Instant::now();
+        assert_eq!(
+            authenticate(&mut pam_h, &settings(DelayMode::Sleep, 1), &early),
+            Err(PamResultCode::PAM_SUCCESS)
+        );
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(pam_h.messages.borrow().is_empty());
+
+        // or the application is asked to delay it
+        let mut pam_h = MockPamHandle::default();
+        let start = std::time::Instant::now();
+        authenticate(&mut pam_h, &settings(DelayMode::PamFailDelay, 2), &early).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(*pam_h.fail_delays.borrow(), vec![2_000_000]);
+        assert!(pam_h.messages.borrow().is_empty());
+
+        // 0 disables it, failures past the free tries are delayed by the lock instead
+        let late = Tally {
+            failures_count: 7,
+            ..Tally::default()
+        };
+        for (soft_delay_seconds, tally) in [(0, &early), (2, &late)] {
+            let mut pam_h = MockPamHandle::default();
+            authenticate(
+                &mut pam_h,
+                &settings(DelayMode::PamFailDelay, soft_delay_seconds),
+                tally,
+            )
+            .unwrap_err();
+            assert!(!pam_h.fail_delays.borrow().contains(&2_000_000));
+        }
+    }
+
+    #[test]
+    fn test_unavailable_result() {
+        let settings = |action: Actions, fail_mode: FailMode| Settings {
+            action: Some(action),
+            ..bounce_settings(
+                User::new(1000, "user", 1000),
+                Config {
+                    fail_mode,
+                    ..Config::default()
+                },
+            )
+        };
+        let read_only = || {
+            AuthRampError::io(
+                "Error creating tally directory /var/run/authramp",
+                std::io::Error::from(std::io::ErrorKind::ReadOnlyFilesystem),
+            )
+        };
+
+        // open mode skips the hook with a warning
+        let pam_h = MockPamHandle::default();
+        assert_eq!(
+            unavailable_result(
+                &pam_h,
+                &settings(Actions::AUTHFAIL, FailMode::Open),
+                "auth",
+                read_only()
+            ),
+            Ok(PamResultCode::PAM_IGNORE)
+        );
+        assert_eq!(
+            unavailable_result(
+                &pam_h,
+                &settings(Actions::PREAUTH, FailMode::Open),
+                "auth",
+                read_only()
+            ),
+            Ok(PamResultCode::PAM_SUCCESS)
+        );
+        assert!(pam_h
+            .logs
+            .borrow()
+            .iter()
+            .all(|log| log.contains("The tally storage is unavailable")));
+
+        // closed mode and other errors fail the hook
+        assert_eq!(
+            unavailable_result(
+                &pam_h,
+                &settings(Actions::AUTHFAIL, FailMode::Closed),
+                "auth",
+                read_only()
+            ),
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+        assert_eq!(
+            unavailable_result(
+                &pam_h,
+                &settings(Actions::AUTHFAIL, FailMode::Open),
+                "auth",
+                AuthRampError::io(
+                    "Error writing tally file",
+                    std::io::Error::other("disk full")
+                )
+            ),
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+    }
+
+    #[test]
+    fn test_authenticate_attempt_marker() {
+        let settings = |action: Actions, attempt_counted: Option<bool>| Settings {
+            action: Some(action),
+            attempt_counted,
+            ..bounce_settings(
+                User::new(1000, "user", 1000),
+                Config {
+                    delay_mode: DelayMode::PamFailDelay,
+                    soft_delay_seconds: 2,
+                    ..Config::default()
+                },
+            )
+        };
+        let tally = Tally {
+            failures_count: 1,
+            ..Tally::default()
+        };
+
+        // preauth starts an attempt
+        let mut pam_h = MockPamHandle::default();
+        authenticate(&mut pam_h, &settings(Actions::PREAUTH, Some(true)), &tally).unwrap();
+        assert_eq!(
+            pam_h.get_data::<bool>(ATTEMPT_MARKER).unwrap(),
+            Some(&false)
+        );
+
+        // its first failure is marked and delayed
+        authenticate(
+            &mut pam_h,
+            &settings(Actions::AUTHFAIL, Some(false)),
+            &tally,
+        )
+        .unwrap_err();
+        assert_eq!(pam_h.get_data::<bool>(ATTEMPT_MARKER).unwrap(), Some(&true));
+        assert_eq!(*pam_h.fail_delays.borrow(), vec![2_000_000]);
+
+        // later lines of the same attempt aren't delayed again
+        authenticate(&mut pam_h, &settings(Actions::AUTHFAIL, Some(true)), &tally).unwrap_err();
+        assert_eq!(*pam_h.fail_delays.borrow(), vec![2_000_000]);
+
+        // without preauth there's no attempt to mark
+        let mut pam_h = MockPamHandle::default();
+        authenticate(&mut pam_h, &settings(Actions::AUTHFAIL, None), &tally).unwrap_err();
+        assert_eq!(pam_h.get_data::<bool>(ATTEMPT_MARKER).unwrap(), None);
+        assert_eq!(*pam_h.fail_delays.borrow(), vec![2_000_000]);
+    }
+
+    /// The default `lockout_message` of a lock, in the locale of the environment.
+    fn lockout_message(config: &Config, unlock_instant: DateTime<Utc>) -> String {
+        config
+            .lockout_message(config.locale())
+            .replace("{unlock_time}", &config.message_time(unlock_instant))
+    }
+
+    /// Builds the placeholder values of a lock with 7 failures of "user".
+    fn lock_message(config: &Config) -> LockMessage<'_> {
+        LockMessage {
+            config,
+            // "auto" is English, independent of the environment
+            locale: Locale::from_name(&config.locale).unwrap_or_default(),
+            user: "user".to_string(),
+            failures: 7,
+            unlock_instant: DateTime::parse_from_rfc3339("2024-01-01T13:30:05Z")
+                .unwrap()
+                .with_timezone(&Utc),
+        }
+    }
+
+    #[test]
+    fn test_lock_message() {
+        // a format independent of the local timezone
+        let mut config = Config {
+            message_time_format: "%s".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(
+            lock_message(&config).lockout(TimeDelta::seconds(90)),
+            Some("Account locked until 1704115805.".to_string())
+        );
+        assert_eq!(
+            countdown_message(&lock_message(&config), TimeDelta::seconds(90), None),
+            Some("Account locked! Unlocking in 1 minute and 30 seconds.".to_string())
+        );
+
+        // translated defaults
+        config.locale = "de".to_string();
+        assert_eq!(
+            lock_message(&config).lockout(TimeDelta::seconds(90)),
+            Some("Konto gesperrt bis 1704115805.".to_string())
+        );
+        assert_eq!(
+            countdown_message(&lock_message(&config), TimeDelta::seconds(30), None),
+            Some("Konto gesperrt! Entsperrung in 30 Sekunden.".to_string())
+        );
+
+        // every placeholder
+        config.lockout_message = Some("{user} {failures} {remaining} {unlock_time}".to_string());
+        assert_eq!(
+            lock_message(&config).lockout(TimeDelta::seconds(90)),
+            Some("user 7 1 Minute und 30 Sekunden 1704115805".to_string())
+        );
+
+        // literal braces and unknown placeholders
+        config.lockout_message = Some("{{user}} {unknown} {user".to_string());
+        assert_eq!(
+            lock_message(&config).lockout(TimeDelta::seconds(90)),
+            Some("{user} {unknown} {user".to_string())
+        );
+
+        // empty templates suppress the messages
+        config.lockout_message = Some(String::new());
+        config.countdown_message = Some(String::new());
+        assert_eq!(lock_message(&config).lockout(TimeDelta::seconds(90)), None);
+        assert_eq!(
+            countdown_message(&lock_message(&config), TimeDelta::seconds(90), None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_init_authramp() {
+        let temp_dir = tempdir::TempDir::new("test_init_authramp").unwrap();
+        let conf_file_path = temp_dir.path().join("authramp.conf");
+        std::fs::write(
+            &conf_file_path,
+            format!(
+                "[Configuration]\ntally_dir = {:?}\neven_deny_root = true\nexempt_services = [\"cron\"]\n",
+                temp_dir.path().join("tally")
+            ),
+        )
+        .unwrap();
+        let conf = CString::new(format!("conf={}", conf_file_path.display())).unwrap();
+        let authfail = CString::new("authfail").unwrap();
+        let args = [conf.as_c_str(), authfail.as_c_str()];
+        let pam_h = |service: &str| {
+            let mut pam_h = MockPamHandle {
+                user: Some("root".to_string()),
+                ..MockPamHandle::default()
+            };
+            pam_h
+                .items
+                .insert(ItemType::Service, CString::new(service).unwrap());
+            pam_h
+        };
+
+        // the user and the service are read from the handle, the failure is recorded
+        let mut sshd = pam_h("sshd");
+        assert_eq!(
+            init_authramp(&mut sshd, &args, 0, "auth", authenticate),
+            Err(PamResultCode::PAM_SUCCESS)
+        );
+        assert_eq!(sshd.log_field("AUTHRAMP_USER").as_deref(), Some("root"));
+        assert_eq!(sshd.get_data::<i32>(TRANSACTION_MARKER).unwrap(), Some(&1));
+        assert!(temp_dir.path().join("tally").join("root").exists());
+
+        // exempt services are skipped before the tally is touched
+        let mut cron = pam_h("cron");
+        assert_eq!(
+            init_authramp(&mut cron, &args, 0, "auth", authenticate),
+            Ok(PamResultCode::PAM_SUCCESS)
+        );
+        assert!(cron
+            .logs
+            .borrow()
+            .contains(&"Service \"cron\" is exempt. Skipping the auth hook.".to_string()));
+        assert_eq!(cron.get_data::<i32>(TRANSACTION_MARKER).unwrap(), None);
+
+        // without a user the lookup fails according to fail_mode
+        let mut anonymous = MockPamHandle::default();
+        assert!(init_authramp(&mut anonymous, &args, 0, "auth", authenticate).is_err());
+    }
+
+    #[test]
+    fn test_settings_cache() {
+        let temp_dir = tempdir::TempDir::new("test_settings_cache").unwrap();
+        let conf_file_path = temp_dir.path().join("authramp.conf");
+        std::fs::write(&conf_file_path, "[Configuration]\nfree_tries = 7\n").unwrap();
+        let conf = CString::new(format!("conf={}", conf_file_path.display())).unwrap();
+        let args = [conf.as_c_str(), c"conf_missing=deny"];
+        let mut pam_h = MockPamHandle {
+            user: Some("root".to_string()),
+            ..MockPamHandle::default()
+        };
+
+        let settings = Settings::build(None, &args, 0, "auth", Some(&mut pam_h)).unwrap();
+        assert_eq!(settings.config.free_tries, 7);
+
+        // later hooks of the transaction don't read the file, not even an invalid one
+        std::fs::write(&conf_file_path, "[Configuration]\nfree_tries = = 3\n").unwrap();
+        pam_h.logs.borrow_mut().clear();
+        let settings = Settings::build(None, &args, 0, "account", Some(&mut pam_h)).unwrap();
+        assert_eq!(settings.config.free_tries, 7);
+        assert!(
+            !pam_h
+                .logs
+                .borrow()
+                .iter()
+                .any(|log| log.contains("Ignoring")),
+            "{:?}",
+            pam_h.logs.borrow()
+        );
+
+        // a new transaction reads it
+        let mut pam_h = MockPamHandle {
+            user: Some("root".to_string()),
+            ..MockPamHandle::default()
+        };
+        assert!(Settings::build(None, &args, 0, "auth", Some(&mut pam_h)).is_err());
     }
 }
@@ -0,0 +1,170 @@
+//! # Mock PAM Handle
+//!
+//! The `mock` module provides `MockPamHandle`, an implementation of `PamApi` that records what
+//! the module logic does instead of talking to libpam. Functions that are generic over `PamApi`
+//! can be unit tested with it, without installing the module and running a PAM transaction.
+//!
+//! ## License
+//!
+//! pam-authramp
+//! Copyright (C) 2023 github.com/34N0
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+
+use libc::c_int;
+use pam::items::{Item, ItemType};
+use pam::{LogLevel, PamApi, PamMessageStyle, PamResult, PamResultCode};
+
+/// Records the calls of the module logic to the PAM handle.
+#[derive(Default)]
+pub struct MockPamHandle {
+    /// User name returned by `get_user`, `PAM_USER_UNKNOWN` if not set
+    pub user: Option<String>,
+    /// String items like the service, `PAM_USER` takes precedence over `user` if set
+    pub items: HashMap<ItemType, CString>,
+    /// Result code of a failing conversation, messages are delivered if not set
+    pub conv_error: Option<PamResultCode>,
+    /// Logged messages
+    pub logs: RefCell<Vec<String>>,
+    /// Messages passed to the conversation, in the order they were sent
+    pub messages: RefCell<Vec<(PamMessageStyle, String)>>,
+    /// Variables set in the PAM environment, as `NAME=value`
+    pub env: RefCell<Vec<String>>,
+    /// Requested fail delays in microseconds
+    pub fail_delays: RefCell<Vec<u32>>,
+    /// Structured log fields, as `(name, value)`
+    pub log_fields: RefCell<Vec<(String, String)>>,
+    /// Module data stored with `set_data`
+    pub data: HashMap<String, Box<dyn Any>>,
+}
+
+impl MockPamHandle {
+    /// Looks up a variable set in the PAM environment.
+    ///
+    /// # Returns
+    /// The value of the last `NAME=value` set for `name`, `None` if it wasn't set
+    pub fn getenv(&self, name: &str) -> Option<String> {
+        self.env.borrow().iter().rev().find_map(|name_value| {
+            name_value
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('='))
+                .map(str::to_string)
+        })
+    }
+
+    /// Looks up a structured log field set with `set_log_field`.
+    ///
+    /// # Returns
+    /// The last value set for `name`, `None` if it wasn't set
+    pub fn log_field(&self, name: &str) -> Option<String> {
+        self.log_fields
+            .borrow()
+            .iter()
+            .rev()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.clone())
+    }
+}
+
+impl PamApi for MockPamHandle {
+    fn get_user(&self, _prompt: Option<&str>) -> PamResult<String> {
+        self.user.clone().ok_or(PamResultCode::PAM_USER_UNKNOWN)
+    }
+
+    fn get_cached_user(&mut self, prompt: Option<&str>) -> PamResult<String> {
+        match self.items.get(&ItemType::User) {
+            Some(user) => Ok(user.to_string_lossy().into_owned()),
+            None => self.get_user(prompt),
+        }
+    }
+
+    fn get_item<T: Item>(&self) -> PamResult<Option<T>> {
+        // only string items are stored, the conversation is the only other item
+        if T::type_id() == ItemType::Conv {
+            return Ok(None);
+        }
+        Ok(self
+            .items
+            .get(&T::type_id())
+            .map(|value| unsafe { T::from_raw(value.as_ptr().cast()) }))
+    }
+
+    fn set_item<T: Item>(&mut self, item: T) -> PamResult<()> {
+        if T::type_id() == ItemType::Conv {
+            return Err(PamResultCode::PAM_BAD_ITEM);
+        }
+        let value = unsafe { CStr::from_ptr(item.into_raw().cast()) }.to_owned();
+        self.items.insert(T::type_id(), value);
+        Ok(())
+    }
+
+    fn get_data<T: 'static>(&self, key: &str) -> PamResult<Option<&T>> {
+        Ok(self.data.get(key).and_then(|data| data.downcast_ref()))
+    }
+
+    fn set_data<T: 'static>(&mut self, key: &str, data: T) -> PamResult<()> {
+        self.data.insert(key.to_string(), Box::new(data));
+        Ok(())
+    }
+
+    fn putenv(&self, name_value: &str) -> PamResult<()> {
+        self.env.borrow_mut().push(name_value.to_string());
+        Ok(())
+    }
+
+    fn fail_delay(&self, usec: u32) -> PamResult<()> {
+        self.fail_delays.borrow_mut().push(usec);
+        Ok(())
+    }
+
+    fn log(&self, _level: LogLevel, message: String) -> PamResult<()> {
+        self.logs.borrow_mut().push(message);
+        Ok(())
+    }
+
+    // every level is recorded, to the same log
+
+    fn set_log_level(&mut self, _level: LogLevel) -> PamResult<()> {
+        Ok(())
+    }
+
+    fn set_log_facility(&mut self, _ident: &str, _hook: &str, _facility: c_int) -> PamResult<()> {
+        Ok(())
+    }
+
+    fn set_log_journal(&mut self, _ident: &str) -> PamResult<()> {
+        Ok(())
+    }
+
+    fn set_log_field(&self, name: &str, value: &str) {
+        self.log_fields
+            .borrow_mut()
+            .push((name.to_string(), value.to_string()));
+    }
+
+    fn send_messages(&self, msgs: &[(PamMessageStyle, &str)]) -> PamResult<Vec<Option<CString>>> {
+        if let Some(pam_code) = self.conv_error {
+            return Err(pam_code);
+        }
+        self.messages
+            .borrow_mut()
+            .extend(msgs.iter().map(|(style, msg)| (*style, (*msg).to_string())));
+        Ok(vec![None; msgs.len()])
+    }
+}